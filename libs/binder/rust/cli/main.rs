@@ -0,0 +1,400 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! `binder-cli`: inspect binder services from the command line, in the spirit
+//! of the `service` and `dumpsys` tools.
+//!
+//! ```text
+//! binder-cli list
+//! binder-cli check <name>
+//! binder-cli ping <name>
+//! binder-cli dump <name> [args...]
+//! binder-cli descriptor <name>
+//! binder-cli extension <name>
+//! binder-cli call [--oneway] [--interface <descriptor>] <name> <code> [<type> <value>]...
+//! ```
+//!
+//! Arguments of `call` are written in order, each as a type followed by a
+//! value. The types are `i32`, `i64`, `f32`, `f64`, `string` and `bool`.
+//!
+//! The NDK can only make transactions to services associated with an
+//! interface class, and can only associate a class whose descriptor matches
+//! the one of the service, so `call` needs the interface descriptor of the
+//! service, unless it is already known to this process.
+
+use binder::parcel::ParcelFileDescriptor;
+use binder::{
+    AssociateClass, Binder, IBinder, InterfaceClass, Parcel, Remotable, SpIBinder, StatusCode,
+    TransactionCode, TransactionFlags,
+};
+
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::io::{self, Write};
+use std::process;
+use std::sync::Once;
+
+const USAGE: &str = "\
+usage: binder-cli <command> [args...]
+
+commands:
+  list                              list all registered services
+  check <name>                      check whether a service is registered
+  ping <name>                       ping a service
+  dump <name> [args...]             dump the state of a service
+  descriptor <name>                 print the interface descriptor of a service
+  extension <name>                  check whether a service has an extension
+  call [--oneway] [--interface <descriptor>] <name> <code> [<type> <value>]...
+                                    call a service; types are i32, i64, f32,
+                                    f64, string and bool";
+
+/// Errors reported to the user.
+enum Error {
+    Usage(String),
+    NotFound(String),
+    Binder(String, StatusCode),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            Error::NotFound(name) => write!(f, "service '{}' not found", name),
+            Error::Binder(what, status) => write!(f, "{} failed: {:?}", what, status),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("binder-cli: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let (command, args) = args
+        .split_first()
+        .ok_or_else(|| Error::Usage("missing command".to_owned()))?;
+    match (command.as_str(), args) {
+        ("list", []) => list(),
+        ("check", [name]) => check(name),
+        ("ping", [name]) => ping(name),
+        ("dump", [name, args @ ..]) => dump(name, args),
+        ("descriptor", [name]) => descriptor(name),
+        ("extension", [name]) => extension(name),
+        ("call", args) => call(args),
+        ("help", []) | ("--help", []) => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(Error::Usage(format!(
+            "invalid command '{}'",
+            args_to_string(command, args)
+        ))),
+    }
+}
+
+fn args_to_string(command: &str, args: &[String]) -> String {
+    let mut all = vec![command];
+    all.extend(args.iter().map(String::as_str));
+    all.join(" ")
+}
+
+fn get_service(name: &str) -> Result<SpIBinder> {
+    binder::check_service(name).ok_or_else(|| Error::NotFound(name.to_owned()))
+}
+
+fn list() -> Result<()> {
+    let services = binder::list_services().map_err(|e| Error::Binder("list".to_owned(), e))?;
+    println!("Found {} services:", services.len());
+    for (i, name) in services.iter().enumerate() {
+        println!("{}\t{}", i, name);
+    }
+    Ok(())
+}
+
+fn check(name: &str) -> Result<()> {
+    let found = binder::check_service(name).is_some();
+    println!(
+        "Service {}: {}",
+        name,
+        if found { "found" } else { "not found" }
+    );
+    Ok(())
+}
+
+fn ping(name: &str) -> Result<()> {
+    get_service(name)?
+        .ping_binder()
+        .map_err(|e| Error::Binder("ping".to_owned(), e))?;
+    println!("Service {}: alive", name);
+    Ok(())
+}
+
+fn dump(name: &str, args: &[String]) -> Result<()> {
+    let mut service = get_service(name)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let stdout = io::stdout();
+    // The service writes to the file descriptor directly, so anything
+    // buffered must go out first.
+    let _ = stdout.lock().flush();
+    service
+        .dump(&stdout, &args)
+        .map_err(|e| Error::Binder("dump".to_owned(), e))
+}
+
+fn describe(binder: &mut SpIBinder) -> String {
+    match binder.get_class() {
+        Some(class) => class.get_descriptor(),
+        None => "(unknown, no interface class is associated)".to_owned(),
+    }
+}
+
+fn descriptor(name: &str) -> Result<()> {
+    let mut service = get_service(name)?;
+    println!("{}", describe(&mut service));
+    Ok(())
+}
+
+fn extension(name: &str) -> Result<()> {
+    let extension = get_service(name)?
+        .get_extension()
+        .map_err(|e| Error::Binder("get_extension".to_owned(), e))?;
+    match extension {
+        Some(mut extension) => println!("Service {}: extension {}", name, describe(&mut extension)),
+        None => println!("Service {}: no extension", name),
+    }
+    Ok(())
+}
+
+/// A typed argument of `call`.
+enum Arg {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Bool(bool),
+}
+
+impl Arg {
+    fn parse(ty: &str, value: &str) -> Result<Arg> {
+        let invalid = || Error::Usage(format!("invalid {} argument '{}'", ty, value));
+        Ok(match ty {
+            "i32" => Arg::I32(parse_int(value).ok_or_else(invalid)?),
+            "i64" => Arg::I64(parse_int(value).ok_or_else(invalid)?),
+            "f32" => Arg::F32(value.parse().map_err(|_| invalid())?),
+            "f64" => Arg::F64(value.parse().map_err(|_| invalid())?),
+            "string" => Arg::String(value.to_owned()),
+            "bool" => Arg::Bool(value.parse().map_err(|_| invalid())?),
+            _ => return Err(Error::Usage(format!("unknown argument type '{}'", ty))),
+        })
+    }
+
+    fn write(&self, parcel: &mut Parcel) -> binder::Result<()> {
+        match self {
+            Arg::I32(v) => parcel.write(v),
+            Arg::I64(v) => parcel.write(v),
+            Arg::F32(v) => parcel.write(v),
+            Arg::F64(v) => parcel.write(v),
+            Arg::String(v) => parcel.write(v),
+            Arg::Bool(v) => parcel.write(v),
+        }
+    }
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal integer.
+fn parse_int<T: TryFrom<i128>>(value: &str) -> Option<T> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i128>().ok()?,
+    };
+    T::try_from(if negative { -magnitude } else { magnitude }).ok()
+}
+
+fn call(args: &[String]) -> Result<()> {
+    let mut flags: TransactionFlags = 0;
+    let mut interface = None;
+    let mut args = args;
+    loop {
+        match args {
+            [flag, rest @ ..] if flag == "--oneway" => {
+                flags |= SpIBinder::FLAG_ONEWAY;
+                args = rest;
+            }
+            [flag, descriptor, rest @ ..] if flag == "--interface" => {
+                interface = Some(descriptor.clone());
+                args = rest;
+            }
+            _ => break,
+        }
+    }
+    let (name, code, args) = match args {
+        [name, code, args @ ..] => (name, code, args),
+        _ => {
+            return Err(Error::Usage(
+                "call needs a service name and a code".to_owned(),
+            ))
+        }
+    };
+    let code: TransactionCode = parse_int(code)
+        .ok_or_else(|| Error::Usage(format!("invalid transaction code '{}'", code)))?;
+    if args.len() % 2 != 0 {
+        return Err(Error::Usage(
+            "arguments must be pairs of type and value".to_owned(),
+        ));
+    }
+    let args = args
+        .chunks(2)
+        .map(|pair| Arg::parse(&pair[0], &pair[1]))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut service = get_service(name)?;
+    if service.get_class().is_none() {
+        let descriptor = interface.ok_or_else(|| {
+            Error::Usage(format!(
+                "the interface descriptor of '{}' is unknown, pass it with --interface",
+                name
+            ))
+        })?;
+        if !service.associate_class(CallInterface::class_for(descriptor)) {
+            return Err(Error::Binder(
+                format!("associating '{}' with the interface", name),
+                StatusCode::BAD_TYPE,
+            ));
+        }
+    }
+
+    let reply = service
+        .transact(code, flags, |data| {
+            args.iter().try_for_each(|arg| arg.write(data))
+        })
+        .map_err(|e| Error::Binder("transaction".to_owned(), e))?;
+    if flags & SpIBinder::FLAG_ONEWAY == 0 {
+        print_parcel(&reply).map_err(|e| Error::Binder("reading the reply".to_owned(), e))?;
+    }
+    Ok(())
+}
+
+/// Print the contents of a parcel as 32-bit words, with their offsets, the
+/// bytes as text and the words as integers. Objects are printed as such.
+fn print_parcel(parcel: &Parcel) -> binder::Result<()> {
+    println!("Result: Parcel({} bytes)", parcel.get_data_size());
+    while parcel.remaining() > 0 {
+        let position = parcel.checkpoint();
+        let offset = position.offset();
+        if let Ok(word) = parcel.read::<u32>() {
+            let text: String = word
+                .to_ne_bytes()
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            println!(
+                "  0x{:08x}: {:08x} '{}' {}",
+                offset, word, text, word as i32
+            );
+            continue;
+        }
+        // Plain reads fail on objects, so try reading one instead.
+        parcel.rewind_to(position)?;
+        if let Ok(binder) = parcel.read::<Option<SpIBinder>>() {
+            match binder {
+                Some(mut binder) => {
+                    println!("  0x{:08x}: binder {}", offset, describe(&mut binder))
+                }
+                None => println!("  0x{:08x}: null binder", offset),
+            }
+            continue;
+        }
+        parcel.rewind_to(position)?;
+        match parcel.read::<Option<ParcelFileDescriptor>>()? {
+            Some(_) => println!("  0x{:08x}: file descriptor", offset),
+            None => println!("  0x{:08x}: null file descriptor", offset),
+        }
+    }
+    Ok(())
+}
+
+/// Interface class used to make transactions to services whose descriptor is
+/// given on the command line. Only proxies are ever associated with it.
+struct CallInterface;
+
+/// Descriptor of `CallInterface`, set once before its class is defined.
+static mut CALL_DESCRIPTOR: &str = "";
+
+impl CallInterface {
+    fn class_for(descriptor: String) -> InterfaceClass {
+        static DESCRIPTOR_INIT: Once = Once::new();
+        DESCRIPTOR_INIT.call_once(|| unsafe {
+            // Safety: This assignment is guarded by the `DESCRIPTOR_INIT`
+            // `Once` variable, and happens before the class is defined, which
+            // is the only reader.
+            CALL_DESCRIPTOR = Box::leak(descriptor.into_boxed_str());
+        });
+        Self::get_class()
+    }
+}
+
+impl Remotable for CallInterface {
+    fn get_descriptor() -> &'static str {
+        unsafe {
+            // Safety: `CALL_DESCRIPTOR` is only written once, by `class_for`,
+            // before the class is defined.
+            CALL_DESCRIPTOR
+        }
+    }
+
+    fn on_transact(
+        &self,
+        _code: TransactionCode,
+        _data: &Parcel,
+        _reply: &mut Parcel,
+    ) -> binder::Result<()> {
+        Err(StatusCode::UNKNOWN_TRANSACTION)
+    }
+
+    fn get_class() -> InterfaceClass {
+        static CLASS_INIT: Once = Once::new();
+        static mut CLASS: Option<InterfaceClass> = None;
+
+        CLASS_INIT.call_once(|| unsafe {
+            // Safety: This assignment is guarded by the `CLASS_INIT` `Once`
+            // variable, and therefore is thread-safe, as it can only occur
+            // once.
+            CLASS = Some(InterfaceClass::new::<Binder<CallInterface>>());
+        });
+        unsafe {
+            // Safety: The `CLASS` variable can only be mutated once, above,
+            // and is subsequently safe to read from any thread.
+            CLASS.unwrap()
+        }
+    }
+}
@@ -16,20 +16,25 @@
 
 //! Trait definitions for binder objects
 
-use crate::error::{status_t, Result, StatusCode};
-use crate::parcel::Parcel;
+use crate::error::{status_t, Result, Status, StatusCode};
+use crate::parcel::{Deserialize, Parcel, SerializeArgs};
 use crate::proxy::{DeathRecipient, SpIBinder, WpIBinder};
 use crate::sys;
 
+use std::any::TypeId;
 use std::borrow::Borrow;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::ffi::{c_void, CStr, CString};
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::os::raw::c_char;
 use std::os::unix::io::AsRawFd;
 use std::ptr;
+use std::sync::Mutex;
+use std::time::Duration;
 
 /// Binder action to perform.
 ///
@@ -49,11 +54,52 @@ pub type TransactionFlags = u32;
 /// interfaces) must implement this trait.
 ///
 /// This is equivalent `IInterface` in C++.
-pub trait Interface: Send {
+///
+/// Interfaces are `Sync`, so a [`Strong`] reference to an interface can be
+/// shared between threads, for example in an `Arc`.
+pub trait Interface: Send + Sync {
     /// Convert this binder object into a generic [`SpIBinder`] reference.
     fn as_binder(&self) -> SpIBinder {
         panic!("This object was not a Binder object and cannot be converted into an SpIBinder.")
     }
+
+    /// The type of the object implementing the interface, for
+    /// [`downcast_interface`]. This can't be overridden, so the downcast can
+    /// rely on it.
+    #[doc(hidden)]
+    fn interface_type_id(&self, _: private::Internal) -> TypeId
+    where
+        Self: 'static,
+    {
+        TypeId::of::<Self>()
+    }
+}
+
+mod private {
+    /// Prevents implementations of [`Interface`](super::Interface) from
+    /// overriding `interface_type_id`.
+    #[derive(Debug)]
+    pub struct Internal;
+}
+
+/// Returns `object` as a `T`, if that is its concrete type.
+///
+/// Used by [`declare_binder_interface!`] to implement
+/// [`FromIBinder::downcast_local`].
+#[doc(hidden)]
+pub fn downcast_interface<T, I>(object: &I) -> Option<&T>
+where
+    T: 'static,
+    I: Interface + ?Sized + 'static,
+{
+    if object.interface_type_id(private::Internal) != TypeId::of::<T>() {
+        return None;
+    }
+    unsafe {
+        // Safety: `interface_type_id` can't be overridden, so it returns the
+        // type of the object behind `object`, which is `T`.
+        Some(&*(object as *const I as *const T))
+    }
 }
 
 /// A local service that can be remotable via Binder.
@@ -73,6 +119,13 @@ pub trait Remotable: Send + Sync {
     /// the same between all implementations of that interface.
     fn get_descriptor() -> &'static str;
 
+    /// Whether each object has its own interface descriptor, set by the class
+    /// it was created with, such as the classes of
+    /// [`InterfaceClass::runtime_class`]. The descriptor of these objects is
+    /// read from their class instead of [`get_descriptor`](Self::get_descriptor).
+    #[doc(hidden)]
+    const HAS_RUNTIME_CLASS: bool = false;
+
     /// Handle and reply to a request to invoke a transaction on this object.
     ///
     /// `reply` may be [`None`] if the sender does not expect a reply.
@@ -130,6 +183,82 @@ pub trait IBinder {
         input_callback: F,
     ) -> Result<Parcel>;
 
+    /// Perform a generic operation with the object, giving up on the reply
+    /// after `timeout`.
+    ///
+    /// This is [`transact`](IBinder::transact), except that it fails with
+    /// `StatusCode::TIMED_OUT` if no reply arrived within `timeout`. A
+    /// transaction cannot be cancelled once sent, so it is performed on a
+    /// separate thread, which drops the reply if it arrives too late. The
+    /// calling thread is free to make other calls as soon as this returns.
+    /// One-way transactions do not wait for a reply and are sent directly.
+    ///
+    /// Each call spawns a thread, which lives until the remote object replies,
+    /// however long after the timeout that is. A remote object that never
+    /// replies keeps its threads forever. To bound the damage, at most 16 such
+    /// threads run at once in a process, and further calls fail with
+    /// `StatusCode::WOULD_BLOCK` until one of them finishes.
+    ///
+    /// A proxy can apply a default timeout to all of its calls by keeping it
+    /// in a field declared with [`declare_binder_interface!`]:
+    ///
+    /// ```ignore
+    /// declare_binder_interface! {
+    ///     IFoo["android.foo.IFoo"] {
+    ///         native: BnFoo(on_transact),
+    ///         proxy: BpFoo {
+    ///             timeout: Duration = Duration::from_secs(5)
+    ///         },
+    ///     }
+    /// }
+    ///
+    /// impl IFoo for BpFoo {
+    ///     fn foo(&self) -> binder::Result<i32> {
+    ///         let reply = self.binder.transact_with_timeout(FOO, 0, self.timeout, |_| Ok(()))?;
+    ///         reply.read()
+    ///     }
+    /// }
+    /// ```
+    fn transact_with_timeout<F: FnOnce(&mut Parcel) -> Result<()>>(
+        &self,
+        code: TransactionCode,
+        flags: TransactionFlags,
+        timeout: Duration,
+        input_callback: F,
+    ) -> Result<Parcel>;
+
+    /// Call a method of the object with typed arguments and return value.
+    ///
+    /// `args` is a tuple of the arguments, which are written to the request
+    /// in order, or `()` for methods without arguments. If this binder is
+    /// associated with an [`InterfaceClass`], `libbinder_ndk` writes the
+    /// interface token before the arguments.
+    ///
+    /// The reply starts with the exception header written by the callee,
+    /// read like `AParcel_readStatusHeader`. An exception is returned as the
+    /// error, otherwise the return value follows the header. Use `()` as
+    /// `Ret` for methods without a return value.
+    ///
+    /// ```ignore
+    /// let sum: i32 = binder.call(ADD_TRANSACTION, &(1i32, 2i32))?;
+    /// ```
+    fn call<Args, Ret>(
+        &self,
+        code: TransactionCode,
+        args: &Args,
+    ) -> std::result::Result<Ret, Status>
+    where
+        Args: SerializeArgs + ?Sized,
+        Ret: Deserialize,
+    {
+        let reply = self.transact(code, 0, |data| args.serialize_args(data))?;
+        let status: Status = reply.read()?;
+        if !status.is_ok() {
+            return Err(status);
+        }
+        Ok(reply.read()?)
+    }
+
     /// Register the recipient for a notification if this binder
     /// goes away. If this binder object unexpectedly goes away
     /// (typically because its hosting process has been killed),
@@ -167,6 +296,14 @@ pub trait IBinder {
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct InterfaceClass(*const sys::AIBinder_Class);
 
+/// The type implementing the callbacks of each class, by class pointer.
+/// Classes are never freed.
+static CLASS_TYPES: Mutex<Option<HashMap<usize, TypeId>>> = Mutex::new(None);
+
+/// Classes of [`InterfaceClass::runtime_class`], by the type implementing
+/// their callbacks and their interface descriptor.
+static RUNTIME_CLASSES: Mutex<Option<HashMap<(TypeId, String), usize>>> = Mutex::new(None);
+
 impl InterfaceClass {
     /// Get a Binder NDK `AIBinder_Class` pointer for this object type.
     ///
@@ -174,8 +311,34 @@ impl InterfaceClass {
     /// multiple times for the same type will result in distinct class
     /// pointers. A static getter for this value is implemented in
     /// [`declare_binder_interface!`].
-    pub fn new<I: InterfaceClassMethods>() -> InterfaceClass {
-        let descriptor = CString::new(I::get_descriptor()).unwrap();
+    pub fn new<I: InterfaceClassMethods + 'static>() -> InterfaceClass {
+        Self::new_with_descriptor::<I>(I::get_descriptor())
+    }
+
+    /// The class with the callbacks of `I` and the given interface descriptor,
+    /// for objects whose descriptor is only known at runtime, such as
+    /// [`DynamicService`](crate::DynamicService). `I` must set
+    /// [`Remotable::HAS_RUNTIME_CLASS`].
+    ///
+    /// Classes are never freed, so a single class is defined for each type and
+    /// descriptor, and returned by later calls.
+    pub(crate) fn runtime_class<I: InterfaceClassMethods + 'static>(
+        descriptor: &str,
+    ) -> InterfaceClass {
+        let mut classes = RUNTIME_CLASSES.lock().unwrap_or_else(|e| e.into_inner());
+        let ptr = *classes
+            .get_or_insert_with(HashMap::new)
+            .entry((TypeId::of::<I>(), descriptor.to_owned()))
+            .or_insert_with(|| Self::new_with_descriptor::<I>(descriptor).0 as usize);
+        InterfaceClass(ptr as *const sys::AIBinder_Class)
+    }
+
+    /// Define a new class with the callbacks of `I` but the given interface
+    /// descriptor.
+    fn new_with_descriptor<I: InterfaceClassMethods + 'static>(
+        descriptor: &str,
+    ) -> InterfaceClass {
+        let descriptor = CString::new(descriptor).unwrap();
         let ptr = unsafe {
             // Safety: `AIBinder_Class_define` expects a valid C string, and
             // three valid callback functions, all non-null pointers. The C
@@ -198,9 +361,25 @@ impl InterfaceClass {
             sys::AIBinder_Class_setHandleShellCommand(class, None);
             class
         };
+        CLASS_TYPES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashMap::new)
+            .insert(ptr as usize, TypeId::of::<I>());
         InterfaceClass(ptr)
     }
 
+    /// Whether the objects of this class are `I`, which holds the Rust object
+    /// in the user data of its binders.
+    pub(crate) fn has_type<I: InterfaceClassMethods + 'static>(&self) -> bool {
+        CLASS_TYPES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .and_then(|types| types.get(&(self.0 as usize)))
+            .is_some_and(|&type_id| type_id == TypeId::of::<I>())
+    }
+
     /// Construct an `InterfaceClass` out of a raw, non-null `AIBinder_Class`
     /// pointer.
     ///
@@ -248,6 +427,22 @@ impl<I: FromIBinder + ?Sized> Strong<I> {
     pub fn downgrade(this: &Strong<I>) -> Weak<I> {
         Weak::new(this)
     }
+
+    /// Returns the object of type `T` implementing the interface, if this is
+    /// a local service, without making a transaction.
+    ///
+    /// Services hosted by the [fake service manager](crate::testing) are used
+    /// through proxies, so this returns `None` for them.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let service = BnFoo::new_binder(MyFoo::default());
+    /// let my_foo: &MyFoo = service.as_local().unwrap();
+    /// ```
+    pub fn as_local<T: 'static>(&self) -> Option<&T> {
+        I::downcast_local(&self.0)
+    }
 }
 
 impl<I: FromIBinder + ?Sized> Clone for Strong<I> {
@@ -305,6 +500,12 @@ impl<I: FromIBinder + ?Sized> PartialEq for Strong<I> {
 
 impl<I: FromIBinder + ?Sized> Eq for Strong<I> {}
 
+impl<I: FromIBinder + ?Sized> Hash for Strong<I> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_binder().hash(state)
+    }
+}
+
 /// Weak reference to a binder object
 #[derive(Debug)]
 pub struct Weak<I: FromIBinder + ?Sized> {
@@ -361,6 +562,12 @@ impl<I: FromIBinder + ?Sized> PartialEq for Weak<I> {
 
 impl<I: FromIBinder + ?Sized> Eq for Weak<I> {}
 
+impl<I: FromIBinder + ?Sized> Hash for Weak<I> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.weak_binder.hash(state)
+    }
+}
+
 /// Create a function implementing a static getter for an interface class.
 ///
 /// Each binder interface (i.e. local [`Remotable`] service or remote proxy
@@ -491,6 +698,15 @@ pub trait FromIBinder: Interface {
     /// Returns a trait object for the `Self` interface if this object
     /// implements that interface.
     fn try_from(ibinder: SpIBinder) -> Result<Strong<Self>>;
+
+    /// Returns `this` as the object of type `T` implementing the interface,
+    /// if `this` is a local service.
+    ///
+    /// This is implemented by [`declare_binder_interface!`] for the services
+    /// it creates, and returns `None` by default.
+    fn downcast_local<T: 'static>(_this: &Self) -> Option<&T> {
+        None
+    }
 }
 
 /// Trait for transparent Rust wrappers around android C++ native types.
@@ -654,7 +870,7 @@ macro_rules! declare_binder_interface {
                 $descriptor
             }
 
-            fn from_binder(mut binder: $crate::SpIBinder) -> $crate::Result<Self> {
+            fn from_binder(binder: $crate::SpIBinder) -> $crate::Result<Self> {
                 Ok(Self { binder, $($fname: $finit),* })
             }
         }
@@ -713,6 +929,17 @@ macro_rules! declare_binder_interface {
                 use $crate::AssociateClass;
 
                 let existing_class = ibinder.get_class();
+                if $crate::rpc::is_proxy(&ibinder) {
+                    // Objects received over RPC are local objects of a class
+                    // per descriptor, which forward transactions to the peer.
+                    // They can't be associated with our class, so they are
+                    // always used through a proxy.
+                    let descriptor = existing_class.map(|class| class.get_descriptor());
+                    if descriptor.as_deref() != Some(<$native as $crate::Remotable>::get_descriptor()) {
+                        return Err($crate::StatusCode::BAD_TYPE.into());
+                    }
+                    return Ok($crate::Strong::new(Box::new(<$proxy as $crate::Proxy>::from_binder(ibinder)?)));
+                }
                 if let Some(class) = existing_class {
                     if class != <$native as $crate::Remotable>::get_class() &&
                         class.get_descriptor() == <$native as $crate::Remotable>::get_descriptor()
@@ -731,16 +958,25 @@ macro_rules! declare_binder_interface {
                         std::convert::TryFrom::try_from(ibinder.clone());
                     if let Ok(service) = service {
                         // We were able to associate with our expected class and
-                        // the service is local.
-                        return Ok($crate::Strong::new(Box::new(service)));
-                    } else {
-                        // Service is remote
-                        return Ok($crate::Strong::new(Box::new(<$proxy as $crate::Proxy>::from_binder(ibinder)?)));
+                        // the service is local. Services hosted by the fake
+                        // service manager are still used through a proxy, so
+                        // that calls to them go through transact.
+                        if !$crate::testing::is_intercepted(&ibinder) {
+                            return Ok($crate::Strong::new(Box::new(service)));
+                        }
                     }
+                    // Service is remote
+                    return Ok($crate::Strong::new(Box::new(<$proxy as $crate::Proxy>::from_binder(ibinder)?)));
                 }
 
                 Err($crate::StatusCode::BAD_TYPE.into())
             }
+
+            fn downcast_local<T: 'static>(this: &Self) -> Option<&T> {
+                // Local services are always a `Binder<$native>`, see above.
+                let service: &$crate::Binder<$native> = $crate::downcast_interface(this)?;
+                $crate::downcast_interface(&*service.0)
+            }
         }
 
         impl $crate::parcel::Serialize for dyn $interface + '_
@@ -820,3 +1056,111 @@ macro_rules! declare_binder_enum {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native::Binder;
+    use crate::proxy::Proxy;
+    use crate::DynamicService;
+
+    pub trait IToken: Interface {}
+
+    declare_binder_interface! {
+        IToken["android.binder.test.IToken"] {
+            native: BnToken(on_transact),
+            proxy: BpToken,
+        }
+    }
+
+    fn on_transact(
+        _service: &dyn IToken,
+        _code: TransactionCode,
+        _data: &Parcel,
+        _reply: &mut Parcel,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    impl IToken for BpToken {}
+
+    impl IToken for Binder<BnToken> {}
+
+    struct Token(i32);
+
+    impl Interface for Token {}
+
+    impl IToken for Token {}
+
+    #[test]
+    fn test_downcast_local() {
+        let token = BnToken::new_binder(Token(7));
+        assert_eq!(token.as_local::<Token>().map(|token| token.0), Some(7));
+        assert!(token.as_local::<i32>().is_none());
+        assert!(token.as_local::<Binder<BnToken>>().is_none());
+
+        let proxy: Strong<dyn IToken> =
+            Strong::new(Box::new(BpToken::from_binder(token.as_binder()).unwrap()));
+        assert!(proxy.as_local::<Token>().is_none());
+
+        let binder = token.as_binder();
+        assert!(binder.downcast_local::<BnToken>().is_some());
+        assert!(binder.downcast_local::<()>().is_none());
+        assert!(Binder::new(()).as_binder().downcast_local::<()>().is_some());
+
+        let service = DynamicService::builder("android.binder.test.IToken")
+            .build()
+            .as_binder();
+        assert_eq!(
+            service
+                .downcast_local::<DynamicService>()
+                .map(DynamicService::descriptor),
+            Some("android.binder.test.IToken")
+        );
+        assert!(service.downcast_local::<BnToken>().is_none());
+    }
+
+    #[test]
+    fn test_share_strong_between_threads() {
+        use std::collections::HashSet;
+        use std::sync::Arc;
+        use std::thread;
+
+        let token = BnToken::new_binder(Token(7));
+        let proxy: Strong<dyn IToken> =
+            Strong::new(Box::new(BpToken::from_binder(token.as_binder()).unwrap()));
+        let other = BnToken::new_binder(Token(8));
+
+        // The proxy and the service are the same binder object
+        let tokens: HashSet<Strong<dyn IToken>> = vec![token.clone(), proxy.clone(), other.clone()]
+            .into_iter()
+            .collect();
+        assert_eq!(tokens.len(), 2);
+        let weaks: HashSet<Weak<dyn IToken>> =
+            vec![Strong::downgrade(&token), Strong::downgrade(&proxy)]
+                .into_iter()
+                .collect();
+        assert_eq!(weaks.len(), 1);
+
+        let tokens = Arc::new(tokens);
+        let proxy = Arc::new(proxy);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let tokens = Arc::clone(&tokens);
+                let proxy = Arc::clone(&proxy);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        assert!(tokens.contains(&*proxy));
+                        let weak = Strong::downgrade(&proxy);
+                        assert_eq!(weak.upgrade().as_ref(), Ok(&*proxy));
+                        assert_eq!(proxy.as_local::<Token>().map(|token| token.0), None);
+                        assert!(proxy.as_binder().is_binder_alive());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Services whose transactions are handled by closures registered at runtime.
+
+use crate::binder::{Interface, InterfaceClass, Remotable, TransactionCode};
+use crate::error::{Result, StatusCode};
+use crate::native::Binder;
+use crate::parcel::Parcel;
+
+use std::collections::HashMap;
+
+type Handler = Box<dyn Fn(&Parcel, &mut Parcel) -> Result<()> + Send + Sync>;
+type Fallback = Box<dyn Fn(TransactionCode, &Parcel, &mut Parcel) -> Result<()> + Send + Sync>;
+
+/// A service handling each transaction code with a closure, for services
+/// whose interface is only known at runtime, such as plugins, test doubles
+/// and bridges to other IPC systems.
+///
+/// The service has an [`InterfaceClass`] for its descriptor, shared with the
+/// other dynamic services with the same descriptor, so `libbinder_ndk` writes
+/// and checks the interface token of its transactions like for AIDL services.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use binder::{DynamicService, StatusCode};
+/// let service = DynamicService::builder("android.example.ICounter")
+///     .on(1, |data, reply| {
+///         let value: i32 = data.read()?;
+///         reply.write(&(value + 1))
+///     })
+///     .fallback(|_code, _data, _reply| Err(StatusCode::UNKNOWN_TRANSACTION))
+///     .build();
+/// binder::add_service("counter", binder::Interface::as_binder(&service))
+///     .expect("Failed to register service");
+/// ```
+pub struct DynamicService {
+    descriptor: String,
+    handlers: HashMap<TransactionCode, Handler>,
+    fallback: Option<Fallback>,
+}
+
+impl DynamicService {
+    /// Start building a service for the interface `descriptor`.
+    pub fn builder(descriptor: &str) -> DynamicServiceBuilder {
+        DynamicServiceBuilder {
+            service: DynamicService {
+                descriptor: descriptor.to_owned(),
+                handlers: HashMap::new(),
+                fallback: None,
+            },
+        }
+    }
+
+    /// The interface descriptor of the service.
+    pub fn descriptor(&self) -> &str {
+        &self.descriptor
+    }
+}
+
+impl Interface for DynamicService {}
+
+impl Remotable for DynamicService {
+    fn get_descriptor() -> &'static str {
+        // Dynamic services use a class per descriptor.
+        ""
+    }
+
+    const HAS_RUNTIME_CLASS: bool = true;
+
+    fn on_transact(&self, code: TransactionCode, data: &Parcel, reply: &mut Parcel) -> Result<()> {
+        match (self.handlers.get(&code), &self.fallback) {
+            (Some(handler), _) => handler(data, reply),
+            (None, Some(fallback)) => fallback(code, data, reply),
+            (None, None) => Err(StatusCode::UNKNOWN_TRANSACTION),
+        }
+    }
+
+    binder_fn_get_class!(Binder::<Self>);
+}
+
+/// Builder for a [`DynamicService`], created by [`DynamicService::builder`].
+pub struct DynamicServiceBuilder {
+    service: DynamicService,
+}
+
+impl DynamicServiceBuilder {
+    /// Handle transactions with `code` with `handler`, replacing any handler
+    /// previously set for `code`.
+    pub fn on<F>(mut self, code: TransactionCode, handler: F) -> Self
+    where
+        F: Fn(&Parcel, &mut Parcel) -> Result<()> + Send + Sync + 'static,
+    {
+        self.service.handlers.insert(code, Box::new(handler));
+        self
+    }
+
+    /// Handle transactions without a handler with `fallback`. Without a
+    /// fallback, they fail with `UNKNOWN_TRANSACTION`.
+    pub fn fallback<F>(mut self, fallback: F) -> Self
+    where
+        F: Fn(TransactionCode, &Parcel, &mut Parcel) -> Result<()> + Send + Sync + 'static,
+    {
+        self.service.fallback = Some(Box::new(fallback));
+        self
+    }
+
+    /// Create the service.
+    pub fn build(self) -> Binder<DynamicService> {
+        let class =
+            InterfaceClass::runtime_class::<Binder<DynamicService>>(&self.service.descriptor);
+        Binder::new_with_class(self.service, class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binder::IBinder;
+    use crate::interceptor::{Direction, LatencyHistograms};
+    use crate::proxy::SpIBinder;
+    use crate::sys;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_dynamic_service() {
+        let service = DynamicService::builder("android.binder.test.IDynamic")
+            .on(1, |data, reply| {
+                let value: i32 = data.read()?;
+                reply.write(&(value + 1))
+            })
+            .fallback(|code, _data, reply| reply.write(&(code as i32)))
+            .build();
+        assert_eq!(service.descriptor(), "android.binder.test.IDynamic");
+        let histograms = Arc::new(LatencyHistograms::new());
+        service.add_interceptor(histograms.clone());
+
+        let mut binder = service.as_binder();
+        assert_eq!(
+            binder
+                .get_class()
+                .map(|class| class.get_descriptor())
+                .as_deref(),
+            Some("android.binder.test.IDynamic")
+        );
+        let reply = binder.transact(1, 0, |data| data.write(&41i32)).unwrap();
+        assert_eq!(reply.read::<i32>(), Ok(42));
+        let reply = binder.transact(5, 0, |_| Ok(())).unwrap();
+        assert_eq!(reply.read::<i32>(), Ok(5));
+        // Interceptors see the descriptor of the class of the service
+        assert_eq!(
+            histograms.count("android.binder.test.IDynamic", Direction::Incoming, 1),
+            1
+        );
+
+        // Services with the same descriptor share their class
+        let other = DynamicService::builder("android.binder.test.IDynamic")
+            .build()
+            .as_binder();
+        assert_eq!(
+            other.transact(1, 0, |_| Ok(())).err(),
+            Some(StatusCode::UNKNOWN_TRANSACTION)
+        );
+        assert_eq!(
+            <*const sys::AIBinder_Class>::from(other.clone().get_class().unwrap()),
+            <*const sys::AIBinder_Class>::from(binder.get_class().unwrap())
+        );
+
+        // The service can be sent in parcels
+        let mut parcel = Parcel::new_for_test(&mut binder).unwrap();
+        let start = parcel.checkpoint();
+        parcel.write(&other).unwrap();
+        parcel.rewind_to(start).unwrap();
+        assert_eq!(parcel.read::<SpIBinder>(), Ok(other));
+    }
+}
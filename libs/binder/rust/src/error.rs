@@ -14,12 +14,19 @@
  * limitations under the License.
  */
 
+#[cfg(feature = "ndk")]
 use crate::binder::AsNative;
+#[cfg(feature = "memory_parcel")]
+use crate::parcel::StatusHeader;
 use crate::sys;
 
 use std::error;
 use std::ffi::CStr;
+#[cfg(feature = "memory_parcel")]
+use std::ffi::CString;
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+#[cfg(feature = "ndk")]
+use std::io;
 use std::result;
 
 pub use sys::binder_status_t as status_t;
@@ -43,6 +50,16 @@ pub fn status_result(status: status_t) -> Result<()> {
     }
 }
 
+/// Convert an I/O error into a status code. `status_t` values for errors
+/// returned by system calls are negated `errno` values.
+#[cfg(feature = "ndk")]
+pub(crate) fn status_from_io_error(error: io::Error) -> StatusCode {
+    match parse_status_code(-error.raw_os_error().unwrap_or(0)) {
+        StatusCode::OK => StatusCode::UNKNOWN_ERROR,
+        status => status,
+    }
+}
+
 fn parse_status_code(code: i32) -> StatusCode {
     match code {
         e if e == StatusCode::OK as i32 => StatusCode::OK,
@@ -94,8 +111,10 @@ fn parse_exception_code(code: i32) -> ExceptionCode {
 /// track of and chain binder errors along with service specific errors.
 ///
 /// Used in AIDL transactions to represent failed transactions.
+#[cfg(feature = "ndk")]
 pub struct Status(*mut sys::AStatus);
 
+#[cfg(feature = "ndk")]
 impl Status {
     /// Create a status object representing a successful transaction.
     pub fn ok() -> Self {
@@ -251,6 +270,222 @@ impl Status {
     }
 }
 
+/// Conversions for the in-memory parcel backend, which doesn't use
+/// `libbinder_ndk` to read and write status headers.
+#[cfg(all(feature = "memory_parcel", feature = "ndk"))]
+impl Status {
+    /// The status header for this status, or `None` for a transaction
+    /// failure, which is reported as the result of the write instead of being
+    /// sent.
+    pub(crate) fn to_header(&self) -> Result<Option<StatusHeader>> {
+        let exception = unsafe {
+            // Safety: `Status` always contains a valid `AStatus` pointer.
+            sys::AStatus_getExceptionCode(self.as_native())
+        };
+        if exception == ExceptionCode::TRANSACTION_FAILED as i32 {
+            status_result(self.transaction_error() as i32)?;
+            return Ok(None);
+        }
+        let message = unsafe {
+            // Safety: `Status` always contains a valid `AStatus` pointer, and
+            // `AStatus_getMessage` returns a null-terminated string owned by
+            // the status, which outlives this borrow.
+            CStr::from_ptr(sys::AStatus_getMessage(self.as_native()))
+        };
+        Ok(Some(StatusHeader {
+            exception,
+            message: message.to_string_lossy().into_owned(),
+            service_specific_error: self.service_specific_error(),
+        }))
+    }
+
+    /// Create a status from a status header.
+    pub(crate) fn from_header(header: StatusHeader) -> Status {
+        if header.exception == ExceptionCode::NONE as i32 {
+            return Status::ok();
+        }
+        // Messages with embedded nulls are truncated, as they would be by the
+        // C++ `Status`.
+        let message = header.message.split('\0').next().unwrap_or_default();
+        let message = CString::new(message).unwrap();
+        if header.exception == ExceptionCode::SERVICE_SPECIFIC as i32 {
+            return Status::new_service_specific_error(
+                header.service_specific_error,
+                Some(&message),
+            );
+        }
+        let ptr = unsafe {
+            // Safety: Any exception code is valid for
+            // `AStatus_fromExceptionCodeWithMessage`, and `message` is a
+            // valid C string. The function returns a new, owned `AStatus`.
+            sys::AStatus_fromExceptionCodeWithMessage(header.exception, message.as_ptr())
+        };
+        Self(ptr)
+    }
+}
+
+/// High-level binder status object that encapsulates a standard way to keep
+/// track of and chain binder errors along with service specific errors.
+///
+/// Without `libbinder_ndk`, the status holds the fields of the C++
+/// `android::binder::Status` itself.
+#[cfg(not(feature = "ndk"))]
+pub struct Status {
+    exception: i32,
+    /// The service specific error, or the transaction error of a
+    /// `TRANSACTION_FAILED` exception.
+    error_code: i32,
+    message: String,
+}
+
+#[cfg(not(feature = "ndk"))]
+impl Status {
+    /// Create a status object representing a successful transaction.
+    pub fn ok() -> Self {
+        Self {
+            exception: ExceptionCode::NONE as i32,
+            error_code: StatusCode::OK as i32,
+            message: String::new(),
+        }
+    }
+
+    /// Create a status object from a service specific error
+    pub fn new_service_specific_error(err: i32, message: Option<&CStr>) -> Status {
+        Self {
+            exception: ExceptionCode::SERVICE_SPECIFIC as i32,
+            error_code: err,
+            message: message.map(|m| m.to_string_lossy().into_owned()).unwrap_or_default(),
+        }
+    }
+
+    /// Create a status object from an exception code
+    pub fn new_exception(exception: ExceptionCode, message: Option<&CStr>) -> Status {
+        Self {
+            message: message.map(|m| m.to_string_lossy().into_owned()).unwrap_or_default(),
+            ..exception.into()
+        }
+    }
+
+    /// Returns `true` if this status represents a successful transaction.
+    pub fn is_ok(&self) -> bool {
+        self.exception == ExceptionCode::NONE as i32
+    }
+
+    /// Returns a description of the status, in the format of the C++
+    /// `Status::toString8`.
+    pub fn get_description(&self) -> String {
+        let code = self.exception_code();
+        let detail = match code {
+            ExceptionCode::NONE => return "No error".to_string(),
+            ExceptionCode::SERVICE_SPECIFIC => format!("{}: ", self.error_code),
+            ExceptionCode::TRANSACTION_FAILED => format!("{:?}: ", self.transaction_error()),
+            _ => String::new(),
+        };
+        format!("Status({}, EX_{:?}): '{}{}'", self.exception, code, detail, self.message)
+    }
+
+    /// Returns the exception code of the status.
+    pub fn exception_code(&self) -> ExceptionCode {
+        parse_exception_code(self.exception)
+    }
+
+    /// Return a status code representing a transaction failure, or
+    /// `StatusCode::OK` if there was no transaction failure.
+    ///
+    /// If this method returns `OK`, the status may still represent a different
+    /// exception or a service specific error. To find out if this transaction
+    /// as a whole is okay, use [`is_ok`](Self::is_ok) instead.
+    pub fn transaction_error(&self) -> StatusCode {
+        match self.exception_code() {
+            ExceptionCode::TRANSACTION_FAILED => parse_status_code(self.error_code),
+            _ => StatusCode::OK,
+        }
+    }
+
+    /// Return a service specific error if this status represents one.
+    ///
+    /// This function will only ever return a non-zero result if
+    /// [`exception_code`](Self::exception_code) returns
+    /// `ExceptionCode::SERVICE_SPECIFIC`. If this function returns 0, the
+    /// status object may still represent a different exception or status. To
+    /// find out if this transaction as a whole is okay, use
+    /// [`is_ok`](Self::is_ok) instead.
+    pub fn service_specific_error(&self) -> i32 {
+        match self.exception_code() {
+            ExceptionCode::SERVICE_SPECIFIC => self.error_code,
+            _ => 0,
+        }
+    }
+
+    /// Calls `op` if the status was ok, otherwise returns an `Err` value of
+    /// `self`.
+    pub fn and_then<T, F>(self, op: F) -> result::Result<T, Status>
+    where
+        F: FnOnce() -> result::Result<T, Status>,
+    {
+        <result::Result<(), Status>>::from(self)?;
+        op()
+    }
+
+    /// The status header for this status, or `None` for a transaction
+    /// failure, which is reported as the result of the write instead of being
+    /// sent.
+    pub(crate) fn to_header(&self) -> Result<Option<StatusHeader>> {
+        if self.exception_code() == ExceptionCode::TRANSACTION_FAILED {
+            status_result(self.transaction_error() as i32)?;
+            return Ok(None);
+        }
+        Ok(Some(StatusHeader {
+            exception: self.exception,
+            message: self.message.clone(),
+            service_specific_error: self.service_specific_error(),
+        }))
+    }
+
+    /// Create a status from a status header.
+    pub(crate) fn from_header(header: StatusHeader) -> Status {
+        if header.exception == ExceptionCode::NONE as i32 {
+            return Status::ok();
+        }
+        // Messages with embedded nulls are truncated, as they would be by the
+        // C++ `Status`.
+        let message = header.message.split('\0').next().unwrap_or_default();
+        let message = CString::new(message).unwrap();
+        if header.exception == ExceptionCode::SERVICE_SPECIFIC as i32 {
+            return Status::new_service_specific_error(
+                header.service_specific_error,
+                Some(&message),
+            );
+        }
+        // Unknown exception codes become `TRANSACTION_FAILED`, as they do in
+        // `libbinder_ndk`.
+        Status::new_exception(parse_exception_code(header.exception), Some(&message))
+    }
+}
+
+#[cfg(not(feature = "ndk"))]
+impl From<status_t> for Status {
+    fn from(status: status_t) -> Status {
+        let error_code = parse_status_code(status);
+        let exception = match error_code {
+            StatusCode::OK => ExceptionCode::NONE,
+            _ => ExceptionCode::TRANSACTION_FAILED,
+        };
+        Self { exception: exception as i32, error_code: error_code as i32, message: String::new() }
+    }
+}
+
+#[cfg(not(feature = "ndk"))]
+impl From<ExceptionCode> for Status {
+    fn from(code: ExceptionCode) -> Status {
+        let error_code = match code {
+            ExceptionCode::TRANSACTION_FAILED => StatusCode::FAILED_TRANSACTION,
+            _ => StatusCode::OK,
+        };
+        Self { exception: code as i32, error_code: error_code as i32, message: String::new() }
+    }
+}
+
 impl error::Error for Status {}
 
 impl Display for Status {
@@ -293,6 +528,7 @@ impl From<StatusCode> for Status {
     }
 }
 
+#[cfg(feature = "ndk")]
 impl From<status_t> for Status {
     fn from(status: status_t) -> Status {
         let ptr = unsafe {
@@ -305,6 +541,7 @@ impl From<status_t> for Status {
     }
 }
 
+#[cfg(feature = "ndk")]
 impl From<ExceptionCode> for Status {
     fn from(code: ExceptionCode) -> Status {
         let ptr = unsafe {
@@ -335,6 +572,7 @@ impl From<Status> for status_t {
     }
 }
 
+#[cfg(feature = "ndk")]
 impl Drop for Status {
     fn drop(&mut self) {
         unsafe {
@@ -347,6 +585,7 @@ impl Drop for Status {
     }
 }
 
+#[cfg(feature = "ndk")]
 /// # Safety
 ///
 /// `Status` always contains a valid pointer to an `AStatus` object, so we can
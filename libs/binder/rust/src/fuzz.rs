@@ -0,0 +1,105 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Helpers for fuzzing parcel deserialization and binder services.
+//!
+//! These functions turn arbitrary fuzzer input into parcels and transactions.
+//! Deserialization and `on_transact` code must reject malformed input with an
+//! error; a crash or panic while running one of these helpers is a bug.
+//!
+//! The fuzz targets for this crate are in `tests/fuzzers`. They only depend on
+//! `libfuzzer-sys`, so they can be built with Soong (`rust_fuzz`) or used as
+//! `cargo fuzz` targets.
+//!
+//! # Example
+//!
+//! A libFuzzer target checking that `Vec<String>` deserialization handles
+//! hostile input:
+//!
+//! ```ignore
+//! #![no_main]
+//! use libfuzzer_sys::fuzz_target;
+//!
+//! fuzz_target!(|data: &[u8]| {
+//!     let _ = binder::fuzz::deserialize::<Vec<String>>(data);
+//! });
+//! ```
+
+use crate::binder::IBinder;
+use crate::error::{Result, StatusCode};
+use crate::parcel::{Deserialize, Parcel};
+use crate::proxy::SpIBinder;
+
+use std::convert::TryInto;
+
+/// Create a new parcel containing `data`, positioned at the start of the data.
+///
+/// The parcel contains plain data only. Bytes that look like binder objects or
+/// file descriptors are not registered as objects in the parcel, so reading
+/// them fails the same way reading a corrupt parcel would.
+pub fn parcel_from_bytes(data: &[u8]) -> Result<Parcel> {
+    Parcel::new_with_data(data)
+}
+
+/// Read a `D` from a parcel containing `data`.
+pub fn deserialize<D: Deserialize>(data: &[u8]) -> Result<D> {
+    parcel_from_bytes(data)?.read()
+}
+
+/// Send a transaction built from `data` to `binder`, returning the reply.
+///
+/// The first 4 bytes of `data`, in native byte order, are the transaction
+/// code, which lets the fuzzer explore every code the service handles. The
+/// rest of `data` follows the interface token in the transaction data, so a
+/// local service receives it as the argument data in `on_transact`. Input
+/// shorter than 4 bytes is rejected with `StatusCode::BAD_VALUE`.
+pub fn transact(binder: &SpIBinder, data: &[u8]) -> Result<Parcel> {
+    if data.len() < 4 {
+        return Err(StatusCode::BAD_VALUE);
+    }
+    let (code, data) = data.split_at(4);
+    let code = u32::from_ne_bytes(code.try_into().unwrap());
+    binder.transact(code, 0, |input| input.write_words(data))
+}
+
+#[test]
+fn test_parcel_from_bytes() {
+    let mut data = vec![];
+    data.extend_from_slice(&42i32.to_ne_bytes());
+    data.extend_from_slice(&(-1i64).to_ne_bytes());
+    // A trailing partial word is padded with zeros
+    data.push(7);
+
+    let parcel = parcel_from_bytes(&data).unwrap();
+    assert_eq!(parcel.read::<i32>(), Ok(42));
+    assert_eq!(parcel.read::<i64>(), Ok(-1));
+    assert_eq!(parcel.read::<i32>(), Ok(7));
+    assert_eq!(parcel.read::<i32>(), Err(StatusCode::NOT_ENOUGH_DATA));
+}
+
+#[test]
+fn test_deserialize_hostile_input() {
+    // Array length larger than the available data
+    let data = 1000i32.to_ne_bytes();
+    assert!(deserialize::<Vec<u8>>(&data).is_err());
+    assert!(deserialize::<Vec<String>>(&data).is_err());
+    // Negative length other than the null marker
+    let data = (-2i32).to_ne_bytes();
+    assert_eq!(deserialize::<Vec<i32>>(&data), Err(StatusCode::BAD_VALUE));
+    // Binder objects can't be forged from plain data
+    assert!(deserialize::<SpIBinder>(&[0xff; 32]).is_err());
+    assert!(deserialize::<Vec<u32>>(&[]).is_err());
+}
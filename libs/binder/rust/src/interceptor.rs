@@ -0,0 +1,507 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Hooks around outgoing and incoming transactions.
+//!
+//! An [`Interceptor`] registered with [`add_global_interceptor`] sees every
+//! transaction sent by [`IBinder::transact`](crate::IBinder::transact) and
+//! every transaction handled by a local Rust service in this process. An
+//! interceptor added to a single service with
+//! [`Binder::add_interceptor`](crate::Binder::add_interceptor) only sees the
+//! transactions handled by that service, and calls made to it from this
+//! process.
+//!
+//! Two interceptors are provided: [`LatencyHistograms`], which collects
+//! per-code latency histograms that can be printed in a dump, and
+//! `TracingInterceptor`, which wraps each transaction in a `tracing` span when
+//! the `tracing` feature is enabled.
+
+use crate::binder::{TransactionCode, TransactionFlags};
+use crate::error::Result;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Direction of an intercepted transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Direction {
+    /// A transaction sent from this process
+    Outgoing,
+    /// A transaction handled by a local service
+    Incoming,
+}
+
+/// Details of an intercepted transaction.
+#[derive(Clone, Debug)]
+pub struct TransactionInfo<'a> {
+    /// Whether the transaction is sent or handled by this process
+    pub direction: Direction,
+    /// Interface descriptor of the target binder, empty if the binder is not
+    /// associated with an interface class
+    pub descriptor: &'a str,
+    /// Transaction code
+    pub code: TransactionCode,
+    /// Transaction flags. `libbinder_ndk` does not pass flags to local
+    /// services, so for incoming transactions only `FLAG_ONEWAY` is reported.
+    pub flags: TransactionFlags,
+    /// Size in bytes of the transaction data
+    pub data_size: usize,
+}
+
+/// Hooks called before and after transactions.
+///
+/// Both hooks for a transaction are called on the thread performing or
+/// handling the transaction. Hooks are called while a transaction is in
+/// progress, so they should be fast and must not block on other transactions.
+pub trait Interceptor: Send + Sync {
+    /// Called before the transaction is sent or handled.
+    fn before_transaction(&self, _info: &TransactionInfo) {}
+
+    /// Called after the transaction completes, with the time spent in the
+    /// transaction and its result.
+    fn after_transaction(&self, _info: &TransactionInfo, _duration: Duration, _result: &Result<()>) {}
+}
+
+/// Registered interceptors.
+#[derive(Default)]
+struct Registry {
+    global: Vec<Arc<dyn Interceptor>>,
+    /// Interceptors of local services, keyed by the address of the Rust object
+    /// of the service
+    local: HashMap<usize, Vec<Arc<dyn Interceptor>>>,
+}
+
+/// Number of registered interceptors, so that transactions do not have to take
+/// the lock when there are none.
+static INTERCEPTOR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+fn with_registry<R, F: FnOnce(&mut Registry) -> R>(f: F) -> R {
+    // A panic while holding the lock can't leave the registry inconsistent.
+    let mut registry: MutexGuard<_> = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let registry = registry.get_or_insert_with(Default::default);
+    let result = f(registry);
+    let count = registry.global.len() + registry.local.values().map(Vec::len).sum::<usize>();
+    INTERCEPTOR_COUNT.store(count, Ordering::SeqCst);
+    result
+}
+
+/// Register an interceptor for every transaction in this process.
+pub fn add_global_interceptor(interceptor: Arc<dyn Interceptor>) {
+    with_registry(|registry| registry.global.push(interceptor))
+}
+
+/// Remove an interceptor registered with [`add_global_interceptor`].
+///
+/// Returns `false` if the interceptor was not registered.
+pub fn remove_global_interceptor(interceptor: &Arc<dyn Interceptor>) -> bool {
+    with_registry(|registry| remove(&mut registry.global, interceptor))
+}
+
+fn remove(interceptors: &mut Vec<Arc<dyn Interceptor>>, interceptor: &Arc<dyn Interceptor>) -> bool {
+    let len = interceptors.len();
+    interceptors.retain(|i| !Arc::ptr_eq(i, interceptor));
+    interceptors.len() != len
+}
+
+pub(crate) fn add_local_interceptor(object: *const c_void, interceptor: Arc<dyn Interceptor>) {
+    with_registry(|registry| registry.local.entry(object as usize).or_default().push(interceptor))
+}
+
+pub(crate) fn remove_local_interceptor(
+    object: *const c_void,
+    interceptor: &Arc<dyn Interceptor>,
+) -> bool {
+    with_registry(|registry| match registry.local.get_mut(&(object as usize)) {
+        Some(interceptors) => {
+            let removed = remove(interceptors, interceptor);
+            if interceptors.is_empty() {
+                registry.local.remove(&(object as usize));
+            }
+            removed
+        }
+        None => false,
+    })
+}
+
+pub(crate) fn forget_local_interceptors(object: *const c_void) {
+    if INTERCEPTOR_COUNT.load(Ordering::SeqCst) != 0 {
+        with_registry(|registry| registry.local.remove(&(object as usize)));
+    }
+}
+
+/// A transaction whose `before_transaction` hooks have been called.
+pub(crate) struct Intercepted {
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    direction: Direction,
+    descriptor: Cow<'static, str>,
+    code: TransactionCode,
+    flags: TransactionFlags,
+    data_size: usize,
+    start: Instant,
+}
+
+/// Call the `before_transaction` hooks of the interceptors that apply to a
+/// transaction, if there are any.
+///
+/// `object` is the Rust object of the target service, or null if the target
+/// is not a local Rust service. The descriptor is only computed if an
+/// interceptor needs it.
+pub(crate) fn begin<F>(
+    object: *const c_void,
+    direction: Direction,
+    descriptor: F,
+    code: TransactionCode,
+    flags: TransactionFlags,
+    data_size: usize,
+) -> Option<Intercepted>
+where
+    F: FnOnce() -> Cow<'static, str>,
+{
+    if INTERCEPTOR_COUNT.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+    let interceptors: Vec<_> = with_registry(|registry| {
+        let local = registry.local.get(&(object as usize));
+        registry.global.iter().chain(local.into_iter().flatten()).cloned().collect()
+    });
+    if interceptors.is_empty() {
+        return None;
+    }
+    let intercepted = Intercepted {
+        interceptors,
+        direction,
+        descriptor: descriptor(),
+        code,
+        flags,
+        data_size,
+        start: Instant::now(),
+    };
+    let info = intercepted.info();
+    for interceptor in &intercepted.interceptors {
+        interceptor.before_transaction(&info);
+    }
+    Some(Intercepted {
+        start: Instant::now(),
+        ..intercepted
+    })
+}
+
+impl Intercepted {
+    fn info(&self) -> TransactionInfo<'_> {
+        TransactionInfo {
+            direction: self.direction,
+            descriptor: &self.descriptor,
+            code: self.code,
+            flags: self.flags,
+            data_size: self.data_size,
+        }
+    }
+
+    /// Call the `after_transaction` hooks, in reverse order of the
+    /// `before_transaction` hooks.
+    pub(crate) fn finish(self, result: &Result<()>) {
+        let duration = self.start.elapsed();
+        let info = self.info();
+        for interceptor in self.interceptors.iter().rev() {
+            interceptor.after_transaction(&info, duration, result);
+        }
+    }
+}
+
+/// Number of histogram buckets. Bucket `i` counts transactions that took less
+/// than `2^i` microseconds, and the last bucket counts all slower ones.
+const BUCKETS: usize = 24;
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    errors: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    fn add(&mut self, duration: Duration, failed: bool) {
+        let micros = duration.as_micros();
+        let bucket = (0..BUCKETS - 1)
+            .find(|&i| micros < 1u128 << i)
+            .unwrap_or(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        if failed {
+            self.errors += 1;
+        }
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+}
+
+/// Interceptor collecting a latency histogram for each interface, code and
+/// direction.
+///
+/// The collected histograms are printed by the `Display` implementation, for
+/// example from a service's dump handler.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use binder::interceptor::{add_global_interceptor, LatencyHistograms};
+/// # use std::sync::Arc;
+/// let histograms = Arc::new(LatencyHistograms::new());
+/// add_global_interceptor(histograms.clone());
+/// // ... later, when dumping:
+/// println!("{}", histograms);
+/// ```
+#[derive(Default)]
+pub struct LatencyHistograms {
+    histograms: Mutex<HistogramMap>,
+}
+
+/// Histograms keyed by interface descriptor, then by direction and code.
+type HistogramMap = HashMap<String, HashMap<(Direction, TransactionCode), Histogram>>;
+
+impl LatencyHistograms {
+    /// Create an empty set of histograms.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Discard all collected data.
+    pub fn reset(&self) {
+        self.lock().clear()
+    }
+
+    /// Returns the number of recorded transactions for an interface, code and
+    /// direction.
+    pub fn count(&self, descriptor: &str, direction: Direction, code: TransactionCode) -> u64 {
+        self.lock()
+            .get(descriptor)
+            .and_then(|codes| codes.get(&(direction, code)))
+            .map_or(0, |histogram| histogram.count)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HistogramMap> {
+        self.histograms.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Interceptor for LatencyHistograms {
+    fn after_transaction(&self, info: &TransactionInfo, duration: Duration, result: &Result<()>) {
+        let mut histograms = self.lock();
+        if !histograms.contains_key(info.descriptor) {
+            histograms.insert(info.descriptor.to_owned(), HashMap::new());
+        }
+        histograms
+            .get_mut(info.descriptor)
+            .unwrap()
+            .entry((info.direction, info.code))
+            .or_default()
+            .add(duration, result.is_err());
+    }
+}
+
+impl fmt::Display for LatencyHistograms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let histograms = self.lock();
+        let mut descriptors: Vec<_> = histograms.keys().collect();
+        descriptors.sort();
+        for descriptor in descriptors {
+            let name = if descriptor.is_empty() { "<no interface>" } else { descriptor };
+            writeln!(f, "{}:", name)?;
+            let mut codes: Vec<_> = histograms[descriptor].iter().collect();
+            codes.sort_by_key(|(key, _)| *key);
+            for ((direction, code), histogram) in codes {
+                writeln!(
+                    f,
+                    "  code {} ({:?}): count {}, errors {}, mean {}us, max {}us",
+                    code,
+                    direction,
+                    histogram.count,
+                    histogram.errors,
+                    histogram.total.as_micros() / histogram.count.max(1) as u128,
+                    histogram.max.as_micros(),
+                )?;
+                for (i, count) in histogram.buckets.iter().enumerate() {
+                    if *count == 0 {
+                        continue;
+                    }
+                    if i == BUCKETS - 1 {
+                        writeln!(f, "    >= {}us: {}", 1u64 << (BUCKETS - 2), count)?;
+                    } else {
+                        writeln!(f, "    < {}us: {}", 1u64 << i, count)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tracing")]
+pub use self::tracing_interceptor::TracingInterceptor;
+
+#[cfg(feature = "tracing")]
+mod tracing_interceptor {
+    use super::{Direction, Interceptor, TransactionInfo};
+    use crate::error::Result;
+
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use tracing::field::Empty;
+    use tracing::span::EnteredSpan;
+
+    thread_local! {
+        /// Spans of the transactions in progress on this thread. Transactions
+        /// on one thread always nest, so the innermost span is last.
+        static SPANS: RefCell<Vec<EnteredSpan>> = const { RefCell::new(vec![]) };
+    }
+
+    /// Interceptor wrapping each transaction in a `tracing` span.
+    ///
+    /// Outgoing transactions are recorded as `binder_transact` spans and
+    /// incoming transactions as `binder_on_transact` spans. `tracing` span
+    /// names must be static, so the interface descriptor and code are fields
+    /// of the span, along with the flags, data size, duration and status.
+    #[derive(Debug, Default)]
+    pub struct TracingInterceptor;
+
+    impl Interceptor for TracingInterceptor {
+        fn before_transaction(&self, info: &TransactionInfo) {
+            let span = match info.direction {
+                Direction::Outgoing => tracing::info_span!(
+                    "binder_transact",
+                    interface = info.descriptor,
+                    code = info.code,
+                    flags = info.flags,
+                    data_size = info.data_size as u64,
+                    duration_us = Empty,
+                    status = Empty,
+                ),
+                Direction::Incoming => tracing::info_span!(
+                    "binder_on_transact",
+                    interface = info.descriptor,
+                    code = info.code,
+                    flags = info.flags,
+                    data_size = info.data_size as u64,
+                    duration_us = Empty,
+                    status = Empty,
+                ),
+            };
+            SPANS.with(|spans| spans.borrow_mut().push(span.entered()));
+        }
+
+        fn after_transaction(&self, _info: &TransactionInfo, duration: Duration, result: &Result<()>) {
+            if let Some(span) = SPANS.with(|spans| spans.borrow_mut().pop()) {
+                span.record("duration_us", duration.as_micros() as u64);
+                let status = match result {
+                    Ok(()) => 0,
+                    Err(e) => *e as i32,
+                };
+                span.record("status", status);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binder::{IBinder, Interface, Remotable};
+    use crate::error::StatusCode;
+    use crate::native::Binder;
+    use crate::parcel::Parcel;
+
+    struct Service;
+
+    impl Interface for Service {}
+
+    impl Remotable for Service {
+        fn get_descriptor() -> &'static str {
+            "android.binder.test.IInterceptorTest"
+        }
+
+        fn on_transact(&self, code: TransactionCode, _: &Parcel, _: &mut Parcel) -> Result<()> {
+            match code {
+                1 => Ok(()),
+                _ => Err(StatusCode::UNKNOWN_TRANSACTION),
+            }
+        }
+
+        binder_fn_get_class!(Binder::<Self>);
+    }
+
+    #[derive(Default)]
+    struct Log(Mutex<Vec<String>>);
+
+    impl Interceptor for Log {
+        fn before_transaction(&self, info: &TransactionInfo) {
+            self.0.lock().unwrap().push(format!("before {:?} {}", info.direction, info.code));
+        }
+
+        fn after_transaction(&self, info: &TransactionInfo, _: Duration, result: &Result<()>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("after {:?} {} {:?}", info.direction, info.code, result));
+        }
+    }
+
+    #[test]
+    fn test_local_interceptors() {
+        let service = Binder::new(Service);
+        let log = Arc::new(Log::default());
+        let histograms = Arc::new(LatencyHistograms::new());
+        service.add_interceptor(log.clone());
+        service.add_interceptor(histograms.clone());
+
+        let binder = service.as_binder();
+        binder.transact(1, 0, |_| Ok(())).unwrap();
+        assert!(binder.transact(2, 0, |_| Ok(())).is_err());
+
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            [
+                "before Outgoing 1",
+                "before Incoming 1",
+                "after Incoming 1 Ok(())",
+                "after Outgoing 1 Ok(())",
+                "before Outgoing 2",
+                "before Incoming 2",
+                "after Incoming 2 Err(UNKNOWN_TRANSACTION)",
+                "after Outgoing 2 Err(UNKNOWN_TRANSACTION)",
+            ]
+        );
+
+        let descriptor = Service::get_descriptor();
+        assert_eq!(histograms.count(descriptor, Direction::Incoming, 1), 1);
+        assert_eq!(histograms.count(descriptor, Direction::Outgoing, 2), 1);
+        let dump = histograms.to_string();
+        assert!(dump.contains("android.binder.test.IInterceptorTest:"));
+        assert!(dump.contains("code 2 (Incoming): count 1, errors 1"));
+
+        let log: Arc<dyn Interceptor> = log;
+        assert!(service.remove_interceptor(&log));
+        assert!(!service.remove_interceptor(&log));
+    }
+}
@@ -21,6 +21,10 @@
 //! top of the binder NDK library to be usable by APEX modules, and therefore
 //! only exposes functionality available in the NDK interface.
 //!
+//! Without the `ndk` feature, the crate doesn't use the NDK library, and only
+//! provides in-memory [`Parcel`]s, the parcelable traits and [`Status`], with
+//! the `memory_parcel` feature.
+//!
 //! # Example
 //!
 //! The following example illustrates how the AIDL backend will use this crate.
@@ -93,38 +97,146 @@
 //! }
 //! ```
 
-#[macro_use]
-mod proxy;
+#[cfg(not(any(feature = "ndk", feature = "memory_parcel")))]
+compile_error!("binder needs at least one of the `ndk` and `memory_parcel` features");
 
+#[cfg(feature = "ndk")]
 #[macro_use]
 mod binder;
+
+#[cfg(feature = "ndk")]
+#[macro_use]
+mod proxy;
+#[cfg(feature = "ndk")]
+mod dynamic;
 mod error;
+#[cfg(feature = "ndk")]
 mod native;
+#[cfg(feature = "ndk")]
+mod panic_policy;
+#[cfg(feature = "ndk")]
+mod reconnect;
+#[cfg(feature = "ndk")]
 mod state;
 
 use binder_ndk_sys as sys;
 
+#[cfg(feature = "ndk")]
+pub mod fuzz;
+#[cfg(feature = "ndk")]
+pub mod interceptor;
 pub mod parcel;
+#[cfg(feature = "ndk")]
+pub mod recording;
+#[cfg(feature = "ndk")]
+pub mod rpc;
+#[cfg(feature = "ndk")]
+pub mod shared_memory;
+#[cfg(all(feature = "ndk", any(test, feature = "testing")))]
+pub mod testing;
+
+/// Hooks of the fake service manager, which do nothing when it isn't built.
+#[cfg(all(feature = "ndk", not(any(test, feature = "testing"))))]
+#[doc(hidden)]
+pub mod testing {
+    use crate::error::{Result, StatusCode};
+    use crate::proxy::{DeathRecipient, SpIBinder};
+    use crate::sys;
+
+    #[inline]
+    pub fn is_intercepted(_binder: &SpIBinder) -> bool {
+        false
+    }
+
+    #[inline]
+    pub(crate) fn is_installed() -> bool {
+        false
+    }
+
+    #[inline]
+    pub(crate) fn add_service(_identifier: &str, _binder: SpIBinder) -> Result<()> {
+        Err(StatusCode::NO_INIT)
+    }
+
+    #[inline]
+    pub(crate) fn get_service(_identifier: &str) -> Option<SpIBinder> {
+        None
+    }
+
+    #[inline]
+    pub(crate) fn list_services() -> Vec<String> {
+        vec![]
+    }
+
+    #[inline]
+    pub(crate) fn check_transaction(_binder: *const sys::AIBinder) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn is_dead(_binder: *const sys::AIBinder) -> bool {
+        false
+    }
+
+    #[inline]
+    pub(crate) fn link_to_death(
+        _binder: *const sys::AIBinder,
+        _recipient: &DeathRecipient,
+    ) -> Option<Result<()>> {
+        None
+    }
+
+    #[inline]
+    pub(crate) fn unlink_to_death(
+        _binder: *const sys::AIBinder,
+        _recipient: &DeathRecipient,
+    ) -> Option<Result<()>> {
+        None
+    }
+
+    #[inline]
+    pub(crate) fn forget_recipient(_recipient: &DeathRecipient) {}
+}
 
+#[cfg(feature = "ndk")]
 pub use crate::binder::{
     FromIBinder, IBinder, Interface, InterfaceClass, Remotable, Strong, TransactionCode,
     TransactionFlags, Weak,
 };
+#[cfg(feature = "ndk")]
+#[doc(hidden)]
+pub use crate::binder::downcast_interface;
+#[cfg(feature = "ndk")]
+pub use dynamic::{DynamicService, DynamicServiceBuilder};
 pub use error::{status_t, ExceptionCode, Result, Status, StatusCode};
+#[cfg(feature = "ndk")]
 pub use native::add_service;
+#[cfg(feature = "ndk")]
 pub use native::Binder;
+#[cfg(feature = "ndk")]
+pub use panic_policy::{panic_policy, set_panic_policy, PanicPolicy};
 pub use parcel::Parcel;
-pub use proxy::{get_interface, get_service};
+#[cfg(feature = "ndk")]
+pub use proxy::{check_service, get_interface, get_service, list_services};
+#[cfg(feature = "ndk")]
+pub use proxy::{wait_for_interface, wait_for_service};
+#[cfg(feature = "ndk")]
 pub use proxy::{AssociateClass, DeathRecipient, Proxy, SpIBinder, WpIBinder};
-pub use state::{ProcessState, ThreadState};
+#[cfg(feature = "ndk")]
+pub use reconnect::{DeadObjectError, Reconnecting};
+#[cfg(feature = "ndk")]
+pub use shared_memory::SharedMemory;
+#[cfg(feature = "ndk")]
+pub use state::{ProcessState, ProcessStateBuilder, ThreadState};
 
 /// The public API usable outside AIDL-generated interface crates.
+#[cfg(feature = "ndk")]
 pub mod public_api {
     pub use super::parcel::ParcelFileDescriptor;
     pub use super::{add_service, get_interface};
     pub use super::{
-        ExceptionCode, Interface, ProcessState, SpIBinder, Status, StatusCode, Strong, ThreadState,
-        Weak, WpIBinder,
+        ExceptionCode, Interface, ProcessState, Reconnecting, SharedMemory, SpIBinder, Status,
+        StatusCode, Strong, ThreadState, Weak, WpIBinder,
     };
 
     /// Binder result containing a [`Status`] on error.
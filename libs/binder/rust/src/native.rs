@@ -14,17 +14,28 @@
  * limitations under the License.
  */
 
-use crate::binder::{AsNative, Interface, InterfaceClassMethods, Remotable, TransactionCode};
+use crate::binder::{
+    AsNative, IBinder, Interface, InterfaceClass, InterfaceClassMethods, Remotable,
+    TransactionCode, TransactionFlags,
+};
 use crate::error::{status_result, status_t, Result, StatusCode};
-use crate::parcel::{Parcel, Serialize};
-use crate::proxy::SpIBinder;
+use crate::parcel::{self, DeserializeLimits, Parcel, Serialize};
+use crate::proxy::{self, SpIBinder};
+use crate::interceptor::{self, Direction, Interceptor};
+use crate::panic_policy;
+use crate::recording::{self, Recorder};
+use crate::state::ThreadState;
 use crate::sys;
+use crate::testing;
 
 use std::convert::TryFrom;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::ffi::{c_void, CString};
 use std::mem::ManuallyDrop;
 use std::ops::Deref;
 use std::ptr;
+use std::sync::Arc;
 
 /// Rust wrapper around Binder remotable objects.
 ///
@@ -48,17 +59,31 @@ pub struct Binder<T: Remotable> {
 /// to how `Box<T>` is `Send` if `T` is `Send`.
 unsafe impl<T: Remotable> Send for Binder<T> {}
 
+/// # Safety
+///
+/// A `Binder<T>` is `Sync` for the same reasons it is `Send`: the C++ ABBinder
+/// is thread-safe and `Remotable` requires `Sync`. The Rust object is only
+/// accessed through shared references, and the extension is only set through
+/// a unique reference, as `AIBinder_setExtension` isn't thread-safe.
+unsafe impl<T: Remotable> Sync for Binder<T> {}
+
 impl<T: Remotable> Binder<T> {
     /// Create a new Binder remotable object.
     ///
     /// This moves the `rust_object` into an owned [`Box`] and Binder will
     /// manage its lifetime.
     pub fn new(rust_object: T) -> Binder<T> {
-        let class = T::get_class();
+        Self::new_with_class(rust_object, T::get_class())
+    }
+
+    /// Create a new Binder remotable object with a class other than
+    /// `T::get_class()`, which must have been defined with the callbacks of
+    /// `Binder<T>`.
+    pub(crate) fn new_with_class(rust_object: T, class: InterfaceClass) -> Binder<T> {
         let rust_object = Box::into_raw(Box::new(rust_object));
         let ibinder = unsafe {
-            // Safety: `AIBinder_new` expects a valid class pointer (which we
-            // initialize via `get_class`), and an arbitrary pointer
+            // Safety: `AIBinder_new` expects a valid class pointer (which
+            // `InterfaceClass` guarantees), and an arbitrary pointer
             // argument. The caller owns the returned `AIBinder` pointer, which
             // is a strong reference to a `BBinder`. This reference should be
             // decremented via `AIBinder_decStrong` when the reference lifetime
@@ -161,6 +186,38 @@ impl<T: Remotable> Binder<T> {
     pub fn get_descriptor() -> &'static str {
         T::get_descriptor()
     }
+
+    /// Record every transaction handled by this object, or stop recording if
+    /// `recorder` is `None`.
+    ///
+    /// Replaces any recorder previously installed on this object. See
+    /// [`recording`](crate::recording) for the recording format and replay.
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        recording::set_recorder(self.rust_object as *const c_void, recorder)
+    }
+
+    /// Register an interceptor for the transactions handled by this object.
+    ///
+    /// The interceptor also sees transactions sent to this object from this
+    /// process. See [`interceptor`](crate::interceptor).
+    pub fn add_interceptor(&self, interceptor: Arc<dyn Interceptor>) {
+        interceptor::add_local_interceptor(self.rust_object as *const c_void, interceptor)
+    }
+
+    /// Limit the resources used to read the data of every transaction handled
+    /// by this object, or remove the limits if `limits` is `None`.
+    ///
+    /// See [`DeserializeLimits`].
+    pub fn set_deserialize_limits(&self, limits: Option<DeserializeLimits>) {
+        parcel::set_service_limits(self.rust_object as *const c_void, limits)
+    }
+
+    /// Remove an interceptor added with [`Binder::add_interceptor`].
+    ///
+    /// Returns `false` if the interceptor was not registered on this object.
+    pub fn remove_interceptor(&self, interceptor: &Arc<dyn Interceptor>) -> bool {
+        interceptor::remove_local_interceptor(self.rust_object as *const c_void, interceptor)
+    }
 }
 
 impl<T: Remotable> Interface for Binder<T> {
@@ -206,13 +263,60 @@ impl<T: Remotable> InterfaceClassMethods for Binder<T> {
         data: *const sys::AParcel,
         reply: *mut sys::AParcel,
     ) -> status_t {
+        let flags = LOCAL_FLAGS.with(Cell::take).unwrap_or_else(kernel_flags);
+        let previous_flags = INCOMING_FLAGS.with(|incoming| incoming.replace(Some(flags)));
         let res = {
             let mut reply = Parcel::borrowed(reply).unwrap();
-            let data = Parcel::borrowed(data as *mut sys::AParcel).unwrap();
+            let mut data = Parcel::borrowed(data as *mut sys::AParcel).unwrap();
             let object = sys::AIBinder_getUserData(binder);
+            if panic_policy::is_poisoned(object) {
+                INCOMING_FLAGS.with(|incoming| incoming.set(previous_flags));
+                return StatusCode::DEAD_OBJECT as status_t;
+            }
+            let limits = parcel::service_limits(object);
+            if let Some(limits) = limits {
+                data.set_deserialize_limits(limits);
+            }
+            let descriptor = if T::HAS_RUNTIME_CLASS {
+                proxy::class_descriptor(binder)
+            } else {
+                Cow::Borrowed(T::get_descriptor())
+            };
             let binder: &T = &*(object as *const T);
-            binder.on_transact(code, &data, &mut reply)
+            // Interceptors and recorders run user code too, which must not
+            // unwind into C++. Their panics don't poison the service.
+            let res = panic_policy::catch_panic(&descriptor, || {
+                let record = recording::begin(object, &data);
+                let intercepted = interceptor::begin(
+                    object,
+                    Direction::Incoming,
+                    || descriptor.clone(),
+                    code,
+                    flags,
+                    data.get_data_size() as usize,
+                );
+                let res = panic_policy::catch_panic(&descriptor, || {
+                    binder.on_transact(code, &data, &mut reply)
+                })
+                .unwrap_or_else(|| {
+                    panic_policy::poison_if_needed(object);
+                    Err(StatusCode::UNKNOWN_ERROR)
+                });
+                if let Some(intercepted) = intercepted {
+                    intercepted.finish(&res);
+                }
+                if let Some(record) = record {
+                    record.finish(code, &data, &reply, &res);
+                }
+                res
+            })
+            .unwrap_or(Err(StatusCode::UNKNOWN_ERROR));
+            if limits.is_some() {
+                data.clear_deserialize_limits();
+            }
+            res
         };
+        INCOMING_FLAGS.with(|incoming| incoming.set(previous_flags));
         match res {
             Ok(()) => 0i32,
             Err(e) => e as i32,
@@ -227,7 +331,18 @@ impl<T: Remotable> InterfaceClassMethods for Binder<T> {
     /// Must be called with a valid pointer to a `T` object. After this call,
     /// the pointer will be invalid and should not be dereferenced.
     unsafe extern "C" fn on_destroy(object: *mut c_void) {
-        ptr::drop_in_place(object as *mut T)
+        panic_policy::forget_poisoned(object);
+        parcel::set_service_limits(object, None);
+        // The class of the object is not known here.
+        let descriptor = if T::HAS_RUNTIME_CLASS {
+            std::any::type_name::<T>()
+        } else {
+            T::get_descriptor()
+        };
+        // Dropping recorders and interceptors runs user code.
+        panic_policy::catch_panic(descriptor, || recording::set_recorder(object, None));
+        panic_policy::catch_panic(descriptor, || interceptor::forget_local_interceptors(object));
+        panic_policy::catch_panic(descriptor, || ptr::drop_in_place(object as *mut T));
     }
 
     /// Called whenever a new, local `AIBinder` object is needed of a specific
@@ -331,11 +446,51 @@ unsafe impl<B: Remotable> AsNative<sys::AIBinder> for Binder<B> {
     }
 }
 
+thread_local! {
+    /// Flags of the in-process transaction this thread is sending, taken by the
+    /// `on_transact` which `libbinder_ndk` calls directly on this thread.
+    static LOCAL_FLAGS: Cell<Option<TransactionFlags>> = const { Cell::new(None) };
+    /// Flags of the transaction being handled on this thread
+    static INCOMING_FLAGS: Cell<Option<TransactionFlags>> = const { Cell::new(None) };
+}
+
+/// Calls `transact`, which sends a transaction with `flags` to a local object
+/// of this process, making the flags available to its `on_transact`.
+pub(crate) fn with_local_flags<R>(flags: TransactionFlags, transact: impl FnOnce() -> R) -> R {
+    let previous = LOCAL_FLAGS.with(|local| local.replace(Some(flags)));
+    let result = transact();
+    LOCAL_FLAGS.with(|local| local.set(previous));
+    result
+}
+
+/// Returns the flags of the incoming transaction being handled on this thread.
+///
+/// `libbinder_ndk` does not pass transaction flags to `on_transact`. Flags of
+/// transactions from this process are recorded by the sending thread, see
+/// `with_local_flags`, and for transactions from other processes a calling PID
+/// of 0 identifies a oneway transaction.
+pub(crate) fn incoming_flags() -> TransactionFlags {
+    INCOMING_FLAGS
+        .with(Cell::get)
+        .unwrap_or_else(kernel_flags)
+}
+
+fn kernel_flags() -> TransactionFlags {
+    if ThreadState::get_calling_pid() == 0 {
+        SpIBinder::FLAG_ONEWAY
+    } else {
+        0
+    }
+}
+
 /// Register a new service with the default service manager.
 ///
 /// Registers the given binder object with the given identifier. If successful,
 /// this service can then be retrieved using that identifier.
 pub fn add_service(identifier: &str, mut binder: SpIBinder) -> Result<()> {
+    if testing::is_installed() {
+        return testing::add_service(identifier, binder);
+    }
     let instance = CString::new(identifier).unwrap();
     let status = unsafe {
         // Safety: `AServiceManager_addService` expects valid `AIBinder` and C
@@ -0,0 +1,207 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Handling of panics in Rust code called by libbinder.
+//!
+//! Unwinding out of a callback called from C++ is undefined behavior, so
+//! transactions, object destruction and death notifications are run under
+//! [`catch_unwind`](std::panic::catch_unwind), and what happens next is
+//! decided by the process-wide [`PanicPolicy`].
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// What to do when a service or a death recipient panics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    /// Abort the process.
+    Abort,
+    /// Log the panic. A transaction which panicked fails with
+    /// `UNKNOWN_ERROR`, which the client sees as a
+    /// [`TRANSACTION_FAILED`](crate::ExceptionCode::TRANSACTION_FAILED)
+    /// exception. This is the default.
+    Report,
+    /// Log the panic like [`Report`](PanicPolicy::Report), and fail every
+    /// later transaction to the service which panicked with `DEAD_OBJECT`,
+    /// as its state may be inconsistent.
+    ///
+    /// The service is still alive, so clients only see the `DEAD_OBJECT`
+    /// errors: their death recipients are not notified with `binderDied`.
+    ReportAndPoison,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Report as u8);
+
+/// Set the policy for panics in services and death recipients of this process.
+pub fn set_panic_policy(policy: PanicPolicy) {
+    POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+/// The policy for panics in services and death recipients of this process.
+pub fn panic_policy() -> PanicPolicy {
+    match POLICY.load(Ordering::SeqCst) {
+        p if p == PanicPolicy::Abort as u8 => PanicPolicy::Abort,
+        p if p == PanicPolicy::ReportAndPoison as u8 => PanicPolicy::ReportAndPoison,
+        _ => PanicPolicy::Report,
+    }
+}
+
+/// Run `f`, catching and logging any panic.
+///
+/// Returns `None` if `f` panicked, or aborts if the policy is
+/// [`Abort`](PanicPolicy::Abort).
+pub(crate) fn catch_panic<R, F: FnOnce() -> R>(context: &str, f: F) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            log::error!("{} panicked: {}", context, panic_message(&*payload));
+            if panic_policy() == PanicPolicy::Abort {
+                std::process::abort();
+            }
+            None
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<unknown panic payload>"
+    }
+}
+
+/// Number of poisoned services, so that transactions do not have to take the
+/// lock when there are none.
+static POISONED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Poisoned local services, keyed by the address of their Rust object.
+static POISONED: Mutex<Option<HashSet<usize>>> = Mutex::new(None);
+
+fn poisoned() -> MutexGuard<'static, Option<HashSet<usize>>> {
+    POISONED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Poison the local service with the given Rust object if the policy is
+/// [`ReportAndPoison`](PanicPolicy::ReportAndPoison).
+pub(crate) fn poison_if_needed(object: *const c_void) {
+    if panic_policy() == PanicPolicy::ReportAndPoison {
+        let mut poisoned = poisoned();
+        let poisoned = poisoned.get_or_insert_with(Default::default);
+        poisoned.insert(object as usize);
+        POISONED_COUNT.store(poisoned.len(), Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn is_poisoned(object: *const c_void) -> bool {
+    POISONED_COUNT.load(Ordering::SeqCst) != 0
+        && poisoned()
+            .as_ref()
+            .is_some_and(|poisoned| poisoned.contains(&(object as usize)))
+}
+
+/// Forget that the local service with the given Rust object was poisoned,
+/// when it is destroyed.
+pub(crate) fn forget_poisoned(object: *const c_void) {
+    if POISONED_COUNT.load(Ordering::SeqCst) != 0 {
+        let mut poisoned = poisoned();
+        if let Some(poisoned) = poisoned.as_mut() {
+            poisoned.remove(&(object as usize));
+            POISONED_COUNT.store(poisoned.len(), Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binder::{IBinder, Interface, TransactionCode};
+    use crate::error::{Result, StatusCode};
+    use crate::parcel::Parcel;
+    use crate::proxy::SpIBinder;
+
+    pub trait IPanic: Interface {}
+
+    declare_binder_interface! {
+        IPanic["android.binder.test.IPanic"] {
+            native: BnPanic(on_transact),
+            proxy: BpPanic,
+        }
+    }
+
+    fn on_transact(
+        _service: &dyn IPanic,
+        code: TransactionCode,
+        _data: &Parcel,
+        reply: &mut Parcel,
+    ) -> Result<()> {
+        if code == SpIBinder::FIRST_CALL_TRANSACTION {
+            panic!("test panic");
+        }
+        reply.write(&1i32)
+    }
+
+    impl IPanic for BpPanic {}
+
+    impl IPanic for crate::native::Binder<BnPanic> {}
+
+    struct Panic;
+
+    impl Interface for Panic {}
+
+    impl IPanic for Panic {}
+
+    #[test]
+    fn test_panic_policy() {
+        use std::process::Command;
+
+        // The policy is global and would change how the panics of other tests
+        // are handled, so run this test alone in a child process.
+        const CHILD: &str = "BINDER_RS_TEST_PANIC_POLICY_CHILD";
+        if std::env::var_os(CHILD).is_none() {
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "panic_policy::tests::test_panic_policy", "--test-threads=1"])
+                .env(CHILD, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let service = BnPanic::new_binder(Panic).as_binder();
+        let ping = || {
+            service
+                .transact(SpIBinder::FIRST_CALL_TRANSACTION + 1, 0, |_| Ok(()))
+                .and_then(|reply| reply.read::<i32>())
+        };
+        let panic = || service.transact(SpIBinder::FIRST_CALL_TRANSACTION, 0, |_| Ok(()));
+
+        assert_eq!(panic_policy(), PanicPolicy::Report);
+        assert_eq!(panic().err(), Some(StatusCode::UNKNOWN_ERROR));
+        assert_eq!(ping(), Ok(1));
+
+        set_panic_policy(PanicPolicy::ReportAndPoison);
+        assert_eq!(panic().err(), Some(StatusCode::UNKNOWN_ERROR));
+        assert_eq!(ping().err(), Some(StatusCode::DEAD_OBJECT));
+    }
+}
@@ -16,23 +16,77 @@
 
 //! Container for messages that are sent via binder.
 
+#[cfg(feature = "ndk")]
 use crate::binder::AsNative;
-use crate::error::{status_result, Result, StatusCode};
+#[cfg(feature = "ndk")]
+use crate::error::status_result;
+use crate::error::{Result, StatusCode};
+#[cfg(feature = "ndk")]
 use crate::proxy::SpIBinder;
+#[cfg(feature = "ndk")]
 use crate::sys;
 
 use std::cell::RefCell;
 use std::convert::TryInto;
+use std::mem;
+#[cfg(feature = "ndk")]
 use std::mem::ManuallyDrop;
+#[cfg(feature = "ndk")]
 use std::ptr;
 
+/// Return `$body` from the enclosing function if `$parcel` is an in-memory
+/// parcel, with `$memory` bound to its [`MemoryParcel`]. This does nothing
+/// without the `memory_parcel` feature.
+///
+/// Code without this guard falls through to the `libbinder_ndk` calls, whose
+/// [`Parcel::try_as_native`] fails with `INVALID_OPERATION` for in-memory
+/// parcels. Without the `ndk` feature every parcel is in memory, so this
+/// always returns and the `libbinder_ndk` code after it must be left out with
+/// `#[cfg(feature = "ndk")]`.
+macro_rules! memory_backend {
+    ($parcel:expr, |$memory:tt| $body:expr) => {
+        #[cfg(all(feature = "memory_parcel", feature = "ndk"))]
+        {
+            if let $crate::parcel::Parcel::Memory($memory) = $parcel {
+                return $body;
+            }
+        }
+        #[cfg(not(feature = "ndk"))]
+        let $crate::parcel::Parcel::Memory($memory) = $parcel;
+        #[cfg(not(feature = "ndk"))]
+        return $body;
+    };
+}
+
+mod borrowed;
+#[cfg(feature = "ndk")]
 mod file_descriptor;
+mod inspect;
+mod interface_token;
+mod limits;
+#[cfg(feature = "memory_parcel")]
+mod memory;
 mod parcelable;
+#[cfg(feature = "serde")]
+pub mod serde;
+mod value;
 
+pub use self::borrowed::Utf16Str;
+#[cfg(feature = "ndk")]
 pub use self::file_descriptor::ParcelFileDescriptor;
+pub use self::inspect::{DecodedParcel, DecodedValue, Divergence, ParcelType};
+pub use self::limits::DeserializeLimits;
+#[cfg(feature = "ndk")]
+pub(crate) use self::limits::{service_limits, set_service_limits};
+#[cfg(feature = "memory_parcel")]
+pub use self::memory::MemoryParcel;
+#[cfg(feature = "memory_parcel")]
+pub(crate) use self::memory::StatusHeader;
 pub use self::parcelable::{
-    Deserialize, DeserializeArray, DeserializeOption, Serialize, SerializeArray, SerializeOption,
+    Deserialize, DeserializeArray, DeserializeOption, Serialize, SerializeArgs, SerializeArray,
+    SerializeOption,
 };
+pub use self::value::ParcelValue;
 
 /// Container for a message (data and object references) that can be sent
 /// through Binder.
@@ -43,30 +97,41 @@ pub use self::parcelable::{
 /// original Binder in the Parcel.
 pub enum Parcel {
     /// Owned parcel pointer
+    #[cfg(feature = "ndk")]
     Owned(*mut sys::AParcel),
     /// Borrowed parcel pointer (will not be destroyed on drop)
+    #[cfg(feature = "ndk")]
     Borrowed(*mut sys::AParcel),
+    /// Parcel data held in Rust memory, without a native `AParcel`
+    #[cfg(feature = "memory_parcel")]
+    Memory(MemoryParcel),
 }
 
-/// # Safety
-///
-/// The `Parcel` constructors guarantee that a `Parcel` object will always
-/// contain a valid pointer to an `AParcel`.
-unsafe impl AsNative<sys::AParcel> for Parcel {
-    fn as_native(&self) -> *const sys::AParcel {
+#[cfg(feature = "ndk")]
+impl Parcel {
+    /// Returns the `AParcel` of a parcel backed by `libbinder_ndk`.
+    ///
+    /// The `Parcel` constructors guarantee that the pointer is valid. Fails
+    /// with `INVALID_OPERATION` for in-memory parcels, which have no
+    /// `AParcel`.
+    pub(crate) fn try_as_native(&self) -> Result<*const sys::AParcel> {
         match *self {
-            Self::Owned(x) | Self::Borrowed(x) => x,
+            Self::Owned(x) | Self::Borrowed(x) => Ok(x),
+            #[cfg(feature = "memory_parcel")]
+            Self::Memory(_) => Err(StatusCode::INVALID_OPERATION),
         }
     }
 
-    fn as_native_mut(&mut self) -> *mut sys::AParcel {
+    /// Returns the `AParcel` of a parcel backed by `libbinder_ndk`, see
+    /// [`try_as_native`](Self::try_as_native).
+    pub(crate) fn try_as_native_mut(&mut self) -> Result<*mut sys::AParcel> {
         match *self {
-            Self::Owned(x) | Self::Borrowed(x) => x,
+            Self::Owned(x) | Self::Borrowed(x) => Ok(x),
+            #[cfg(feature = "memory_parcel")]
+            Self::Memory(_) => Err(StatusCode::INVALID_OPERATION),
         }
     }
-}
 
-impl Parcel {
     /// Create a borrowed reference to a parcel object from a raw pointer.
     ///
     /// # Safety
@@ -91,10 +156,43 @@ impl Parcel {
 
     /// Consume the parcel, transferring ownership to the caller if the parcel
     /// was owned.
-    pub(crate) fn into_raw(mut self) -> *mut sys::AParcel {
-        let ptr = self.as_native_mut();
+    ///
+    /// Fails with `INVALID_OPERATION` for in-memory parcels.
+    pub(crate) fn into_raw(mut self) -> Result<*mut sys::AParcel> {
+        let ptr = self.try_as_native_mut()?;
+        Self::forget_native_limits(ptr);
         let _ = ManuallyDrop::new(self);
-        ptr
+        Ok(ptr)
+    }
+}
+
+#[cfg(feature = "memory_parcel")]
+impl Parcel {
+    /// Create a new, empty parcel whose data is held in Rust memory.
+    ///
+    /// In-memory parcels produce exactly the same bytes as parcels backed by
+    /// `libbinder_ndk`, but cannot hold binder objects or file descriptors.
+    /// They are read and written without calling into `libbinder_ndk`, except
+    /// to convert status headers from and to [`Status`](crate::Status), which
+    /// wraps an NDK `AStatus` when the crate is built with the `ndk` feature.
+    pub fn new_in_memory() -> Parcel {
+        Self::Memory(MemoryParcel::new())
+    }
+
+    /// Create an in-memory parcel over previously serialized data, positioned
+    /// at the start of the data.
+    pub fn from_bytes(data: Vec<u8>) -> Parcel {
+        Self::Memory(MemoryParcel::from_bytes(data))
+    }
+
+    /// Returns the serialized data of an in-memory parcel, or `None` if this
+    /// parcel is backed by `libbinder_ndk`.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Memory(memory) => Some(memory.as_bytes()),
+            #[cfg(feature = "ndk")]
+            _ => None,
+        }
     }
 }
 
@@ -102,9 +200,14 @@ impl Parcel {
 impl Parcel {
     /// Data written to parcelable is zero'd before being deleted or reallocated.
     pub fn mark_sensitive(&mut self) {
-        unsafe {
-            // Safety: guaranteed to have a parcel object, and this method never fails
-            sys::AParcel_markSensitive(self.as_native())
+        match self {
+            #[cfg(feature = "ndk")]
+            Self::Owned(x) | Self::Borrowed(x) => unsafe {
+                // Safety: guaranteed to have a parcel object, and this method never fails
+                sys::AParcel_markSensitive(*x)
+            },
+            #[cfg(feature = "memory_parcel")]
+            Self::Memory(memory) => memory.mark_sensitive(),
         }
     }
 
@@ -157,30 +260,44 @@ impl Parcel {
     where for<'a>
         F: Fn(&'a WritableSubParcel<'a>) -> Result<()>
     {
-        let start = self.get_data_position();
+        let start = self.checkpoint();
         self.write(&0i32)?;
         {
             let subparcel = WritableSubParcel(RefCell::new(self));
             f(&subparcel)?;
         }
-        let end = self.get_data_position();
-        unsafe {
-            self.set_data_position(start)?;
-        }
+        let end = self.checkpoint();
+        self.rewind_to(start)?;
         assert!(end >= start);
-        self.write(&(end - start))?;
-        unsafe {
-            self.set_data_position(end)?;
-        }
-        Ok(())
+        self.write(&(end.offset() - start.offset()))?;
+        self.rewind_to(end)
     }
 
     /// Returns the current position in the parcel data.
     pub fn get_data_position(&self) -> i32 {
-        unsafe {
-            // Safety: `Parcel` always contains a valid pointer to an `AParcel`,
-            // and this call is otherwise safe.
-            sys::AParcel_getDataPosition(self.as_native())
+        match self {
+            #[cfg(feature = "ndk")]
+            Self::Owned(x) | Self::Borrowed(x) => unsafe {
+                // Safety: `Parcel` always contains a valid pointer to an `AParcel`,
+                // and this call is otherwise safe.
+                sys::AParcel_getDataPosition(*x)
+            },
+            #[cfg(feature = "memory_parcel")]
+            Self::Memory(memory) => memory.data_position(),
+        }
+    }
+
+    /// Returns the total size of the parcel data.
+    pub fn get_data_size(&self) -> i32 {
+        match self {
+            #[cfg(feature = "ndk")]
+            Self::Owned(x) | Self::Borrowed(x) => unsafe {
+                // Safety: `Parcel` always contains a valid pointer to an `AParcel`,
+                // and this call is otherwise safe.
+                sys::AParcel_getDataSize(*x)
+            },
+            #[cfg(feature = "memory_parcel")]
+            Self::Memory(memory) => memory.data_size(),
         }
     }
 
@@ -197,7 +314,98 @@ impl Parcel {
     /// accesses are bounds checked, this call is still safe, but we can't rely
     /// on that.
     pub unsafe fn set_data_position(&self, pos: i32) -> Result<()> {
-        status_result(sys::AParcel_setDataPosition(self.as_native(), pos))
+        memory_backend!(self, |memory| memory.set_data_position(pos));
+        #[cfg(feature = "ndk")]
+        {
+            status_result(sys::AParcel_setDataPosition(self.try_as_native()?, pos))
+        }
+    }
+}
+
+/// A position in the data of a [`Parcel`], returned by [`Parcel::checkpoint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ParcelPosition(i32);
+
+impl ParcelPosition {
+    /// The start of the parcel data.
+    pub const START: Self = Self(0);
+
+    /// The offset of this position from the start of the parcel data, in
+    /// bytes.
+    pub fn offset(self) -> i32 {
+        self.0
+    }
+}
+
+// Cursor methods
+impl Parcel {
+    /// Returns the current position in the parcel data, to go back to it
+    /// later with [`Parcel::rewind_to`].
+    pub fn checkpoint(&self) -> ParcelPosition {
+        ParcelPosition(self.get_data_position())
+    }
+
+    /// Move the current read/write position in the parcel to `position`.
+    ///
+    /// Fails with `BAD_INDEX` if the position is outside the parcel data.
+    pub fn rewind_to(&self, position: ParcelPosition) -> Result<()> {
+        if position.0 < 0 || position.0 > self.get_data_size() {
+            return Err(StatusCode::BAD_INDEX);
+        }
+        unsafe {
+            // Safety: The position is within the parcel data.
+            self.set_data_position(position.0)
+        }
+    }
+
+    /// Read a type that implements [`Deserialize`] without moving the
+    /// current position.
+    pub fn peek<D: Deserialize>(&self) -> Result<D> {
+        let position = self.checkpoint();
+        let result = self.read();
+        self.rewind_to(position)?;
+        result
+    }
+
+    /// Returns the number of bytes between the current position and the end
+    /// of the parcel data, or of the segment being read with
+    /// [`Parcel::sized_read`].
+    pub fn remaining(&self) -> usize {
+        let end = self.segment_end().unwrap_or_else(|| self.get_data_size());
+        (end - self.get_data_position()).max(0) as usize
+    }
+
+    /// The end of the innermost segment of this parcel being read with
+    /// [`Parcel::sized_read`] on this thread.
+    fn segment_end(&self) -> Option<i32> {
+        SEGMENT_ENDS.with(|ends| {
+            let ends = ends.borrow();
+            let key = self as *const Parcel as usize;
+            ends.iter().rev().find(|(parcel, _)| *parcel == key).map(|&(_, end)| end)
+        })
+    }
+}
+
+thread_local! {
+    /// Ends of the segments being read with `Parcel::sized_read` on this
+    /// thread, with the address of the `Parcel` they are read from, which is
+    /// borrowed for the duration of the read.
+    static SEGMENT_ENDS: RefCell<Vec<(usize, i32)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A segment end pushed on `SEGMENT_ENDS`, popped when dropped.
+struct SegmentEnd;
+
+impl SegmentEnd {
+    fn push(parcel: &Parcel, end: i32) -> SegmentEnd {
+        SEGMENT_ENDS.with(|ends| ends.borrow_mut().push((parcel as *const Parcel as usize, end)));
+        SegmentEnd
+    }
+}
+
+impl Drop for SegmentEnd {
+    fn drop(&mut self) {
+        SEGMENT_ENDS.with(|ends| ends.borrow_mut().pop());
     }
 }
 
@@ -211,6 +419,37 @@ impl<'a> WritableSubParcel<'a> {
     }
 }
 
+/// A segment of a readable parcel, used for [`Parcel::sized_read`].
+pub struct ReadableSubParcel<'a> {
+    parcel: &'a Parcel,
+    end: i32,
+}
+
+impl<'a> ReadableSubParcel<'a> {
+    /// Read a type that implements [`Deserialize`] from the sub-parcel.
+    ///
+    /// Fails with `NOT_ENOUGH_DATA`, without moving the position, if the value
+    /// does not fit in the sub-parcel. Array lengths are checked against the
+    /// end of the sub-parcel before reading the arrays.
+    pub fn read<D: Deserialize>(&self) -> Result<D> {
+        if !self.has_more_data() {
+            return Err(StatusCode::NOT_ENOUGH_DATA);
+        }
+        let start = self.parcel.checkpoint();
+        let value = self.parcel.read()?;
+        if self.parcel.get_data_position() > self.end {
+            self.parcel.rewind_to(start)?;
+            return Err(StatusCode::NOT_ENOUGH_DATA);
+        }
+        Ok(value)
+    }
+
+    /// Whether there is data left to read in the sub-parcel.
+    pub fn has_more_data(&self) -> bool {
+        self.parcel.get_data_position() < self.end
+    }
+}
+
 // Data deserialization methods
 impl Parcel {
     /// Attempt to read a type that implements [`Deserialize`] from this
@@ -219,11 +458,61 @@ impl Parcel {
         D::deserialize(self)
     }
 
+    /// Perform a series of reads from a segment of the `Parcel` prepended with
+    /// its length (in bytes), as written by [`Parcel::sized_write`].
+    ///
+    /// The length is validated against the parcel data, and the callback can't
+    /// read past the end of the segment: such reads fail with
+    /// `NOT_ENOUGH_DATA`. After the callback returns successfully, the parcel
+    /// is positioned at the end of the segment, skipping any data the callback
+    /// did not read, such as fields added by a newer version of a parcelable.
+    ///
+    /// The segment is read one nesting level deeper, see
+    /// [`Parcel::read_nested`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use binder::{Parcel, Result};
+    /// # fn read(parcel: &Parcel) -> Result<(u32, u32)> {
+    /// parcel.sized_read(|subparcel| {
+    ///     let a = subparcel.read()?;
+    ///     // Added in a later version of the parcelable
+    ///     let b = if subparcel.has_more_data() {
+    ///         subparcel.read()?
+    ///     } else {
+    ///         0
+    ///     };
+    ///     Ok((a, b))
+    /// })
+    /// # }
+    /// ```
+    pub fn sized_read<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&ReadableSubParcel) -> Result<R>,
+    {
+        let start = self.get_data_position();
+        let len: i32 = self.read()?;
+        // The length includes the length element itself, and the segment must
+        // fit in any segment being read.
+        if len < 4 || (len - 4) as usize > self.remaining() {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        let end = start + len;
+        let result = self.read_nested(|parcel| {
+            let _end = SegmentEnd::push(parcel, end);
+            f(&ReadableSubParcel { parcel, end })
+        })?;
+        self.rewind_to(ParcelPosition(end))?;
+        Ok(result)
+    }
+
     /// Read a vector size from the `Parcel` and resize the given output vector
     /// to be correctly sized for that amount of data.
     ///
     /// This method is used in AIDL-generated server side code for methods that
-    /// take a mutable slice reference parameter.
+    /// take a mutable slice reference parameter. Fails with `BAD_VALUE` if the
+    /// vector would exceed the [`DeserializeLimits`] of the parcel.
     pub fn resize_out_vec<D: Default + Deserialize>(&self, out_vec: &mut Vec<D>) -> Result<()> {
         let len: i32 = self.read()?;
 
@@ -233,6 +522,7 @@ impl Parcel {
 
         // usize in Rust may be 16-bit, so i32 may not fit
         let len = len.try_into().unwrap();
+        self.check_allocation(len, 0, mem::size_of::<D>())?;
         out_vec.resize_with(len, Default::default);
 
         Ok(())
@@ -243,7 +533,8 @@ impl Parcel {
     /// the vector should be null.
     ///
     /// This method is used in AIDL-generated server side code for methods that
-    /// take a mutable slice reference parameter.
+    /// take a mutable slice reference parameter. Fails with `BAD_VALUE` if the
+    /// vector would exceed the [`DeserializeLimits`] of the parcel.
     pub fn resize_nullable_out_vec<D: Default + Deserialize>(
         &self,
         out_vec: &mut Option<Vec<D>>,
@@ -255,6 +546,7 @@ impl Parcel {
         } else {
             // usize in Rust may be 16-bit, so i32 may not fit
             let len = len.try_into().unwrap();
+            self.check_allocation(len, 0, mem::size_of::<D>())?;
             let mut vec = Vec::with_capacity(len);
             vec.resize_with(len, Default::default);
             *out_vec = Some(vec);
@@ -266,7 +558,59 @@ impl Parcel {
 
 // Internal APIs
 impl Parcel {
+    /// Create a new parcel containing a copy of `data`, positioned at the
+    /// start of the data.
+    ///
+    /// The data is copied as plain 4-byte words, so it cannot contain binder
+    /// objects or file descriptors. A trailing partial word is zero-padded.
+    #[cfg(feature = "ndk")]
+    pub(crate) fn new_with_data(data: &[u8]) -> Result<Parcel> {
+        let mut parcel = unsafe {
+            // Safety: `AParcel_create` returns a new, owned parcel, or null.
+            // `Parcel::owned` takes ownership of the parcel and handles null.
+            Parcel::owned(sys::AParcel_create()).ok_or(StatusCode::NO_MEMORY)?
+        };
+        parcel.write_words(data)?;
+        parcel.rewind_to(ParcelPosition::START)?;
+        Ok(parcel)
+    }
+
+    /// Append `data` to the parcel as 4-byte words, zero-padding the final
+    /// word.
+    #[cfg(feature = "ndk")]
+    pub(crate) fn write_words(&mut self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.write(&i32::from_ne_bytes(word))?;
+        }
+        Ok(())
+    }
+
+    /// Copy the parcel data from `start` to the end of the parcel, leaving the
+    /// data position unchanged.
+    ///
+    /// Copying stops early at data that can't be read as plain words, such as
+    /// a binder object.
+    #[cfg(feature = "ndk")]
+    pub(crate) fn data_bytes(&self, start: i32) -> Vec<u8> {
+        let position = self.checkpoint();
+        let mut data = vec![];
+        if self.rewind_to(ParcelPosition(start)).is_ok() {
+            while self.remaining() > 0 {
+                match self.read::<u32>() {
+                    Ok(word) => data.extend_from_slice(&word.to_ne_bytes()),
+                    Err(_) => break,
+                }
+            }
+        }
+        let _ = self.rewind_to(position);
+        data
+    }
+
+    #[cfg(feature = "ndk")]
     pub(crate) fn write_binder(&mut self, binder: Option<&SpIBinder>) -> Result<()> {
+        let parcel = self.try_as_native_mut()?;
         unsafe {
             // Safety: `Parcel` always contains a valid pointer to an
             // `AParcel`. `AsNative` for `Option<SpIBinder`> will either return
@@ -279,20 +623,22 @@ impl Parcel {
             // refcount before the call. The refcount will be immediately
             // decremented when this temporary is dropped.
             status_result(sys::AParcel_writeStrongBinder(
-                self.as_native_mut(),
+                parcel,
                 binder.cloned().as_native_mut(),
             ))
         }
     }
 
+    #[cfg(feature = "ndk")]
     pub(crate) fn read_binder(&self) -> Result<Option<SpIBinder>> {
+        let parcel = self.try_as_native()?;
         let mut binder = ptr::null_mut();
         let status = unsafe {
             // Safety: `Parcel` always contains a valid pointer to an
             // `AParcel`. We pass a valid, mutable out pointer to the `binder`
             // parameter. After this call, `binder` will be either null or a
             // valid pointer to an `AIBinder` owned by the caller.
-            sys::AParcel_readStrongBinder(self.as_native(), &mut binder)
+            sys::AParcel_readStrongBinder(parcel, &mut binder)
         };
 
         status_result(status)?;
@@ -305,10 +651,12 @@ impl Parcel {
     }
 }
 
+#[cfg(feature = "ndk")]
 impl Drop for Parcel {
     fn drop(&mut self) {
         // Run the C++ Parcel complete object destructor
         if let Self::Owned(ptr) = *self {
+            Self::forget_native_limits(ptr);
             unsafe {
                 // Safety: `Parcel` always contains a valid pointer to an
                 // `AParcel`. If we own the parcel, we can safely delete it
@@ -560,3 +908,112 @@ fn test_sized_write() {
         &arr,
     );
 }
+
+#[test]
+fn test_sized_read() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.get_data_position();
+
+    // A newer writer with an extra field
+    parcel
+        .sized_write(|subparcel| {
+            subparcel.write(&1i32)?;
+            subparcel.write(&2i32)?;
+            subparcel.write("extra")
+        })
+        .unwrap();
+    parcel.write(&3i32).unwrap();
+    let truncated = parcel.get_data_position();
+    // An older writer without the second field
+    parcel.sized_write(|subparcel| subparcel.write(&4i32)).unwrap();
+    let invalid = parcel.get_data_position();
+    parcel.write(&-1i32).unwrap();
+    parcel.write(&1000i32).unwrap();
+    unsafe {
+        parcel.set_data_position(start).unwrap();
+    }
+
+    let read_fields = |subparcel: &ReadableSubParcel| {
+        let a: i32 = subparcel.read()?;
+        let b: i32 = if subparcel.has_more_data() {
+            subparcel.read()?
+        } else {
+            0
+        };
+        Ok((a, b))
+    };
+    assert_eq!(parcel.sized_read(read_fields), Ok((1, 2)));
+    assert_eq!(parcel.read::<i32>(), Ok(3));
+    assert_eq!(parcel.get_data_position(), truncated);
+    assert_eq!(parcel.sized_read(read_fields), Ok((4, 0)));
+
+    unsafe {
+        parcel.set_data_position(truncated).unwrap();
+    }
+    assert_eq!(
+        parcel.sized_read(|subparcel| subparcel.read::<i64>()),
+        Err(StatusCode::NOT_ENOUGH_DATA)
+    );
+
+    unsafe {
+        parcel.set_data_position(invalid).unwrap();
+    }
+    assert_eq!(parcel.sized_read(|_| Ok(())), Err(StatusCode::BAD_VALUE));
+    assert_eq!(parcel.sized_read(|_| Ok(())), Err(StatusCode::BAD_VALUE));
+
+    // An array length which only fits in the data following the segment
+    let array = ParcelPosition(parcel.get_data_size());
+    parcel.rewind_to(array).unwrap();
+    parcel.sized_write(|subparcel| subparcel.write(&2i32)).unwrap();
+    parcel.write(&[7i32, 8][..]).unwrap();
+    parcel.rewind_to(array).unwrap();
+    assert_eq!(
+        parcel.sized_read(|subparcel| {
+            assert_eq!(subparcel.parcel.remaining(), 4);
+            subparcel.read::<Vec<i32>>()
+        }),
+        Err(StatusCode::BAD_VALUE)
+    );
+}
+
+#[test]
+fn test_cursor() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.checkpoint();
+
+    parcel.write(&1i32).unwrap();
+    parcel.write(&2i64).unwrap();
+    let end = parcel.checkpoint();
+    assert_eq!(end.offset() - start.offset(), 12);
+    assert_eq!(parcel.remaining(), 0);
+
+    parcel.rewind_to(start).unwrap();
+    assert_eq!(parcel.remaining(), 12);
+    assert_eq!(parcel.peek::<i32>(), Ok(1));
+    assert_eq!(parcel.peek::<i32>(), Ok(1));
+    assert_eq!(parcel.checkpoint(), start);
+    assert_eq!(parcel.read::<i32>(), Ok(1));
+    assert_eq!(parcel.peek::<i64>(), Ok(2));
+    assert_eq!(parcel.remaining(), 8);
+
+    // Failed peeks don't move the position either
+    assert_eq!(parcel.peek::<Vec<i32>>(), Err(StatusCode::BAD_VALUE));
+    assert_eq!(parcel.remaining(), 8);
+
+    parcel.rewind_to(end).unwrap();
+    assert_eq!(parcel.peek::<i32>(), Err(StatusCode::NOT_ENOUGH_DATA));
+    assert_eq!(
+        parcel.rewind_to(ParcelPosition(end.offset() + 4)),
+        Err(StatusCode::BAD_INDEX)
+    );
+    assert_eq!(parcel.rewind_to(ParcelPosition(-4)), Err(StatusCode::BAD_INDEX));
+    assert_eq!(parcel.checkpoint(), end);
+}
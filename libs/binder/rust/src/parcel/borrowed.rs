@@ -0,0 +1,341 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reads of parcel data into views borrowing the parcel.
+//!
+//! Only in-memory parcels are read without copying. `libbinder_ndk` does not
+//! expose the data of native parcels, so native parcels get no benefit from
+//! these reads: their data is copied as by the owned reads, and strings are
+//! converted to UTF-8 by `libbinder_ndk`, then back to UTF-16.
+
+#[cfg(feature = "ndk")]
+use super::parcelable::read_native_string;
+use super::{DeserializeArray, Parcel};
+use crate::error::{Result, StatusCode};
+
+use std::borrow::Cow;
+use std::char::{decode_utf16, DecodeUtf16Error, REPLACEMENT_CHARACTER};
+use std::fmt;
+
+/// A UTF-16 string read from a [`Parcel`], in native byte order and without
+/// its null terminator.
+///
+/// The string is not validated, so it may contain unpaired surrogates.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Utf16Str<'a>(Cow<'a, [u8]>);
+
+impl<'a> Utf16Str<'a> {
+    /// The number of UTF-16 code units in the string.
+    pub fn len(&self) -> usize {
+        self.0.len() / 2
+    }
+
+    /// Whether the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The UTF-16 data of the string, in native byte order.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Iterate over the UTF-16 code units of the string.
+    pub fn code_units(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0
+            .chunks_exact(2)
+            .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+    }
+
+    /// Iterate over the characters of the string, or the unpaired surrogates
+    /// that can't be decoded.
+    pub fn chars(&self) -> impl Iterator<Item = std::result::Result<char, DecodeUtf16Error>> + '_ {
+        decode_utf16(self.code_units())
+    }
+
+    /// Convert the string to UTF-8, like reading a [`String`] from the parcel.
+    ///
+    /// Returns `BAD_VALUE` if the string contains unpaired surrogates.
+    pub fn to_utf8(&self) -> Result<String> {
+        self.chars()
+            .collect::<std::result::Result<_, _>>()
+            .or(Err(StatusCode::BAD_VALUE))
+    }
+
+    /// Copy the string data if it is borrowed from the parcel.
+    pub fn into_owned(self) -> Utf16Str<'static> {
+        Utf16Str(Cow::Owned(self.0.into_owned()))
+    }
+}
+
+impl PartialEq<str> for Utf16Str<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.code_units().eq(other.encode_utf16())
+    }
+}
+
+impl PartialEq<&str> for Utf16Str<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+/// Unpaired surrogates are displayed as `U+FFFD REPLACEMENT CHARACTER`.
+impl fmt::Display for Utf16Str<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use fmt::Write;
+
+        self.chars()
+            .try_for_each(|c| f.write_char(c.unwrap_or(REPLACEMENT_CHARACTER)))
+    }
+}
+
+impl Parcel {
+    /// Read a byte array, borrowing its data from an in-memory parcel. Native
+    /// parcels get no benefit from this, their data is copied as by
+    /// `Vec<u8>`.
+    ///
+    /// This reads the same data as `Vec<u8>`. Returns `UNEXPECTED_NULL` for a
+    /// null array.
+    pub fn read_bytes_ref(&self) -> Result<Cow<'_, [u8]>> {
+        self.read_nullable_bytes_ref()?
+            .ok_or(StatusCode::UNEXPECTED_NULL)
+    }
+
+    /// Read a nullable byte array, borrowing its data from an in-memory
+    /// parcel. Native parcels get no benefit from this, their data is copied
+    /// as by `Option<Vec<u8>>`.
+    ///
+    /// This reads the same data as `Option<Vec<u8>>`.
+    pub fn read_nullable_bytes_ref(&self) -> Result<Option<Cow<'_, [u8]>>> {
+        memory_backend!(self, |memory| Ok(memory.read_byte_array_inplace()?.map(Cow::Borrowed)));
+        #[cfg(feature = "ndk")]
+        {
+            Ok(u8::deserialize_array(self)?.map(Cow::Owned))
+        }
+    }
+
+    /// Read a string as UTF-16, borrowing its data from an in-memory parcel.
+    /// Native parcels get no benefit from this, as `libbinder_ndk` converts
+    /// their strings to UTF-8, which is converted back.
+    ///
+    /// This reads the same data as [`String`], but without converting it to
+    /// UTF-8. Returns `UNEXPECTED_NULL` for a null string.
+    pub fn read_utf16_str(&self) -> Result<Utf16Str<'_>> {
+        self.read_nullable_utf16_str()?
+            .ok_or(StatusCode::UNEXPECTED_NULL)
+    }
+
+    /// Read a nullable string as UTF-16, borrowing its data from an in-memory
+    /// parcel. Native parcels get no benefit from this, as `libbinder_ndk`
+    /// converts their strings to UTF-8, which is converted back.
+    ///
+    /// Like `Parcel::readString16Inplace`, a malformed string is read as null.
+    pub fn read_nullable_utf16_str(&self) -> Result<Option<Utf16Str<'_>>> {
+        memory_backend!(self, |memory| {
+            Ok(memory
+                .read_string16_inplace()?
+                .map(|bytes| Utf16Str(Cow::Borrowed(bytes))))
+        });
+        #[cfg(feature = "ndk")]
+        {
+            match read_native_string(self)? {
+                Some(utf8) => Ok(Some(Utf16Str(Cow::Owned(utf8_to_utf16(&utf8)?)))),
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// Read an array, calling `f` with every element instead of collecting
+    /// them into a `Vec`.
+    ///
+    /// This reads the same data as `Option<Vec<D>>`. Returns the number of
+    /// elements, or `None` for a null array. Reading stops at the first error
+    /// returned by `f`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use binder::{Parcel, Result};
+    /// # fn sum(parcel: &Parcel) -> Result<i64> {
+    /// let mut sum = 0i64;
+    /// parcel.deserialize_array_with(|value: i32| {
+    ///     sum += value as i64;
+    ///     Ok(())
+    /// })?;
+    /// # Ok(sum)
+    /// # }
+    /// ```
+    pub fn deserialize_array_with<D, F>(&self, f: F) -> Result<Option<usize>>
+    where
+        D: DeserializeArray,
+        F: FnMut(D) -> Result<()>,
+    {
+        D::deserialize_array_with(self, f)
+    }
+}
+
+/// Convert a string read by `libbinder_ndk` back to UTF-16, in native byte
+/// order.
+///
+/// `libutils` encodes unpaired surrogates as 3-byte sequences, which
+/// `String::from_utf8` rejects, so the sequences are decoded here.
+#[cfg(feature = "ndk")]
+fn utf8_to_utf16(utf8: &[u8]) -> Result<Vec<u8>> {
+    let mut utf16 = Vec::with_capacity(utf8.len() * 2);
+    let mut bytes = utf8.iter().copied();
+    while let Some(first) = bytes.next() {
+        let (continuations, mut code) = match first.leading_ones() {
+            0 => (0, u32::from(first)),
+            2 => (1, u32::from(first & 0x1f)),
+            3 => (2, u32::from(first & 0x0f)),
+            4 => (3, u32::from(first & 0x07)),
+            _ => return Err(StatusCode::BAD_VALUE),
+        };
+        for _ in 0..continuations {
+            match bytes.next() {
+                Some(byte) if byte & 0xc0 == 0x80 => code = code << 6 | u32::from(byte & 0x3f),
+                _ => return Err(StatusCode::BAD_VALUE),
+            }
+        }
+        match code {
+            0..=0xffff => utf16.extend_from_slice(&(code as u16).to_ne_bytes()),
+            0x10000..=0x10ffff => {
+                let code = code - 0x10000;
+                utf16.extend_from_slice(&(0xd800 | (code >> 10) as u16).to_ne_bytes());
+                utf16.extend_from_slice(&(0xdc00 | (code & 0x3ff) as u16).to_ne_bytes());
+            }
+            _ => return Err(StatusCode::BAD_VALUE),
+        }
+    }
+    Ok(utf16)
+}
+
+#[test]
+fn test_read_borrowed() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.checkpoint();
+
+    parcel.write(&b"Hello"[..]).unwrap();
+    parcel.write(&None::<Vec<u8>>).unwrap();
+    parcel.write("Hello, \u{1F980}").unwrap();
+    parcel.write(&None::<String>).unwrap();
+    parcel.write(&[1i32, 2, 3][..]).unwrap();
+    parcel.write(&["a", "bc"][..]).unwrap();
+    parcel.write(&b"xyz"[..]).unwrap();
+    parcel.rewind_to(start).unwrap();
+
+    assert_eq!(&*parcel.read_bytes_ref().unwrap(), b"Hello");
+    assert_eq!(parcel.read_bytes_ref(), Err(StatusCode::UNEXPECTED_NULL));
+
+    let s = parcel.read_utf16_str().unwrap();
+    assert_eq!(s.len(), 9);
+    assert_eq!(s, "Hello, \u{1F980}");
+    assert_eq!(s.to_string(), "Hello, \u{1F980}");
+    assert_eq!(parcel.read_nullable_utf16_str(), Ok(None));
+
+    let mut values = vec![];
+    assert_eq!(
+        parcel.deserialize_array_with(|v: i32| {
+            values.push(v);
+            Ok(())
+        }),
+        Ok(Some(3))
+    );
+    assert_eq!(values, [1, 2, 3]);
+
+    let mut strings = vec![];
+    assert_eq!(
+        parcel.deserialize_array_with(|s: String| {
+            strings.push(s);
+            Ok(())
+        }),
+        Ok(Some(2))
+    );
+    assert_eq!(strings, ["a", "bc"]);
+
+    assert_eq!(
+        parcel.deserialize_array_with(|_: u8| Err(StatusCode::BAD_VALUE)),
+        Err(StatusCode::BAD_VALUE)
+    );
+}
+
+#[test]
+fn test_read_malformed_utf16_str() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.checkpoint();
+
+    // Longer than the parcel, and too long to compute the size of on 32-bit
+    parcel.write(&(i32::MAX - 1)).unwrap();
+    parcel.write(&0i32).unwrap();
+    parcel.rewind_to(start).unwrap();
+    assert_eq!(parcel.read_nullable_utf16_str(), Ok(None));
+}
+
+#[test]
+fn test_read_unpaired_surrogate() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.checkpoint();
+
+    // "a", an unpaired surrogate and U+1F980 in UTF-16, and the terminator
+    let units: [u16; 6] = [0x61, 0xd800, 0xd83e, 0xdd80, 0, 0];
+    let bytes: Vec<u8> = units.iter().flat_map(|u| u.to_ne_bytes()).collect();
+    parcel.write(&4i32).unwrap();
+    parcel.write_words(&bytes).unwrap();
+    parcel.rewind_to(start).unwrap();
+
+    let s = parcel.read_utf16_str().unwrap();
+    assert_eq!(s.code_units().collect::<Vec<_>>(), &units[..4]);
+    assert_eq!(s.to_string(), "a\u{FFFD}\u{1F980}");
+}
+
+#[cfg(feature = "memory_parcel")]
+#[test]
+fn test_read_borrowed_in_memory() {
+    let mut parcel = Parcel::new_in_memory();
+    parcel.write(&b"Hello"[..]).unwrap();
+    parcel.write("Hello").unwrap();
+    parcel.write(&b"xyz"[..]).unwrap();
+    let parcel = Parcel::from_bytes(parcel.as_bytes().unwrap().to_vec());
+
+    assert!(matches!(
+        parcel.read_bytes_ref(),
+        Ok(Cow::Borrowed(b"Hello"))
+    ));
+    assert_eq!(parcel.read_utf16_str().unwrap(), "Hello");
+
+    let mut bytes = vec![];
+    assert_eq!(
+        parcel.deserialize_array_with(|b: u8| {
+            bytes.push(b);
+            Ok(())
+        }),
+        Ok(Some(3))
+    );
+    assert_eq!(bytes, b"xyz");
+}
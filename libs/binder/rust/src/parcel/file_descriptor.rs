@@ -18,74 +18,253 @@ use super::{
     Deserialize, DeserializeArray, DeserializeOption, Parcel, Serialize, SerializeArray,
     SerializeOption,
 };
-use crate::binder::AsNative;
-use crate::error::{status_result, Result, StatusCode};
+use crate::error::{status_from_io_error, status_result, Result, StatusCode};
 use crate::sys;
 
-use std::fs::File;
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{ChildStderr, ChildStdin, ChildStdout};
 
 /// Rust version of the Java class android.os.ParcelFileDescriptor
-#[derive(Debug)]
-pub struct ParcelFileDescriptor(File);
+///
+/// A `ParcelFileDescriptor` owns a file descriptor of any kind: files,
+/// sockets, pipes, eventfds, memfds, etc. It is closed when the
+/// `ParcelFileDescriptor` is dropped.
+///
+/// To send a file descriptor without giving up ownership of it, write a
+/// [`BorrowedFd`] to the parcel instead, which serializes exactly like a
+/// `ParcelFileDescriptor`.
+pub struct ParcelFileDescriptor(OwnedFd, FileView);
 
 impl ParcelFileDescriptor {
     /// Create a new `ParcelFileDescriptor`
-    pub fn new(file: File) -> Self {
-        Self(file)
+    pub fn new<F: Into<OwnedFd>>(fd: F) -> Self {
+        let fd = fd.into();
+        let view = FileView::new(&fd);
+        Self(fd, view)
+    }
+
+    /// Create a new `ParcelFileDescriptor` referring to the same open file
+    /// description as `fd`.
+    pub fn dup(fd: BorrowedFd<'_>) -> Result<Self> {
+        fd.try_clone_to_owned()
+            .map(Self::new)
+            .map_err(status_from_io_error)
+    }
+
+    /// Duplicate the file descriptor, returning a new `ParcelFileDescriptor`
+    /// referring to the same open file description.
+    pub fn try_clone(&self) -> Result<Self> {
+        Self::dup(self.0.as_fd())
+    }
+
+    /// Create a pipe, returning its read and write ends, in that order.
+    pub fn pipe() -> Result<(Self, Self)> {
+        let mut fds = [-1; 2];
+        let ret = unsafe {
+            // Safety: `fds` is a valid array of two file descriptors, which
+            // `pipe2` fills in on success.
+            libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC)
+        };
+        if ret < 0 {
+            return Err(status_from_io_error(io::Error::last_os_error()));
+        }
+        unsafe {
+            // Safety: `pipe2` succeeded, so both file descriptors are valid and
+            // owned by us.
+            Ok((Self::from_raw_fd(fds[0]), Self::from_raw_fd(fds[1])))
+        }
+    }
+
+    /// Open the file at `path` for reading.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path, "r")
+    }
+
+    /// Open the file at `path` with a Java `ParcelFileDescriptor` mode string:
+    /// `"r"`, `"w"` or `"wt"` (write and truncate), `"wa"` (write and append),
+    /// `"rw"`, or `"rwt"` (read, write and truncate). Files opened for writing
+    /// are created if they do not exist.
+    ///
+    /// Fails with `StatusCode::BAD_VALUE` if the mode is not valid.
+    pub fn open<P: AsRef<Path>>(path: P, mode: &str) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        match mode {
+            "r" => options.read(true),
+            "w" | "wt" => options.write(true).create(true).truncate(true),
+            "wa" => options.append(true).create(true),
+            "rw" => options.read(true).write(true).create(true),
+            "rwt" => options.read(true).write(true).create(true).truncate(true),
+            _ => return Err(StatusCode::BAD_VALUE),
+        };
+        options
+            .open(path)
+            .map(Self::new)
+            .map_err(status_from_io_error)
+    }
+}
+
+impl AsRef<OwnedFd> for ParcelFileDescriptor {
+    fn as_ref(&self) -> &OwnedFd {
+        &self.0
     }
 }
 
+/// Kept for compatibility with code written when `ParcelFileDescriptor` held a
+/// `File`. Prefer [`as_fd`](AsFd::as_fd) or `AsRef<OwnedFd>`.
 impl AsRef<File> for ParcelFileDescriptor {
     fn as_ref(&self) -> &File {
-        &self.0
+        &self.1 .0
+    }
+}
+
+impl fmt::Debug for ParcelFileDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ParcelFileDescriptor").field(&self.0).finish()
+    }
+}
+
+/// A `File` for the file descriptor owned by a `ParcelFileDescriptor`, which
+/// never closes it.
+struct FileView(ManuallyDrop<File>);
+
+impl FileView {
+    fn new(fd: &OwnedFd) -> Self {
+        let file = unsafe {
+            // Safety: `fd` is a valid file descriptor. The `File` is never
+            // dropped, so it doesn't close the file descriptor, and it is only
+            // borrowed for as long as the `ParcelFileDescriptor` which owns
+            // `fd` and this view.
+            File::from_raw_fd(fd.as_raw_fd())
+        };
+        Self(ManuallyDrop::new(file))
+    }
+}
+
+impl AsFd for ParcelFileDescriptor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl AsRawFd for ParcelFileDescriptor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for ParcelFileDescriptor {
+    fn into_raw_fd(self) -> RawFd {
+        self.0.into_raw_fd()
+    }
+}
+
+impl FromRawFd for ParcelFileDescriptor {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self::new(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+macro_rules! impl_fd_conversions {
+    ($($ty:ty)*) => {
+        $(
+            impl From<$ty> for ParcelFileDescriptor {
+                fn from(fd: $ty) -> Self {
+                    Self::new(fd)
+                }
+            }
+        )*
+    };
+}
+
+impl_fd_conversions! {
+    OwnedFd
+    File
+    UnixStream
+    ChildStdin
+    ChildStdout
+    ChildStderr
+}
+
+impl From<ParcelFileDescriptor> for OwnedFd {
+    fn from(fd: ParcelFileDescriptor) -> OwnedFd {
+        fd.0
     }
 }
 
 impl From<ParcelFileDescriptor> for File {
-    fn from(file: ParcelFileDescriptor) -> File {
-        file.0
+    fn from(fd: ParcelFileDescriptor) -> File {
+        fd.0.into()
     }
 }
 
-impl Serialize for ParcelFileDescriptor {
+impl From<ParcelFileDescriptor> for UnixStream {
+    fn from(fd: ParcelFileDescriptor) -> UnixStream {
+        fd.0.into()
+    }
+}
+
+impl Serialize for BorrowedFd<'_> {
     fn serialize(&self, parcel: &mut Parcel) -> Result<()> {
-        let fd = self.0.as_raw_fd();
+        memory_backend!(parcel, |_| Err(StatusCode::FDS_NOT_ALLOWED));
         let status = unsafe {
             // Safety: `Parcel` always contains a valid pointer to an
-            // `AParcel`. Likewise, `ParcelFileDescriptor` always contains a
-            // valid file, so we can borrow a valid file
-            // descriptor. `AParcel_writeParcelFileDescriptor` does NOT take
-            // ownership of the fd, so we need not duplicate it first.
-            sys::AParcel_writeParcelFileDescriptor(parcel.as_native_mut(), fd)
+            // `AParcel`. `BorrowedFd` is always a valid file descriptor for
+            // the duration of the call. `AParcel_writeParcelFileDescriptor`
+            // does NOT take ownership of the fd, it duplicates it into the
+            // parcel, so we need not duplicate it first.
+            sys::AParcel_writeParcelFileDescriptor(parcel.try_as_native_mut()?, self.as_raw_fd())
         };
         status_result(status)
     }
 }
 
-impl SerializeArray for ParcelFileDescriptor {}
+impl SerializeArray for BorrowedFd<'_> {}
 
-impl SerializeOption for ParcelFileDescriptor {
+impl SerializeOption for BorrowedFd<'_> {
     fn serialize_option(this: Option<&Self>, parcel: &mut Parcel) -> Result<()> {
-        if let Some(f) = this {
-            f.serialize(parcel)
+        memory_backend!(parcel, |_| Err(StatusCode::FDS_NOT_ALLOWED));
+        if let Some(fd) = this {
+            fd.serialize(parcel)
         } else {
             let status = unsafe {
                 // Safety: `Parcel` always contains a valid pointer to an
                 // `AParcel`. `AParcel_writeParcelFileDescriptor` accepts the
                 // value `-1` as the file descriptor to signify serializing a
                 // null file descriptor.
-                sys::AParcel_writeParcelFileDescriptor(parcel.as_native_mut(), -1i32)
+                sys::AParcel_writeParcelFileDescriptor(parcel.try_as_native_mut()?, -1i32)
             };
             status_result(status)
         }
     }
 }
 
+impl SerializeArray for Option<BorrowedFd<'_>> {}
+
+impl Serialize for ParcelFileDescriptor {
+    fn serialize(&self, parcel: &mut Parcel) -> Result<()> {
+        self.as_fd().serialize(parcel)
+    }
+}
+
+impl SerializeArray for ParcelFileDescriptor {}
+
+impl SerializeOption for ParcelFileDescriptor {
+    fn serialize_option(this: Option<&Self>, parcel: &mut Parcel) -> Result<()> {
+        SerializeOption::serialize_option(this.map(|f| f.as_fd()).as_ref(), parcel)
+    }
+}
+
 impl SerializeArray for Option<ParcelFileDescriptor> {}
 
 impl DeserializeOption for ParcelFileDescriptor {
     fn deserialize_option(parcel: &Parcel) -> Result<Option<Self>> {
+        memory_backend!(parcel, |_| Err(StatusCode::FDS_NOT_ALLOWED));
         let mut fd = -1i32;
         unsafe {
             // Safety: `Parcel` always contains a valid pointer to an
@@ -96,20 +275,20 @@ impl DeserializeOption for ParcelFileDescriptor {
             // descriptor to its caller if it was non-null, so we must take
             // ownership of the file and ensure that it is eventually closed.
             status_result(sys::AParcel_readParcelFileDescriptor(
-                parcel.as_native(),
+                parcel.try_as_native()?,
                 &mut fd,
             ))?;
         }
         if fd < 0 {
             Ok(None)
         } else {
-            let file = unsafe {
+            let fd = unsafe {
                 // Safety: At this point, we know that the file descriptor was
                 // not -1, so must be a valid, owned file descriptor which we
-                // can safely turn into a `File`.
-                File::from_raw_fd(fd)
+                // can safely turn into an `OwnedFd`.
+                OwnedFd::from_raw_fd(fd)
             };
-            Ok(Some(ParcelFileDescriptor::new(file)))
+            Ok(Some(ParcelFileDescriptor::new(fd)))
         }
     }
 }
@@ -125,3 +304,64 @@ impl Deserialize for ParcelFileDescriptor {
 }
 
 impl DeserializeArray for ParcelFileDescriptor {}
+
+#[test]
+fn test_file_descriptors() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+    use std::io::{Read, Write};
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.checkpoint();
+
+    let (read, write) = ParcelFileDescriptor::pipe().unwrap();
+    let (socket, _peer) = UnixStream::pair().unwrap();
+    parcel.write(&write.as_fd()).unwrap();
+    parcel.write(&Some(socket.as_fd())).unwrap();
+    parcel.write(&Option::<BorrowedFd>::None).unwrap();
+    parcel.write(&[read.as_fd()][..]).unwrap();
+
+    parcel.rewind_to(start).unwrap();
+
+    let mut received_write: File = parcel.read::<ParcelFileDescriptor>().unwrap().into();
+    let socket: UnixStream = parcel.read::<ParcelFileDescriptor>().unwrap().into();
+    assert!(socket.peer_addr().is_ok());
+    assert!(parcel
+        .read::<Option<ParcelFileDescriptor>>()
+        .unwrap()
+        .is_none());
+    let mut reads: Vec<ParcelFileDescriptor> = parcel.read().unwrap();
+    assert_eq!(reads.len(), 1);
+
+    // The fds in the parcel are duplicates of the originals, so the pipe is
+    // only closed once all of them are
+    drop(parcel);
+    drop(read);
+    drop(write);
+    received_write.write_all(b"hello").unwrap();
+    drop(received_write);
+    let mut data = String::new();
+    File::from(reads.remove(0).try_clone().unwrap())
+        .read_to_string(&mut data)
+        .unwrap();
+    assert_eq!(data, "hello");
+}
+
+#[test]
+fn test_open() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let null = ParcelFileDescriptor::open_read_only("/dev/null").unwrap();
+    let file: &File = null.as_ref();
+    assert_eq!(file.as_raw_fd(), null.as_raw_fd());
+    assert!(file.metadata().unwrap().file_type().is_char_device());
+    assert_eq!(
+        ParcelFileDescriptor::open("/dev/null", "x").err(),
+        Some(StatusCode::BAD_VALUE)
+    );
+    assert_eq!(
+        ParcelFileDescriptor::open_read_only("/does/not/exist").err(),
+        Some(StatusCode::NAME_NOT_FOUND)
+    );
+}
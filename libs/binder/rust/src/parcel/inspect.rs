@@ -0,0 +1,618 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Human-readable dumps of parcel data, for debugging failed transactions.
+
+use super::{Parcel, ParcelPosition};
+#[cfg(feature = "ndk")]
+use super::ParcelFileDescriptor;
+use crate::error::{Result, StatusCode};
+#[cfg(feature = "ndk")]
+use crate::proxy::SpIBinder;
+
+use std::fmt::{self, Write};
+
+/// Type of a value expected in a parcel, for [`Parcel::decode`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParcelType {
+    /// `i32`, also used for `u32` and AIDL `char`
+    I32,
+    /// `i64`
+    I64,
+    /// `f32`
+    F32,
+    /// `f64`
+    F64,
+    /// `bool`, written as an `i32`
+    Bool,
+    /// UTF-16 string, written by `String` and `&str`
+    String,
+    /// Byte array, written by `[u8]` and `[i8]`, packed into words
+    Bytes,
+    /// Non-null binder object
+    Binder,
+    /// Non-null file descriptor, written by `ParcelFileDescriptor`
+    FileDescriptor,
+    /// Length-prefixed array of elements of the given type
+    Array(Box<ParcelType>),
+    /// Nullable value of the given type. Strings, arrays, binders and file
+    /// descriptors have their own null representation; other types are
+    /// preceded by a non-null marker.
+    Nullable(Box<ParcelType>),
+    /// Fields written by [`Parcel::sized_write`], prefixed with their size.
+    /// AIDL parcelables are written as a nullable parcelable.
+    Parcelable(Vec<ParcelType>),
+}
+
+impl ParcelType {
+    /// Array of elements of type `element`
+    pub fn array(element: ParcelType) -> ParcelType {
+        ParcelType::Array(Box::new(element))
+    }
+
+    /// Nullable value of type `inner`
+    pub fn nullable(inner: ParcelType) -> ParcelType {
+        ParcelType::Nullable(Box::new(inner))
+    }
+}
+
+impl fmt::Display for ParcelType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParcelType::I32 => write!(f, "i32"),
+            ParcelType::I64 => write!(f, "i64"),
+            ParcelType::F32 => write!(f, "f32"),
+            ParcelType::F64 => write!(f, "f64"),
+            ParcelType::Bool => write!(f, "bool"),
+            ParcelType::String => write!(f, "String"),
+            ParcelType::Bytes => write!(f, "Vec<u8>"),
+            ParcelType::Binder => write!(f, "binder"),
+            ParcelType::FileDescriptor => write!(f, "fd"),
+            ParcelType::Array(element) => write!(f, "Vec<{}>", element),
+            ParcelType::Nullable(inner) => write!(f, "Option<{}>", inner),
+            ParcelType::Parcelable(_) => write!(f, "parcelable"),
+        }
+    }
+}
+
+/// A value decoded by [`Parcel::decode`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedValue {
+    /// Position of the value in the parcel
+    pub offset: i32,
+    /// Nesting level of the value inside arrays and parcelables
+    pub depth: usize,
+    /// Expected type of the value, with its index inside arrays
+    pub name: String,
+    /// The decoded value
+    pub value: String,
+}
+
+/// The point where the parcel data diverged from the expected types.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Position of the value that could not be decoded
+    pub offset: i32,
+    /// What was expected at `offset`
+    pub expected: String,
+    /// The error returned when decoding it
+    pub error: StatusCode,
+}
+
+/// Annotated dump of a parcel decoded with [`Parcel::decode`].
+///
+/// The `Display` implementation prints every decoded value with its offset,
+/// followed by the point of divergence or unexpected trailing data, if any,
+/// as a hex dump.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedParcel {
+    values: Vec<DecodedValue>,
+    divergence: Option<Divergence>,
+    remaining: String,
+}
+
+impl DecodedParcel {
+    /// The values decoded before decoding stopped.
+    pub fn values(&self) -> &[DecodedValue] {
+        &self.values
+    }
+
+    /// Where and why decoding stopped, if the data did not match the
+    /// expected types.
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    /// Whether the parcel contained exactly the expected types.
+    pub fn is_complete(&self) -> bool {
+        self.divergence.is_none() && self.remaining.is_empty()
+    }
+}
+
+impl fmt::Display for DecodedParcel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for value in &self.values {
+            writeln!(
+                f,
+                "{:08x}: {:indent$}{}: {}",
+                value.offset,
+                "",
+                value.name,
+                value.value,
+                indent = value.depth * 2
+            )?;
+        }
+        if let Some(divergence) = &self.divergence {
+            writeln!(
+                f,
+                "{:08x}: diverged, expected {}: {:?}",
+                divergence.offset, divergence.expected, divergence.error
+            )?;
+            write!(f, "remaining data:\n{}", self.remaining)
+        } else if !self.remaining.is_empty() {
+            write!(
+                f,
+                "unexpected data after the last value:\n{}",
+                self.remaining
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Parcel {
+    /// Return a hex dump of all the data in the parcel, 16 bytes per line
+    /// with their offsets. Binder and file descriptor objects are shown as
+    /// such, as their contents are only meaningful to the kernel.
+    ///
+    /// The data position is left unchanged.
+    pub fn hexdump(&self) -> String {
+        let position = self.get_data_position();
+        let dump = self.hexdump_from(0);
+        let _ = self.seek(position);
+        dump
+    }
+
+    /// Decode the parcel data from the current position as a sequence of
+    /// values of the expected types, stopping at the first value that can't
+    /// be decoded.
+    ///
+    /// The data position is left unchanged, so this can be called on the
+    /// data of a failed transaction before or after reading from it.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let decoded = data.decode(&[
+    ///     ParcelType::I32,
+    ///     ParcelType::String,
+    ///     ParcelType::array(ParcelType::I64),
+    ///     ParcelType::nullable(ParcelType::Binder),
+    /// ]);
+    /// assert!(decoded.is_complete(), "{}", decoded);
+    /// ```
+    pub fn decode(&self, types: &[ParcelType]) -> DecodedParcel {
+        let position = self.get_data_position();
+        let mut decoder = Decoder {
+            parcel: self,
+            values: vec![],
+            depth: 0,
+        };
+        let divergence = types
+            .iter()
+            .try_for_each(|ty| decoder.decode(ty.to_string(), ty))
+            .err();
+        let values = decoder.values;
+        let remaining = match &divergence {
+            Some(divergence) => self.hexdump_from(divergence.offset),
+            None => self.hexdump_from(self.get_data_position()),
+        };
+        let _ = self.seek(position);
+        DecodedParcel {
+            values,
+            divergence,
+            remaining,
+        }
+    }
+
+    /// Move the data position to `pos`, which must be inside the data.
+    fn seek(&self, pos: i32) -> Result<()> {
+        self.rewind_to(ParcelPosition(pos))
+    }
+
+    /// Hex dump the data from `start` to the end of the parcel. Moves the
+    /// data position.
+    fn hexdump_from(&self, start: i32) -> String {
+        let mut dump = String::new();
+        if self.seek(start).is_err() {
+            return dump;
+        }
+        let mut run_start = start;
+        let mut run = vec![];
+        while self.get_data_position() < self.get_data_size() {
+            let offset = self.get_data_position();
+            if let Ok(word) = self.read::<u32>() {
+                if run.is_empty() {
+                    run_start = offset;
+                }
+                run.extend_from_slice(&word.to_ne_bytes());
+                continue;
+            }
+            write_hex_lines(&mut dump, run_start, &run);
+            run.clear();
+
+            // Plain reads fail on objects. File descriptors are preceded by a
+            // non-null marker and a flag word, which must be read with them.
+            let object = if self.seek(offset).is_ok() && read_binder(self).is_ok() {
+                Some("binder object")
+            } else if offset >= 8
+                && self.seek(offset - 8).is_ok()
+                && matches!(read_file_descriptor(self), Ok(true))
+            {
+                Some("file descriptor object")
+            } else {
+                None
+            };
+            let end = self.get_data_position();
+            match object {
+                Some(object) if end > offset => {
+                    let _ = writeln!(dump, "{:08x}: <{}, {} bytes>", offset, object, end - offset);
+                }
+                _ => {
+                    let _ = writeln!(
+                        dump,
+                        "{:08x}: <{} unreadable bytes>",
+                        offset,
+                        self.get_data_size() - offset
+                    );
+                    return dump;
+                }
+            }
+        }
+        write_hex_lines(&mut dump, run_start, &run);
+        dump
+    }
+}
+
+fn write_hex_lines(dump: &mut String, start: i32, data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (j, byte) in line.iter().enumerate() {
+            if j > 0 && j % 4 == 0 {
+                hex.push(' ');
+            }
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        let text: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(
+            dump,
+            "{:08x}: {:35}  {}",
+            start as usize + i * 16,
+            hex,
+            text
+        );
+    }
+}
+
+struct Decoder<'a> {
+    parcel: &'a Parcel,
+    values: Vec<DecodedValue>,
+    depth: usize,
+}
+
+impl Decoder<'_> {
+    /// Decode a value of type `ty`, recording it as `name`.
+    fn decode(&mut self, name: String, ty: &ParcelType) -> std::result::Result<(), Divergence> {
+        let offset = self.parcel.get_data_position();
+        let expected = name.clone();
+        let diverged = move |error| Divergence {
+            offset,
+            expected: expected.clone(),
+            error,
+        };
+        let value = match ty {
+            ParcelType::I32 => {
+                let value: i32 = self.parcel.read().map_err(&diverged)?;
+                format!("{} ({:#010x})", value, value)
+            }
+            ParcelType::I64 => {
+                let value: i64 = self.parcel.read().map_err(&diverged)?;
+                format!("{} ({:#018x})", value, value)
+            }
+            ParcelType::F32 => self.parcel.read::<f32>().map_err(&diverged)?.to_string(),
+            ParcelType::F64 => self.parcel.read::<f64>().map_err(&diverged)?.to_string(),
+            ParcelType::Bool => self.parcel.read::<bool>().map_err(&diverged)?.to_string(),
+            ParcelType::String => format!("{:?}", self.parcel.read::<String>().map_err(&diverged)?),
+            ParcelType::Bytes => describe_bytes(&self.parcel.read::<Vec<u8>>().map_err(&diverged)?),
+            ParcelType::Binder => read_binder(self.parcel).map_err(&diverged)?,
+            ParcelType::FileDescriptor => {
+                if !read_file_descriptor(self.parcel).map_err(&diverged)? {
+                    return Err(diverged(StatusCode::UNEXPECTED_NULL));
+                }
+                "file descriptor".to_owned()
+            }
+            ParcelType::Array(element) => {
+                let len: i32 = self.parcel.read().map_err(&diverged)?;
+                if len == -1 {
+                    return Err(diverged(StatusCode::UNEXPECTED_NULL));
+                }
+                let remaining = self.parcel.get_data_size() - self.parcel.get_data_position();
+                if len < 0 || len > remaining {
+                    return Err(diverged(StatusCode::BAD_VALUE));
+                }
+                self.push(offset, name, format!("{} elements", len));
+                self.depth += 1;
+                for i in 0..len {
+                    self.decode(format!("[{}] {}", i, element), element)?;
+                }
+                self.depth -= 1;
+                return Ok(());
+            }
+            ParcelType::Nullable(inner) => match **inner {
+                ParcelType::String => match self.parcel.read::<Option<String>>() {
+                    Ok(value) => value.map_or("null".to_owned(), |s| format!("{:?}", s)),
+                    Err(e) => return Err(diverged(e)),
+                },
+                ParcelType::Binder => read_binder(self.parcel).map_err(&diverged)?,
+                ParcelType::FileDescriptor => {
+                    match read_file_descriptor(self.parcel).map_err(&diverged)? {
+                        true => "file descriptor".to_owned(),
+                        false => "null".to_owned(),
+                    }
+                }
+                ParcelType::Array(_) | ParcelType::Bytes => {
+                    if self.parcel.read::<i32>().map_err(&diverged)? == -1 {
+                        "null".to_owned()
+                    } else {
+                        self.parcel.seek(offset).map_err(&diverged)?;
+                        return self.decode(name, inner);
+                    }
+                }
+                _ => {
+                    if self.parcel.read::<i32>().map_err(&diverged)? == 0 {
+                        "null".to_owned()
+                    } else {
+                        return self.decode(name, inner);
+                    }
+                }
+            },
+            ParcelType::Parcelable(fields) => {
+                let size: i32 = self.parcel.read().map_err(&diverged)?;
+                let end = offset.checked_add(size);
+                let end = match end {
+                    Some(end) if size >= 4 && end <= self.parcel.get_data_size() => end,
+                    _ => return Err(diverged(StatusCode::BAD_VALUE)),
+                };
+                self.push(offset, name, format!("{} bytes", size));
+                self.depth += 1;
+                for field in fields {
+                    self.decode(field.to_string(), field)?;
+                }
+                let position = self.parcel.get_data_position();
+                if position > end {
+                    return Err(Divergence {
+                        offset: end,
+                        expected: format!("end of parcelable at {:#x}", end),
+                        error: StatusCode::BAD_VALUE,
+                    });
+                }
+                if position < end {
+                    // Fields added in newer versions of the parcelable are
+                    // skipped, as AIDL does.
+                    self.push(
+                        position,
+                        "unknown fields".to_owned(),
+                        format!("{} bytes", end - position),
+                    );
+                    self.parcel.seek(end).map_err(&diverged)?;
+                }
+                self.depth -= 1;
+                return Ok(());
+            }
+        };
+        self.push(offset, name, value);
+        Ok(())
+    }
+
+    fn push(&mut self, offset: i32, name: String, value: String) {
+        self.values.push(DecodedValue {
+            offset,
+            depth: self.depth,
+            name,
+            value,
+        });
+    }
+}
+
+fn describe_bytes(bytes: &[u8]) -> String {
+    const PREVIEW: usize = 16;
+    let mut description = format!("{} bytes [", bytes.len());
+    for (i, byte) in bytes.iter().take(PREVIEW).enumerate() {
+        if i > 0 {
+            description.push(' ');
+        }
+        let _ = write!(description, "{:02x}", byte);
+    }
+    if bytes.len() > PREVIEW {
+        description.push_str(" ...");
+    }
+    description.push(']');
+    description
+}
+
+/// Read a nullable binder object and describe it.
+#[cfg(feature = "ndk")]
+fn read_binder(parcel: &Parcel) -> Result<String> {
+    let binder: Option<SpIBinder> = parcel.read()?;
+    Ok(match binder {
+        Some(mut binder) => {
+            let kind = if binder.is_remote() {
+                "remote"
+            } else {
+                "local"
+            };
+            match binder.get_class() {
+                Some(class) => format!("{} binder {:?}", kind, class.get_descriptor()),
+                None => format!("{} binder", kind),
+            }
+        }
+        None => "null".to_owned(),
+    })
+}
+
+/// Without `libbinder_ndk`, parcels are in memory and hold no binder objects.
+#[cfg(not(feature = "ndk"))]
+fn read_binder(_parcel: &Parcel) -> Result<String> {
+    Err(StatusCode::INVALID_OPERATION)
+}
+
+/// Read a nullable file descriptor object, returning whether it was non-null.
+#[cfg(feature = "ndk")]
+fn read_file_descriptor(parcel: &Parcel) -> Result<bool> {
+    Ok(parcel.read::<Option<ParcelFileDescriptor>>()?.is_some())
+}
+
+/// Without `libbinder_ndk`, parcels are in memory and hold no file
+/// descriptors.
+#[cfg(not(feature = "ndk"))]
+fn read_file_descriptor(_parcel: &Parcel) -> Result<bool> {
+    Err(StatusCode::FDS_NOT_ALLOWED)
+}
+
+#[test]
+fn test_hexdump() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    // The binder object ends the line of the interface token, so the values
+    // after it start a new line.
+    parcel.write(&service).unwrap();
+    let start = parcel.get_data_position();
+    parcel.write(&0x0403_0201i32).unwrap();
+    parcel.write("hi").unwrap();
+    parcel.write(&7i32).unwrap();
+
+    let dump = parcel.hexdump();
+    assert_eq!(parcel.get_data_position(), parcel.get_data_size());
+    assert!(dump.starts_with("00000000: "), "{}", dump);
+    assert!(dump.contains("<binder object, "), "{}", dump);
+    let expected = format!(
+        "{:08x}: 01020304 02000000 68006900 00000000  ........h.i.....\n\
+         {:08x}: {:35}  ....\n",
+        start,
+        start + 16,
+        "07000000"
+    );
+    assert!(dump.ends_with(&expected), "{}", dump);
+}
+
+#[test]
+fn test_decode() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.get_data_position();
+    parcel.write(&42i32).unwrap();
+    parcel
+        .sized_write(|sub| {
+            sub.write("inner")?;
+            sub.write(&[1i64, 2][..])?;
+            sub.write(&true)
+        })
+        .unwrap();
+    parcel.write(&Option::<SpIBinder>::None).unwrap();
+    parcel.write(&service).unwrap();
+    parcel.write(&5i32).unwrap();
+
+    unsafe {
+        parcel.set_data_position(start).unwrap();
+    }
+    let decoded = parcel.decode(&[
+        ParcelType::I32,
+        // Only the first two fields are known, so the last one is skipped
+        ParcelType::Parcelable(vec![ParcelType::String, ParcelType::array(ParcelType::I64)]),
+        ParcelType::nullable(ParcelType::Binder),
+        ParcelType::Binder,
+        ParcelType::I32,
+    ]);
+    assert!(decoded.is_complete(), "{}", decoded);
+    assert_eq!(parcel.get_data_position(), start);
+    let values: Vec<_> = decoded
+        .values()
+        .iter()
+        .map(|v| (v.depth, v.name.as_str(), v.value.as_str()))
+        .collect();
+    assert_eq!(
+        values,
+        [
+            (0, "i32", "42 (0x0000002a)"),
+            (0, "parcelable", "44 bytes"),
+            (1, "String", "\"inner\""),
+            (1, "Vec<i64>", "2 elements"),
+            (2, "[0] i64", "1 (0x0000000000000001)"),
+            (2, "[1] i64", "2 (0x0000000000000002)"),
+            (1, "unknown fields", "4 bytes"),
+            (0, "Option<binder>", "null"),
+            (0, "binder", "local binder \"\""),
+            (0, "i32", "5 (0x00000005)"),
+        ]
+    );
+    assert_eq!(decoded.values()[0].offset, start);
+
+    // A binder where there is an integer
+    let decoded = parcel.decode(&[ParcelType::Binder]);
+    let divergence = decoded.divergence().unwrap();
+    assert_eq!(divergence.offset, start);
+    assert_eq!(divergence.expected, "binder");
+    assert!(
+        decoded.to_string().contains("diverged, expected binder"),
+        "{}",
+        decoded
+    );
+
+    // A parcelable with more fields than its size
+    let decoded = parcel.decode(&[
+        ParcelType::I32,
+        ParcelType::Parcelable(vec![
+            ParcelType::String,
+            ParcelType::array(ParcelType::I64),
+            ParcelType::Bool,
+            ParcelType::I32,
+        ]),
+    ]);
+    let divergence = decoded.divergence().unwrap();
+    assert_eq!(divergence.offset, start + 48);
+    assert_eq!(divergence.error, StatusCode::BAD_VALUE);
+
+    // Data left over
+    let decoded = parcel.decode(&[ParcelType::I32]);
+    assert!(decoded.divergence().is_none());
+    assert!(!decoded.is_complete());
+}
@@ -0,0 +1,132 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The interface token that starts every AIDL transaction.
+//!
+//! Transactions made through [`IBinder::transact`](crate::IBinder::transact)
+//! already start with the token, which `libbinder_ndk` writes for the
+//! [`InterfaceClass`](crate::InterfaceClass) of the binder and checks before
+//! calling [`Remotable::on_transact`](crate::Remotable::on_transact). These
+//! methods are for parcels that don't go through `libbinder_ndk`, such as
+//! in-memory parcels, and use the same format as `Parcel::writeInterfaceToken`
+//! in `libbinder`.
+
+use super::Parcel;
+use crate::error::{Result, StatusCode};
+
+/// Set in the strict mode policy to ask the callee to gather violations.
+const STRICT_MODE_PENALTY_GATHER: i32 = 1 << 31;
+
+/// The work source of a caller that doesn't propagate one.
+const UNSET_WORK_SOURCE: i32 = -1;
+
+const fn pack_chars(c: [u8; 4]) -> i32 {
+    i32::from_be_bytes(c)
+}
+
+/// Identifies the copy of `libbinder` that wrote the token. Soong sets
+/// `android_vndk` for vendor variants.
+#[allow(unexpected_cfgs)]
+const HEADER: i32 = if cfg!(android_vndk) {
+    pack_chars(*b"VNDR")
+} else {
+    pack_chars(*b"SYST")
+};
+
+impl Parcel {
+    /// Write the interface token for `descriptor`.
+    ///
+    /// The token holds a strict mode policy without penalties, an unset work
+    /// source and a header identifying the system or vendor partition,
+    /// followed by the descriptor.
+    pub fn write_interface_token(&mut self, descriptor: &str) -> Result<()> {
+        self.write(&STRICT_MODE_PENALTY_GATHER)?;
+        self.write(&UNSET_WORK_SOURCE)?;
+        self.write(&HEADER)?;
+        self.write(descriptor)
+    }
+
+    /// Read an interface token and check that it is for `expected`.
+    ///
+    /// Returns `BAD_TYPE`, like `libbinder_ndk` does for transactions, if the
+    /// token was written by a different copy of `libbinder` or for another
+    /// interface. The strict mode policy and work source are ignored.
+    pub fn enforce_interface(&self, expected: &str) -> Result<()> {
+        let _strict_mode_policy: i32 = self.read()?;
+        let _work_source: i32 = self.read()?;
+        if self.read::<i32>()? != HEADER {
+            return Err(StatusCode::BAD_TYPE);
+        }
+        match self.read_nullable_utf16_str()? {
+            Some(descriptor) if descriptor == expected => Ok(()),
+            _ => Err(StatusCode::BAD_TYPE),
+        }
+    }
+}
+
+#[test]
+fn test_interface_token() {
+    use super::ParcelPosition;
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+
+    // `libbinder_ndk` already wrote the token for the empty descriptor of `()`
+    assert_eq!(parcel.enforce_interface(""), Ok(()));
+    parcel.rewind_to(ParcelPosition::START).unwrap();
+    assert_eq!(parcel.enforce_interface("foo"), Err(StatusCode::BAD_TYPE));
+
+    let start = parcel.checkpoint();
+    parcel
+        .write_interface_token("android.binder.test.IFoo")
+        .unwrap();
+    parcel.write(&1i32).unwrap();
+    parcel.rewind_to(start).unwrap();
+    assert_eq!(parcel.enforce_interface("android.binder.test.IFoo"), Ok(()));
+    assert_eq!(parcel.read::<i32>(), Ok(1));
+
+    parcel.rewind_to(start).unwrap();
+    assert_eq!(
+        parcel.enforce_interface("android.binder.test.IBar"),
+        Err(StatusCode::BAD_TYPE)
+    );
+
+    // A token from a different partition
+    parcel.rewind_to(start).unwrap();
+    parcel.write(&0i32).unwrap();
+    parcel.write(&UNSET_WORK_SOURCE).unwrap();
+    parcel.write(&pack_chars(*b"XXXX")).unwrap();
+    parcel.write("android.binder.test.IFoo").unwrap();
+    parcel.rewind_to(start).unwrap();
+    assert_eq!(
+        parcel.enforce_interface("android.binder.test.IFoo"),
+        Err(StatusCode::BAD_TYPE)
+    );
+}
+
+#[cfg(feature = "memory_parcel")]
+#[test]
+fn test_interface_token_in_memory() {
+    let mut parcel = Parcel::new_in_memory();
+    parcel
+        .write_interface_token("android.binder.test.IFoo")
+        .unwrap();
+    let parcel = Parcel::from_bytes(parcel.as_bytes().unwrap().to_vec());
+    assert_eq!(parcel.enforce_interface("android.binder.test.IFoo"), Ok(()));
+    assert_eq!(parcel.remaining(), 0);
+}
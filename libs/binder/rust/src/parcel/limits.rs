@@ -0,0 +1,395 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Limits on the memory and recursion used to deserialize a parcel.
+//!
+//! Array lengths are read from the parcel, so they are always checked against
+//! the size of the remaining data before allocating the array. A
+//! [`DeserializeLimits`] policy can further bound the allocations made while
+//! reading a parcel, for instance for the out arrays of a transaction, whose
+//! length is sent without any data.
+
+use super::Parcel;
+use crate::error::{Result, StatusCode};
+
+use std::cell::Cell;
+#[cfg(feature = "ndk")]
+use std::cell::RefCell;
+#[cfg(feature = "ndk")]
+use std::collections::HashMap;
+#[cfg(feature = "ndk")]
+use std::ffi::c_void;
+use std::rc::Rc;
+#[cfg(feature = "ndk")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "ndk")]
+use std::sync::{Mutex, MutexGuard};
+
+/// Limits on the resources used to deserialize a [`Parcel`].
+///
+/// Reads exceeding a limit fail with `BAD_VALUE`. Limits are set on a parcel
+/// with [`Parcel::set_deserialize_limits`], or on every transaction handled
+/// by a service with
+/// [`Binder::set_deserialize_limits`](crate::Binder::set_deserialize_limits).
+///
+/// # Examples
+///
+/// ```
+/// # use binder::parcel::DeserializeLimits;
+/// let limits = DeserializeLimits {
+///     max_elements: 1024,
+///     max_total_bytes: 64 * 1024,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeserializeLimits {
+    /// Maximum number of elements of a single array.
+    pub max_elements: usize,
+    /// Maximum number of bytes allocated for all the arrays read from the
+    /// parcel.
+    pub max_total_bytes: usize,
+    /// Maximum nesting depth of arrays, nullable values and parcelables read
+    /// with [`Parcel::read_nested`].
+    pub max_depth: usize,
+}
+
+impl DeserializeLimits {
+    /// No limit besides the size of the parcel data.
+    pub const UNLIMITED: Self = Self {
+        max_elements: usize::MAX,
+        max_total_bytes: usize::MAX,
+        max_depth: usize::MAX,
+    };
+}
+
+impl Default for DeserializeLimits {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
+/// The limits of a parcel and the resources used so far.
+#[derive(Debug)]
+pub(crate) struct LimitState {
+    limits: DeserializeLimits,
+    total_bytes: Cell<usize>,
+    depth: Cell<usize>,
+}
+
+impl LimitState {
+    pub(crate) fn new(limits: DeserializeLimits) -> Rc<Self> {
+        Rc::new(Self {
+            limits,
+            total_bytes: Cell::new(0),
+            depth: Cell::new(0),
+        })
+    }
+}
+
+#[cfg(feature = "ndk")]
+thread_local! {
+    /// Limits of native parcels, keyed by their `AParcel` pointer. `Parcel`
+    /// is not `Send`, so their limits are only needed on the thread which set
+    /// them. Borrowed parcels, such as the ones created for each array
+    /// element, share the entry of the parcel they borrow. Entries are removed
+    /// when an owned `Parcel` is dropped or consumed, parcels moved to another
+    /// thread internally lose their limits first, and `on_transact` clears the
+    /// limits it sets on the transaction data.
+    static NATIVE_LIMITS: RefCell<HashMap<usize, Rc<LimitState>>> = RefCell::new(HashMap::new());
+}
+
+/// Number of services with limits, so that transactions do not have to take
+/// the lock when there are none.
+#[cfg(feature = "ndk")]
+static SERVICE_LIMITS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Limits of local services, keyed by the address of their Rust object.
+#[cfg(feature = "ndk")]
+static SERVICE_LIMITS: Mutex<Option<HashMap<usize, DeserializeLimits>>> = Mutex::new(None);
+
+#[cfg(feature = "ndk")]
+fn service_limits_map() -> MutexGuard<'static, Option<HashMap<usize, DeserializeLimits>>> {
+    // A panic while holding the lock can't leave the map inconsistent.
+    SERVICE_LIMITS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Set the limits of the data of the transactions handled by the local
+/// service with the given Rust object, or remove them if `limits` is `None`.
+#[cfg(feature = "ndk")]
+pub(crate) fn set_service_limits(object: *const c_void, limits: Option<DeserializeLimits>) {
+    if limits.is_none() && SERVICE_LIMITS_COUNT.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let mut map = service_limits_map();
+    let map = map.get_or_insert_with(HashMap::new);
+    match limits {
+        Some(limits) => map.insert(object as usize, limits),
+        None => map.remove(&(object as usize)),
+    };
+    SERVICE_LIMITS_COUNT.store(map.len(), Ordering::SeqCst);
+}
+
+#[cfg(feature = "ndk")]
+pub(crate) fn service_limits(object: *const c_void) -> Option<DeserializeLimits> {
+    if SERVICE_LIMITS_COUNT.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+    service_limits_map()
+        .as_ref()
+        .and_then(|map| map.get(&(object as usize)).copied())
+}
+
+impl Parcel {
+    /// Limit the resources used to read this parcel, replacing any previous
+    /// limits and resetting the resources counted so far.
+    pub fn set_deserialize_limits(&mut self, limits: DeserializeLimits) {
+        self.set_limit_state(Some(LimitState::new(limits)))
+    }
+
+    /// Remove the limits set with
+    /// [`set_deserialize_limits`](Self::set_deserialize_limits).
+    pub fn clear_deserialize_limits(&mut self) {
+        self.set_limit_state(None)
+    }
+
+    /// The limits set on this parcel, if any.
+    pub fn deserialize_limits(&self) -> Option<DeserializeLimits> {
+        self.limit_state().map(|state| state.limits)
+    }
+
+    fn set_limit_state(&mut self, state: Option<Rc<LimitState>>) {
+        memory_backend!(self, |memory| memory.limits = state);
+        #[cfg(feature = "ndk")]
+        {
+            let key = match self.try_as_native() {
+                Ok(parcel) => parcel as usize,
+                Err(_) => return,
+            };
+            NATIVE_LIMITS.with(|limits| {
+                let mut limits = limits.borrow_mut();
+                match state {
+                    Some(state) => limits.insert(key, state),
+                    None => limits.remove(&key),
+                }
+            });
+        }
+    }
+
+    fn limit_state(&self) -> Option<Rc<LimitState>> {
+        memory_backend!(self, |memory| memory.limits.clone());
+        #[cfg(feature = "ndk")]
+        {
+            let key = self.try_as_native().ok()? as usize;
+            NATIVE_LIMITS.with(|limits| {
+                let limits = limits.borrow();
+                if limits.is_empty() {
+                    None
+                } else {
+                    limits.get(&key).cloned()
+                }
+            })
+        }
+    }
+
+    /// Forget the limits of a native parcel which is being destroyed.
+    #[cfg(feature = "ndk")]
+    pub(crate) fn forget_native_limits(ptr: *const crate::sys::AParcel) {
+        // The thread local may already be gone if the parcel is dropped while
+        // the thread exits.
+        let _ = NATIVE_LIMITS.try_with(|limits| {
+            if let Ok(mut limits) = limits.try_borrow_mut() {
+                if !limits.is_empty() {
+                    limits.remove(&(ptr as usize));
+                }
+            }
+        });
+    }
+
+    /// Read a value one nesting level deeper, failing with `BAD_VALUE` if
+    /// this exceeds the maximum depth of the parcel.
+    ///
+    /// This is used to read the elements of arrays and nullable values, and
+    /// should be used by parcelables containing other parcelables.
+    pub fn read_nested<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Parcel) -> Result<R>,
+    {
+        let state = match self.limit_state() {
+            Some(state) => state,
+            None => return f(self),
+        };
+        let depth = state.depth.get();
+        if depth >= state.limits.max_depth {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        state.depth.set(depth + 1);
+        let result = f(self);
+        state.depth.set(depth);
+        result
+    }
+
+    /// Check the length prefix of the array at the current position, without
+    /// moving the position, before allocating memory for the array.
+    ///
+    /// Each element takes `data_size` bytes of parcel data and `alloc_size`
+    /// bytes of memory.
+    pub(crate) fn check_array_len(&self, data_size: usize, alloc_size: usize) -> Result<()> {
+        let position = self.checkpoint();
+        let len = self.read::<i32>();
+        let result = match len {
+            Ok(len) if len >= 0 => self.check_allocation(len as usize, data_size, alloc_size),
+            // Let the array read report null arrays and read errors.
+            _ => Ok(()),
+        };
+        self.rewind_to(position)?;
+        result
+    }
+
+    /// Check that `len` elements taking `data_size` bytes of parcel data each
+    /// fit in the remaining data, and that allocating `alloc_size` bytes for
+    /// each of them is within the limits of the parcel.
+    ///
+    /// A `data_size` of 0 skips the data check, for arrays whose elements are
+    /// not in the parcel.
+    pub(crate) fn check_allocation(
+        &self,
+        len: usize,
+        data_size: usize,
+        alloc_size: usize,
+    ) -> Result<()> {
+        let remaining = self.remaining();
+        let fits = len
+            .checked_mul(data_size)
+            .is_some_and(|size| size <= remaining);
+        if data_size != 0 && !fits {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        if let Some(state) = self.limit_state() {
+            if len > state.limits.max_elements {
+                return Err(StatusCode::BAD_VALUE);
+            }
+            let total_bytes = len
+                .checked_mul(alloc_size)
+                .and_then(|bytes| bytes.checked_add(state.total_bytes.get()))
+                .filter(|&total_bytes| total_bytes <= state.limits.max_total_bytes)
+                .ok_or(StatusCode::BAD_VALUE)?;
+            state.total_bytes.set(total_bytes);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_deserialize_limits() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.get_data_position();
+
+    // An array claiming more elements than the parcel holds
+    parcel.write(&i32::MAX).unwrap();
+    parcel.write(&[1i32, 2, 3][..]).unwrap();
+    parcel.write(&[1i64; 8][..]).unwrap();
+    parcel.write(&["nested"][..]).unwrap();
+    // Length of an out array
+    parcel.write(&100i32).unwrap();
+    let seek = |parcel: &Parcel, pos| unsafe {
+        parcel.set_data_position(pos).unwrap();
+    };
+    seek(&parcel, start);
+
+    assert_eq!(parcel.read::<Vec<i32>>(), Err(StatusCode::BAD_VALUE));
+    assert_eq!(parcel.read::<Vec<String>>(), Err(StatusCode::BAD_VALUE));
+    assert_eq!(parcel.get_data_position(), start);
+
+    let arrays = start + 4;
+    let strings = arrays + 16 + 68;
+    let out = strings + 24;
+
+    parcel.set_deserialize_limits(DeserializeLimits {
+        max_elements: 4,
+        ..Default::default()
+    });
+    assert_eq!(parcel.deserialize_limits().unwrap().max_elements, 4);
+    seek(&parcel, arrays);
+    assert_eq!(parcel.read::<Vec<i32>>(), Ok(vec![1, 2, 3]));
+    assert_eq!(parcel.read::<Vec<i64>>(), Err(StatusCode::BAD_VALUE));
+
+    // The first array uses 12 of the 64 bytes
+    parcel.set_deserialize_limits(DeserializeLimits {
+        max_total_bytes: 64,
+        ..Default::default()
+    });
+    seek(&parcel, arrays);
+    assert_eq!(parcel.read::<Vec<i32>>(), Ok(vec![1, 2, 3]));
+    assert_eq!(parcel.read::<Vec<i64>>(), Err(StatusCode::BAD_VALUE));
+
+    parcel.set_deserialize_limits(DeserializeLimits {
+        max_depth: 0,
+        ..Default::default()
+    });
+    seek(&parcel, strings);
+    assert_eq!(parcel.read::<Vec<String>>(), Err(StatusCode::BAD_VALUE));
+    parcel.set_deserialize_limits(DeserializeLimits {
+        max_depth: 1,
+        ..Default::default()
+    });
+    seek(&parcel, strings);
+    assert_eq!(parcel.read::<Vec<String>>().unwrap(), ["nested"]);
+
+    // Out arrays have no data in the parcel, only their length
+    parcel.clear_deserialize_limits();
+    assert_eq!(parcel.deserialize_limits(), None);
+    seek(&parcel, out);
+    let mut out_vec: Vec<i32> = vec![];
+    parcel.resize_out_vec(&mut out_vec).unwrap();
+    assert_eq!(out_vec.len(), 100);
+
+    parcel.set_deserialize_limits(DeserializeLimits {
+        max_elements: 99,
+        ..Default::default()
+    });
+    seek(&parcel, out);
+    assert_eq!(
+        parcel.resize_out_vec(&mut out_vec),
+        Err(StatusCode::BAD_VALUE)
+    );
+}
+
+#[test]
+fn test_limits_kept_across_element_reads() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.checkpoint();
+    parcel.write(&["a", "bc"][..]).unwrap();
+    parcel.write(&[1i32; 8][..]).unwrap();
+    parcel.rewind_to(start).unwrap();
+
+    // Elements of string arrays are read through parcels borrowing this one
+    parcel.set_deserialize_limits(DeserializeLimits {
+        max_elements: 4,
+        ..Default::default()
+    });
+    assert_eq!(parcel.read::<Vec<String>>().unwrap(), ["a", "bc"]);
+    assert_eq!(parcel.deserialize_limits().map(|l| l.max_elements), Some(4));
+    assert_eq!(parcel.read::<Vec<i32>>(), Err(StatusCode::BAD_VALUE));
+}
@@ -0,0 +1,582 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Parcel backend that keeps its data in Rust memory.
+//!
+//! The layout of the data is identical to that of a flattened C++ `Parcel`, so
+//! bytes produced by a [`MemoryParcel`] can be read by `libbinder` and vice
+//! versa. Every value is padded to a multiple of 4 bytes and stored in native
+//! byte order.
+//!
+//! This backend doesn't call into `libbinder_ndk`.
+
+use crate::error::{Result, StatusCode};
+use crate::parcel::limits::LimitState;
+
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::mem;
+use std::rc::Rc;
+
+// Exception codes from `binder_status.h`.
+const EX_NONE: i32 = 0;
+const EX_SERVICE_SPECIFIC: i32 = -8;
+const EX_PARCELABLE: i32 = -9;
+/// Exception code of a "fat" reply header, which is skipped when reading a
+/// status header.
+const EX_HAS_REPLY_HEADER: i32 = -128;
+
+/// The fields of a status header, converted from and to a
+/// [`Status`](crate::Status) by its owner.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct StatusHeader {
+    pub(crate) exception: i32,
+    pub(crate) message: String,
+    pub(crate) service_specific_error: i32,
+}
+
+/// Parcel data held in a Rust buffer rather than in a `libbinder_ndk` parcel.
+///
+/// Only plain data is supported. Writing or reading binder objects fails with
+/// `StatusCode::INVALID_OPERATION`, and file descriptors fail with
+/// `StatusCode::FDS_NOT_ALLOWED`.
+#[derive(Debug, Default)]
+pub struct MemoryParcel {
+    data: Vec<u8>,
+    position: Cell<usize>,
+    sensitive: bool,
+    pub(crate) limits: Option<Rc<LimitState>>,
+}
+
+impl MemoryParcel {
+    /// Create a new, empty parcel.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create a parcel over previously serialized data, positioned at the
+    /// start of the data.
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            position: Cell::new(0),
+            sensitive: false,
+            limits: None,
+        }
+    }
+
+    /// Returns the serialized data of this parcel.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Consume the parcel, returning the serialized data.
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        mem::take(&mut self.data)
+    }
+
+    pub(crate) fn data_position(&self) -> i32 {
+        // The position is always set from an i32 or advanced by a write,
+        // which checks that the data size still fits in an i32.
+        self.position.get() as i32
+    }
+
+    pub(crate) fn set_data_position(&self, pos: i32) -> Result<()> {
+        let pos = pos.try_into().or(Err(StatusCode::BAD_VALUE))?;
+        self.position.set(pos);
+        Ok(())
+    }
+
+    pub(crate) fn data_size(&self) -> i32 {
+        self.data.len() as i32
+    }
+
+    pub(crate) fn mark_sensitive(&mut self) {
+        self.sensitive = true;
+    }
+
+    /// Write `bytes` at the current position, padded with zeros to a multiple
+    /// of 4 bytes.
+    fn write_aligned(&mut self, bytes: &[u8]) -> Result<()> {
+        let start = self.position.get();
+        let end = start
+            .checked_add(pad_size(bytes.len()))
+            .filter(|&end| end <= i32::MAX as usize)
+            .ok_or(StatusCode::NO_MEMORY)?;
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..start + bytes.len()].copy_from_slice(bytes);
+        for b in &mut self.data[start + bytes.len()..end] {
+            *b = 0;
+        }
+        self.position.set(end);
+        Ok(())
+    }
+
+    /// Read `len` bytes from the current position and skip over any padding,
+    /// like `Parcel::readInplace`.
+    fn read_inplace(&self, len: usize) -> Option<&[u8]> {
+        let start = self.position.get();
+        let end = start
+            .checked_add(pad_size(len))
+            .filter(|&end| end <= self.data.len())?;
+        self.position.set(end);
+        Some(&self.data[start..start + len])
+    }
+
+    fn read_aligned<const N: usize>(&self) -> Result<[u8; N]> {
+        self.read_inplace(N)
+            .map(|bytes| bytes.try_into().unwrap())
+            .ok_or(StatusCode::NOT_ENOUGH_DATA)
+    }
+
+    pub(crate) fn write_i32(&mut self, val: i32) -> Result<()> {
+        self.write_aligned(&val.to_ne_bytes())
+    }
+
+    pub(crate) fn read_i32(&self) -> Result<i32> {
+        self.read_aligned().map(i32::from_ne_bytes)
+    }
+
+    /// Write the length prefix of an array, like
+    /// `AParcel_writeParcelableArray` and friends.
+    pub(crate) fn write_array_len(&mut self, len: Option<usize>) -> Result<()> {
+        match len {
+            Some(len) => self.write_i32(len.try_into().or(Err(StatusCode::BAD_VALUE))?),
+            None => self.write_i32(-1),
+        }
+    }
+
+    /// Read the length prefix of an array. Returns `None` for a null array.
+    pub(crate) fn read_array_len(&self) -> Result<Option<usize>> {
+        match self.read_i32()? {
+            -1 => Ok(None),
+            len if len < -1 => Err(StatusCode::BAD_VALUE),
+            len => Ok(Some(len as usize)),
+        }
+    }
+
+    /// Write a string in UTF-16 with a length prefix and null terminator, like
+    /// `Parcel::writeString16`.
+    pub(crate) fn write_string16(&mut self, s: Option<&str>) -> Result<()> {
+        let s = match s {
+            Some(s) => s,
+            None => return self.write_i32(-1),
+        };
+        let mut bytes = Vec::with_capacity((s.len() + 1) * 2);
+        let mut len = 0usize;
+        for c in s.encode_utf16() {
+            bytes.extend_from_slice(&c.to_ne_bytes());
+            len += 1;
+        }
+        bytes.extend_from_slice(&0u16.to_ne_bytes());
+        self.write_i32(len.try_into().or(Err(StatusCode::BAD_VALUE))?)?;
+        self.write_aligned(&bytes)
+    }
+
+    /// Read a string written by [`write_string16`](Self::write_string16).
+    ///
+    /// Like `Parcel::readString16Inplace`, a malformed string is read as null.
+    pub(crate) fn read_string16(&self) -> Result<Option<String>> {
+        let len = self.read_i32()?;
+        if len < 0 || len == i32::MAX {
+            return Ok(None);
+        }
+        let len = len as usize;
+        let bytes = match self.read_inplace((len + 1) * 2) {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        let chars: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+            .collect();
+        if chars[len] != 0 {
+            return Ok(None);
+        }
+        String::from_utf16(&chars[..len])
+            .map(Some)
+            .or(Err(StatusCode::BAD_VALUE))
+    }
+
+    /// Read a byte array in place, without copying it. Returns `None` for a
+    /// null array.
+    pub(crate) fn read_byte_array_inplace(&self) -> Result<Option<&[u8]>> {
+        match self.read_array_len()? {
+            // libbinder_ndk reports a short array as an allocation failure.
+            Some(len) => self.read_inplace(len).map(Some).ok_or(StatusCode::NO_MEMORY),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the UTF-16 data of a string written by
+    /// [`write_string16`](Self::write_string16) in place, without the null
+    /// terminator.
+    ///
+    /// Like [`read_string16`](Self::read_string16), a malformed string is read
+    /// as null.
+    pub(crate) fn read_string16_inplace(&self) -> Result<Option<&[u8]>> {
+        let len = self.read_i32()?;
+        if len < 0 || len == i32::MAX {
+            return Ok(None);
+        }
+        let len = len as usize;
+        Ok(self
+            .read_inplace((len + 1) * 2)
+            .filter(|bytes| bytes[len * 2..] == [0, 0])
+            .map(|bytes| &bytes[..len * 2]))
+    }
+
+    /// Write a status header, like `AParcel_writeStatusHeader`.
+    pub(crate) fn write_status(&mut self, status: &StatusHeader) -> Result<()> {
+        self.write_i32(status.exception)?;
+        if status.exception == EX_NONE {
+            return Ok(());
+        }
+        self.write_string16(Some(&status.message))?;
+        // Empty remote stack trace header
+        self.write_i32(0)?;
+        if status.exception == EX_SERVICE_SPECIFIC {
+            self.write_i32(status.service_specific_error)
+        } else if status.exception == EX_PARCELABLE {
+            // Sending parcelable blobs is not supported
+            self.write_i32(0)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read a status header, like `AParcel_readStatusHeader`.
+    pub(crate) fn read_status(&self) -> Result<StatusHeader> {
+        let mut exception = self.read_i32()?;
+        if exception == EX_HAS_REPLY_HEADER {
+            self.skip_header()?;
+            exception = EX_NONE;
+        }
+        if exception == EX_NONE {
+            return Ok(StatusHeader::default());
+        }
+
+        let message = self.read_string16()?.ok_or(StatusCode::UNEXPECTED_NULL)?;
+        let stack_trace_size = self.read_i32()?;
+        let available = self.data.len().saturating_sub(self.position.get());
+        if stack_trace_size < 0 || stack_trace_size as usize > available {
+            return Err(StatusCode::UNKNOWN_ERROR);
+        }
+        self.position
+            .set(self.position.get() + stack_trace_size as usize);
+
+        let mut service_specific_error = 0;
+        if exception == EX_SERVICE_SPECIFIC {
+            service_specific_error = self.read_i32()?;
+        } else if exception == EX_PARCELABLE {
+            self.skip_header()?;
+        }
+        Ok(StatusHeader {
+            exception,
+            message,
+            service_specific_error,
+        })
+    }
+
+    /// Skip a header whose first field is its size in bytes, including the
+    /// size field itself.
+    fn skip_header(&self) -> Result<()> {
+        let start = self.position.get();
+        let available = self.data.len().saturating_sub(start);
+        let size = self.read_i32()?;
+        if size < 0 || size as usize > available {
+            return Err(StatusCode::UNKNOWN_ERROR);
+        }
+        self.position.set(start + size as usize);
+        Ok(())
+    }
+}
+
+impl Drop for MemoryParcel {
+    fn drop(&mut self) {
+        if self.sensitive {
+            for b in &mut self.data {
+                // Volatile so the zeroing is not optimized away.
+                unsafe {
+                    // Safety: `b` is a valid, exclusive reference.
+                    std::ptr::write_volatile(b, 0);
+                }
+            }
+        }
+    }
+}
+
+fn pad_size(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Primitive types with a fixed representation in a [`MemoryParcel`].
+///
+/// The array methods default to writing every element separately, as
+/// `libbinder_ndk` does for `char16_t` and parcelable arrays.
+pub(crate) trait MemoryPrimitive: Sized + Copy {
+    fn write(self, parcel: &mut MemoryParcel) -> Result<()>;
+
+    fn read(parcel: &MemoryParcel) -> Result<Self>;
+
+    fn write_array(slice: &[Self], parcel: &mut MemoryParcel) -> Result<()> {
+        parcel.write_array_len(Some(slice.len()))?;
+        slice.iter().try_for_each(|v| v.write(parcel))
+    }
+
+    fn read_array(parcel: &MemoryParcel) -> Result<Option<Vec<Self>>> {
+        let len = match parcel.read_array_len()? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        // Don't trust the length for the allocation: each element takes at
+        // least 4 bytes.
+        let remaining = parcel.data.len().saturating_sub(parcel.position.get());
+        let mut vec = Vec::with_capacity(len.min(remaining / 4));
+        for _ in 0..len {
+            vec.push(Self::read(parcel)?);
+        }
+        Ok(Some(vec))
+    }
+}
+
+impl MemoryPrimitive for bool {
+    fn write(self, parcel: &mut MemoryParcel) -> Result<()> {
+        parcel.write_i32(self as i32)
+    }
+
+    fn read(parcel: &MemoryParcel) -> Result<Self> {
+        parcel.read_i32().map(|v| v != 0)
+    }
+}
+
+impl MemoryPrimitive for u16 {
+    fn write(self, parcel: &mut MemoryParcel) -> Result<()> {
+        parcel.write_i32(self as i32)
+    }
+
+    fn read(parcel: &MemoryParcel) -> Result<Self> {
+        parcel.read_i32().map(|v| v as u16)
+    }
+}
+
+impl MemoryPrimitive for i16 {
+    fn write(self, parcel: &mut MemoryParcel) -> Result<()> {
+        (self as u16).write(parcel)
+    }
+
+    fn read(parcel: &MemoryParcel) -> Result<Self> {
+        u16::read(parcel).map(|v| v as i16)
+    }
+}
+
+/// Implement [`MemoryPrimitive`] for types whose arrays are written as packed
+/// data after the length prefix, like the `libbinder_ndk` `WriteArray`
+/// template.
+macro_rules! packed_memory_primitives {
+    {
+        $(
+            $ty:ty: write($write_self:ident, $write_parcel:ident) $write:block
+                    read($read_parcel:ident) $read:block
+        )*
+    } => {
+        $(
+            impl MemoryPrimitive for $ty {
+                fn write($write_self, $write_parcel: &mut MemoryParcel) -> Result<()> $write
+
+                fn read($read_parcel: &MemoryParcel) -> Result<Self> $read
+
+                fn write_array(slice: &[Self], parcel: &mut MemoryParcel) -> Result<()> {
+                    parcel.write_array_len(Some(slice.len()))?;
+                    if slice.is_empty() {
+                        return Ok(());
+                    }
+                    let bytes: Vec<u8> = slice.iter().flat_map(|v| v.to_ne_bytes()).collect();
+                    parcel.write_aligned(&bytes)
+                }
+
+                fn read_array(parcel: &MemoryParcel) -> Result<Option<Vec<Self>>> {
+                    let len = match parcel.read_array_len()? {
+                        Some(len) => len,
+                        None => return Ok(None),
+                    };
+                    if len == 0 {
+                        return Ok(Some(vec![]));
+                    }
+                    const SIZE: usize = mem::size_of::<$ty>();
+                    let size = len.checked_mul(SIZE).ok_or(StatusCode::NO_MEMORY)?;
+                    // libbinder_ndk reports a short array as an allocation
+                    // failure.
+                    let bytes = parcel.read_inplace(size).ok_or(StatusCode::NO_MEMORY)?;
+                    Ok(Some(
+                        bytes
+                            .chunks_exact(SIZE)
+                            .map(|c| <$ty>::from_ne_bytes(c.try_into().unwrap()))
+                            .collect(),
+                    ))
+                }
+            }
+        )*
+    };
+}
+
+packed_memory_primitives! {
+    i8: write(self, parcel) { parcel.write_i32(self as i32) }
+        read(parcel) { parcel.read_i32().map(|v| v as i8) }
+    u8: write(self, parcel) { (self as i8).write(parcel) }
+        read(parcel) { i8::read(parcel).map(|v| v as u8) }
+    i32: write(self, parcel) { parcel.write_i32(self) }
+         read(parcel) { parcel.read_i32() }
+    u32: write(self, parcel) { parcel.write_aligned(&self.to_ne_bytes()) }
+         read(parcel) { parcel.read_aligned().map(u32::from_ne_bytes) }
+    i64: write(self, parcel) { parcel.write_aligned(&self.to_ne_bytes()) }
+         read(parcel) { parcel.read_aligned().map(i64::from_ne_bytes) }
+    u64: write(self, parcel) { parcel.write_aligned(&self.to_ne_bytes()) }
+         read(parcel) { parcel.read_aligned().map(u64::from_ne_bytes) }
+    f32: write(self, parcel) { parcel.write_aligned(&self.to_ne_bytes()) }
+         read(parcel) { parcel.read_aligned().map(f32::from_ne_bytes) }
+    f64: write(self, parcel) { parcel.write_aligned(&self.to_ne_bytes()) }
+         read(parcel) { parcel.read_aligned().map(f64::from_ne_bytes) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryParcel;
+    use crate::binder::Interface;
+    use crate::error::{ExceptionCode, Status, StatusCode};
+    use crate::native::Binder;
+    use crate::parcel::Parcel;
+
+    use std::ffi::CString;
+
+    /// Serialize `f` into an NDK parcel and an in-memory parcel and check that
+    /// both produce the same bytes.
+    fn assert_same_bytes<F: Fn(&mut Parcel) -> crate::Result<()>>(f: F) {
+        let mut service = Binder::new(()).as_binder();
+        let mut ndk = Parcel::new_for_test(&mut service).unwrap();
+        let start = ndk.get_data_position();
+        f(&mut ndk).unwrap();
+        let end = ndk.get_data_size();
+        let mut ndk_bytes = vec![];
+        unsafe {
+            ndk.set_data_position(start).unwrap();
+        }
+        while ndk.get_data_position() < end {
+            ndk_bytes.extend_from_slice(&ndk.read::<u32>().unwrap().to_ne_bytes());
+        }
+
+        let mut memory = Parcel::new_in_memory();
+        f(&mut memory).unwrap();
+        assert_eq!(memory.as_bytes().unwrap(), &ndk_bytes[..]);
+    }
+
+    #[test]
+    fn test_primitives_match_ndk() {
+        assert_same_bytes(|p| {
+            p.write(&true)?;
+            p.write(&-3i8)?;
+            p.write(&200u8)?;
+            p.write(&0xfffeu16)?;
+            p.write(&-2i16)?;
+            p.write(&i32::MIN)?;
+            p.write(&u32::MAX)?;
+            p.write(&-1234567890123i64)?;
+            p.write(&u64::MAX)?;
+            p.write(&1.5f32)?;
+            p.write(&-2.25f64)
+        });
+    }
+
+    #[test]
+    fn test_arrays_match_ndk() {
+        assert_same_bytes(|p| {
+            p.write(&[true, false, true][..])?;
+            p.write(&[1u8, 2, 3, 4, 5][..])?;
+            p.write(&[-1i8, 7][..])?;
+            p.write(&[1u16, 0xffff][..])?;
+            p.write(&[-1i16, 1][..])?;
+            p.write(&[1i32, -1][..])?;
+            p.write(&[1u32, 2][..])?;
+            p.write(&[1i64, -1][..])?;
+            p.write(&[1u64][..])?;
+            p.write(&[0.5f32, -0.5][..])?;
+            p.write(&[0.25f64][..])?;
+            p.write(&(None as Option<Vec<i32>>))?;
+            p.write(&Vec::<u8>::new())
+        });
+    }
+
+    #[test]
+    fn test_strings_match_ndk() {
+        assert_same_bytes(|p| {
+            p.write("")?;
+            p.write("Hello, Binder!")?;
+            p.write("Embedded null \0 and non-ASCII \u{1f600}")?;
+            p.write(&(None as Option<String>))?;
+            p.write(&["a", "bc", "def"][..])?;
+            p.write(&[Some("x".to_string()), None][..])
+        });
+    }
+
+    #[test]
+    fn test_status_and_framing_match_ndk() {
+        assert_same_bytes(|p| {
+            p.write(&Status::ok())?;
+            p.write(&Status::new_exception(
+                ExceptionCode::ILLEGAL_ARGUMENT,
+                Some(&CString::new("bad argument").unwrap()),
+            ))?;
+            p.write(&Status::new_service_specific_error(42, None))?;
+            p.sized_write(|sub| {
+                sub.write(&1i32)?;
+                sub.write("sized")
+            })?;
+            p.write(&Some(vec![7i32]))
+        });
+    }
+
+    #[test]
+    fn test_memory_round_trip() {
+        let mut parcel = Parcel::new_in_memory();
+        parcel.write(&5i64).unwrap();
+        parcel.write("round trip").unwrap();
+        parcel.write(&[1.0f64, 2.0][..]).unwrap();
+        parcel
+            .write(&Status::new_service_specific_error(
+                7,
+                Some(&CString::new("oops").unwrap()),
+            ))
+            .unwrap();
+
+        let parcel = Parcel::from_bytes(parcel.as_bytes().unwrap().to_vec());
+        assert_eq!(parcel.read::<i64>(), Ok(5));
+        assert_eq!(parcel.read::<String>().unwrap(), "round trip");
+        assert_eq!(parcel.read::<Vec<f64>>(), Ok(vec![1.0, 2.0]));
+        let status: Status = parcel.read().unwrap();
+        assert_eq!(status.service_specific_error(), 7);
+        assert_eq!(parcel.read::<i32>(), Err(StatusCode::NOT_ENOUGH_DATA));
+    }
+
+    #[test]
+    fn test_memory_rejects_objects() {
+        let mut parcel = Parcel::new_in_memory();
+        let service = Binder::new(()).as_binder();
+        assert_eq!(parcel.write(&service), Err(StatusCode::INVALID_OPERATION));
+        assert!(MemoryParcel::new().as_bytes().is_empty());
+    }
+}
@@ -14,17 +14,31 @@
  * limitations under the License.
  */
 
+#[cfg(feature = "ndk")]
 use crate::binder::{AsNative, FromIBinder, Strong};
-use crate::error::{status_result, status_t, Result, Status, StatusCode};
+#[cfg(feature = "ndk")]
+use crate::error::{status_result, status_t};
+use crate::error::{Result, Status, StatusCode};
+#[cfg(feature = "memory_parcel")]
+use crate::parcel::memory::MemoryPrimitive;
 use crate::parcel::Parcel;
+#[cfg(feature = "ndk")]
 use crate::proxy::SpIBinder;
+#[cfg(feature = "ndk")]
 use crate::sys;
 
+#[cfg(feature = "ndk")]
 use std::convert::TryInto;
+#[cfg(feature = "ndk")]
 use std::ffi::c_void;
+#[cfg(feature = "ndk")]
 use std::os::raw::{c_char, c_ulong};
-use std::mem::{self, MaybeUninit};
+use std::mem;
+#[cfg(feature = "ndk")]
+use std::mem::MaybeUninit;
+#[cfg(feature = "ndk")]
 use std::ptr;
+#[cfg(feature = "ndk")]
 use std::slice;
 
 /// A struct whose instances can be written to a [`Parcel`].
@@ -51,19 +65,27 @@ pub trait Deserialize: Sized {
 pub trait SerializeArray: Serialize + Sized {
     /// Serialize an array of this type into the given [`Parcel`].
     fn serialize_array(slice: &[Self], parcel: &mut Parcel) -> Result<()> {
-        let res = unsafe {
-            // Safety: Safe FFI, slice will always be a safe pointer to pass.
-            sys::AParcel_writeParcelableArray(
-                parcel.as_native_mut(),
-                slice.as_ptr() as *const c_void,
-                slice.len().try_into().or(Err(StatusCode::BAD_VALUE))?,
-                Some(serialize_element::<Self>),
-            )
-        };
-        status_result(res)
+        memory_backend!(parcel, |memory| {
+            memory.write_array_len(Some(slice.len()))?;
+            slice.iter().try_for_each(|element| parcel.write(element))
+        });
+        #[cfg(feature = "ndk")]
+        {
+            let res = unsafe {
+                // Safety: Safe FFI, slice will always be a safe pointer to pass.
+                sys::AParcel_writeParcelableArray(
+                    parcel.try_as_native_mut()?,
+                    slice.as_ptr() as *const c_void,
+                    slice.len().try_into().or(Err(StatusCode::BAD_VALUE))?,
+                    Some(serialize_element::<Self>),
+                )
+            };
+            status_result(res)
+        }
     }
 }
 
+#[cfg(feature = "ndk")]
 /// Callback to serialize an element of a generic parcelable array.
 ///
 /// Safety: We are relying on binder_ndk to not overrun our slice. As long as it
@@ -97,31 +119,81 @@ unsafe extern "C" fn serialize_element<T: Serialize>(
 pub trait DeserializeArray: Deserialize {
     /// Deserialize an array of type from the given [`Parcel`].
     fn deserialize_array(parcel: &Parcel) -> Result<Option<Vec<Self>>> {
-        let mut vec: Option<Vec<MaybeUninit<Self>>> = None;
-        let res = unsafe {
-            // Safety: Safe FFI, vec is the correct opaque type expected by
-            // allocate_vec and deserialize_element.
-            sys::AParcel_readParcelableArray(
-                parcel.as_native(),
-                &mut vec as *mut _ as *mut c_void,
-                Some(allocate_vec::<Self>),
-                Some(deserialize_element::<Self>),
-            )
-        };
-        status_result(res)?;
-        let vec: Option<Vec<Self>> = unsafe {
-            // Safety: We are assuming that the NDK correctly initialized every
-            // element of the vector by now, so we know that all the
-            // MaybeUninits are now properly initialized. We can transmute from
-            // Vec<MaybeUninit<T>> to Vec<T> because MaybeUninit<T> has the same
-            // alignment and size as T, so the pointer to the vector allocation
-            // will be compatible.
-            mem::transmute(vec)
-        };
-        Ok(vec)
+        // Every element takes at least 4 bytes.
+        parcel.check_array_len(4, mem::size_of::<Self>())?;
+        memory_backend!(parcel, |memory| {
+            let len = match memory.read_array_len()? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            (0..len)
+                .map(|_| parcel.read_nested(Parcel::read))
+                .collect::<Result<_>>()
+                .map(Some)
+        });
+        #[cfg(feature = "ndk")]
+        {
+            let mut vec: Option<Vec<MaybeUninit<Self>>> = None;
+            let res = unsafe {
+                // Safety: Safe FFI, vec is the correct opaque type expected by
+                // allocate_vec and deserialize_element.
+                sys::AParcel_readParcelableArray(
+                    parcel.try_as_native()?,
+                    &mut vec as *mut _ as *mut c_void,
+                    Some(allocate_vec::<Self>),
+                    Some(deserialize_element::<Self>),
+                )
+            };
+            status_result(res)?;
+            let vec: Option<Vec<Self>> = unsafe {
+                // Safety: We are assuming that the NDK correctly initialized every
+                // element of the vector by now, so we know that all the
+                // MaybeUninits are now properly initialized. We can transmute from
+                // Vec<MaybeUninit<T>> to Vec<T> because MaybeUninit<T> has the same
+                // alignment and size as T, so the pointer to the vector allocation
+                // will be compatible.
+                mem::transmute(vec)
+            };
+            Ok(vec)
+        }
+    }
+
+    /// Deserialize an array of type from the given [`Parcel`], calling `f`
+    /// with every element instead of collecting them. Returns the number of
+    /// elements, or `None` for a null array.
+    fn deserialize_array_with<F>(parcel: &Parcel, f: F) -> Result<Option<usize>>
+    where
+        F: FnMut(Self) -> Result<()>,
+    {
+        visit_array(parcel, true, f)
     }
 }
 
+/// Read an array whose elements are written one by one, calling `f` with
+/// every element. Elements are read one nesting level deeper if `nested`.
+fn visit_array<T, F>(parcel: &Parcel, nested: bool, mut f: F) -> Result<Option<usize>>
+where
+    T: Deserialize,
+    F: FnMut(T) -> Result<()>,
+{
+    let len = match parcel.read::<i32>()? {
+        -1 => return Ok(None),
+        len if len < -1 => return Err(StatusCode::BAD_VALUE),
+        len => len as usize,
+    };
+    // Every element takes at least 4 bytes.
+    parcel.check_allocation(len, 4, 0)?;
+    for _ in 0..len {
+        if nested {
+            f(parcel.read_nested(Parcel::read)?)?;
+        } else {
+            f(parcel.read()?)?;
+        }
+    }
+    Ok(Some(len))
+}
+
+#[cfg(feature = "ndk")]
 /// Callback to deserialize a parcelable element.
 ///
 /// The opaque array data pointer must be a mutable pointer to an
@@ -146,7 +218,7 @@ unsafe extern "C" fn deserialize_element<T: Deserialize>(
         None => return StatusCode::UNEXPECTED_NULL as status_t,
         Some(p) => p,
     };
-    let element = match parcel.read() {
+    let element = match parcel.read_nested(Parcel::read) {
         Ok(e) => e,
         Err(code) => return code as status_t,
     };
@@ -181,11 +253,12 @@ pub trait DeserializeOption: Deserialize {
         if null == 0 {
             Ok(None)
         } else {
-            parcel.read().map(Some)
+            parcel.read_nested(Parcel::read).map(Some)
         }
     }
 }
 
+#[cfg(feature = "ndk")]
 /// Callback to allocate a vector for parcel array read functions.
 ///
 /// This variant is for APIs which use an out buffer pointer.
@@ -208,8 +281,12 @@ unsafe extern "C" fn allocate_vec_with_buffer<T>(
     res
 }
 
+#[cfg(feature = "ndk")]
 /// Callback to allocate a vector for parcel array read functions.
 ///
+/// The length is not checked here, so array reads must check it against the
+/// parcel with `Parcel::check_array_len` before calling into the NDK.
+///
 /// # Safety
 ///
 /// The opaque data pointer passed to the array read function must be a mutable
@@ -250,11 +327,13 @@ macro_rules! impl_parcelable {
     {Serialize, $ty:ty, $write_fn:path} => {
         impl Serialize for $ty {
             fn serialize(&self, parcel: &mut Parcel) -> Result<()> {
+                memory_backend!(parcel, |memory| MemoryPrimitive::write(*self, memory));
+                #[cfg(feature = "ndk")]
                 unsafe {
                     // Safety: `Parcel` always contains a valid pointer to an
                     // `AParcel`, and any `$ty` literal value is safe to pass to
                     // `$write_fn`.
-                    status_result($write_fn(parcel.as_native_mut(), *self))
+                    status_result($write_fn(parcel.try_as_native_mut()?, *self))
                 }
             }
         }
@@ -263,15 +342,19 @@ macro_rules! impl_parcelable {
     {Deserialize, $ty:ty, $read_fn:path} => {
         impl Deserialize for $ty {
             fn deserialize(parcel: &Parcel) -> Result<Self> {
-                let mut val = Self::default();
-                unsafe {
-                    // Safety: `Parcel` always contains a valid pointer to an
-                    // `AParcel`. We pass a valid, mutable pointer to `val`, a
-                    // literal of type `$ty`, and `$read_fn` will write the
-                    // value read into `val` if successful
-                    status_result($read_fn(parcel.as_native(), &mut val))?
-                };
-                Ok(val)
+                memory_backend!(parcel, |memory| MemoryPrimitive::read(memory));
+                #[cfg(feature = "ndk")]
+                {
+                    let mut val = Self::default();
+                    unsafe {
+                        // Safety: `Parcel` always contains a valid pointer to an
+                        // `AParcel`. We pass a valid, mutable pointer to `val`, a
+                        // literal of type `$ty`, and `$read_fn` will write the
+                        // value read into `val` if successful
+                        status_result($read_fn(parcel.try_as_native()?, &mut val))?
+                    };
+                    Ok(val)
+                }
             }
         }
     };
@@ -279,55 +362,98 @@ macro_rules! impl_parcelable {
     {SerializeArray, $ty:ty, $write_array_fn:path} => {
         impl SerializeArray for $ty {
             fn serialize_array(slice: &[Self], parcel: &mut Parcel) -> Result<()> {
-                let status = unsafe {
-                    // Safety: `Parcel` always contains a valid pointer to an
-                    // `AParcel`. If the slice is > 0 length, `slice.as_ptr()`
-                    // will be a valid pointer to an array of elements of type
-                    // `$ty`. If the slice length is 0, `slice.as_ptr()` may be
-                    // dangling, but this is safe since the pointer is not
-                    // dereferenced if the length parameter is 0.
-                    $write_array_fn(
-                        parcel.as_native_mut(),
-                        slice.as_ptr(),
-                        slice
-                            .len()
-                            .try_into()
-                            .or(Err(StatusCode::BAD_VALUE))?,
-                    )
-                };
-                status_result(status)
+                memory_backend!(parcel, |memory| MemoryPrimitive::write_array(slice, memory));
+                #[cfg(feature = "ndk")]
+                {
+                    let status = unsafe {
+                        // Safety: `Parcel` always contains a valid pointer to an
+                        // `AParcel`. If the slice is > 0 length, `slice.as_ptr()`
+                        // will be a valid pointer to an array of elements of type
+                        // `$ty`. If the slice length is 0, `slice.as_ptr()` may be
+                        // dangling, but this is safe since the pointer is not
+                        // dereferenced if the length parameter is 0.
+                        $write_array_fn(
+                            parcel.try_as_native_mut()?,
+                            slice.as_ptr(),
+                            slice
+                                .len()
+                                .try_into()
+                                .or(Err(StatusCode::BAD_VALUE))?,
+                        )
+                    };
+                    status_result(status)
+                }
             }
         }
     };
 
     {DeserializeArray, $ty:ty, $read_array_fn:path} => {
+        // Elements smaller than 4 bytes are written as 4 bytes.
+        impl_parcelable!{@deserialize_array, $ty, $read_array_fn, 4, {
+            fn deserialize_array_with<F>(parcel: &Parcel, f: F) -> Result<Option<usize>>
+            where
+                F: FnMut(Self) -> Result<()>,
+            {
+                visit_array(parcel, false, f)
+            }
+        }}
+    };
+
+    // Byte arrays are packed, so their elements can't be read one by one.
+    {DeserializeByteArray, $ty:ty, $read_array_fn:path} => {
+        impl_parcelable!{@deserialize_array, $ty, $read_array_fn, 1, {
+            fn deserialize_array_with<F>(parcel: &Parcel, mut f: F) -> Result<Option<usize>>
+            where
+                F: FnMut(Self) -> Result<()>,
+            {
+                let bytes = match parcel.read_nullable_bytes_ref()? {
+                    Some(bytes) => bytes,
+                    None => return Ok(None),
+                };
+                for &b in bytes.iter() {
+                    f(b as Self)?;
+                }
+                Ok(Some(bytes.len()))
+            }
+        }}
+    };
+
+    {@deserialize_array, $ty:ty, $read_array_fn:path, $min_size:expr, {$($extra:tt)*}} => {
         impl DeserializeArray for $ty {
             fn deserialize_array(parcel: &Parcel) -> Result<Option<Vec<Self>>> {
-                let mut vec: Option<Vec<MaybeUninit<Self>>> = None;
-                let status = unsafe {
-                    // Safety: `Parcel` always contains a valid pointer to an
-                    // `AParcel`. `allocate_vec<T>` expects the opaque pointer to
-                    // be of type `*mut Option<Vec<MaybeUninit<T>>>`, so `&mut vec` is
-                    // correct for it.
-                    $read_array_fn(
-                        parcel.as_native(),
-                        &mut vec as *mut _ as *mut c_void,
-                        Some(allocate_vec_with_buffer),
-                    )
-                };
-                status_result(status)?;
-                let vec: Option<Vec<Self>> = unsafe {
-                    // Safety: We are assuming that the NDK correctly
-                    // initialized every element of the vector by now, so we
-                    // know that all the MaybeUninits are now properly
-                    // initialized. We can transmute from Vec<MaybeUninit<T>> to
-                    // Vec<T> because MaybeUninit<T> has the same alignment and
-                    // size as T, so the pointer to the vector allocation will
-                    // be compatible.
-                    mem::transmute(vec)
-                };
-                Ok(vec)
+                let size = mem::size_of::<Self>();
+                parcel.check_array_len(size.max($min_size), size)?;
+                memory_backend!(parcel, |memory| MemoryPrimitive::read_array(memory));
+                #[cfg(feature = "ndk")]
+                {
+                    let mut vec: Option<Vec<MaybeUninit<Self>>> = None;
+                    let status = unsafe {
+                        // Safety: `Parcel` always contains a valid pointer to an
+                        // `AParcel`. `allocate_vec<T>` expects the opaque pointer to
+                        // be of type `*mut Option<Vec<MaybeUninit<T>>>`, so `&mut vec` is
+                        // correct for it.
+                        $read_array_fn(
+                            parcel.try_as_native()?,
+                            &mut vec as *mut _ as *mut c_void,
+                            Some(allocate_vec_with_buffer),
+                        )
+                    };
+                    status_result(status)?;
+                    let vec: Option<Vec<Self>> = unsafe {
+                        // Safety: We are assuming that the NDK correctly
+                        // initialized every element of the vector by now, so we
+                        // know that all the MaybeUninits are now properly
+                        // initialized. We can transmute from Vec<MaybeUninit<T>> to
+                        // Vec<T> because MaybeUninit<T> has the same alignment and
+                        // size as T, so the pointer to the vector allocation will
+                        // be compatible.
+                        mem::transmute(vec)
+                    };
+                    Ok(vec)
+                }
             }
+
+            $($extra)*
         }
     };
 }
@@ -338,12 +464,12 @@ parcelable_primitives! {
 
     // This is only safe because `Option<Vec<u8>>` is interchangeable with
     // `Option<Vec<i8>>` (what the allocator function actually allocates.
-    impl DeserializeArray for u8 = sys::AParcel_readByteArray;
+    impl DeserializeByteArray for u8 = sys::AParcel_readByteArray;
 
     impl Serialize for i8 = sys::AParcel_writeByte;
     impl Deserialize for i8 = sys::AParcel_readByte;
     impl SerializeArray for i8 = sys::AParcel_writeByteArray;
-    impl DeserializeArray for i8 = sys::AParcel_readByteArray;
+    impl DeserializeByteArray for i8 = sys::AParcel_readByteArray;
 
     impl Serialize for u16 = sys::AParcel_writeChar;
     impl Deserialize for u16 = sys::AParcel_readChar;
@@ -402,20 +528,24 @@ impl Deserialize for u8 {
 
 impl SerializeArray for u8 {
     fn serialize_array(slice: &[Self], parcel: &mut Parcel) -> Result<()> {
-        let status = unsafe {
-            // Safety: `Parcel` always contains a valid pointer to an
-            // `AParcel`. If the slice is > 0 length, `slice.as_ptr()` will be a
-            // valid pointer to an array of elements of type `$ty`. If the slice
-            // length is 0, `slice.as_ptr()` may be dangling, but this is safe
-            // since the pointer is not dereferenced if the length parameter is
-            // 0.
-            sys::AParcel_writeByteArray(
-                parcel.as_native_mut(),
-                slice.as_ptr() as *const i8,
-                slice.len().try_into().or(Err(StatusCode::BAD_VALUE))?,
-            )
-        };
-        status_result(status)
+        memory_backend!(parcel, |memory| MemoryPrimitive::write_array(slice, memory));
+        #[cfg(feature = "ndk")]
+        {
+            let status = unsafe {
+                // Safety: `Parcel` always contains a valid pointer to an
+                // `AParcel`. If the slice is > 0 length, `slice.as_ptr()` will be a
+                // valid pointer to an array of elements of type `$ty`. If the slice
+                // length is 0, `slice.as_ptr()` may be dangling, but this is safe
+                // since the pointer is not dereferenced if the length parameter is
+                // 0.
+                sys::AParcel_writeByteArray(
+                    parcel.try_as_native_mut()?,
+                    slice.as_ptr() as *const i8,
+                    slice.len().try_into().or(Err(StatusCode::BAD_VALUE))?,
+                )
+            };
+            status_result(status)
+        }
     }
 }
 
@@ -433,25 +563,31 @@ impl Deserialize for i16 {
 
 impl SerializeArray for i16 {
     fn serialize_array(slice: &[Self], parcel: &mut Parcel) -> Result<()> {
-        let status = unsafe {
-            // Safety: `Parcel` always contains a valid pointer to an
-            // `AParcel`. If the slice is > 0 length, `slice.as_ptr()` will be a
-            // valid pointer to an array of elements of type `$ty`. If the slice
-            // length is 0, `slice.as_ptr()` may be dangling, but this is safe
-            // since the pointer is not dereferenced if the length parameter is
-            // 0.
-            sys::AParcel_writeCharArray(
-                parcel.as_native_mut(),
-                slice.as_ptr() as *const u16,
-                slice.len().try_into().or(Err(StatusCode::BAD_VALUE))?,
-            )
-        };
-        status_result(status)
+        memory_backend!(parcel, |memory| MemoryPrimitive::write_array(slice, memory));
+        #[cfg(feature = "ndk")]
+        {
+            let status = unsafe {
+                // Safety: `Parcel` always contains a valid pointer to an
+                // `AParcel`. If the slice is > 0 length, `slice.as_ptr()` will be a
+                // valid pointer to an array of elements of type `$ty`. If the slice
+                // length is 0, `slice.as_ptr()` may be dangling, but this is safe
+                // since the pointer is not dereferenced if the length parameter is
+                // 0.
+                sys::AParcel_writeCharArray(
+                    parcel.try_as_native_mut()?,
+                    slice.as_ptr() as *const u16,
+                    slice.len().try_into().or(Err(StatusCode::BAD_VALUE))?,
+                )
+            };
+            status_result(status)
+        }
     }
 }
 
 impl SerializeOption for str {
     fn serialize_option(this: Option<&Self>, parcel: &mut Parcel) -> Result<()> {
+        memory_backend!(parcel, |memory| memory.write_string16(this));
+        #[cfg(feature = "ndk")]
         match this {
             None => unsafe {
                 // Safety: `Parcel` always contains a valid pointer to an
@@ -459,7 +595,7 @@ impl SerializeOption for str {
                 // `AParcel_writeString` requires that the length is -1 to
                 // indicate that we want to serialize a null string.
                 status_result(sys::AParcel_writeString(
-                    parcel.as_native_mut(),
+                    parcel.try_as_native_mut()?,
                     ptr::null(),
                     -1,
                 ))
@@ -474,7 +610,7 @@ impl SerializeOption for str {
                 // necessary, we will need to null-terminate the str buffer
                 // before sending it.
                 status_result(sys::AParcel_writeString(
-                    parcel.as_native_mut(),
+                    parcel.try_as_native_mut()?,
                     s.as_ptr() as *const c_char,
                     s.as_bytes()
                         .len()
@@ -514,30 +650,45 @@ impl SerializeArray for Option<String> {}
 
 impl Deserialize for Option<String> {
     fn deserialize(parcel: &Parcel) -> Result<Self> {
-        let mut vec: Option<Vec<u8>> = None;
-        let status = unsafe {
-            // Safety: `Parcel` always contains a valid pointer to an `AParcel`.
-            // `Option<Vec<u8>>` is equivalent to the expected `Option<Vec<i8>>`
-            // for `allocate_vec`, so `vec` is safe to pass as the opaque data
-            // pointer on platforms where char is signed.
-            sys::AParcel_readString(
-                parcel.as_native(),
-                &mut vec as *mut _ as *mut c_void,
-                Some(allocate_vec_with_buffer),
-            )
-        };
-
-        status_result(status)?;
-        vec.map(|mut s| {
-            // The vector includes a null-terminator and we don't want the
-            // string to be null-terminated for Rust.
-            s.pop();
-            String::from_utf8(s).or(Err(StatusCode::BAD_VALUE))
-        })
-        .transpose()
+        memory_backend!(parcel, |memory| memory.read_string16());
+        #[cfg(feature = "ndk")]
+        {
+            read_native_string(parcel)?
+                .map(|s| String::from_utf8(s).or(Err(StatusCode::BAD_VALUE)))
+                .transpose()
+        }
     }
 }
 
+/// Read a nullable string from a native parcel, as converted from UTF-16 by
+/// `libbinder_ndk`.
+///
+/// `libutils` encodes unpaired surrogates like any other code point, so the
+/// result is not always valid UTF-8.
+#[cfg(feature = "ndk")]
+pub(super) fn read_native_string(parcel: &Parcel) -> Result<Option<Vec<u8>>> {
+    let mut vec: Option<Vec<u8>> = None;
+    let status = unsafe {
+        // Safety: `Parcel` always contains a valid pointer to an `AParcel`.
+        // `Option<Vec<u8>>` is equivalent to the expected `Option<Vec<i8>>`
+        // for `allocate_vec`, so `vec` is safe to pass as the opaque data
+        // pointer on platforms where char is signed.
+        sys::AParcel_readString(
+            parcel.try_as_native()?,
+            &mut vec as *mut _ as *mut c_void,
+            Some(allocate_vec_with_buffer),
+        )
+    };
+
+    status_result(status)?;
+    Ok(vec.map(|mut s| {
+        // The vector includes a null-terminator and we don't want the
+        // string to be null-terminated for Rust.
+        s.pop();
+        s
+    }))
+}
+
 impl DeserializeArray for Option<String> {}
 
 impl Deserialize for String {
@@ -594,13 +745,18 @@ impl<T: DeserializeArray> DeserializeOption for Vec<T> {
 
 impl Serialize for Status {
     fn serialize(&self, parcel: &mut Parcel) -> Result<()> {
+        memory_backend!(parcel, |memory| match self.to_header()? {
+            Some(header) => memory.write_status(&header),
+            None => Ok(()),
+        });
+        #[cfg(feature = "ndk")]
         unsafe {
             // Safety: `Parcel` always contains a valid pointer to an `AParcel`
             // and `Status` always contains a valid pointer to an `AStatus`, so
             // both parameters are valid and safe. This call does not take
             // ownership of either of its parameters.
             status_result(sys::AParcel_writeStatusHeader(
-                parcel.as_native_mut(),
+                parcel.try_as_native_mut()?,
                 self.as_native(),
             ))
         }
@@ -609,37 +765,44 @@ impl Serialize for Status {
 
 impl Deserialize for Status {
     fn deserialize(parcel: &Parcel) -> Result<Self> {
-        let mut status_ptr = ptr::null_mut();
-        let ret_status = unsafe {
-            // Safety: `Parcel` always contains a valid pointer to an
-            // `AParcel`. We pass a mutable out pointer which will be
-            // assigned a valid `AStatus` pointer if the function returns
-            // status OK. This function passes ownership of the status
-            // pointer to the caller, if it was assigned.
-            sys::AParcel_readStatusHeader(parcel.as_native(), &mut status_ptr)
-        };
-        status_result(ret_status)?;
-        Ok(unsafe {
-            // Safety: At this point, the return status of the read call was ok,
-            // so we know that `status_ptr` is a valid, owned pointer to an
-            // `AStatus`, from which we can safely construct a `Status` object.
-            Status::from_ptr(status_ptr)
-        })
+        memory_backend!(parcel, |memory| memory.read_status().map(Status::from_header));
+        #[cfg(feature = "ndk")]
+        {
+            let mut status_ptr = ptr::null_mut();
+            let ret_status = unsafe {
+                // Safety: `Parcel` always contains a valid pointer to an
+                // `AParcel`. We pass a mutable out pointer which will be
+                // assigned a valid `AStatus` pointer if the function returns
+                // status OK. This function passes ownership of the status
+                // pointer to the caller, if it was assigned.
+                sys::AParcel_readStatusHeader(parcel.try_as_native()?, &mut status_ptr)
+            };
+            status_result(ret_status)?;
+            Ok(unsafe {
+                // Safety: At this point, the return status of the read call was ok,
+                // so we know that `status_ptr` is a valid, owned pointer to an
+                // `AStatus`, from which we can safely construct a `Status` object.
+                Status::from_ptr(status_ptr)
+            })
+        }
     }
 }
 
+#[cfg(feature = "ndk")]
 impl<T: Serialize + FromIBinder + ?Sized> Serialize for Strong<T> {
     fn serialize(&self, parcel: &mut Parcel) -> Result<()> {
         Serialize::serialize(&**self, parcel)
     }
 }
 
+#[cfg(feature = "ndk")]
 impl<T: SerializeOption + FromIBinder + ?Sized> SerializeOption for Strong<T> {
     fn serialize_option(this: Option<&Self>, parcel: &mut Parcel) -> Result<()> {
         SerializeOption::serialize_option(this.map(|b| &**b), parcel)
     }
 }
 
+#[cfg(feature = "ndk")]
 impl<T: FromIBinder + ?Sized> Deserialize for Strong<T> {
     fn deserialize(parcel: &Parcel) -> Result<Self> {
         let ibinder: SpIBinder = parcel.read()?;
@@ -647,6 +810,7 @@ impl<T: FromIBinder + ?Sized> Deserialize for Strong<T> {
     }
 }
 
+#[cfg(feature = "ndk")]
 impl<T: FromIBinder + ?Sized> DeserializeOption for Strong<T> {
     fn deserialize_option(parcel: &Parcel) -> Result<Option<Self>> {
         let ibinder: Option<SpIBinder> = parcel.read()?;
@@ -679,6 +843,50 @@ impl<T: DeserializeOption> Deserialize for Option<T> {
     }
 }
 
+/// The arguments of a call made with [`IBinder::call`](crate::IBinder::call).
+///
+/// This is implemented for tuples of up to 8 [`Serialize`] values, which are
+/// written to the parcel in order, and for `()` for calls without arguments.
+pub trait SerializeArgs {
+    /// Serialize all arguments into the given [`Parcel`].
+    fn serialize_args(&self, parcel: &mut Parcel) -> Result<()>;
+}
+
+impl SerializeArgs for () {
+    fn serialize_args(&self, _parcel: &mut Parcel) -> Result<()> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_serialize_args {
+    ($($name:ident),+) => {
+        impl<$($name: Serialize),+> SerializeArgs for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn serialize_args(&self, parcel: &mut Parcel) -> Result<()> {
+                let ($($name,)+) = self;
+                $(parcel.write($name)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_serialize_args!(A);
+impl_serialize_args!(A, B);
+impl_serialize_args!(A, B, C);
+impl_serialize_args!(A, B, C, D);
+impl_serialize_args!(A, B, C, D, E);
+impl_serialize_args!(A, B, C, D, E, F);
+impl_serialize_args!(A, B, C, D, E, F, G);
+impl_serialize_args!(A, B, C, D, E, F, G, H);
+
+/// The result of a call without a return value, which reads nothing.
+impl Deserialize for () {
+    fn deserialize(_parcel: &Parcel) -> Result<Self> {
+        Ok(())
+    }
+}
+
 #[test]
 fn test_custom_parcelable() {
     use crate::binder::Interface;
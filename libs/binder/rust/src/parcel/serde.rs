@@ -0,0 +1,964 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Serialization of [`serde`](::serde) types into a [`Parcel`].
+//!
+//! Values are written in the layout of the equivalent AIDL types, so that a
+//! type deriving `serde::Serialize` and `serde::Deserialize` can be sent
+//! between Rust processes without a hand-written parcelable:
+//!
+//! * Integers of up to 32 bits, `bool` and `char` take 32 bits, like AIDL
+//!   `int`. Other numbers use their own size.
+//! * Strings are UTF-16, like AIDL `String`.
+//! * Structs are framed like AIDL parcelables, with the size of the struct
+//!   before its fields, as written by [`Parcel::sized_write`]. Fields missing
+//!   at the end of a struct, written by an older version of the type, are
+//!   read with their `#[serde(default)]`, or as `None` for options. Fields
+//!   added by a newer version are skipped.
+//! * Fields are identified by their position, not their name. A field with
+//!   `#[serde(skip)]` is left out on both sides and is fine, but a field
+//!   skipped on one side only shifts the fields after it. Writing a struct
+//!   whose `#[serde(skip_serializing_if)]` skips a field fails with
+//!   `BAD_VALUE`. Fields with `skip_serializing` or `skip_deserializing`
+//!   can't be detected, and must not be used.
+//! * Sequences and maps are prefixed with their number of elements.
+//! * Options are prefixed with 1, or written as 0 for `None`, like nullable
+//!   AIDL parcelables.
+//! * Enums are the index of the variant followed by its fields.
+//!
+//! The format is not self-describing, so `deserialize_any` and types relying
+//! on it, such as untagged enums, are not supported.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use binder::parcel::serde::{from_parcel, to_parcel};
+//! # use binder::{Parcel, Result};
+//! #[derive(serde::Serialize, serde::Deserialize)]
+//! struct Config {
+//!     name: String,
+//!     retries: u32,
+//! }
+//!
+//! # fn round_trip(parcel: &mut Parcel, config: &Config) -> Result<Config> {
+//! to_parcel(config, parcel)?;
+//! # let config =
+//! from_parcel::<Config>(parcel)
+//! # ; config
+//! # }
+//! ```
+
+use super::{Parcel, ParcelPosition};
+use crate::error::{Result, StatusCode};
+
+use ::serde::de::{self, IntoDeserializer};
+use ::serde::ser;
+use std::convert::TryInto;
+use std::fmt;
+
+/// Write `value` to `parcel`.
+///
+/// Fails with `BAD_VALUE` if `value` can't be serialized.
+pub fn to_parcel<T: ser::Serialize + ?Sized>(value: &T, parcel: &mut Parcel) -> Result<()> {
+    value.serialize(&mut Serializer { parcel }).map_err(|e| e.0)
+}
+
+/// Read a value of type `T` from `parcel`.
+///
+/// Fails with `BAD_VALUE` if the data is not a valid `T`, or with the error of
+/// the first read from the parcel that failed.
+pub fn from_parcel<'de, T: de::Deserialize<'de>>(parcel: &Parcel) -> Result<T> {
+    T::deserialize(Deserializer { parcel }).map_err(|e| e.0)
+}
+
+/// A [`StatusCode`] as the error type required by `serde`.
+#[derive(Debug)]
+struct Error(StatusCode);
+
+impl From<StatusCode> for Error {
+    fn from(status: StatusCode) -> Self {
+        Self(status)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        log::error!("Failed to serialize into parcel: {}", msg);
+        Self(StatusCode::BAD_VALUE)
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        log::error!("Failed to deserialize from parcel: {}", msg);
+        Self(StatusCode::BAD_VALUE)
+    }
+}
+
+type SerdeResult<T> = std::result::Result<T, Error>;
+
+fn len_to_i32(len: usize) -> SerdeResult<i32> {
+    Ok(len.try_into().or(Err(StatusCode::BAD_VALUE))?)
+}
+
+struct Serializer<'p> {
+    parcel: &'p mut Parcel,
+}
+
+impl<'p> Serializer<'p> {
+    fn write<S: super::Serialize + ?Sized>(&mut self, value: &S) -> SerdeResult<()> {
+        Ok(self.parcel.write(value)?)
+    }
+
+    /// Start a sequence or map, whose length is written by `Compound::end`.
+    fn counted<'a>(&'a mut self) -> SerdeResult<Compound<'a, 'p>> {
+        let position = self.parcel.checkpoint();
+        self.write(&0i32)?;
+        Ok(Compound {
+            frame: Frame::Count { position, count: 0 },
+            ser: self,
+        })
+    }
+
+    /// Start a struct, whose size is written by `Compound::end`.
+    fn sized<'a>(&'a mut self) -> SerdeResult<Compound<'a, 'p>> {
+        let start = self.parcel.checkpoint();
+        self.write(&0i32)?;
+        Ok(Compound {
+            frame: Frame::Sized { start },
+            ser: self,
+        })
+    }
+}
+
+/// The header to write when a compound value is complete.
+enum Frame {
+    None,
+    Count {
+        position: ParcelPosition,
+        count: usize,
+    },
+    Sized {
+        start: ParcelPosition,
+    },
+}
+
+struct Compound<'a, 'p> {
+    ser: &'a mut Serializer<'p>,
+    frame: Frame,
+}
+
+impl Compound<'_, '_> {
+    fn element<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> SerdeResult<()> {
+        if let Frame::Count { count, .. } = &mut self.frame {
+            *count += 1;
+        }
+        value.serialize(&mut *self.ser)
+    }
+
+    fn field<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> SerdeResult<()> {
+        value.serialize(&mut *self.ser)
+    }
+
+    /// Fields are positional, so skipping one would shift the fields after it
+    /// when they are read.
+    fn skip_field(key: &'static str) -> SerdeResult<()> {
+        Err(ser::Error::custom(format_args!("field `{}` can't be skipped", key)))
+    }
+
+    fn end(self) -> SerdeResult<()> {
+        let parcel = &mut *self.ser.parcel;
+        let (position, header) = match self.frame {
+            Frame::None => return Ok(()),
+            Frame::Count { position, count } => (position, len_to_i32(count)?),
+            Frame::Sized { start } => (start, parcel.checkpoint().offset() - start.offset()),
+        };
+        let end = parcel.checkpoint();
+        parcel.rewind_to(position)?;
+        parcel.write(&header)?;
+        Ok(parcel.rewind_to(end)?)
+    }
+}
+
+impl<'a, 'p> ser::Serializer for &'a mut Serializer<'p> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a, 'p>;
+    type SerializeTuple = Compound<'a, 'p>;
+    type SerializeTupleStruct = Compound<'a, 'p>;
+    type SerializeTupleVariant = Compound<'a, 'p>;
+    type SerializeMap = Compound<'a, 'p>;
+    type SerializeStruct = Compound<'a, 'p>;
+    type SerializeStructVariant = Compound<'a, 'p>;
+
+    fn serialize_bool(self, v: bool) -> SerdeResult<()> {
+        self.write(&v)
+    }
+
+    fn serialize_i8(self, v: i8) -> SerdeResult<()> {
+        self.write(&(v as i32))
+    }
+
+    fn serialize_i16(self, v: i16) -> SerdeResult<()> {
+        self.write(&(v as i32))
+    }
+
+    fn serialize_i32(self, v: i32) -> SerdeResult<()> {
+        self.write(&v)
+    }
+
+    fn serialize_i64(self, v: i64) -> SerdeResult<()> {
+        self.write(&v)
+    }
+
+    fn serialize_u8(self, v: u8) -> SerdeResult<()> {
+        self.write(&(v as i32))
+    }
+
+    fn serialize_u16(self, v: u16) -> SerdeResult<()> {
+        self.write(&(v as i32))
+    }
+
+    fn serialize_u32(self, v: u32) -> SerdeResult<()> {
+        self.write(&v)
+    }
+
+    fn serialize_u64(self, v: u64) -> SerdeResult<()> {
+        self.write(&v)
+    }
+
+    fn serialize_f32(self, v: f32) -> SerdeResult<()> {
+        self.write(&v)
+    }
+
+    fn serialize_f64(self, v: f64) -> SerdeResult<()> {
+        self.write(&v)
+    }
+
+    fn serialize_char(self, v: char) -> SerdeResult<()> {
+        self.write(&(v as u32))
+    }
+
+    fn serialize_str(self, v: &str) -> SerdeResult<()> {
+        self.write(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> SerdeResult<()> {
+        self.write(v)
+    }
+
+    fn serialize_none(self) -> SerdeResult<()> {
+        self.write(&0i32)
+    }
+
+    fn serialize_some<T: ser::Serialize + ?Sized>(self, value: &T) -> SerdeResult<()> {
+        self.write(&1i32)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> SerdeResult<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> SerdeResult<()> {
+        self.sized()?.end()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> SerdeResult<()> {
+        self.write(&variant_index)
+    }
+
+    fn serialize_newtype_struct<T: ser::Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> SerdeResult<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ser::Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> SerdeResult<()> {
+        self.write(&variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> SerdeResult<Compound<'a, 'p>> {
+        self.counted()
+    }
+
+    fn serialize_tuple(self, _len: usize) -> SerdeResult<Compound<'a, 'p>> {
+        Ok(Compound {
+            ser: self,
+            frame: Frame::None,
+        })
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> SerdeResult<Compound<'a, 'p>> {
+        self.sized()
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> SerdeResult<Compound<'a, 'p>> {
+        self.write(&variant_index)?;
+        Ok(Compound {
+            ser: self,
+            frame: Frame::None,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> SerdeResult<Compound<'a, 'p>> {
+        self.counted()
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> SerdeResult<Compound<'a, 'p>> {
+        self.sized()
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> SerdeResult<Compound<'a, 'p>> {
+        self.write(&variant_index)?;
+        self.sized()
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> SerdeResult<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> SerdeResult<()> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> SerdeResult<()> {
+        self.field(value)
+    }
+
+    fn end(self) -> SerdeResult<()> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> SerdeResult<()> {
+        self.field(value)
+    }
+
+    fn end(self) -> SerdeResult<()> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> SerdeResult<()> {
+        self.field(value)
+    }
+
+    fn end(self) -> SerdeResult<()> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeMap for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ser::Serialize + ?Sized>(&mut self, key: &T) -> SerdeResult<()> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> SerdeResult<()> {
+        self.field(value)
+    }
+
+    fn end(self) -> SerdeResult<()> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ser::Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> SerdeResult<()> {
+        self.field(value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> SerdeResult<()> {
+        Compound::skip_field(key)
+    }
+
+    fn end(self) -> SerdeResult<()> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ser::Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> SerdeResult<()> {
+        self.field(value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> SerdeResult<()> {
+        Compound::skip_field(key)
+    }
+
+    fn end(self) -> SerdeResult<()> {
+        Compound::end(self)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Deserializer<'p> {
+    parcel: &'p Parcel,
+}
+
+impl<'p> Deserializer<'p> {
+    fn read<D: super::Deserialize>(self) -> SerdeResult<D> {
+        Ok(self.parcel.read()?)
+    }
+
+    fn read_small_int<T: std::convert::TryFrom<i32>>(self) -> SerdeResult<T> {
+        Ok(self
+            .read::<i32>()?
+            .try_into()
+            .or(Err(StatusCode::BAD_VALUE))?)
+    }
+
+    /// Read a length prefix, checking it against the limits of the parcel.
+    fn read_len(self) -> SerdeResult<usize> {
+        let len: i32 = self.read()?;
+        if len < 0 {
+            return Err(StatusCode::BAD_VALUE.into());
+        }
+        let len = len as usize;
+        self.parcel.check_allocation(len, 0, 0)?;
+        Ok(len)
+    }
+
+    /// Run `f` one nesting level deeper, see [`Parcel::read_nested`].
+    fn nested<R>(self, f: impl FnOnce() -> SerdeResult<R>) -> SerdeResult<R> {
+        let mut result = Err(StatusCode::BAD_VALUE.into());
+        self.parcel.read_nested(|_| {
+            result = f();
+            Ok(())
+        })?;
+        result
+    }
+
+    /// Read a struct framed like [`Parcel::sized_read`], then skip any
+    /// fields that `f` didn't read.
+    fn sized<R>(self, f: impl FnOnce(i32) -> SerdeResult<R>) -> SerdeResult<R> {
+        let start = self.parcel.get_data_position();
+        let len: i32 = self.read()?;
+        let end = start
+            .checked_add(len)
+            .filter(|&end| len >= 4 && end <= self.parcel.get_data_size())
+            .ok_or(StatusCode::BAD_VALUE)?;
+        let result = self.nested(|| f(end))?;
+        if self.parcel.get_data_position() > end {
+            return Err(StatusCode::BAD_VALUE.into());
+        }
+        self.parcel.rewind_to(ParcelPosition(end))?;
+        Ok(result)
+    }
+}
+
+macro_rules! deserialize_read {
+    ($($method:ident => $visit:ident($read:ident::<$ty:ty>),)*) => {
+        $(
+            fn $method<V: de::Visitor<'de>>(self, visitor: V) -> SerdeResult<V::Value> {
+                visitor.$visit(self.$read::<$ty>()?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_> {
+    type Error = Error;
+
+    deserialize_read! {
+        deserialize_bool => visit_bool(read::<bool>),
+        deserialize_i8 => visit_i8(read_small_int::<i8>),
+        deserialize_i16 => visit_i16(read_small_int::<i16>),
+        deserialize_i32 => visit_i32(read::<i32>),
+        deserialize_i64 => visit_i64(read::<i64>),
+        deserialize_u8 => visit_u8(read_small_int::<u8>),
+        deserialize_u16 => visit_u16(read_small_int::<u16>),
+        deserialize_u32 => visit_u32(read::<u32>),
+        deserialize_u64 => visit_u64(read::<u64>),
+        deserialize_f32 => visit_f32(read::<f32>),
+        deserialize_f64 => visit_f64(read::<f64>),
+        deserialize_str => visit_string(read::<String>),
+        deserialize_string => visit_string(read::<String>),
+        deserialize_bytes => visit_byte_buf(read::<Vec<u8>>),
+        deserialize_byte_buf => visit_byte_buf(read::<Vec<u8>>),
+    }
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, _visitor: V) -> SerdeResult<V::Value> {
+        Err(de::Error::custom("parcels are not self-describing"))
+    }
+
+    fn deserialize_char<V: de::Visitor<'de>>(self, visitor: V) -> SerdeResult<V::Value> {
+        let c = std::char::from_u32(self.read()?).ok_or(StatusCode::BAD_VALUE)?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> SerdeResult<V::Value> {
+        match self.read::<i32>()? {
+            0 => visitor.visit_none(),
+            _ => self.nested(|| visitor.visit_some(self)),
+        }
+    }
+
+    fn deserialize_unit<V: de::Visitor<'de>>(self, visitor: V) -> SerdeResult<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> SerdeResult<V::Value> {
+        self.sized(|_| visitor.visit_unit())
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> SerdeResult<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: de::Visitor<'de>>(self, visitor: V) -> SerdeResult<V::Value> {
+        let len = self.read_len()?;
+        self.nested(|| visitor.visit_seq(Elements { de: self, len }))
+    }
+
+    fn deserialize_tuple<V: de::Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> SerdeResult<V::Value> {
+        visitor.visit_seq(Elements { de: self, len })
+    }
+
+    fn deserialize_tuple_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> SerdeResult<V::Value> {
+        self.sized(|end| {
+            visitor.visit_seq(Fields {
+                de: self,
+                fields: None,
+                len,
+                index: 0,
+                end,
+            })
+        })
+    }
+
+    fn deserialize_map<V: de::Visitor<'de>>(self, visitor: V) -> SerdeResult<V::Value> {
+        let len = self.read_len()?;
+        self.nested(|| visitor.visit_map(Elements { de: self, len }))
+    }
+
+    fn deserialize_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> SerdeResult<V::Value> {
+        self.sized(|end| {
+            visitor.visit_map(Fields {
+                de: self,
+                fields: Some(fields),
+                len: fields.len(),
+                index: 0,
+                end,
+            })
+        })
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> SerdeResult<V::Value> {
+        self.nested(|| visitor.visit_enum(self))
+    }
+
+    fn deserialize_identifier<V: de::Visitor<'de>>(self, _visitor: V) -> SerdeResult<V::Value> {
+        Err(de::Error::custom("parcels don't contain identifiers"))
+    }
+
+    fn deserialize_ignored_any<V: de::Visitor<'de>>(self, _visitor: V) -> SerdeResult<V::Value> {
+        Err(de::Error::custom("parcels are not self-describing"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// The elements of a sequence, map or tuple, whose length is known.
+struct Elements<'p> {
+    de: Deserializer<'p>,
+    len: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> SerdeResult<Option<T::Value>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> SerdeResult<Option<K::Value>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(self.de).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> SerdeResult<V::Value> {
+        seed.deserialize(self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+/// The fields of a struct, read until the end of its frame.
+struct Fields<'p> {
+    de: Deserializer<'p>,
+    /// The names of the fields, to read them as a map.
+    fields: Option<&'static [&'static str]>,
+    len: usize,
+    index: usize,
+    end: i32,
+}
+
+impl Fields<'_> {
+    fn has_next(&self) -> bool {
+        self.index < self.len && self.de.parcel.get_data_position() < self.end
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Fields<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> SerdeResult<Option<T::Value>> {
+        if !self.has_next() {
+            return Ok(None);
+        }
+        self.index += 1;
+        seed.deserialize(self.de).map(Some)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Fields<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> SerdeResult<Option<K::Value>> {
+        let fields = match self.fields {
+            Some(fields) if self.has_next() => fields,
+            _ => return Ok(None),
+        };
+        let key = fields[self.index];
+        self.index += 1;
+        seed.deserialize(key.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> SerdeResult<V::Value> {
+        seed.deserialize(self.de)
+    }
+}
+
+impl<'de, 'p> de::EnumAccess<'de> for Deserializer<'p> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> SerdeResult<(V::Value, Self)> {
+        let index: u32 = self.read()?;
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> SerdeResult<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> SerdeResult<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, len: usize, visitor: V) -> SerdeResult<V::Value> {
+        visitor.visit_seq(Elements { de: self, len })
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> SerdeResult<V::Value> {
+        de::Deserializer::deserialize_struct(self, "", fields, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binder::Interface;
+    use crate::native::Binder;
+    use ::serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        retries: u8,
+        ratio: f64,
+        tags: Vec<String>,
+        limits: BTreeMap<String, u64>,
+        parent: Option<Box<Config>>,
+        mode: Mode,
+        point: (i16, char),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Mode {
+        Off,
+        Fixed(i32),
+        Range { min: i32, max: i32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V1 {
+        a: i32,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V2 {
+        a: i32,
+        b: Option<String>,
+        #[serde(default)]
+        c: Vec<i32>,
+    }
+
+    fn config(name: &str, parent: Option<Config>, mode: Mode) -> Config {
+        Config {
+            name: name.into(),
+            retries: 3,
+            ratio: 0.5,
+            tags: vec!["a".into(), "b".into()],
+            limits: vec![("x".to_string(), 1), ("y".to_string(), 2)]
+                .into_iter()
+                .collect(),
+            parent: parent.map(Box::new),
+            mode,
+            point: (-1, '\u{1F980}'),
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        let mut service = Binder::new(()).as_binder();
+        let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+        let start = parcel.checkpoint();
+
+        let value = config(
+            "child",
+            Some(config("parent", None, Mode::Fixed(7))),
+            Mode::Range { min: 1, max: 2 },
+        );
+        to_parcel(&value, &mut parcel).unwrap();
+        to_parcel(&Mode::Off, &mut parcel).unwrap();
+        parcel.rewind_to(start).unwrap();
+        assert_eq!(from_parcel::<Config>(&parcel).unwrap(), value);
+        assert_eq!(from_parcel::<Mode>(&parcel).unwrap(), Mode::Off);
+        assert_eq!(parcel.remaining(), 0);
+
+        // Structs are framed like `sized_write`
+        parcel.rewind_to(start).unwrap();
+        to_parcel(&V1 { a: 5 }, &mut parcel).unwrap();
+        parcel.rewind_to(start).unwrap();
+        assert_eq!(parcel.read::<i32>(), Ok(8));
+        assert_eq!(parcel.read::<i32>(), Ok(5));
+
+        // Older and newer versions of a struct
+        parcel.rewind_to(start).unwrap();
+        to_parcel(&V1 { a: 5 }, &mut parcel).unwrap();
+        to_parcel(
+            &V2 {
+                a: 6,
+                b: Some("b".into()),
+                c: vec![1],
+            },
+            &mut parcel,
+        )
+        .unwrap();
+        parcel.write(&9i32).unwrap();
+        parcel.rewind_to(start).unwrap();
+        assert_eq!(
+            from_parcel::<V2>(&parcel).unwrap(),
+            V2 {
+                a: 5,
+                b: None,
+                c: vec![]
+            }
+        );
+        assert_eq!(from_parcel::<V1>(&parcel).unwrap(), V1 { a: 6 });
+        assert_eq!(parcel.read::<i32>(), Ok(9));
+
+        // Invalid data
+        parcel.rewind_to(start).unwrap();
+        parcel.write(&3i32).unwrap();
+        parcel.write(&300i32).unwrap();
+        parcel.rewind_to(start).unwrap();
+        assert_eq!(
+            from_parcel::<V1>(&parcel).err(),
+            Some(StatusCode::BAD_VALUE)
+        );
+        parcel
+            .rewind_to(ParcelPosition(start.offset() + 4))
+            .unwrap();
+        assert_eq!(
+            from_parcel::<u8>(&parcel).err(),
+            Some(StatusCode::BAD_VALUE)
+        );
+        parcel.rewind_to(start).unwrap();
+        parcel.write(&3u32).unwrap();
+        parcel.rewind_to(start).unwrap();
+        assert_eq!(
+            from_parcel::<Mode>(&parcel).err(),
+            Some(StatusCode::BAD_VALUE)
+        );
+    }
+
+    #[test]
+    fn test_serde_skipped_fields() {
+        #[derive(Serialize)]
+        struct Skipping {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            a: Option<i32>,
+            b: i32,
+        }
+
+        let mut service = Binder::new(()).as_binder();
+        let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+        let value = Skipping {
+            a: Some(1),
+            b: 2,
+        };
+        to_parcel(&value, &mut parcel).unwrap();
+        let value = Skipping { a: None, ..value };
+        assert_eq!(
+            to_parcel(&value, &mut parcel).err(),
+            Some(StatusCode::BAD_VALUE)
+        );
+    }
+}
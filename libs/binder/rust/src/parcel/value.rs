@@ -0,0 +1,327 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Dynamically typed values, in the format of `Parcel.writeValue` in Java.
+
+use super::{Deserialize, Parcel, Serialize};
+use crate::error::{Result, StatusCode};
+
+use std::convert::TryInto;
+use std::mem;
+
+// Keep in sync with libs/binder/ParcelValTypes.h and
+// frameworks/base/core/java/android/os/Parcel.java. `VAL_FLOAT` and `VAL_LIST`
+// are only in the latter.
+const VAL_NULL: i32 = -1;
+const VAL_STRING: i32 = 0;
+const VAL_INTEGER: i32 = 1;
+const VAL_MAP: i32 = 2;
+const VAL_BUNDLE: i32 = 3;
+const VAL_LONG: i32 = 6;
+const VAL_FLOAT: i32 = 7;
+const VAL_DOUBLE: i32 = 8;
+const VAL_BOOLEAN: i32 = 9;
+const VAL_LIST: i32 = 11;
+const VAL_BYTEARRAY: i32 = 13;
+const VAL_STRINGARRAY: i32 = 14;
+const VAL_INTARRAY: i32 = 18;
+const VAL_LONGARRAY: i32 = 19;
+const VAL_PERSISTABLEBUNDLE: i32 = 25;
+
+// Keep in sync with BUNDLE_MAGIC* in
+// frameworks/base/core/java/android/os/BaseBundle.java.
+const BUNDLE_MAGIC: i32 = 0x4C444E42;
+const BUNDLE_MAGIC_NATIVE: i32 = 0x4C444E44;
+
+/// A dynamically typed value, as written by `Parcel.writeValue` in Java.
+///
+/// These are the values of `Bundle` entries and of untyped `List` and `Map`
+/// objects. Other Java types, such as parcelables and binders, can't be read
+/// and fail with `BAD_TYPE`.
+///
+/// A null string, array, map, list or bundle is read as [`ParcelValue::Null`],
+/// like Java reads it as `null`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParcelValue {
+    /// `null`
+    Null,
+    /// `String`
+    String(String),
+    /// `Integer`
+    Int(i32),
+    /// `Long`
+    Long(i64),
+    /// `Float`
+    Float(f32),
+    /// `Double`
+    Double(f64),
+    /// `Boolean`
+    Bool(bool),
+    /// `byte[]`
+    ByteArray(Vec<u8>),
+    /// `int[]`
+    IntArray(Vec<i32>),
+    /// `long[]`
+    LongArray(Vec<i64>),
+    /// `String[]`, whose elements may be null
+    StringArray(Vec<Option<String>>),
+    /// `Map`, with its entries in parcel order
+    Map(Vec<(ParcelValue, ParcelValue)>),
+    /// `List`
+    List(Vec<ParcelValue>),
+    /// `Bundle`, with its entries in parcel order
+    Bundle(Vec<(String, ParcelValue)>),
+    /// `PersistableBundle`, with its entries in parcel order
+    PersistableBundle(Vec<(String, ParcelValue)>),
+}
+
+impl ParcelValue {
+    /// The `VAL_*` tag written before the value.
+    fn tag(&self) -> i32 {
+        match self {
+            Self::Null => VAL_NULL,
+            Self::String(_) => VAL_STRING,
+            Self::Int(_) => VAL_INTEGER,
+            Self::Long(_) => VAL_LONG,
+            Self::Float(_) => VAL_FLOAT,
+            Self::Double(_) => VAL_DOUBLE,
+            Self::Bool(_) => VAL_BOOLEAN,
+            Self::ByteArray(_) => VAL_BYTEARRAY,
+            Self::IntArray(_) => VAL_INTARRAY,
+            Self::LongArray(_) => VAL_LONGARRAY,
+            Self::StringArray(_) => VAL_STRINGARRAY,
+            Self::Map(_) => VAL_MAP,
+            Self::List(_) => VAL_LIST,
+            Self::Bundle(_) => VAL_BUNDLE,
+            Self::PersistableBundle(_) => VAL_PERSISTABLEBUNDLE,
+        }
+    }
+}
+
+impl Serialize for ParcelValue {
+    fn serialize(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.write(&self.tag())?;
+        match self {
+            Self::Null => Ok(()),
+            Self::String(value) => parcel.write(value),
+            Self::Int(value) => parcel.write(value),
+            Self::Long(value) => parcel.write(value),
+            Self::Float(value) => parcel.write(value),
+            Self::Double(value) => parcel.write(value),
+            Self::Bool(value) => parcel.write(value),
+            Self::ByteArray(value) => parcel.write(value),
+            Self::IntArray(value) => parcel.write(value),
+            Self::LongArray(value) => parcel.write(value),
+            Self::StringArray(value) => parcel.write(value),
+            Self::Map(entries) => {
+                write_len(parcel, entries.len())?;
+                entries.iter().try_for_each(|(key, value)| {
+                    parcel.write(key)?;
+                    parcel.write(value)
+                })
+            }
+            Self::List(values) => {
+                write_len(parcel, values.len())?;
+                values.iter().try_for_each(|value| parcel.write(value))
+            }
+            Self::Bundle(entries) | Self::PersistableBundle(entries) => {
+                write_bundle(parcel, entries)
+            }
+        }
+    }
+}
+
+impl Deserialize for ParcelValue {
+    fn deserialize(parcel: &Parcel) -> Result<Self> {
+        let tag: i32 = parcel.read()?;
+        let value = match tag {
+            VAL_NULL => Self::Null,
+            VAL_STRING => nullable(parcel.read()?, Self::String),
+            VAL_INTEGER => Self::Int(parcel.read()?),
+            VAL_LONG => Self::Long(parcel.read()?),
+            VAL_FLOAT => Self::Float(parcel.read()?),
+            VAL_DOUBLE => Self::Double(parcel.read()?),
+            VAL_BOOLEAN => Self::Bool(parcel.read()?),
+            VAL_BYTEARRAY => nullable(parcel.read()?, Self::ByteArray),
+            VAL_INTARRAY => nullable(parcel.read()?, Self::IntArray),
+            VAL_LONGARRAY => nullable(parcel.read()?, Self::LongArray),
+            VAL_STRINGARRAY => nullable(parcel.read()?, Self::StringArray),
+            VAL_MAP => nullable(read_map(parcel)?, Self::Map),
+            VAL_LIST => nullable(read_list(parcel)?, Self::List),
+            VAL_BUNDLE => nullable(read_bundle(parcel)?, Self::Bundle),
+            VAL_PERSISTABLEBUNDLE => nullable(read_bundle(parcel)?, Self::PersistableBundle),
+            _ => return Err(StatusCode::BAD_TYPE),
+        };
+        Ok(value)
+    }
+}
+
+fn nullable<T>(value: Option<T>, variant: fn(T) -> ParcelValue) -> ParcelValue {
+    value.map_or(ParcelValue::Null, variant)
+}
+
+fn write_len(parcel: &mut Parcel, len: usize) -> Result<()> {
+    let len: i32 = len.try_into().or(Err(StatusCode::BAD_VALUE))?;
+    parcel.write(&len)
+}
+
+/// Read the length of a map or list, or `None` for a null one, and check that
+/// its entries of at least `data_size` bytes each fit in the parcel.
+fn read_len<T>(parcel: &Parcel, data_size: usize) -> Result<Option<usize>> {
+    let len: i32 = parcel.read()?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let len = len as usize;
+    parcel.check_allocation(len, data_size, mem::size_of::<T>())?;
+    Ok(Some(len))
+}
+
+fn read_map(parcel: &Parcel) -> Result<Option<Vec<(ParcelValue, ParcelValue)>>> {
+    let len = match read_len::<(ParcelValue, ParcelValue)>(parcel, 8)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    (0..len)
+        .map(|_| parcel.read_nested(|parcel| Ok((parcel.read()?, parcel.read()?))))
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+fn read_list(parcel: &Parcel) -> Result<Option<Vec<ParcelValue>>> {
+    let len = match read_len::<ParcelValue>(parcel, 4)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    (0..len)
+        .map(|_| parcel.read_nested(Parcel::read))
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// Write a bundle like `BaseBundle.writeToParcelInner` in Java: the length of
+/// the bundle data, which doesn't include the length and magic number, or 0
+/// for an empty bundle.
+fn write_bundle(parcel: &mut Parcel, entries: &[(String, ParcelValue)]) -> Result<()> {
+    if entries.is_empty() {
+        return parcel.write(&0i32);
+    }
+    let length_position = parcel.checkpoint();
+    parcel.write(&0i32)?;
+    parcel.write(&BUNDLE_MAGIC_NATIVE)?;
+    let start = parcel.checkpoint();
+    write_len(parcel, entries.len())?;
+    for (key, value) in entries {
+        parcel.write(key)?;
+        parcel.write(value)?;
+    }
+    let end = parcel.checkpoint();
+    parcel.rewind_to(length_position)?;
+    parcel.write(&(end.offset() - start.offset()))?;
+    parcel.rewind_to(end)
+}
+
+fn read_bundle(parcel: &Parcel) -> Result<Option<Vec<(String, ParcelValue)>>> {
+    let length: i32 = parcel.read()?;
+    if length < 0 {
+        return Ok(None);
+    }
+    if length == 0 {
+        return Ok(Some(vec![]));
+    }
+    let magic: i32 = parcel.read()?;
+    if magic != BUNDLE_MAGIC && magic != BUNDLE_MAGIC_NATIVE {
+        return Err(StatusCode::BAD_VALUE);
+    }
+    if length as usize > parcel.remaining() {
+        return Err(StatusCode::BAD_VALUE);
+    }
+    let len = read_len::<(String, ParcelValue)>(parcel, 8)?.ok_or(StatusCode::BAD_VALUE)?;
+    (0..len)
+        .map(|_| parcel.read_nested(|parcel| Ok((parcel.read()?, parcel.read()?))))
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+#[test]
+fn test_parcel_value() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.checkpoint();
+
+    let value = ParcelValue::List(vec![
+        ParcelValue::Null,
+        ParcelValue::String("Hello".into()),
+        ParcelValue::Int(1),
+        ParcelValue::Long(-2),
+        ParcelValue::Float(0.5),
+        ParcelValue::Double(1.25),
+        ParcelValue::Bool(true),
+        ParcelValue::ByteArray(vec![1, 2, 3]),
+        ParcelValue::IntArray(vec![4, 5]),
+        ParcelValue::LongArray(vec![6]),
+        ParcelValue::StringArray(vec![Some("a".into()), None]),
+        ParcelValue::Map(vec![(ParcelValue::Int(7), ParcelValue::String("b".into()))]),
+        ParcelValue::Bundle(vec![
+            ("empty".into(), ParcelValue::Bundle(vec![])),
+            (
+                "nested".into(),
+                ParcelValue::PersistableBundle(vec![("c".into(), ParcelValue::Int(8))]),
+            ),
+        ]),
+    ]);
+    parcel.write(&value).unwrap();
+    parcel.write(&9i32).unwrap();
+    parcel.rewind_to(start).unwrap();
+    assert_eq!(parcel.read::<ParcelValue>(), Ok(value));
+    assert_eq!(parcel.read::<i32>(), Ok(9));
+
+    // The layout of `Parcel.writeValue` in Java
+    parcel.rewind_to(start).unwrap();
+    parcel.write(&ParcelValue::IntArray(vec![4, 5])).unwrap();
+    parcel
+        .write(&ParcelValue::Bundle(vec![(
+            "k".into(),
+            ParcelValue::Bool(false),
+        )]))
+        .unwrap();
+    parcel.rewind_to(start).unwrap();
+    let read_i32s = |n| {
+        (0..n)
+            .map(|_| parcel.read::<i32>().unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(read_i32s(4), [VAL_INTARRAY, 2, 4, 5]);
+    assert_eq!(read_i32s(4), [VAL_BUNDLE, 20, BUNDLE_MAGIC_NATIVE, 1]);
+    assert_eq!(parcel.read::<String>().as_deref(), Ok("k"));
+    assert_eq!(parcel.read::<i32>(), Ok(VAL_BOOLEAN));
+    assert_eq!(parcel.read::<i32>(), Ok(0));
+
+    // Null values and unsupported types
+    parcel.rewind_to(start).unwrap();
+    parcel.write(&VAL_STRING).unwrap();
+    parcel.write(&None::<String>).unwrap();
+    parcel.write(&VAL_BUNDLE).unwrap();
+    parcel.write(&-1i32).unwrap();
+    parcel.write(&4i32).unwrap();
+    parcel.rewind_to(start).unwrap();
+    assert_eq!(parcel.read::<ParcelValue>(), Ok(ParcelValue::Null));
+    assert_eq!(parcel.read::<ParcelValue>(), Ok(ParcelValue::Null));
+    assert_eq!(parcel.read::<ParcelValue>(), Err(StatusCode::BAD_TYPE));
+}
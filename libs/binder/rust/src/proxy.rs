@@ -17,21 +17,32 @@
 //! Rust API for interacting with a remote binder service.
 
 use crate::binder::{
-    AsNative, FromIBinder, IBinder, Interface, InterfaceClass, Strong, TransactionCode, TransactionFlags,
+    AsNative, FromIBinder, IBinder, Interface, InterfaceClass, Remotable, Strong, TransactionCode,
+    TransactionFlags,
 };
 use crate::error::{status_result, Result, StatusCode};
+use crate::native::{self, Binder};
 use crate::parcel::{
     Deserialize, DeserializeArray, DeserializeOption, Parcel, Serialize, SerializeArray,
     SerializeOption,
 };
+use crate::interceptor::{self, Direction};
+use crate::panic_policy;
 use crate::sys;
+use crate::testing;
 
+use std::borrow::Cow;
 use std::convert::TryInto;
 use std::cmp::Ordering;
 use std::ffi::{c_void, CString};
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::os::unix::io::AsRawFd;
 use std::ptr;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
 /// A strong reference to a Binder remote object.
 ///
@@ -50,6 +61,16 @@ impl fmt::Debug for SpIBinder {
 /// An `SpIBinder` is a handle to a C++ IBinder, which is thread-safe
 unsafe impl Send for SpIBinder {}
 
+/// # Safety
+///
+/// An `SpIBinder` holds no state besides its `AIBinder` pointer, so sharing a
+/// reference between threads is equivalent to sending clones, which `Send`
+/// already allows. The `AIBinder` methods taking a shared reference are safe
+/// to call concurrently: reference counts are atomic, the class is associated
+/// under a lock, death recipients and the proxies of remote binders are
+/// tracked under locks and transactions may be made from any thread.
+unsafe impl Sync for SpIBinder {}
+
 impl SpIBinder {
     /// Create an `SpIBinder` wrapper object from a raw `AIBinder` pointer.
     ///
@@ -122,6 +143,39 @@ impl SpIBinder {
     pub fn downgrade(&mut self) -> WpIBinder {
         WpIBinder::new(self)
     }
+
+    /// Returns the Rust object of this binder, if it is a local
+    /// [`Binder<T>`](Binder).
+    ///
+    /// For services declared with [`declare_binder_interface!`], `T` is the
+    /// native type wrapping the service, see [`Strong::as_local`] to get the
+    /// service itself.
+    pub fn downcast_local<T: Remotable + 'static>(&self) -> Option<&T> {
+        let class = unsafe {
+            // Safety: `SpIBinder` guarantees that it always contains a valid
+            // `AIBinder` pointer. `AIBinder_getClass` only reads the class of
+            // the binder.
+            sys::AIBinder_getClass(self.as_native() as *mut sys::AIBinder)
+        };
+        if class.is_null() {
+            return None;
+        }
+        let class = unsafe {
+            // Safety: `class` is a valid, non-null class pointer.
+            InterfaceClass::from_ptr(class)
+        };
+        if !class.has_type::<Binder<T>>() {
+            return None;
+        }
+        unsafe {
+            // Safety: The user data of local binders of a class defined with
+            // the callbacks of `Binder<T>` is the `T` owned by the binder,
+            // which lives as long as this strong reference. Remote binders
+            // have no user data.
+            (sys::AIBinder_getUserData(self.as_native() as *mut sys::AIBinder) as *const T)
+                .as_ref()
+        }
+    }
 }
 
 /// An object that can be associate with an [`InterfaceClass`].
@@ -187,6 +241,15 @@ impl PartialEq for SpIBinder {
 
 impl Eq for SpIBinder {}
 
+/// Hashes the identity of the binder object. `libbinder_ndk` keeps a single
+/// `AIBinder` for each object, including each remote object, so this is
+/// consistent with `Eq`.
+impl Hash for SpIBinder {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 as usize).hash(state)
+    }
+}
+
 impl Clone for SpIBinder {
     fn clone(&self) -> Self {
         unsafe {
@@ -211,6 +274,172 @@ impl Drop for SpIBinder {
     }
 }
 
+/// Returns the interface descriptor of the class associated with `binder`, or
+/// an empty string if there is none.
+pub(crate) fn class_descriptor(binder: *const sys::AIBinder) -> Cow<'static, str> {
+    let class = unsafe {
+        // Safety: `binder` is a valid `AIBinder` pointer, and
+        // `AIBinder_getClass` only reads from it. It returns either null or a
+        // valid class pointer, from which we can safely construct an
+        // `InterfaceClass`.
+        sys::AIBinder_getClass(binder as *mut sys::AIBinder)
+            .as_ref()
+            .map(|p| InterfaceClass::from_ptr(p))
+    };
+    match class {
+        Some(class) => Cow::Owned(class.get_descriptor()),
+        None => Cow::Borrowed(""),
+    }
+}
+
+/// Prepare the input parcel of a transaction to `binder` and fill it in with
+/// `input_callback`.
+fn prepare_transaction<F: FnOnce(&mut Parcel) -> Result<()>>(
+    binder: *const sys::AIBinder,
+    input_callback: F,
+) -> Result<Parcel> {
+    testing::check_transaction(binder)?;
+    let mut input = ptr::null_mut();
+    let status = unsafe {
+        // Safety: `binder` is a valid pointer to an `AIBinder`. It is safe to
+        // cast from an immutable pointer to a mutable pointer here, because
+        // `AIBinder_prepareTransaction` only calls immutable `AIBinder`
+        // methods but the parameter is unfortunately not marked as const.
+        //
+        // After the call, input will be either a valid, owned `AParcel`
+        // pointer, or null.
+        sys::AIBinder_prepareTransaction(binder as *mut sys::AIBinder, &mut input)
+    };
+    status_result(status)?;
+    let mut input = unsafe {
+        // Safety: At this point, `input` is either a valid, owned `AParcel`
+        // pointer, or null. `Parcel::owned` safely handles both cases,
+        // taking ownership of the parcel.
+        Parcel::owned(input).ok_or(StatusCode::UNEXPECTED_NULL)?
+    };
+    input_callback(&mut input)?;
+    Ok(input)
+}
+
+/// Send a transaction prepared by `prepare_transaction` to `binder`.
+fn submit_transaction(
+    binder: *const sys::AIBinder,
+    code: TransactionCode,
+    input: Parcel,
+    flags: TransactionFlags,
+) -> Result<Parcel> {
+    let size = input.get_data_size() as usize;
+    let mut input = input.into_raw()?;
+    let object = unsafe {
+        // Safety: `AIBinder_getUserData` accepts any valid `AIBinder`
+        // pointer and returns null unless it is a local binder. We
+        // only use the result as a key, never dereference it.
+        sys::AIBinder_getUserData(binder as *mut sys::AIBinder)
+    };
+    let intercepted = interceptor::begin(
+        object,
+        Direction::Outgoing,
+        || class_descriptor(binder),
+        code,
+        flags,
+        size,
+    );
+    let mut reply = ptr::null_mut();
+    let mut transact = || unsafe {
+        // Safety: `binder` is a valid pointer to an `AIBinder`. Although
+        // `IBinder::transact` is not a const method, it is still safe to cast
+        // our immutable pointer to mutable for the call. First,
+        // `IBinder::transact` is thread-safe, so concurrency is not an issue.
+        // The only way that `transact` can affect any visible, mutable state
+        // in the current process is by calling `onTransact` for a local
+        // service. However, in order for transactions to be thread-safe, this
+        // method must dynamically lock its data before modifying it. We
+        // enforce this property in Rust by requiring `Sync` for remotable
+        // objects and only providing `on_transact` with an immutable
+        // reference to `self`.
+        //
+        // This call takes ownership of the `input` parcel pointer, and passes
+        // ownership of the `reply` out parameter to its caller. It does not
+        // affect ownership of the `binder` parameter.
+        sys::AIBinder_transact(
+            binder as *mut sys::AIBinder,
+            code,
+            &mut input,
+            &mut reply,
+            flags,
+        )
+    };
+    // `libbinder_ndk` calls the `on_transact` of a local object on this thread,
+    // without its flags.
+    let status = if object.is_null() {
+        transact()
+    } else {
+        native::with_local_flags(flags, transact)
+    };
+    if let Some(intercepted) = intercepted {
+        intercepted.finish(&status_result(status));
+    }
+    status_result(status)?;
+
+    unsafe {
+        // Safety: `reply` is either a valid `AParcel` pointer or null
+        // after the call to `AIBinder_transact` above, so we can
+        // construct a `Parcel` out of it. `AIBinder_transact` passes
+        // ownership of the `reply` parcel to Rust, so we need to
+        // construct an owned variant. `Parcel::owned` takes ownership
+        // of the parcel pointer.
+        Parcel::owned(reply).ok_or(StatusCode::UNEXPECTED_NULL)
+    }
+}
+
+/// A parcel handed over to another thread.
+struct SendParcel(Parcel);
+
+/// # Safety
+///
+/// An `AParcel` is not tied to the thread that created it, and a
+/// `SendParcel` is only ever used by one thread at a time.
+unsafe impl Send for SendParcel {}
+
+impl SendParcel {
+    fn new(mut parcel: Parcel) -> Self {
+        // Limits of native parcels are kept by the thread which set them.
+        parcel.clear_deserialize_limits();
+        Self(parcel)
+    }
+
+    fn into_inner(self) -> Parcel {
+        self.0
+    }
+}
+
+/// Maximum number of threads performing transactions for
+/// `transact_with_timeout` at once, including those whose caller has given up.
+const MAX_TIMEOUT_THREADS: usize = 16;
+
+static TIMEOUT_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the `MAX_TIMEOUT_THREADS` transaction threads, released when
+/// dropped.
+struct TimeoutThread;
+
+impl TimeoutThread {
+    fn reserve() -> Result<TimeoutThread> {
+        TIMEOUT_THREADS
+            .fetch_update(atomic::Ordering::SeqCst, atomic::Ordering::SeqCst, |count| {
+                (count < MAX_TIMEOUT_THREADS).then(|| count + 1)
+            })
+            .map(|_| TimeoutThread)
+            .or(Err(StatusCode::WOULD_BLOCK))
+    }
+}
+
+impl Drop for TimeoutThread {
+    fn drop(&mut self) {
+        TIMEOUT_THREADS.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
 impl<T: AsNative<sys::AIBinder>> IBinder for T {
     /// Perform a binder transaction
     fn transact<F: FnOnce(&mut Parcel) -> Result<()>>(
@@ -219,66 +448,55 @@ impl<T: AsNative<sys::AIBinder>> IBinder for T {
         flags: TransactionFlags,
         input_callback: F,
     ) -> Result<Parcel> {
-        let mut input = ptr::null_mut();
-        let status = unsafe {
-            // Safety: `SpIBinder` guarantees that `self` always contains a
-            // valid pointer to an `AIBinder`. It is safe to cast from an
-            // immutable pointer to a mutable pointer here, because
-            // `AIBinder_prepareTransaction` only calls immutable `AIBinder`
-            // methods but the parameter is unfortunately not marked as const.
-            //
-            // After the call, input will be either a valid, owned `AParcel`
-            // pointer, or null.
-            sys::AIBinder_prepareTransaction(self.as_native() as *mut sys::AIBinder, &mut input)
-        };
-        status_result(status)?;
-        let mut input = unsafe {
-            // Safety: At this point, `input` is either a valid, owned `AParcel`
-            // pointer, or null. `Parcel::owned` safely handles both cases,
-            // taking ownership of the parcel.
-            Parcel::owned(input).ok_or(StatusCode::UNEXPECTED_NULL)?
-        };
-        input_callback(&mut input)?;
-        let mut reply = ptr::null_mut();
-        let status = unsafe {
-            // Safety: `SpIBinder` guarantees that `self` always contains a
-            // valid pointer to an `AIBinder`. Although `IBinder::transact` is
-            // not a const method, it is still safe to cast our immutable
-            // pointer to mutable for the call. First, `IBinder::transact` is
-            // thread-safe, so concurrency is not an issue. The only way that
-            // `transact` can affect any visible, mutable state in the current
-            // process is by calling `onTransact` for a local service. However,
-            // in order for transactions to be thread-safe, this method must
-            // dynamically lock its data before modifying it. We enforce this
-            // property in Rust by requiring `Sync` for remotable objects and
-            // only providing `on_transact` with an immutable reference to
-            // `self`.
-            //
-            // This call takes ownership of the `input` parcel pointer, and
-            // passes ownership of the `reply` out parameter to its caller. It
-            // does not affect ownership of the `binder` parameter.
-            sys::AIBinder_transact(
-                self.as_native() as *mut sys::AIBinder,
-                code,
-                &mut input.into_raw(),
-                &mut reply,
-                flags,
-            )
-        };
-        status_result(status)?;
+        let input = prepare_transaction(self.as_native(), input_callback)?;
+        submit_transaction(self.as_native(), code, input, flags)
+    }
 
-        unsafe {
-            // Safety: `reply` is either a valid `AParcel` pointer or null
-            // after the call to `AIBinder_transact` above, so we can
-            // construct a `Parcel` out of it. `AIBinder_transact` passes
-            // ownership of the `reply` parcel to Rust, so we need to
-            // construct an owned variant. `Parcel::owned` takes ownership
-            // of the parcel pointer.
-            Parcel::owned(reply).ok_or(StatusCode::UNEXPECTED_NULL)
+    fn transact_with_timeout<F: FnOnce(&mut Parcel) -> Result<()>>(
+        &self,
+        code: TransactionCode,
+        flags: TransactionFlags,
+        timeout: Duration,
+        input_callback: F,
+    ) -> Result<Parcel> {
+        if flags & SpIBinder::FLAG_ONEWAY != 0 {
+            return self.transact(code, flags, input_callback);
+        }
+        let slot = TimeoutThread::reserve()?;
+        let input = SendParcel::new(prepare_transaction(self.as_native(), input_callback)?);
+        let binder = unsafe {
+            // Safety: `self.as_native()` is a valid pointer to an `AIBinder`.
+            // The new strong reference keeps it alive on the transaction
+            // thread, and is owned by the resulting `SpIBinder`.
+            sys::AIBinder_incStrong(self.as_native() as *mut sys::AIBinder);
+            SpIBinder::from_raw(self.as_native() as *mut sys::AIBinder)
+        }
+        .ok_or(StatusCode::UNEXPECTED_NULL)?;
+
+        // The reply is always sent without blocking. If the caller has given
+        // up waiting, sending fails and the reply is dropped on the
+        // transaction thread.
+        let (sender, receiver) = mpsc::sync_channel(1);
+        thread::Builder::new()
+            .name("binder_timeout".to_owned())
+            .spawn(move || {
+                let _slot = slot;
+                let reply = submit_transaction(binder.as_native(), code, input.into_inner(), flags);
+                let _ = sender.send(reply.map(SendParcel::new));
+            })
+            .or(Err(StatusCode::NO_MEMORY))?;
+        match receiver.recv_timeout(timeout) {
+            Ok(reply) => reply.map(SendParcel::into_inner),
+            Err(RecvTimeoutError::Timeout) => Err(StatusCode::TIMED_OUT),
+            // The transaction thread panicked
+            Err(RecvTimeoutError::Disconnected) => Err(StatusCode::UNKNOWN_ERROR),
         }
     }
 
     fn is_binder_alive(&self) -> bool {
+        if testing::is_dead(self.as_native()) {
+            return false;
+        }
         unsafe {
             // Safety: `SpIBinder` guarantees that `self` always contains a
             // valid pointer to an `AIBinder`.
@@ -289,6 +507,7 @@ impl<T: AsNative<sys::AIBinder>> IBinder for T {
     }
 
     fn ping_binder(&mut self) -> Result<()> {
+        testing::check_transaction(self.as_native())?;
         let status = unsafe {
             // Safety: `SpIBinder` guarantees that `self` always contains a
             // valid pointer to an `AIBinder`.
@@ -353,6 +572,9 @@ impl<T: AsNative<sys::AIBinder>> IBinder for T {
     }
 
     fn link_to_death(&mut self, recipient: &mut DeathRecipient) -> Result<()> {
+        if let Some(result) = testing::link_to_death(self.as_native(), recipient) {
+            return result;
+        }
         status_result(unsafe {
             // Safety: `SpIBinder` guarantees that `self` always contains a
             // valid pointer to an `AIBinder`. `recipient` can always be
@@ -369,6 +591,9 @@ impl<T: AsNative<sys::AIBinder>> IBinder for T {
     }
 
     fn unlink_to_death(&mut self, recipient: &mut DeathRecipient) -> Result<()> {
+        if let Some(result) = testing::unlink_to_death(self.as_native(), recipient) {
+            return result;
+        }
         status_result(unsafe {
             // Safety: `SpIBinder` guarantees that `self` always contains a
             // valid pointer to an `AIBinder`. `recipient` can always be
@@ -422,7 +647,12 @@ impl DeserializeArray for Option<SpIBinder> {}
 ///
 /// This struct encapsulates the generic C++ `wp<IBinder>` class. This wrapper
 /// is untyped; typed interface access is implemented by the AIDL compiler.
-pub struct WpIBinder(*mut sys::AIBinder_Weak);
+pub struct WpIBinder(
+    *mut sys::AIBinder_Weak,
+    // Address of the `AIBinder` when the reference was created, for `Hash`.
+    // It is never dereferenced.
+    usize,
+);
 
 impl fmt::Debug for WpIBinder {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -435,6 +665,14 @@ impl fmt::Debug for WpIBinder {
 /// A `WpIBinder` is a handle to a C++ IBinder, which is thread-safe.
 unsafe impl Send for WpIBinder {}
 
+/// # Safety
+///
+/// The `AIBinder_Weak` of a `WpIBinder` is only modified when it is deleted,
+/// which requires ownership. Promoting, cloning and comparing it only read the
+/// C++ `wp<AIBinder>`, and promotion is atomic, so they are safe to call
+/// concurrently.
+unsafe impl Sync for WpIBinder {}
+
 impl WpIBinder {
     /// Create a new weak reference from an object that can be converted into a
     /// raw `AIBinder` pointer.
@@ -445,7 +683,7 @@ impl WpIBinder {
             sys::AIBinder_Weak_new(binder.as_native_mut())
         };
         assert!(!ptr.is_null());
-        Self(ptr)
+        Self(ptr, binder.as_native() as usize)
     }
 
     /// Promote this weak reference to a strong reference to the binder object.
@@ -473,7 +711,7 @@ impl Clone for WpIBinder {
             sys::AIBinder_Weak_clone(self.0)
         };
         assert!(!ptr.is_null(), "Unexpected null pointer from AIBinder_Weak_clone");
-        Self(ptr)
+        Self(ptr, self.1)
     }
 }
 
@@ -517,6 +755,15 @@ impl PartialEq for WpIBinder {
 
 impl Eq for WpIBinder {}
 
+/// Hashes the identity of the binder object, which the C++ `wp<AIBinder>`
+/// compares, so this is consistent with `Eq` whether or not the object is
+/// still alive.
+impl Hash for WpIBinder {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.1.hash(state)
+    }
+}
+
 impl Drop for WpIBinder {
     fn drop(&mut self) {
         unsafe {
@@ -532,6 +779,7 @@ impl Drop for WpIBinder {
 pub struct DeathRecipient {
     recipient: *mut sys::AIBinder_DeathRecipient,
     callback: Box<dyn Fn() + Send + 'static>,
+    binder_died: unsafe extern "C" fn(cookie: *mut c_void),
 }
 
 impl DeathRecipient {
@@ -553,6 +801,7 @@ impl DeathRecipient {
         DeathRecipient {
             recipient,
             callback,
+            binder_died: Self::binder_died::<F>,
         }
     }
 
@@ -561,10 +810,17 @@ impl DeathRecipient {
     /// This cookie will be used to link and unlink this death recipient to a
     /// binder object and will be passed to the `binder_died` callback as an
     /// opaque userdata pointer.
-    fn get_cookie(&self) -> *mut c_void {
+    pub(crate) fn get_cookie(&self) -> *mut c_void {
         &*self.callback as *const _ as *mut c_void
     }
 
+    /// Get the callback that should be invoked with the cookie returned by
+    /// `get_cookie` when the linked binder object dies.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn get_callback(&self) -> unsafe extern "C" fn(cookie: *mut c_void) {
+        self.binder_died
+    }
+
     /// Callback invoked from C++ when the binder object dies.
     ///
     /// # Safety
@@ -576,7 +832,7 @@ impl DeathRecipient {
         F: Fn() + Send + 'static,
     {
         let callback = (cookie as *mut F).as_ref().unwrap();
-        callback();
+        panic_policy::catch_panic("DeathRecipient", callback);
     }
 }
 
@@ -597,6 +853,7 @@ unsafe impl AsNative<sys::AIBinder_DeathRecipient> for DeathRecipient {
 
 impl Drop for DeathRecipient {
     fn drop(&mut self) {
+        testing::forget_recipient(self);
         unsafe {
             // Safety: `self.recipient` is always a valid, owned
             // `AIBinder_DeathRecipient` pointer returned by
@@ -640,6 +897,9 @@ unsafe impl<T: Proxy> AsNative<sys::AIBinder> for T {
 /// Retrieve an existing service, blocking for a few seconds if it doesn't yet
 /// exist.
 pub fn get_service(name: &str) -> Option<SpIBinder> {
+    if testing::is_installed() {
+        return testing::get_service(name);
+    }
     let name = CString::new(name).ok()?;
     unsafe {
         // Safety: `AServiceManager_getService` returns either a null pointer or
@@ -649,6 +909,62 @@ pub fn get_service(name: &str) -> Option<SpIBinder> {
     }
 }
 
+/// Retrieve an existing service, returning immediately if it doesn't exist.
+pub fn check_service(name: &str) -> Option<SpIBinder> {
+    if testing::is_installed() {
+        return testing::get_service(name);
+    }
+    let name = CString::new(name).ok()?;
+    unsafe {
+        // Safety: `AServiceManager_checkService` returns either a null pointer
+        // or a valid pointer to an owned `AIBinder`. Either of these values is
+        // safe to pass to `SpIBinder::from_raw`.
+        SpIBinder::from_raw(sys::AServiceManager_checkService(name.as_ptr()))
+    }
+}
+
+/// Class of the service manager, which registers itself as the "manager"
+/// service. Only used to make transactions to it.
+struct ServiceManager;
+
+impl Remotable for ServiceManager {
+    fn get_descriptor() -> &'static str {
+        "android.os.IServiceManager"
+    }
+
+    fn on_transact(&self, _code: TransactionCode, _data: &Parcel, _reply: &mut Parcel) -> Result<()> {
+        Err(StatusCode::UNKNOWN_TRANSACTION)
+    }
+
+    binder_fn_get_class!(Binder<Self>);
+}
+
+// `libbinder_ndk` has no API to list services, so `list_services` calls
+// `IServiceManager` directly. These constants come from
+// `libs/binder/aidl/android/os/IServiceManager.aidl`.
+
+/// `IServiceManager::listServices`, the fourth method of the interface
+const LIST_SERVICES_TRANSACTION: TransactionCode = sys::FIRST_CALL_TRANSACTION + 3;
+/// `IServiceManager::DUMP_FLAG_PRIORITY_ALL`
+const DUMP_FLAG_PRIORITY_ALL: i32 = 15;
+
+/// List the names of all registered services.
+pub fn list_services() -> Result<Vec<String>> {
+    if testing::is_installed() {
+        return Ok(testing::list_services());
+    }
+    let mut manager = check_service("manager").ok_or(StatusCode::NAME_NOT_FOUND)?;
+    if !manager.associate_class(ServiceManager::get_class()) {
+        return Err(StatusCode::BAD_TYPE);
+    }
+    manager
+        .call(LIST_SERVICES_TRANSACTION, &(DUMP_FLAG_PRIORITY_ALL,))
+        .map_err(|status| match status.transaction_error() {
+            StatusCode::OK => StatusCode::FAILED_TRANSACTION,
+            error => error,
+        })
+}
+
 /// Retrieve an existing service for a particular interface, blocking for a few
 /// seconds if it doesn't yet exist.
 pub fn get_interface<T: FromIBinder + ?Sized>(name: &str) -> Result<Strong<T>> {
@@ -659,6 +975,31 @@ pub fn get_interface<T: FromIBinder + ?Sized>(name: &str) -> Result<Strong<T>> {
     }
 }
 
+/// Retrieve a service, waiting for it to be registered if it doesn't yet
+/// exist. This blocks until the service is available.
+pub fn wait_for_service(name: &str) -> Option<SpIBinder> {
+    if testing::is_installed() {
+        return testing::get_service(name);
+    }
+    let name = CString::new(name).ok()?;
+    unsafe {
+        // Safety: `AServiceManager_waitForService` returns either a null
+        // pointer or a valid pointer to an owned `AIBinder`. Either of these
+        // values is safe to pass to `SpIBinder::from_raw`.
+        SpIBinder::from_raw(sys::AServiceManager_waitForService(name.as_ptr()))
+    }
+}
+
+/// Retrieve a service for a particular interface, waiting for it to be
+/// registered if it doesn't yet exist. This blocks until the service is
+/// available.
+pub fn wait_for_interface<T: FromIBinder + ?Sized>(name: &str) -> Result<Strong<T>> {
+    match wait_for_service(name) {
+        Some(service) => FromIBinder::try_from(service),
+        None => Err(StatusCode::NAME_NOT_FOUND),
+    }
+}
+
 /// # Safety
 ///
 /// `SpIBinder` guarantees that `binder` always contains a valid pointer to an
@@ -672,3 +1013,183 @@ unsafe impl AsNative<sys::AIBinder> for SpIBinder {
         self.0
     }
 }
+
+#[test]
+fn test_transact_with_timeout() {
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::sync::Mutex;
+
+    /// Blocks transaction 2 until released, then reports it finished.
+    struct Slow {
+        release: Mutex<Receiver<()>>,
+        finished: Mutex<Sender<i32>>,
+    }
+
+    impl Interface for Slow {}
+
+    impl Remotable for Slow {
+        fn get_descriptor() -> &'static str {
+            "android.binder.test.ISlow"
+        }
+
+        fn on_transact(
+            &self,
+            code: TransactionCode,
+            data: &Parcel,
+            reply: &mut Parcel,
+        ) -> Result<()> {
+            let value: i32 = data.read()?;
+            if code == 2 {
+                let _ = self.release.lock().unwrap().recv();
+                let _ = self.finished.lock().unwrap().send(value);
+            }
+            reply.write(&value)
+        }
+
+        binder_fn_get_class!(Binder::<Self>);
+    }
+
+    let (release, released) = channel();
+    let (finish, finished) = channel();
+    let binder = Binder::new(Slow {
+        release: Mutex::new(released),
+        finished: Mutex::new(finish),
+    })
+    .as_binder();
+    // Only transaction 2 is expected to time out, as it blocks until released
+    let timeout = Duration::from_secs(5);
+    let short_timeout = Duration::from_millis(20);
+
+    let reply = binder
+        .transact_with_timeout(1, 0, timeout, |data| data.write(&1i32))
+        .unwrap();
+    assert_eq!(reply.read::<i32>(), Ok(1));
+
+    assert_eq!(
+        binder
+            .transact_with_timeout(2, 0, short_timeout, |data| data.write(&2i32))
+            .err(),
+        Some(StatusCode::TIMED_OUT)
+    );
+    // The calling thread can make other calls while the slow one is running
+    let reply = binder
+        .transact_with_timeout(1, 0, timeout, |data| data.write(&3i32))
+        .unwrap();
+    assert_eq!(reply.read::<i32>(), Ok(3));
+
+    // The late reply is dropped by the transaction thread
+    release.send(()).unwrap();
+    assert_eq!(finished.recv_timeout(timeout), Ok(2));
+}
+
+#[test]
+fn test_call() {
+    use crate::error::{ExceptionCode, Status};
+
+    struct Adder;
+
+    impl Interface for Adder {}
+
+    impl Remotable for Adder {
+        fn get_descriptor() -> &'static str {
+            "android.binder.test.IAdder"
+        }
+
+        fn on_transact(
+            &self,
+            code: TransactionCode,
+            data: &Parcel,
+            reply: &mut Parcel,
+        ) -> Result<()> {
+            match code {
+                1 => {
+                    let a: i32 = data.read()?;
+                    let b: i32 = data.read()?;
+                    reply.write(&Status::ok())?;
+                    reply.write(&(a + b))
+                }
+                2 => reply.write(&Status::ok()),
+                _ => reply.write(&Status::new_service_specific_error(42, None)),
+            }
+        }
+
+        binder_fn_get_class!(Binder::<Self>);
+    }
+
+    let binder = Binder::new(Adder).as_binder();
+
+    assert_eq!(binder.call::<_, i32>(1, &(1i32, 2i32)).ok(), Some(3));
+    assert_eq!(binder.call::<_, ()>(2, &()).ok(), Some(()));
+
+    let status = binder.call::<_, i32>(3, &(1i32,)).unwrap_err();
+    assert_eq!(status.exception_code(), ExceptionCode::SERVICE_SPECIFIC);
+    assert_eq!(status.service_specific_error(), 42);
+}
+
+#[test]
+fn test_binder_hash() {
+    use std::collections::HashSet;
+
+    let mut binder = Binder::new(()).as_binder();
+    let other = Binder::new(()).as_binder();
+
+    // Binders read from a parcel are the same object
+    let mut parcel = Parcel::new_for_test(&mut binder).unwrap();
+    let start = parcel.checkpoint();
+    parcel.write(&binder).unwrap();
+    parcel.rewind_to(start).unwrap();
+    let read: SpIBinder = parcel.read().unwrap();
+
+    let binders: HashSet<SpIBinder> = vec![binder.clone(), read, other.clone()]
+        .into_iter()
+        .collect();
+    assert_eq!(binders.len(), 2);
+    assert!(binders.contains(&binder));
+    assert!(binders.contains(&other));
+
+    let weak = binder.downgrade();
+    let mut weaks: HashSet<WpIBinder> =
+        vec![weak.clone(), binder.downgrade()].into_iter().collect();
+    assert_eq!(weaks.len(), 1);
+    weaks.insert(other.clone().downgrade());
+    assert_eq!(weaks.len(), 2);
+
+    // Weak references keep their identity after the object is gone
+    drop(binders);
+    drop(parcel);
+    drop(binder);
+    assert!(weak.promote().is_none());
+    assert!(weaks.contains(&weak));
+}
+
+#[test]
+fn test_share_between_threads() {
+    use std::sync::{Arc, Barrier};
+
+    const THREADS: usize = 8;
+
+    let binder = Arc::new(Binder::new(()).as_binder());
+    let weak = Arc::new(SpIBinder::clone(&binder).downgrade());
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let binder = Arc::clone(&binder);
+            let weak = Arc::clone(&weak);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..100 {
+                    assert!(binder.is_binder_alive());
+                    assert_eq!(SpIBinder::clone(&binder), *binder);
+                    assert_eq!(weak.promote().as_ref(), Some(&*binder));
+                    assert_eq!(WpIBinder::clone(&weak), *weak);
+                    assert_eq!(binder.transact(1, 0, |_| Ok(())).err(), None);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Typed interfaces that reconnect to their service after it dies.
+
+use crate::binder::{FromIBinder, IBinder, Strong};
+use crate::error::{ExceptionCode, Result, Status, StatusCode};
+use crate::proxy::{get_interface, wait_for_interface};
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Number of times an idempotent call is retried after the service died.
+const MAX_RETRIES: usize = 3;
+
+/// Number of times a service is fetched again while the service manager
+/// still returns the dead service, and the delay between them.
+const MAX_RESOLVE_ATTEMPTS: usize = 10;
+const RESOLVE_DELAY: Duration = Duration::from_millis(100);
+
+/// Errors of calls made through [`Reconnecting`], which need to tell whether
+/// the service died.
+pub trait DeadObjectError: From<StatusCode> {
+    /// Whether the call failed because the service is dead.
+    fn is_dead_object(&self) -> bool;
+}
+
+impl DeadObjectError for StatusCode {
+    fn is_dead_object(&self) -> bool {
+        *self == StatusCode::DEAD_OBJECT
+    }
+}
+
+impl DeadObjectError for Status {
+    fn is_dead_object(&self) -> bool {
+        self.exception_code() == ExceptionCode::TRANSACTION_FAILED
+            && self.transaction_error() == StatusCode::DEAD_OBJECT
+    }
+}
+
+type ReconnectHook<I> = Arc<dyn Fn(&Strong<I>) + Send + Sync>;
+
+struct State<I: FromIBinder + ?Sized> {
+    interface: Option<Strong<I>>,
+    /// Whether the service was ever fetched, after which it is waited for
+    connected: bool,
+}
+
+/// A typed interface to a named service, which is fetched again when the
+/// service dies, for instance because its process restarted.
+///
+/// The service is fetched lazily with [`get_interface`] on first use. Once it
+/// has died, the next call waits for the service to be registered again with
+/// [`wait_for_interface`], then fires the hook set with
+/// [`on_reconnect`](Reconnecting::on_reconnect).
+///
+/// Calls that fail with `DEAD_OBJECT` are only retried if they are marked as
+/// idempotent by using [`call_idempotent`](Reconnecting::call_idempotent),
+/// as other calls may have taken effect before the service died.
+///
+/// Waiting for a dead service blocks until the service is registered again,
+/// which may be never, and blocks every other thread using the same wrapper
+/// meanwhile. Don't use a `Reconnecting` from threads that must not block
+/// indefinitely, such as binder threads serving calls from other processes.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use binder::{Interface, Reconnecting, Result};
+/// # pub trait IFoo: Interface { fn get(&self) -> Result<i32>; fn register(&self) -> Result<()>; }
+/// # impl binder::FromIBinder for dyn IFoo {
+/// #     fn try_from(_: binder::SpIBinder) -> Result<binder::Strong<dyn IFoo>> { unimplemented!() }
+/// # }
+/// let foo = Reconnecting::<dyn IFoo>::new("foo");
+/// foo.on_reconnect(|foo| {
+///     // Listeners registered with the previous instance are gone
+///     let _ = foo.register();
+/// });
+/// let value = foo.call_idempotent(|foo| foo.get())?;
+/// # Ok::<(), binder::StatusCode>(())
+/// ```
+pub struct Reconnecting<I: FromIBinder + ?Sized> {
+    name: String,
+    state: Mutex<State<I>>,
+    on_reconnect: Mutex<Option<ReconnectHook<I>>>,
+}
+
+impl<I: FromIBinder + ?Sized> Reconnecting<I> {
+    /// Create a wrapper for the service registered as `name`. The service is
+    /// not fetched until it is first used.
+    pub fn new(name: &str) -> Self {
+        Reconnecting {
+            name: name.to_owned(),
+            state: Mutex::new(State {
+                interface: None,
+                connected: false,
+            }),
+            on_reconnect: Mutex::new(None),
+        }
+    }
+
+    /// The name of the service.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set a hook called with the new interface every time the service is
+    /// fetched again after it died, for instance to register listeners with
+    /// the new instance of the service.
+    ///
+    /// The hook is called on the thread making the call that found the
+    /// service dead, before that call is retried. It can make calls through
+    /// this wrapper.
+    pub fn on_reconnect<F>(&self, hook: F)
+    where
+        F: Fn(&Strong<I>) + Send + Sync + 'static,
+    {
+        *lock(&self.on_reconnect) = Some(Arc::new(hook));
+    }
+
+    /// Get the interface, fetching the service if it was never fetched or if
+    /// it died.
+    ///
+    /// If the service died, this blocks until it is registered again, holding
+    /// up all other calls through this wrapper. If the service was never
+    /// fetched, this blocks for the few seconds [`get_interface`] waits for it,
+    /// also holding up other calls, and fails with `NAME_NOT_FOUND` if it is
+    /// still not registered.
+    pub fn get(&self) -> Result<Strong<I>> {
+        let (interface, reconnected) = {
+            let mut state = lock(&self.state);
+            if let Some(interface) = &state.interface {
+                if interface.as_binder().is_binder_alive() {
+                    return Ok(interface.clone());
+                }
+            }
+            let interface = if state.connected {
+                self.resolve_again()?
+            } else {
+                get_interface(&self.name)?
+            };
+            let reconnected = state.connected;
+            state.interface = Some(interface.clone());
+            state.connected = true;
+            (interface, reconnected)
+        };
+        if reconnected {
+            let hook = lock(&self.on_reconnect).clone();
+            if let Some(hook) = hook {
+                hook(&interface);
+            }
+        }
+        Ok(interface)
+    }
+
+    /// Wait for the service to be registered again after it died. The
+    /// service manager may not have noticed the death yet, in which case it
+    /// still returns the dead service for a short while.
+    fn resolve_again(&self) -> Result<Strong<I>> {
+        for _ in 0..MAX_RESOLVE_ATTEMPTS {
+            let interface = wait_for_interface::<I>(&self.name)?;
+            if interface.as_binder().is_binder_alive() {
+                return Ok(interface);
+            }
+            thread::sleep(RESOLVE_DELAY);
+        }
+        Err(StatusCode::DEAD_OBJECT)
+    }
+
+    /// Forget `interface` if it is the current interface, so the service is
+    /// fetched again by the next call.
+    fn forget(&self, interface: &Strong<I>) {
+        let mut state = lock(&self.state);
+        if state.interface.as_ref() == Some(interface) {
+            state.interface = None;
+        }
+    }
+
+    /// Make a call on the interface.
+    ///
+    /// If the call fails because the service died, the error is returned and
+    /// the service is fetched again by the next call, which blocks until the
+    /// service is back, see [`get`](Reconnecting::get). The call is not
+    /// retried, see [`call_idempotent`](Reconnecting::call_idempotent).
+    pub fn call<R, E, F>(&self, f: F) -> std::result::Result<R, E>
+    where
+        E: DeadObjectError,
+        F: FnOnce(&I) -> std::result::Result<R, E>,
+    {
+        let interface = self.get()?;
+        let result = f(&interface);
+        if matches!(&result, Err(e) if e.is_dead_object()) {
+            self.forget(&interface);
+        }
+        result
+    }
+
+    /// Make a call on the interface, retrying it on a new instance of the
+    /// service if the service died. Only use this for calls that have the
+    /// same effect when made more than once.
+    ///
+    /// Retries block until the service is registered again, see
+    /// [`get`](Reconnecting::get).
+    pub fn call_idempotent<R, E, F>(&self, mut f: F) -> std::result::Result<R, E>
+    where
+        E: DeadObjectError,
+        F: FnMut(&I) -> std::result::Result<R, E>,
+    {
+        let mut retries = 0;
+        loop {
+            match self.call(&mut f) {
+                Err(e) if e.is_dead_object() && retries < MAX_RETRIES => retries += 1,
+                result => return result,
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binder::{Interface, TransactionCode};
+    use crate::native::Binder;
+    use crate::parcel::Parcel;
+    use crate::proxy::SpIBinder;
+    use crate::testing::FakeServiceManager;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    pub trait ICounter: Interface {
+        fn get(&self) -> Result<i32>;
+    }
+
+    declare_binder_interface! {
+        ICounter["android.binder.test.ICounter"] {
+            native: BnCounter(on_transact),
+            proxy: BpCounter,
+        }
+    }
+
+    fn on_transact(
+        service: &dyn ICounter,
+        _code: TransactionCode,
+        _data: &Parcel,
+        reply: &mut Parcel,
+    ) -> Result<()> {
+        reply.write(&service.get()?)
+    }
+
+    impl ICounter for BpCounter {
+        fn get(&self) -> Result<i32> {
+            let reply = self
+                .binder
+                .transact(SpIBinder::FIRST_CALL_TRANSACTION, 0, |_| Ok(()))?;
+            reply.read()
+        }
+    }
+
+    impl ICounter for Binder<BnCounter> {
+        fn get(&self) -> Result<i32> {
+            self.0.get()
+        }
+    }
+
+    struct Counter(i32);
+
+    impl Interface for Counter {}
+
+    impl ICounter for Counter {
+        fn get(&self) -> Result<i32> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let sm = FakeServiceManager::install();
+        let restart = |value| {
+            let _ = sm.kill_service("reconnect_test");
+            sm.add_service(
+                "reconnect_test",
+                BnCounter::new_binder(Counter(value)).as_binder(),
+            );
+        };
+        let counter = Reconnecting::<dyn ICounter>::new("reconnect_test");
+        assert_eq!(counter.get().err(), Some(StatusCode::NAME_NOT_FOUND));
+
+        restart(1);
+        let reconnects = Arc::new(AtomicUsize::new(0));
+        {
+            let reconnects = reconnects.clone();
+            counter.on_reconnect(move |counter| {
+                assert_eq!(counter.get(), Ok(2));
+                reconnects.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert_eq!(counter.call_idempotent(|c| c.get()), Ok(1));
+        assert_eq!(reconnects.load(Ordering::SeqCst), 0);
+
+        // Calls that are not idempotent fail, and the next call reconnects
+        assert_eq!(
+            counter.call(|c| {
+                restart(2);
+                c.get()
+            }),
+            Err(StatusCode::DEAD_OBJECT)
+        );
+        assert_eq!(counter.call(|c| c.get()), Ok(2));
+        assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+
+        // Idempotent calls are retried on the new service
+        let mut calls = 0;
+        assert_eq!(
+            counter.call_idempotent(|c| {
+                calls += 1;
+                if calls == 1 {
+                    restart(2);
+                }
+                c.get()
+            }),
+            Ok(2)
+        );
+        assert_eq!(calls, 2);
+        assert_eq!(reconnects.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,457 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Recording and replay of incoming transactions.
+//!
+//! A [`Recorder`] installed on a local service with
+//! [`Binder::set_recorder`](crate::Binder::set_recorder) writes every
+//! transaction handled by that service, together with the reply, to a file.
+//! [`replay`] sends a recording back to a service and reports every
+//! transaction whose reply differs from the recorded one.
+//!
+//! # Format
+//!
+//! A recording starts with a header:
+//!
+//! | Field   | Type      | Value    |
+//! |---------|-----------|----------|
+//! | magic   | `[u8; 4]` | `"BTXR"` |
+//! | version | `u32`     | `1`      |
+//!
+//! followed by one record per transaction:
+//!
+//! | Field       | Type               | Description                                |
+//! |-------------|--------------------|--------------------------------------------|
+//! | timestamp   | `u64`              | Nanoseconds since the Unix epoch           |
+//! | code        | `u32`              | Transaction code                           |
+//! | flags       | `u32`              | Transaction flags                          |
+//! | calling uid | `u32`              | UID of the calling process                 |
+//! | calling pid | `i32`              | PID of the calling process, 0 for oneway   |
+//! | status      | `i32`              | Status returned by `on_transact`           |
+//! | data size   | `u32`              | Size of the transaction data               |
+//! | data        | `[u8; data size]`  | Transaction data after the interface token |
+//! | reply size  | `u32`              | Size of the reply data                     |
+//! | reply       | `[u8; reply size]` | Reply data                                 |
+//!
+//! All header and record integers are little-endian. The data and reply bytes
+//! are raw parcel contents.
+//!
+//! `libbinder_ndk` does not pass transaction flags to `on_transact`. The only
+//! flag that can be recovered is `FLAG_ONEWAY`, from the calling PID of 0 that
+//! the driver reports for oneway transactions, so that is the only flag that
+//! is ever recorded.
+//!
+//! Binder objects and file descriptors in a parcel are not recorded. Data is
+//! recorded up to the first object, and replaying it gives the service a
+//! truncated parcel.
+
+use crate::binder::{IBinder, TransactionCode, TransactionFlags};
+use crate::error::{Result, StatusCode};
+use crate::native;
+use crate::parcel::Parcel;
+use crate::proxy::SpIBinder;
+use crate::state::ThreadState;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::c_void;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 4] = b"BTXR";
+const VERSION: u32 = 1;
+
+/// Number of installed recorders, so that unrecorded transactions do not have
+/// to take the lock.
+static RECORDER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Recorders keyed by the address of the Rust object of the service.
+static RECORDERS: Mutex<Option<HashMap<usize, Arc<Recorder>>>> = Mutex::new(None);
+
+fn recorders() -> MutexGuard<'static, Option<HashMap<usize, Arc<Recorder>>>> {
+    // A panic while holding the lock can't leave the map inconsistent.
+    RECORDERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Writes transactions to a recording.
+pub struct Recorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    /// Create a recorder writing to a new file at `path`, replacing any
+    /// existing file.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Create a recorder writing to `writer`.
+    ///
+    /// The header is written immediately. The writer is flushed after each
+    /// transaction.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<Recorder> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.flush()?;
+        Ok(Recorder {
+            writer: Mutex::new(Box::new(writer)),
+        })
+    }
+
+    fn write(&self, transaction: &RecordedTransaction) -> io::Result<()> {
+        let mut record = vec![];
+        record.extend_from_slice(&transaction.timestamp_ns.to_le_bytes());
+        record.extend_from_slice(&transaction.code.to_le_bytes());
+        record.extend_from_slice(&transaction.flags.to_le_bytes());
+        record.extend_from_slice(&transaction.calling_uid.to_le_bytes());
+        record.extend_from_slice(&transaction.calling_pid.to_le_bytes());
+        record.extend_from_slice(&transaction.status.to_le_bytes());
+        for bytes in &[&transaction.data, &transaction.reply] {
+            let len: u32 = bytes.len().try_into().unwrap();
+            record.extend_from_slice(&len.to_le_bytes());
+            record.extend_from_slice(bytes);
+        }
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(&record)?;
+        writer.flush()
+    }
+}
+
+/// A transaction read from a recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedTransaction {
+    /// Time the transaction was handled, in nanoseconds since the Unix epoch
+    pub timestamp_ns: u64,
+    /// Transaction code
+    pub code: TransactionCode,
+    /// Transaction flags
+    pub flags: TransactionFlags,
+    /// UID of the calling process
+    pub calling_uid: u32,
+    /// PID of the calling process, or 0 for a oneway transaction
+    pub calling_pid: i32,
+    /// Status returned by `on_transact`, 0 on success
+    pub status: i32,
+    /// Transaction data following the interface token
+    pub data: Vec<u8>,
+    /// Reply data
+    pub reply: Vec<u8>,
+}
+
+/// Read all transactions from a recording.
+pub fn read_recording<R: Read>(mut reader: R) -> io::Result<Vec<RecordedTransaction>> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a transaction recording",
+        ));
+    }
+    let version = read_u32(&mut reader)?;
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported recording version {}", version),
+        ));
+    }
+
+    let mut transactions = vec![];
+    loop {
+        let mut timestamp = [0u8; 8];
+        match reader.read_exact(&mut timestamp) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        transactions.push(RecordedTransaction {
+            timestamp_ns: u64::from_le_bytes(timestamp),
+            code: read_u32(&mut reader)?,
+            flags: read_u32(&mut reader)?,
+            calling_uid: read_u32(&mut reader)?,
+            calling_pid: read_u32(&mut reader)? as i32,
+            status: read_u32(&mut reader)? as i32,
+            data: read_bytes(&mut reader)?,
+            reply: read_bytes(&mut reader)?,
+        });
+    }
+    Ok(transactions)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u32(reader)? as u64;
+    let mut bytes = vec![];
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+/// A replayed transaction whose result differs from the recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayMismatch {
+    /// Index of the transaction in the recording
+    pub index: usize,
+    /// The recorded transaction
+    pub expected: RecordedTransaction,
+    /// Status of the replayed transaction, 0 on success
+    pub status: i32,
+    /// Reply data of the replayed transaction
+    pub reply: Vec<u8>,
+}
+
+impl ReplayMismatch {
+    /// Returns the offset of the first byte where the replayed reply differs
+    /// from the recorded reply, or `None` if only the status differs.
+    pub fn first_difference(&self) -> Option<usize> {
+        let expected = &self.expected.reply;
+        expected
+            .iter()
+            .zip(&self.reply)
+            .position(|(a, b)| a != b)
+            .or_else(|| {
+                if expected.len() != self.reply.len() {
+                    Some(expected.len().min(self.reply.len()))
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+/// Send each recorded transaction to `binder` and return the transactions
+/// whose status or reply differs from the recording.
+///
+/// Transactions are sent with the recorded code and flags, so they reach the
+/// service's `on_transact` through the same path as the original calls.
+/// Replies of oneway transactions are never compared.
+pub fn replay(
+    binder: &SpIBinder,
+    transactions: &[RecordedTransaction],
+) -> Result<Vec<ReplayMismatch>> {
+    let mut mismatches = vec![];
+    for (index, transaction) in transactions.iter().enumerate() {
+        let result = binder.transact(transaction.code, transaction.flags, |input| {
+            input.write_words(&transaction.data)
+        });
+        if transaction.flags & SpIBinder::FLAG_ONEWAY != 0 {
+            continue;
+        }
+        let (status, reply) = match result {
+            Ok(reply) => (0, reply.data_bytes(0)),
+            Err(StatusCode::DEAD_OBJECT) => return Err(StatusCode::DEAD_OBJECT),
+            Err(e) => (e as i32, vec![]),
+        };
+        if status != transaction.status || reply != transaction.reply {
+            mismatches.push(ReplayMismatch {
+                index,
+                expected: transaction.clone(),
+                status,
+                reply,
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+pub(crate) fn set_recorder(object: *const c_void, recorder: Option<Recorder>) {
+    let previous = {
+        let mut recorders = recorders();
+        let recorders = recorders.get_or_insert_with(HashMap::new);
+        let previous = match recorder {
+            Some(recorder) => recorders.insert(object as usize, Arc::new(recorder)),
+            None => recorders.remove(&(object as usize)),
+        };
+        RECORDER_COUNT.store(recorders.len(), Ordering::SeqCst);
+        previous
+    };
+    // Dropping the last reference to a recorder drops its writer, which may
+    // block on I/O, so it must not happen while holding the lock.
+    drop(previous);
+}
+
+fn get_recorder(object: *const c_void) -> Option<Arc<Recorder>> {
+    if RECORDER_COUNT.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+    recorders()
+        .as_ref()
+        .and_then(|recorders| recorders.get(&(object as usize)).cloned())
+}
+
+/// Transaction details captured before `on_transact` runs.
+pub(crate) struct PendingRecord {
+    recorder: Arc<Recorder>,
+    timestamp_ns: u64,
+    data_start: i32,
+    calling_uid: u32,
+    calling_pid: i32,
+}
+
+/// Start recording a transaction to the service with the given Rust object,
+/// if a recorder is installed for it.
+pub(crate) fn begin(object: *const c_void, data: &Parcel) -> Option<PendingRecord> {
+    let recorder = get_recorder(object)?;
+    let timestamp_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    Some(PendingRecord {
+        recorder,
+        timestamp_ns,
+        data_start: data.get_data_position(),
+        calling_uid: ThreadState::get_calling_uid(),
+        calling_pid: ThreadState::get_calling_pid(),
+    })
+}
+
+impl PendingRecord {
+    /// Write the transaction and its result to the recording.
+    pub(crate) fn finish(
+        self,
+        code: TransactionCode,
+        data: &Parcel,
+        reply: &Parcel,
+        result: &Result<()>,
+    ) {
+        let transaction = RecordedTransaction {
+            timestamp_ns: self.timestamp_ns,
+            code,
+            flags: native::incoming_flags(),
+            calling_uid: self.calling_uid,
+            calling_pid: self.calling_pid,
+            status: match result {
+                Ok(()) => 0,
+                Err(e) => *e as i32,
+            },
+            data: data.data_bytes(self.data_start),
+            reply: reply.data_bytes(0),
+        };
+        // Recording is best effort and must not change the behavior of the
+        // service.
+        let _ = self.recorder.write(&transaction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binder::{Interface, Remotable};
+    use crate::native::Binder;
+    use crate::parcel::Parcel;
+
+    use std::sync::mpsc;
+
+    struct Echo;
+
+    impl Interface for Echo {}
+
+    impl Remotable for Echo {
+        fn get_descriptor() -> &'static str {
+            "android.binder.test.IRecordingEcho"
+        }
+
+        fn on_transact(
+            &self,
+            code: TransactionCode,
+            data: &Parcel,
+            reply: &mut Parcel,
+        ) -> Result<()> {
+            match code {
+                1 => reply.write(&(data.read::<i32>()? * 2)),
+                2 => reply.write(&data.read::<String>()?),
+                _ => Err(StatusCode::UNKNOWN_TRANSACTION),
+            }
+        }
+
+        binder_fn_get_class!(Binder::<Self>);
+    }
+
+    /// Writer that sends everything it receives to a channel, so the test can
+    /// inspect the recording.
+    struct ChannelWriter(Mutex<mpsc::Sender<Vec<u8>>>);
+
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.lock().unwrap().send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let (sender, receiver) = mpsc::channel();
+        let service = Binder::new(Echo);
+        service.set_recorder(Some(
+            Recorder::new(ChannelWriter(Mutex::new(sender))).unwrap(),
+        ));
+
+        let binder = service.as_binder();
+        let reply = binder.transact(1, 0, |data| data.write(&21i32)).unwrap();
+        assert_eq!(reply.read::<i32>(), Ok(42));
+        let reply = binder.transact(2, 0, |data| data.write("echo")).unwrap();
+        assert_eq!(reply.read::<String>().unwrap(), "echo");
+        assert_eq!(
+            binder.transact(3, 0, |_| Ok(())).err(),
+            Some(StatusCode::UNKNOWN_TRANSACTION)
+        );
+        service.set_recorder(None);
+
+        let recording: Vec<u8> = receiver.try_iter().flatten().collect();
+        let transactions = read_recording(&recording[..]).unwrap();
+        assert_eq!(transactions.len(), 3);
+        assert_eq!(transactions[0].code, 1);
+        assert_eq!(transactions[0].data, 21i32.to_ne_bytes());
+        assert_eq!(transactions[0].reply, 42i32.to_ne_bytes());
+        assert_eq!(
+            transactions[2].status,
+            StatusCode::UNKNOWN_TRANSACTION as i32
+        );
+
+        assert_eq!(replay(&binder, &transactions), Ok(vec![]));
+
+        let mut changed = transactions[0].clone();
+        changed.reply = 43i32.to_ne_bytes().to_vec();
+        let mismatches = replay(&binder, &[changed]).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].reply, 42i32.to_ne_bytes());
+        assert_eq!(mismatches[0].first_difference(), Some(0));
+    }
+
+    #[test]
+    fn test_read_recording_rejects_bad_header() {
+        assert!(read_recording(&b"nope"[..]).is_err());
+        let mut truncated = MAGIC.to_vec();
+        truncated.extend_from_slice(&VERSION.to_le_bytes());
+        truncated.extend_from_slice(&[0u8; 12]);
+        assert!(read_recording(&truncated[..]).is_err());
+    }
+}
@@ -0,0 +1,1237 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Binder RPC over Unix domain and vsock sockets.
+//!
+//! An [`RpcServer`] serves a root binder object on a socket, without the
+//! kernel binder driver. An [`RpcSession`] connects to a server and returns
+//! a binder object for the root object, which can be converted into an
+//! interface with [`FromIBinder`](crate::FromIBinder) like any other binder.
+//! Binder objects passed in either direction inside parcels are proxied over
+//! the same connection, so callbacks work as they do over the driver.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use binder::{Binder, Interface};
+//! use binder::rpc::{RpcServer, RpcSession};
+//! use std::sync::Arc;
+//!
+//! # fn main() -> binder::Result<()> {
+//! # let service = Binder::new(()).as_binder();
+//! let server = Arc::new(RpcServer::new(service));
+//! server.setup_unix_domain_server("/tmp/example.sock")?;
+//! let s = server.clone();
+//! std::thread::spawn(move || s.join());
+//!
+//! let session = RpcSession::new();
+//! session.setup_unix_domain_client("/tmp/example.sock")?;
+//! let root = session.get_root_object()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Protocol
+//!
+//! Each side of a connection sends frames consisting of a `u32` length and a
+//! `u32` command, followed by the command's payload. All integers are
+//! little-endian. When a client connects, the server sends a `ROOT` frame
+//! holding the magic `"BRPC"`, the protocol version and the root object.
+//!
+//! Parcels are sent as a list of segments, each either plain data or a binder
+//! object. Objects are identified by an ID assigned by the side hosting them,
+//! and are sent with the interface descriptor of their class. The host keeps
+//! each object alive until the other side releases every reference it
+//! received.
+//!
+//! # Limitations
+//!
+//! Objects received over RPC are local objects of this process which forward
+//! transactions to the peer. Transactions with codes below
+//! [`FIRST_CALL_TRANSACTION`](crate::IBinder::FIRST_CALL_TRANSACTION), such as
+//! pings and dumps, are handled locally rather than forwarded. They cannot be
+//! linked to death; transactions fail with `StatusCode::DEAD_OBJECT` once the
+//! connection is lost. File descriptors cannot be sent, and objects must be
+//! associated with an interface class to be callable from the peer.
+//!
+//! Incoming transactions are handled by a pool of threads per connection, see
+//! [`RpcServer::set_max_threads`]. Calls nested deeper than the number of
+//! threads on either side deadlock.
+
+use crate::binder::{
+    AsNative, IBinder, Interface, InterfaceClass, Remotable, TransactionCode, TransactionFlags,
+};
+use crate::error::{status_from_io_error, status_result, Result, StatusCode};
+use crate::native::Binder;
+use crate::parcel::{Parcel, ParcelPosition};
+use crate::proxy::{SpIBinder, WpIBinder};
+use crate::sys;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+const MAGIC: &[u8; 4] = b"BRPC";
+const VERSION: u32 = 1;
+
+const CMD_ROOT: u32 = 1;
+const CMD_TRANSACT: u32 = 2;
+const CMD_REPLY: u32 = 3;
+const CMD_DEC_REFS: u32 = 4;
+
+const SEGMENT_DATA: u32 = 1;
+/// An object hosted by the sender of the parcel
+const SEGMENT_SENDER_OBJECT: u32 = 2;
+/// An object hosted by the receiver of the parcel
+const SEGMENT_RECEIVER_OBJECT: u32 = 3;
+
+/// Frames larger than this are rejected. Transactions over the binder driver
+/// are limited to 1MB.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default number of threads handling incoming transactions per connection.
+const DEFAULT_MAX_THREADS: usize = 1;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panic while holding one of these locks can't leave the protected
+    // state inconsistent.
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Serves a root binder object to clients connecting over a socket.
+pub struct RpcServer {
+    root: Mutex<SpIBinder>,
+    max_threads: AtomicUsize,
+    listener: Mutex<Option<Arc<OwnedFd>>>,
+    shutdown: AtomicBool,
+    connections: Mutex<Vec<std::sync::Weak<Connection>>>,
+}
+
+impl RpcServer {
+    /// Create a server for `root`. The server does not accept connections
+    /// until it is set up and [`join`](RpcServer::join) is called.
+    pub fn new(root: SpIBinder) -> RpcServer {
+        RpcServer {
+            root: Mutex::new(root),
+            max_threads: AtomicUsize::new(DEFAULT_MAX_THREADS),
+            listener: Mutex::new(None),
+            shutdown: AtomicBool::new(false),
+            connections: Mutex::new(vec![]),
+        }
+    }
+
+    /// Set the number of threads handling incoming transactions for each
+    /// connection accepted after this call.
+    pub fn set_max_threads(&self, threads: usize) {
+        self.max_threads.store(threads.max(1), Ordering::SeqCst);
+    }
+
+    /// Listen on a Unix domain socket at `path`, which must not exist.
+    pub fn setup_unix_domain_server<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let listener = UnixListener::bind(path).map_err(status_from_io_error)?;
+        self.set_listener(listener.into())
+    }
+
+    /// Listen on a vsock `port`, accepting connections from any CID.
+    pub fn setup_vsock_server(&self, port: u32) -> Result<()> {
+        let socket = vsock_socket()?;
+        let addr = vsock_addr(libc::VMADDR_CID_ANY, port);
+        errno_result(unsafe {
+            // Safety: `socket` is a valid socket, and `addr` is a valid
+            // `sockaddr_vm` whose size we pass.
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        })?;
+        errno_result(unsafe {
+            // Safety: `socket` is a valid, bound socket.
+            libc::listen(socket.as_raw_fd(), libc::SOMAXCONN)
+        })?;
+        self.set_listener(socket)
+    }
+
+    fn set_listener(&self, listener: OwnedFd) -> Result<()> {
+        let mut current = lock(&self.listener);
+        if current.is_some() {
+            return Err(StatusCode::ALREADY_EXISTS);
+        }
+        *current = Some(Arc::new(listener));
+        Ok(())
+    }
+
+    /// Accept connections until [`shutdown`](RpcServer::shutdown) is called.
+    ///
+    /// Each connection is served by its own threads, so this only returns
+    /// once the server is shut down. Fails with `StatusCode::NO_INIT` if the
+    /// server has not been set up.
+    pub fn join(&self) -> Result<()> {
+        let listener = lock(&self.listener).clone().ok_or(StatusCode::NO_INIT)?;
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let fd = unsafe {
+                // Safety: `listener` is a valid, listening socket. We don't
+                // need the peer address, so pass null pointers.
+                libc::accept4(
+                    listener.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    libc::SOCK_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                let error = io::Error::last_os_error();
+                if self.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                match error.kind() {
+                    io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted => continue,
+                    _ => return Err(status_from_io_error(error)),
+                }
+            }
+            let socket = unsafe {
+                // Safety: `accept4` returned a new file descriptor we own.
+                OwnedFd::from_raw_fd(fd)
+            };
+            // A client which fails the handshake only affects its own
+            // connection.
+            if let Ok(connection) = self.accept(socket) {
+                let mut connections = lock(&self.connections);
+                connections.retain(|c| c.strong_count() > 0);
+                connections.push(Arc::downgrade(&connection));
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&self, socket: OwnedFd) -> Result<Arc<Connection>> {
+        let connection = Connection::new(socket)?;
+        let root = lock(&self.root).clone();
+        let mut frame = Frame::new(CMD_ROOT);
+        frame.bytes(MAGIC);
+        frame.u32(VERSION);
+        connection.write_object(&mut frame, root)?;
+        connection.send(frame)?;
+        connection.start(self.max_threads.load(Ordering::SeqCst));
+        Ok(connection)
+    }
+
+    /// Stop accepting connections, and close all connections to clients.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(listener) = lock(&self.listener).as_ref() {
+            unsafe {
+                // Safety: `listener` is a valid socket. Shutting it down wakes
+                // up a thread blocked in `accept`.
+                libc::shutdown(listener.as_raw_fd(), libc::SHUT_RDWR);
+            }
+        }
+        let connections = mem::take(&mut *lock(&self.connections));
+        for connection in connections.iter().filter_map(std::sync::Weak::upgrade) {
+            connection.shutdown();
+        }
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A client connection to an [`RpcServer`].
+///
+/// Dropping the session closes the connection, after which all objects
+/// received from the server return `StatusCode::DEAD_OBJECT`.
+pub struct RpcSession {
+    max_threads: AtomicUsize,
+    connection: Mutex<Option<(Arc<Connection>, SpIBinder)>>,
+}
+
+impl Default for RpcSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RpcSession {
+    /// Create a session which is not connected yet.
+    pub fn new() -> RpcSession {
+        RpcSession {
+            max_threads: AtomicUsize::new(DEFAULT_MAX_THREADS),
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Set the number of threads handling transactions from the server to
+    /// objects sent by this client, such as callbacks. Only affects
+    /// connections made after this call.
+    pub fn set_max_threads(&self, threads: usize) {
+        self.max_threads.store(threads.max(1), Ordering::SeqCst);
+    }
+
+    /// Connect to a server listening on a Unix domain socket at `path`.
+    pub fn setup_unix_domain_client<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let socket = UnixStream::connect(path).map_err(status_from_io_error)?;
+        self.setup(socket.into())
+    }
+
+    /// Connect to a server listening on vsock `port` of the VM `cid`.
+    pub fn setup_vsock_client(&self, cid: u32, port: u32) -> Result<()> {
+        let socket = vsock_socket()?;
+        let addr = vsock_addr(cid, port);
+        errno_result(unsafe {
+            // Safety: `socket` is a valid socket, and `addr` is a valid
+            // `sockaddr_vm` whose size we pass.
+            libc::connect(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        })?;
+        self.setup(socket)
+    }
+
+    fn setup(&self, socket: OwnedFd) -> Result<()> {
+        let mut current = lock(&self.connection);
+        if current.is_some() {
+            return Err(StatusCode::ALREADY_EXISTS);
+        }
+        let connection = Connection::new(socket)?;
+        let frame = connection
+            .read_frame()
+            .map_err(|_| StatusCode::DEAD_OBJECT)?;
+        let mut reader = FrameReader(&frame);
+        if reader.u32()? != CMD_ROOT || reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        if reader.u32()? != VERSION {
+            return Err(StatusCode::INVALID_OPERATION);
+        }
+        let kind = reader.u32()?;
+        let root = connection.read_object(kind, &mut reader)?;
+        connection.start(self.max_threads.load(Ordering::SeqCst));
+        *current = Some((connection, root));
+        Ok(())
+    }
+
+    /// Returns the root object of the server.
+    ///
+    /// Fails with `StatusCode::NO_INIT` if the session is not connected.
+    pub fn get_root_object(&self) -> Result<SpIBinder> {
+        lock(&self.connection)
+            .as_ref()
+            .map(|(_, root)| root.clone())
+            .ok_or(StatusCode::NO_INIT)
+    }
+}
+
+impl Drop for RpcSession {
+    fn drop(&mut self) {
+        if let Some((connection, root)) = lock(&self.connection).take() {
+            drop(root);
+            connection.shutdown();
+        }
+    }
+}
+
+/// Objects of this process sent to the peer.
+#[derive(Default)]
+struct Exported {
+    /// Objects by ID, with the number of references sent to the peer
+    objects: HashMap<u64, (SpIBinder, u64)>,
+    /// IDs by `AIBinder` address
+    ids: HashMap<usize, u64>,
+    next_id: u64,
+}
+
+/// Parcel data received from the peer. Objects are resolved by the thread
+/// reading from the connection, so that references to them are counted in
+/// the order the peer sent them.
+enum Segment {
+    Data(Vec<u8>),
+    Object(SpIBinder),
+}
+
+/// An incoming transaction waiting for a thread.
+struct Incoming {
+    seq: u32,
+    id: u64,
+    code: TransactionCode,
+    flags: TransactionFlags,
+    data: Result<Vec<Segment>>,
+}
+
+/// One connection between two processes. Both sides of a connection can host
+/// objects and send transactions.
+struct Connection {
+    reader: Mutex<Option<File>>,
+    writer: Mutex<File>,
+    socket: RawFd,
+    dead: AtomicBool,
+    next_seq: AtomicU32,
+    /// Callers waiting for replies, by sequence number
+    pending: Mutex<HashMap<u32, Sender<Result<Vec<Segment>>>>>,
+    exported: Mutex<Exported>,
+    /// Proxies for objects hosted by the peer, by ID
+    imported: Mutex<HashMap<u64, WpIBinder>>,
+}
+
+impl Connection {
+    fn new(socket: OwnedFd) -> Result<Arc<Connection>> {
+        let writer = File::from(socket);
+        let reader = writer.try_clone().map_err(status_from_io_error)?;
+        Ok(Arc::new(Connection {
+            socket: writer.as_raw_fd(),
+            reader: Mutex::new(Some(reader)),
+            writer: Mutex::new(writer),
+            dead: AtomicBool::new(false),
+            next_seq: AtomicU32::new(0),
+            pending: Mutex::new(HashMap::new()),
+            exported: Mutex::new(Exported::default()),
+            imported: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Start the threads reading from the connection and handling incoming
+    /// transactions.
+    fn start(self: &Arc<Self>, max_threads: usize) {
+        let (sender, receiver) = mpsc::channel::<Incoming>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..max_threads {
+            let connection = self.clone();
+            let receiver = receiver.clone();
+            thread::spawn(move || connection.handle_transactions(receiver));
+        }
+        let connection = self.clone();
+        thread::spawn(move || connection.read_frames(sender));
+    }
+
+    fn read_frame(&self) -> io::Result<Vec<u8>> {
+        let mut reader = lock(&self.reader);
+        let reader = reader
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut frame = vec![0u8; len];
+        reader.read_exact(&mut frame)?;
+        Ok(frame)
+    }
+
+    fn read_frames(self: Arc<Self>, incoming: Sender<Incoming>) {
+        while let Ok(frame) = self.read_frame() {
+            if self.dispatch(&frame, &incoming).is_err() {
+                break;
+            }
+        }
+        // Dropping `incoming` stops the transaction threads.
+        self.shutdown();
+    }
+
+    /// Handle a frame from the peer. Errors are protocol violations, which
+    /// close the connection.
+    fn dispatch(self: &Arc<Self>, frame: &[u8], incoming: &Sender<Incoming>) -> Result<()> {
+        let mut reader = FrameReader(frame);
+        match reader.u32()? {
+            CMD_TRANSACT => {
+                let transaction = Incoming {
+                    seq: reader.u32()?,
+                    id: reader.u64()?,
+                    code: reader.u32()?,
+                    flags: reader.u32()?,
+                    data: self.read_segments(&mut reader),
+                };
+                incoming.send(transaction).or(Err(StatusCode::DEAD_OBJECT))
+            }
+            CMD_REPLY => {
+                let seq = reader.u32()?;
+                let reply =
+                    status_result(reader.i32()?).and_then(|()| self.read_segments(&mut reader));
+                let waiter = lock(&self.pending).remove(&seq);
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(reply);
+                }
+                Ok(())
+            }
+            CMD_DEC_REFS => {
+                let id = reader.u64()?;
+                let refs = reader.u64()?;
+                self.release(id, refs);
+                Ok(())
+            }
+            _ => Err(StatusCode::BAD_VALUE),
+        }
+    }
+
+    /// Drop `refs` references of the peer to the exported object with the
+    /// given ID, and stop exporting it once none are left.
+    fn release(&self, id: u64, refs: u64) {
+        let released = {
+            let mut exported = lock(&self.exported);
+            let remaining = match exported.objects.get_mut(&id) {
+                Some((_, count)) => {
+                    *count = count.saturating_sub(refs);
+                    *count
+                }
+                None => return,
+            };
+            if remaining == 0 {
+                let (binder, _) = exported.objects.remove(&id).unwrap();
+                exported.ids.remove(&(binder.as_native() as usize));
+                Some(binder)
+            } else {
+                None
+            }
+        };
+        // Released objects are dropped outside the lock, in case destroying
+        // them releases objects of this connection.
+        drop(released);
+    }
+
+    /// Release the references to objects exported into a frame that was not
+    /// sent.
+    fn release_exports(&self, exports: Vec<u64>) {
+        for id in exports {
+            self.release(id, 1);
+        }
+    }
+
+    fn handle_transactions(self: Arc<Self>, incoming: Arc<Mutex<Receiver<Incoming>>>) {
+        loop {
+            let Incoming {
+                seq,
+                id,
+                code,
+                flags,
+                data,
+            } = match lock(&incoming).recv() {
+                Ok(transaction) => transaction,
+                Err(_) => return,
+            };
+            let mut frame = Frame::new(CMD_REPLY);
+            frame.u32(seq);
+            let status_offset = frame.len();
+            frame.u32(0);
+            let reply = data.and_then(|data| {
+                let reply = self.handle_transaction(id, code, flags, &data)?;
+                self.write_parcel(&mut frame, &reply, ParcelPosition::START)?;
+                Ok(reply)
+            });
+            if let Err(e) = reply {
+                self.release_exports(mem::take(&mut frame.exports));
+                frame.truncate(status_offset);
+                frame.u32(e as u32);
+                // No segments
+                frame.u32(0);
+            }
+            if flags & SpIBinder::FLAG_ONEWAY != 0 {
+                self.release_exports(frame.exports);
+            } else if self.send(frame).is_err() {
+                return;
+            }
+            // The reply keeps the objects in it alive until it has been sent,
+            // so that the peer can't receive their release before the reply.
+            drop(reply);
+        }
+    }
+
+    /// Perform an incoming transaction on the local object with the given ID.
+    fn handle_transaction(
+        &self,
+        id: u64,
+        code: TransactionCode,
+        flags: TransactionFlags,
+        data: &[Segment],
+    ) -> Result<Parcel> {
+        let target = lock(&self.exported)
+            .objects
+            .get(&id)
+            .map(|(binder, _)| binder.clone())
+            .ok_or(StatusCode::DEAD_OBJECT)?;
+        target.transact(code, flags, |input| write_segments(input, data))
+    }
+
+    /// Send a transaction to the object of the peer with the given ID, and
+    /// write the reply into `reply`.
+    fn transact(
+        &self,
+        id: u64,
+        code: TransactionCode,
+        flags: TransactionFlags,
+        data: &Parcel,
+        reply: &mut Parcel,
+    ) -> Result<()> {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let mut frame = Frame::new(CMD_TRANSACT);
+        frame.u32(seq);
+        frame.u64(id);
+        frame.u32(code);
+        frame.u32(flags);
+        if let Err(e) = self.write_parcel(&mut frame, data, data.checkpoint()) {
+            self.release_exports(frame.exports);
+            return Err(e);
+        }
+
+        if flags & SpIBinder::FLAG_ONEWAY != 0 {
+            return self.send(frame);
+        }
+        let (sender, receiver) = mpsc::channel();
+        {
+            let mut pending = lock(&self.pending);
+            if self.dead.load(Ordering::SeqCst) {
+                drop(pending);
+                self.release_exports(frame.exports);
+                return Err(StatusCode::DEAD_OBJECT);
+            }
+            pending.insert(seq, sender);
+        }
+        if let Err(e) = self.send(frame) {
+            lock(&self.pending).remove(&seq);
+            return Err(e);
+        }
+        let segments = receiver.recv().or(Err(StatusCode::DEAD_OBJECT))??;
+        write_segments(reply, &segments)
+    }
+
+    /// Send a frame to the peer. If it can't be sent, the references to the
+    /// objects exported into it are released.
+    fn send(&self, frame: Frame) -> Result<()> {
+        let (data, exports) = frame.finish();
+        let result = if self.dead.load(Ordering::SeqCst) {
+            Err(StatusCode::DEAD_OBJECT)
+        } else if data.len() - 4 > MAX_FRAME_SIZE {
+            Err(StatusCode::FAILED_TRANSACTION)
+        } else {
+            lock(&self.writer)
+                .write_all(&data)
+                .or(Err(StatusCode::DEAD_OBJECT))
+        };
+        if result.is_err() {
+            self.release_exports(exports);
+        }
+        result
+    }
+
+    /// Close the connection, failing pending and future transactions and
+    /// releasing all objects held by the peer.
+    fn shutdown(&self) {
+        if self.dead.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        unsafe {
+            // Safety: `self.socket` is owned by `self.writer`, which is still
+            // alive. Shutting down the socket wakes up the reading thread.
+            libc::shutdown(self.socket, libc::SHUT_RDWR);
+        }
+        let pending = mem::take(&mut *lock(&self.pending));
+        let exported = mem::take(&mut *lock(&self.exported));
+        let imported = mem::take(&mut *lock(&self.imported));
+        // Drop everything outside the locks, as destroying objects may call
+        // back into this connection.
+        drop((pending, exported, imported));
+    }
+
+    /// Append the parcel data from `start` to `frame` as segments.
+    fn write_parcel(
+        &self,
+        frame: &mut Frame,
+        parcel: &Parcel,
+        start: ParcelPosition,
+    ) -> Result<()> {
+        let count_offset = frame.len();
+        frame.u32(0);
+        let mut count = 0u32;
+        let mut data = vec![];
+        let position = parcel.checkpoint();
+        parcel.rewind_to(start)?;
+        let mut result = Ok(());
+        while parcel.remaining() > 0 {
+            let word_start = parcel.checkpoint();
+            if let Ok(word) = parcel.read::<u32>() {
+                data.extend_from_slice(&word.to_ne_bytes());
+                continue;
+            }
+            // Plain reads fail on objects.
+            parcel.rewind_to(word_start)?;
+            let object = match parcel.read_binder() {
+                Ok(Some(object)) => object,
+                Ok(None) => {
+                    result = Err(StatusCode::BAD_VALUE);
+                    break;
+                }
+                Err(StatusCode::BAD_TYPE) => {
+                    result = Err(StatusCode::FDS_NOT_ALLOWED);
+                    break;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            if !data.is_empty() {
+                frame.u32(SEGMENT_DATA);
+                frame.u32(data.len() as u32);
+                frame.bytes(&data);
+                data.clear();
+                count += 1;
+            }
+            if let Err(e) = self.write_object(frame, object) {
+                result = Err(e);
+                break;
+            }
+            count += 1;
+        }
+        parcel.rewind_to(position)?;
+        result?;
+        if !data.is_empty() {
+            frame.u32(SEGMENT_DATA);
+            frame.u32(data.len() as u32);
+            frame.bytes(&data);
+            count += 1;
+        }
+        frame.set_u32(count_offset, count);
+        Ok(())
+    }
+
+    /// Read the segments of a parcel sent by the peer.
+    fn read_segments(self: &Arc<Self>, reader: &mut FrameReader) -> Result<Vec<Segment>> {
+        let count = reader.u32()?;
+        let mut segments = vec![];
+        for _ in 0..count {
+            let segment = match reader.u32()? {
+                SEGMENT_DATA => {
+                    let len = reader.u32()? as usize;
+                    Segment::Data(reader.bytes(len)?.to_vec())
+                }
+                kind => Segment::Object(self.read_object(kind, reader)?),
+            };
+            segments.push(segment);
+        }
+        Ok(segments)
+    }
+
+    /// Append an object segment for `object`, exporting it if it is not
+    /// hosted by the peer.
+    fn write_object(&self, frame: &mut Frame, mut object: SpIBinder) -> Result<()> {
+        if let Some(proxy) = RpcProxy::from_binder(&object) {
+            if std::ptr::eq(Arc::as_ptr(&proxy.connection), self) {
+                frame.u32(SEGMENT_RECEIVER_OBJECT);
+                frame.u64(proxy.id);
+                return Ok(());
+            }
+        }
+        let descriptor = object
+            .get_class()
+            .map(|c| c.get_descriptor())
+            .unwrap_or_default();
+        let id = {
+            let mut exported = lock(&self.exported);
+            if self.dead.load(Ordering::SeqCst) {
+                return Err(StatusCode::DEAD_OBJECT);
+            }
+            let key = object.as_native() as usize;
+            let id = match exported.ids.get(&key) {
+                Some(&id) => id,
+                None => {
+                    let id = exported.next_id;
+                    exported.next_id += 1;
+                    exported.ids.insert(key, id);
+                    exported.objects.insert(id, (object.clone(), 0));
+                    id
+                }
+            };
+            exported.objects.get_mut(&id).unwrap().1 += 1;
+            id
+        };
+        frame.exports.push(id);
+        frame.u32(SEGMENT_SENDER_OBJECT);
+        frame.u64(id);
+        frame.u32(descriptor.len() as u32);
+        frame.bytes(descriptor.as_bytes());
+        Ok(())
+    }
+
+    /// Read an object segment of type `kind`, returning the local object or a
+    /// proxy for the object of the peer.
+    fn read_object(self: &Arc<Self>, kind: u32, reader: &mut FrameReader) -> Result<SpIBinder> {
+        match kind {
+            SEGMENT_RECEIVER_OBJECT => {
+                let id = reader.u64()?;
+                lock(&self.exported)
+                    .objects
+                    .get(&id)
+                    .map(|(binder, _)| binder.clone())
+                    .ok_or(StatusCode::BAD_VALUE)
+            }
+            SEGMENT_SENDER_OBJECT => {
+                let id = reader.u64()?;
+                let len = reader.u32()? as usize;
+                let descriptor =
+                    std::str::from_utf8(reader.bytes(len)?).or(Err(StatusCode::BAD_VALUE))?;
+                let mut imported = lock(&self.imported);
+                if self.dead.load(Ordering::SeqCst) {
+                    return Err(StatusCode::DEAD_OBJECT);
+                }
+                if let Some(existing) = imported.get(&id).and_then(WpIBinder::promote) {
+                    if let Some(proxy) = RpcProxy::from_binder(&existing) {
+                        proxy.refs.fetch_add(1, Ordering::SeqCst);
+                        return Ok(existing);
+                    }
+                }
+                let proxy = RpcProxy {
+                    connection: self.clone(),
+                    id,
+                    refs: AtomicU64::new(1),
+                };
+                let class = InterfaceClass::runtime_class::<Binder<RpcProxy>>(descriptor);
+                let mut binder = Binder::new_with_class(proxy, class).as_binder();
+                imported.insert(id, binder.downgrade());
+                Ok(binder)
+            }
+            _ => Err(StatusCode::BAD_VALUE),
+        }
+    }
+}
+
+/// A local object forwarding transactions to an object of the peer.
+struct RpcProxy {
+    connection: Arc<Connection>,
+    id: u64,
+    /// Number of references to the object received from the peer
+    refs: AtomicU64,
+}
+
+impl RpcProxy {
+    /// Returns the `RpcProxy` of `binder`, if it is a proxy for an object
+    /// received over RPC.
+    fn from_binder(binder: &SpIBinder) -> Option<&RpcProxy> {
+        if !binder.clone().get_class()?.has_type::<Binder<RpcProxy>>() {
+            return None;
+        }
+        unsafe {
+            // Safety: `binder` is a valid `AIBinder` of one of the proxy
+            // classes, whose user data is always an `RpcProxy`, which lives
+            // as long as `binder`.
+            (sys::AIBinder_getUserData(binder.as_native() as *mut sys::AIBinder) as *const RpcProxy)
+                .as_ref()
+        }
+    }
+}
+
+impl Remotable for RpcProxy {
+    fn get_descriptor() -> &'static str {
+        // Proxies use a class per descriptor.
+        ""
+    }
+
+    const HAS_RUNTIME_CLASS: bool = true;
+
+    fn on_transact(&self, code: TransactionCode, data: &Parcel, reply: &mut Parcel) -> Result<()> {
+        self.connection
+            .transact(self.id, code, crate::native::incoming_flags(), data, reply)
+    }
+
+    binder_fn_get_class!(Binder::<Self>);
+}
+
+impl Drop for RpcProxy {
+    fn drop(&mut self) {
+        let stale = {
+            let mut imported = lock(&self.connection.imported);
+            match imported.get(&self.id).map(WpIBinder::promote) {
+                // A new proxy has replaced this one
+                Some(Some(binder)) => Some(binder),
+                _ => {
+                    imported.remove(&self.id);
+                    None
+                }
+            }
+        };
+        drop(stale);
+        let mut frame = Frame::new(CMD_DEC_REFS);
+        frame.u64(self.id);
+        frame.u64(self.refs.load(Ordering::SeqCst));
+        let _ = self.connection.send(frame);
+    }
+}
+
+/// Returns true if `binder` is an object received over RPC.
+///
+/// Used by [`declare_binder_interface!`](crate::declare_binder_interface) to
+/// hand out interface proxies for these objects, which can't be associated
+/// with the class of the interface.
+#[doc(hidden)]
+pub fn is_proxy(binder: &SpIBinder) -> bool {
+    RpcProxy::from_binder(binder).is_some()
+}
+
+/// Append the segments of a parcel received from the peer to `parcel`.
+fn write_segments(parcel: &mut Parcel, segments: &[Segment]) -> Result<()> {
+    for segment in segments {
+        match segment {
+            Segment::Data(data) => parcel.write_words(data)?,
+            Segment::Object(object) => parcel.write_binder(Some(object))?,
+        }
+    }
+    Ok(())
+}
+
+/// A frame being built. The length prefix is filled in by `finish`.
+struct Frame {
+    data: Vec<u8>,
+    /// IDs of the objects exported into the frame, each holding a reference
+    /// counted in `Exported::objects`
+    exports: Vec<u64>,
+}
+
+impl Frame {
+    fn new(command: u32) -> Frame {
+        let mut frame = Frame {
+            data: vec![0; 4],
+            exports: vec![],
+        };
+        frame.u32(command);
+        frame
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.data.truncate(len);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn finish(mut self) -> (Vec<u8>, Vec<u64>) {
+        let len = (self.data.len() - 4) as u32;
+        self.set_u32(0, len);
+        (self.data, self.exports)
+    }
+}
+
+/// Bounds checked reads from a received frame.
+struct FrameReader<'a>(&'a [u8]);
+
+impl<'a> FrameReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+fn errno_result(ret: i32) -> Result<i32> {
+    if ret < 0 {
+        Err(status_from_io_error(io::Error::last_os_error()))
+    } else {
+        Ok(ret)
+    }
+}
+
+fn vsock_socket() -> Result<OwnedFd> {
+    let fd = errno_result(unsafe {
+        // Safety: `socket` has no preconditions.
+        libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0)
+    })?;
+    unsafe {
+        // Safety: `socket` returned a new file descriptor we own.
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+fn vsock_addr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    let mut addr: libc::sockaddr_vm = unsafe {
+        // Safety: `sockaddr_vm` is a plain C struct, for which all zeroes is
+        // a valid value.
+        mem::zeroed()
+    };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binder::Strong;
+
+    const ECHO: TransactionCode = SpIBinder::FIRST_CALL_TRANSACTION;
+    const CALL_BACK: TransactionCode = SpIBinder::FIRST_CALL_TRANSACTION + 1;
+    const RETURN_BINDER: TransactionCode = SpIBinder::FIRST_CALL_TRANSACTION + 2;
+    const RECORD_FLAGS: TransactionCode = SpIBinder::FIRST_CALL_TRANSACTION + 3;
+
+    static RECORDED_FLAGS: AtomicU32 = AtomicU32::new(u32::MAX);
+
+    struct RpcTestService;
+
+    impl Remotable for RpcTestService {
+        fn get_descriptor() -> &'static str {
+            "android.binder.rust.test.IRpcTest"
+        }
+
+        fn on_transact(
+            &self,
+            code: TransactionCode,
+            data: &Parcel,
+            reply: &mut Parcel,
+        ) -> Result<()> {
+            match code {
+                ECHO => reply.write(&data.read::<String>()?),
+                CALL_BACK => {
+                    let callback: SpIBinder = data.read()?;
+                    let result = callback.transact(ECHO, 0, |input| input.write("callback"))?;
+                    reply.write(&result.read::<String>()?)
+                }
+                RETURN_BINDER => reply.write(&data.read::<SpIBinder>()?),
+                RECORD_FLAGS => {
+                    RECORDED_FLAGS.store(crate::native::incoming_flags(), Ordering::SeqCst);
+                    Ok(())
+                }
+                _ => Err(StatusCode::UNKNOWN_TRANSACTION),
+            }
+        }
+
+        binder_fn_get_class!(Binder::<Self>);
+    }
+
+    fn echo(binder: &SpIBinder, message: &str) -> Result<String> {
+        binder
+            .transact(ECHO, 0, |input| input.write(message))?
+            .read()
+    }
+
+    #[test]
+    fn test_unix_domain_rpc() {
+        let path = std::env::temp_dir().join(format!("binder_rpc_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let server = Arc::new(RpcServer::new(Binder::new(RpcTestService).as_binder()));
+        server.set_max_threads(2);
+        server.setup_unix_domain_server(&path).unwrap();
+        let joined = {
+            let server = server.clone();
+            thread::spawn(move || server.join())
+        };
+
+        let session = RpcSession::new();
+        assert_eq!(session.get_root_object().err(), Some(StatusCode::NO_INIT));
+        session.setup_unix_domain_client(&path).unwrap();
+        let mut root = session.get_root_object().unwrap();
+        assert_eq!(
+            root.get_class().map(|c| c.get_descriptor()),
+            Some(RpcTestService::get_descriptor().to_owned())
+        );
+        assert_eq!(echo(&root, "hello").unwrap(), "hello");
+
+        // Objects sent to the server are proxied back to the client
+        let callback = Binder::new(RpcTestService).as_binder();
+        let reply = root
+            .transact(CALL_BACK, 0, |input| input.write(&callback))
+            .unwrap();
+        assert_eq!(reply.read::<String>().unwrap(), "callback");
+        let reply = root
+            .transact(RETURN_BINDER, 0, |input| input.write(&callback))
+            .unwrap();
+        assert_eq!(reply.read::<SpIBinder>().unwrap(), callback);
+
+        // The server's own objects are local to it
+        let reply = root
+            .transact(CALL_BACK, 0, |input| input.write(&root))
+            .unwrap();
+        assert_eq!(reply.read::<String>().unwrap(), "callback");
+        let reply = root
+            .transact(RETURN_BINDER, 0, |input| input.write(&root))
+            .unwrap();
+        assert_eq!(reply.read::<SpIBinder>().unwrap(), root);
+
+        let clients: Vec<_> = (0..4)
+            .map(|i| {
+                let root = root.clone();
+                thread::spawn(move || echo(&root, &i.to_string()))
+            })
+            .collect();
+        for (i, client) in clients.into_iter().enumerate() {
+            assert_eq!(client.join().unwrap().unwrap(), i.to_string());
+        }
+
+        drop(session);
+        assert_eq!(echo(&root, "hello").err(), Some(StatusCode::DEAD_OBJECT));
+
+        server.shutdown();
+        joined.join().unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    pub trait IRpcEcho: Interface {
+        fn echo(&self, message: &str) -> Result<String>;
+        fn call_back(&self, callback: &Strong<dyn IRpcEcho>) -> Result<Strong<dyn IRpcEcho>>;
+    }
+
+    declare_binder_interface! {
+        IRpcEcho["android.binder.rust.test.IRpcEcho"] {
+            native: BnRpcEcho(on_echo_transact),
+            proxy: BpRpcEcho,
+        }
+    }
+
+    fn on_echo_transact(
+        service: &dyn IRpcEcho,
+        code: TransactionCode,
+        data: &Parcel,
+        reply: &mut Parcel,
+    ) -> Result<()> {
+        match code {
+            ECHO => reply.write(&service.echo(&data.read::<String>()?)?),
+            CALL_BACK => reply.write(&service.call_back(&data.read()?)?),
+            _ => Err(StatusCode::UNKNOWN_TRANSACTION),
+        }
+    }
+
+    impl IRpcEcho for BpRpcEcho {
+        fn echo(&self, message: &str) -> Result<String> {
+            self.binder
+                .transact(ECHO, 0, |input| input.write(message))?
+                .read()
+        }
+
+        fn call_back(&self, callback: &Strong<dyn IRpcEcho>) -> Result<Strong<dyn IRpcEcho>> {
+            self.binder
+                .transact(CALL_BACK, 0, |input| input.write(callback))?
+                .read()
+        }
+    }
+
+    impl IRpcEcho for Binder<BnRpcEcho> {
+        fn echo(&self, message: &str) -> Result<String> {
+            self.0.echo(message)
+        }
+
+        fn call_back(&self, callback: &Strong<dyn IRpcEcho>) -> Result<Strong<dyn IRpcEcho>> {
+            self.0.call_back(callback)
+        }
+    }
+
+    struct Echo(&'static str);
+
+    impl Interface for Echo {}
+
+    impl IRpcEcho for Echo {
+        fn echo(&self, message: &str) -> Result<String> {
+            Ok(format!("{}{}", self.0, message))
+        }
+
+        fn call_back(&self, callback: &Strong<dyn IRpcEcho>) -> Result<Strong<dyn IRpcEcho>> {
+            assert_eq!(callback.echo("hello")?, "callback: hello");
+            Ok(callback.clone())
+        }
+    }
+
+    #[test]
+    fn test_typed_interface_rpc() {
+        let path =
+            std::env::temp_dir().join(format!("binder_rpc_typed_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let service = BnRpcEcho::new_binder(Echo("server: "));
+        let server = Arc::new(RpcServer::new(service.as_binder()));
+        server.setup_unix_domain_server(&path).unwrap();
+        let joined = {
+            let server = server.clone();
+            thread::spawn(move || server.join())
+        };
+
+        let session = RpcSession::new();
+        session.setup_unix_domain_client(&path).unwrap();
+        let root = session.get_root_object().unwrap();
+        let echo: Strong<dyn IRpcEcho> = root.into_interface().unwrap();
+        assert_eq!(echo.echo("hello").unwrap(), "server: hello");
+
+        // The callback is received as a typed proxy by the server, and comes
+        // back as the local service
+        let callback = BnRpcEcho::new_binder(Echo("callback: "));
+        let returned = echo.call_back(&callback).unwrap();
+        assert_eq!(returned.as_binder(), callback.as_binder());
+
+        // The descriptor of objects received over RPC is still checked
+        let other = Binder::new(RpcTestService).as_binder();
+        let result = echo
+            .as_binder()
+            .transact(CALL_BACK, 0, |input| input.write(&other));
+        assert_eq!(result.err(), Some(StatusCode::BAD_TYPE));
+
+        drop(session);
+        server.shutdown();
+        joined.join().unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_local_oneway_flags() {
+        let binder = Binder::new(RpcTestService).as_binder();
+        binder
+            .transact(RECORD_FLAGS, SpIBinder::FLAG_ONEWAY, |_| Ok(()))
+            .unwrap();
+        assert_eq!(RECORDED_FLAGS.load(Ordering::SeqCst), SpIBinder::FLAG_ONEWAY);
+        binder.transact(RECORD_FLAGS, 0, |_| Ok(())).unwrap();
+        assert_eq!(RECORDED_FLAGS.load(Ordering::SeqCst), 0);
+    }
+}
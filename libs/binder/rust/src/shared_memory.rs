@@ -0,0 +1,804 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Shared memory for passing large buffers between processes.
+//!
+//! Data written into a parcel is copied through the binder transaction buffer,
+//! which is limited to 1MB per process and shared by all in-flight
+//! transactions. A [`SharedMemory`] is a memfd which is sent as a file
+//! descriptor, so the receiving process maps the same pages instead of copying
+//! the data.
+//!
+//! A [`SharedMemoryAllocator`] carves [`SharedMemoryRegion`]s out of a single
+//! shared memory heap, similar to `MemoryDealer` in `libbinder`, so a service
+//! can send many buffers while the client maps the heap once.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use binder::SharedMemory;
+//!
+//! # fn main() -> binder::Result<()> {
+//! let memory = SharedMemory::create("frame", 4 * 1920 * 1080)?;
+//! memory.map_mut()?.write_at(0, &vec![0xff; memory.size()])?;
+//! // Prevent the receiver from modifying the frame
+//! memory.set_read_only()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Concurrent modification
+//!
+//! Unless the memory is read-only, any process holding it may write to it
+//! while it is mapped, so mapped data must be treated as untrusted input and
+//! validated after it has been copied out of the mapping. Use
+//! [`SharedMemory::set_read_only`] before sending data that the receiver
+//! relies on not changing.
+//!
+//! For the same reason, mappings are accessed by copying data in and out with
+//! `read_at` and `write_at`. Borrowing a mapping as a slice is `unsafe`, as
+//! the caller must ensure that no other mapping modifies the memory meanwhile.
+
+use crate::error::{status_from_io_error, Result, StatusCode};
+use crate::parcel::{
+    Deserialize, DeserializeArray, DeserializeOption, Parcel, ParcelFileDescriptor, Serialize,
+    SerializeArray, SerializeOption,
+};
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+/// Seals on every shared memory, so that the size of the memory can never
+/// change while it is mapped. Truncating a mapped file would make accesses to
+/// the mapping past the new end of the file raise `SIGBUS`.
+const SIZE_SEALS: c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+
+/// Seals making a shared memory read-only.
+const READ_ONLY_SEALS: c_int = libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+
+/// A region of shared memory backed by a memfd.
+///
+/// The memory is serialized as a [`ParcelFileDescriptor`] followed by its size
+/// as an `i64`. When deserializing, the file descriptor must be a sealed memfd
+/// of exactly that size, otherwise deserialization fails with
+/// `StatusCode::BAD_VALUE`.
+#[derive(Debug)]
+pub struct SharedMemory {
+    file: File,
+    size: usize,
+}
+
+impl SharedMemory {
+    /// Create a new, zero-filled shared memory of `size` bytes.
+    ///
+    /// `name` is only used for debugging, and shows up in `/proc/<pid>/maps`.
+    pub fn create(name: &str, size: usize) -> Result<Self> {
+        if size == 0 {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        let len = libc::off_t::try_from(size).or(Err(StatusCode::NO_MEMORY))?;
+        let name = CString::new(name).or(Err(StatusCode::BAD_VALUE))?;
+        let fd = errno_result(unsafe {
+            // Safety: `name` is a valid, nul-terminated C string which
+            // `memfd_create` only reads during the call.
+            libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING)
+        })?;
+        let file = unsafe {
+            // Safety: `memfd_create` succeeded, so `fd` is a valid file
+            // descriptor which we own.
+            File::from_raw_fd(fd)
+        };
+        errno_result(unsafe {
+            // Safety: `file` is a valid file descriptor.
+            libc::ftruncate(file.as_raw_fd(), len)
+        })?;
+        errno_result(unsafe {
+            // Safety: `file` is a valid file descriptor.
+            libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, SIZE_SEALS)
+        })?;
+        Ok(Self { file, size })
+    }
+
+    /// Create a new handle to the same memory, duplicating the file
+    /// descriptor.
+    pub fn try_clone(&self) -> Result<Self> {
+        let file = self.file.try_clone().map_err(status_from_io_error)?;
+        Ok(Self {
+            file,
+            size: self.size,
+        })
+    }
+
+    /// Size of the memory in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the file descriptor of the memory.
+    pub fn as_file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns `true` if the memory has been made read-only, by this process
+    /// or by the process it was received from.
+    pub fn is_read_only(&self) -> bool {
+        self.seals()
+            .map(|seals| seals & READ_ONLY_SEALS == READ_ONLY_SEALS)
+            .unwrap_or(false)
+    }
+
+    /// Prevent any further writes to the memory by any process.
+    ///
+    /// This cannot be undone. Fails with `StatusCode::INVALID_OPERATION` if
+    /// the memory is currently mapped writable by any process.
+    pub fn set_read_only(&self) -> Result<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let ret = unsafe {
+            // Safety: `self.file` is a valid file descriptor.
+            libc::fcntl(
+                self.as_file().as_raw_fd(),
+                libc::F_ADD_SEALS,
+                READ_ONLY_SEALS,
+            )
+        };
+        match errno_result(ret) {
+            Err(_) if errno() == libc::EBUSY => Err(StatusCode::INVALID_OPERATION),
+            res => res.map(|_| ()),
+        }
+    }
+
+    /// Map the whole memory for reading.
+    pub fn map(&self) -> Result<SharedMemoryMapping> {
+        self.map_range(0, self.size, false).map(SharedMemoryMapping)
+    }
+
+    /// Map the whole memory for reading and writing.
+    ///
+    /// Fails with `StatusCode::INVALID_OPERATION` if the memory is read-only.
+    pub fn map_mut(&self) -> Result<SharedMemoryMappingMut> {
+        self.map_range(0, self.size, true)
+            .map(SharedMemoryMappingMut)
+    }
+
+    fn seals(&self) -> Result<c_int> {
+        errno_result(unsafe {
+            // Safety: `self.file` is a valid file descriptor.
+            libc::fcntl(self.as_file().as_raw_fd(), libc::F_GET_SEALS)
+        })
+    }
+
+    /// Map `len` bytes starting at `offset`, which need not be page aligned.
+    fn map_range(&self, offset: usize, len: usize, writable: bool) -> Result<Mapping> {
+        let end = offset.checked_add(len).ok_or(StatusCode::BAD_VALUE)?;
+        if len == 0 || end > self.size {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        if writable && self.is_read_only() {
+            return Err(StatusCode::INVALID_OPERATION);
+        }
+        let map_offset = offset - offset % page_size();
+        let map_len = end - map_offset;
+        // Read-only mappings are private so that they never count as writable
+        // mappings, which would prevent the memory from being sealed. Pages of
+        // a private mapping which are never written still show changes made
+        // through shared mappings.
+        let (prot, flags) = if writable {
+            (libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED)
+        } else {
+            (libc::PROT_READ, libc::MAP_PRIVATE)
+        };
+        let base = unsafe {
+            // Safety: We request a new mapping at an address chosen by the
+            // kernel, so no existing memory is affected. `map_offset` is page
+            // aligned and `map_offset + map_len` is within the memfd, whose
+            // size is sealed, so the whole mapping stays backed by the file.
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                prot,
+                flags,
+                self.as_file().as_raw_fd(),
+                map_offset as libc::off_t,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(errno_status());
+        }
+        Ok(Mapping {
+            base,
+            map_len,
+            data: unsafe {
+                // Safety: `offset - map_offset` is less than a page, and the
+                // mapping is at least that large.
+                (base as *mut u8).add(offset - map_offset)
+            },
+            len,
+        })
+    }
+}
+
+impl Serialize for SharedMemory {
+    fn serialize(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.write(&self.file.as_fd())?;
+        parcel.write(&(self.size as i64))
+    }
+}
+
+impl SerializeArray for SharedMemory {}
+
+impl SerializeOption for SharedMemory {}
+
+impl Deserialize for SharedMemory {
+    fn deserialize(parcel: &Parcel) -> Result<Self> {
+        let file: File = parcel.read::<ParcelFileDescriptor>()?.into();
+        let size: i64 = parcel.read()?;
+        let size = usize::try_from(size).or(Err(StatusCode::BAD_VALUE))?;
+        if size == 0 {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        let memory = SharedMemory { file, size };
+        // Only accept memory whose size can't change, otherwise the sender
+        // could crash us by truncating the memory while it is mapped.
+        let seals = memory.seals().or(Err(StatusCode::BAD_VALUE))?;
+        if seals & libc::F_SEAL_SHRINK == 0 {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        let metadata = memory.as_file().metadata().or(Err(StatusCode::BAD_VALUE))?;
+        if metadata.len() != size as u64 {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        Ok(memory)
+    }
+}
+
+impl DeserializeArray for SharedMemory {}
+
+impl DeserializeOption for SharedMemory {}
+
+struct Mapping {
+    base: *mut c_void,
+    map_len: usize,
+    data: *mut u8,
+    len: usize,
+}
+
+// Safety: A `Mapping` only owns the mapped pages, which may be accessed from
+// any thread.
+unsafe impl Send for Mapping {}
+
+// Safety: Shared access to a `Mapping` only copies out of the mapped pages.
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Returns a pointer to `len` bytes starting at `offset` of the mapping.
+    fn range(&self, offset: usize, len: usize) -> Result<*mut u8> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(unsafe {
+                // Safety: `offset` is within the mapping.
+                self.data.add(offset)
+            }),
+            _ => Err(StatusCode::BAD_VALUE),
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let src = self.range(offset, buf.len())?;
+        unsafe {
+            // Safety: `src` is readable for `buf.len()` bytes. `buf` is a
+            // mutable reference, so it can't overlap the mapping unless the
+            // contract of `as_slice` or `as_mut_slice` was broken.
+            ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            // Safety: `base` and `map_len` describe a mapping created by
+            // `mmap` which is owned by this object, and no references to it
+            // can outlive this object.
+            libc::munmap(self.base, self.map_len);
+        }
+    }
+}
+
+/// A read-only mapping of a [`SharedMemory`] or [`SharedMemoryRegion`].
+///
+/// The mapping stays valid after the memory it was mapped from is dropped.
+/// Its contents may be changed at any time through other mappings, so they are
+/// copied out with [`read_at`](Self::read_at) rather than borrowed.
+pub struct SharedMemoryMapping(Mapping);
+
+impl SharedMemoryMapping {
+    /// Size of the mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    /// Returns `true` if the mapping is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+
+    /// Returns a pointer to the start of the mapping, valid for reads of
+    /// [`len`](Self::len) bytes for as long as the mapping is alive.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.0.data
+    }
+
+    /// Copy `buf.len()` bytes starting at `offset` of the mapping into `buf`.
+    ///
+    /// Fails with `StatusCode::BAD_VALUE` if the range is not within the
+    /// mapping.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.0.read_at(offset, buf)
+    }
+
+    /// Returns the contents of the mapping as a slice.
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be written, through any mapping in any process,
+    /// while the returned slice is alive. This holds if the memory is
+    /// read-only, see [`SharedMemory::set_read_only`].
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        // Safety: The mapping is readable and at least `data + len` bytes
+        // long, and lives as long as `self`. The caller guarantees that the
+        // memory is not modified while the slice is alive.
+        slice::from_raw_parts(self.0.data, self.0.len)
+    }
+}
+
+impl fmt::Debug for SharedMemoryMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMemoryMapping")
+            .field("len", &self.0.len)
+            .finish()
+    }
+}
+
+/// A writable mapping of a [`SharedMemory`] or [`SharedMemoryRegion`].
+///
+/// The mapping stays valid after the memory it was mapped from is dropped.
+/// Its contents may be accessed at any time through other mappings, so they are
+/// copied in and out with [`write_at`](Self::write_at) and
+/// [`read_at`](Self::read_at) rather than borrowed.
+pub struct SharedMemoryMappingMut(Mapping);
+
+impl SharedMemoryMappingMut {
+    /// Size of the mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    /// Returns `true` if the mapping is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+
+    /// Returns a pointer to the start of the mapping, valid for reads of
+    /// [`len`](Self::len) bytes for as long as the mapping is alive.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.0.data
+    }
+
+    /// Returns a pointer to the start of the mapping, valid for reads and
+    /// writes of [`len`](Self::len) bytes for as long as the mapping is alive.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.0.data
+    }
+
+    /// Copy `buf.len()` bytes starting at `offset` of the mapping into `buf`.
+    ///
+    /// Fails with `StatusCode::BAD_VALUE` if the range is not within the
+    /// mapping.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.0.read_at(offset, buf)
+    }
+
+    /// Copy `buf` into the mapping, starting at `offset`.
+    ///
+    /// Fails with `StatusCode::BAD_VALUE` if the range is not within the
+    /// mapping.
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<()> {
+        let dst = self.0.range(offset, buf.len())?;
+        unsafe {
+            // Safety: `dst` is writable for `buf.len()` bytes, and no
+            // references into the mapping can be alive while we hold `self`
+            // mutably, so `buf` doesn't overlap it.
+            ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len());
+        }
+        Ok(())
+    }
+
+    /// Returns the contents of the mapping as a slice.
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be written, through any mapping in any process,
+    /// while the returned slice is alive.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        // Safety: The mapping is readable and at least `data + len` bytes
+        // long, and lives as long as `self`. The caller guarantees that the
+        // memory is not modified while the slice is alive.
+        slice::from_raw_parts(self.0.data, self.0.len)
+    }
+
+    /// Returns the contents of the mapping as a mutable slice.
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be accessed through any other mapping, in this or
+    /// any other process, while the returned slice is alive.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: The mapping is writable and at least `data + len` bytes
+        // long, and lives as long as `self`. Borrowing `self` mutably ensures
+        // this is the only reference through this mapping, and the caller
+        // guarantees that there is no access through other mappings.
+        slice::from_raw_parts_mut(self.0.data, self.0.len)
+    }
+}
+
+impl fmt::Debug for SharedMemoryMappingMut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMemoryMappingMut")
+            .field("len", &self.0.len)
+            .finish()
+    }
+}
+
+/// Sub-allocator handing out page-aligned regions of a single shared memory
+/// heap.
+///
+/// Regions are returned to the allocator when they are dropped. Regions
+/// received from another process are never returned to an allocator.
+#[derive(Debug)]
+pub struct SharedMemoryAllocator {
+    memory: Arc<SharedMemory>,
+    free: Arc<Mutex<FreeList>>,
+}
+
+impl SharedMemoryAllocator {
+    /// Create an allocator for the whole of `memory`.
+    pub fn new(memory: SharedMemory) -> Self {
+        let free = FreeList::new(memory.size());
+        Self {
+            memory: Arc::new(memory),
+            free: Arc::new(Mutex::new(free)),
+        }
+    }
+
+    /// Returns the heap regions are allocated from.
+    pub fn memory(&self) -> &SharedMemory {
+        &self.memory
+    }
+
+    /// Allocate a region of `size` bytes, rounded up to a whole number of
+    /// pages.
+    ///
+    /// Fails with `StatusCode::NO_MEMORY` if the heap does not have a large
+    /// enough contiguous free range.
+    pub fn allocate(&self, size: usize) -> Result<SharedMemoryRegion> {
+        if size == 0 {
+            return Err(StatusCode::BAD_VALUE);
+        }
+        let page_size = page_size();
+        let len = size
+            .checked_add(page_size - 1)
+            .ok_or(StatusCode::NO_MEMORY)?
+            / page_size
+            * page_size;
+        let offset = lock(&self.free)
+            .allocate(len)
+            .ok_or(StatusCode::NO_MEMORY)?;
+        Ok(SharedMemoryRegion {
+            memory: self.memory.clone(),
+            offset,
+            size,
+            allocation: Some((self.free.clone(), len)),
+        })
+    }
+}
+
+/// A range of a [`SharedMemory`], allocated from a [`SharedMemoryAllocator`]
+/// or received from another process.
+///
+/// A region is serialized as its [`SharedMemory`] followed by the offset and
+/// size of the region as `i64`s.
+pub struct SharedMemoryRegion {
+    memory: Arc<SharedMemory>,
+    offset: usize,
+    size: usize,
+    /// Allocator to return the region to on drop, and the length of the
+    /// allocation
+    allocation: Option<(Arc<Mutex<FreeList>>, usize)>,
+}
+
+impl SharedMemoryRegion {
+    /// Returns the memory containing this region.
+    pub fn memory(&self) -> &SharedMemory {
+        &self.memory
+    }
+
+    /// Offset of the region in its memory, in bytes.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Map the region for reading.
+    pub fn map(&self) -> Result<SharedMemoryMapping> {
+        self.memory
+            .map_range(self.offset, self.size, false)
+            .map(SharedMemoryMapping)
+    }
+
+    /// Map the region for reading and writing.
+    ///
+    /// Fails with `StatusCode::INVALID_OPERATION` if the memory is read-only.
+    pub fn map_mut(&self) -> Result<SharedMemoryMappingMut> {
+        self.memory
+            .map_range(self.offset, self.size, true)
+            .map(SharedMemoryMappingMut)
+    }
+}
+
+impl Drop for SharedMemoryRegion {
+    fn drop(&mut self) {
+        if let Some((free, len)) = &self.allocation {
+            lock(free).free(self.offset, *len);
+        }
+    }
+}
+
+impl fmt::Debug for SharedMemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMemoryRegion")
+            .field("memory", &self.memory)
+            .field("offset", &self.offset)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl Serialize for SharedMemoryRegion {
+    fn serialize(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.write(&*self.memory)?;
+        parcel.write(&(self.offset as i64))?;
+        parcel.write(&(self.size as i64))
+    }
+}
+
+impl SerializeArray for SharedMemoryRegion {}
+
+impl SerializeOption for SharedMemoryRegion {}
+
+impl Deserialize for SharedMemoryRegion {
+    fn deserialize(parcel: &Parcel) -> Result<Self> {
+        let memory: SharedMemory = parcel.read()?;
+        let offset: i64 = parcel.read()?;
+        let size: i64 = parcel.read()?;
+        let offset = usize::try_from(offset).or(Err(StatusCode::BAD_VALUE))?;
+        let size = usize::try_from(size).or(Err(StatusCode::BAD_VALUE))?;
+        match offset.checked_add(size) {
+            Some(end) if size != 0 && end <= memory.size() => Ok(Self {
+                memory: Arc::new(memory),
+                offset,
+                size,
+                allocation: None,
+            }),
+            _ => Err(StatusCode::BAD_VALUE),
+        }
+    }
+}
+
+impl DeserializeArray for SharedMemoryRegion {}
+
+impl DeserializeOption for SharedMemoryRegion {}
+
+/// Free ranges of a shared memory heap, keyed by offset.
+#[derive(Debug)]
+struct FreeList(BTreeMap<usize, usize>);
+
+impl FreeList {
+    fn new(size: usize) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(0, size);
+        Self(free)
+    }
+
+    /// Take `len` bytes from the first free range that is large enough.
+    fn allocate(&mut self, len: usize) -> Option<usize> {
+        let (&offset, &free_len) = self.0.iter().find(|(_, &free_len)| free_len >= len)?;
+        self.0.remove(&offset);
+        if free_len > len {
+            self.0.insert(offset + len, free_len - len);
+        }
+        Some(offset)
+    }
+
+    /// Return a range, merging it with adjacent free ranges.
+    fn free(&mut self, mut offset: usize, mut len: usize) {
+        if let Some(next_len) = self.0.remove(&(offset + len)) {
+            len += next_len;
+        }
+        if let Some((&prev, &prev_len)) = self.0.range(..offset).next_back() {
+            if prev + prev_len == offset {
+                self.0.remove(&prev);
+                offset = prev;
+                len += prev_len;
+            }
+        }
+        self.0.insert(offset, len);
+    }
+}
+
+fn lock(free: &Mutex<FreeList>) -> std::sync::MutexGuard<'_, FreeList> {
+    // A panic while holding the lock can't leave the free list inconsistent.
+    free.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn page_size() -> usize {
+    unsafe {
+        // Safety: `sysconf` has no preconditions.
+        libc::sysconf(libc::_SC_PAGESIZE) as usize
+    }
+}
+
+fn errno() -> c_int {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// Status for the current `errno`. `status_t` values for errors returned by
+/// system calls are negated `errno` values.
+fn errno_status() -> StatusCode {
+    status_from_io_error(io::Error::last_os_error())
+}
+
+fn errno_result(ret: c_int) -> Result<c_int> {
+    if ret < 0 {
+        Err(errno_status())
+    } else {
+        Ok(ret)
+    }
+}
+
+#[test]
+fn test_map() {
+    let memory = SharedMemory::create("test_map", 10000).unwrap();
+    assert_eq!(memory.size(), 10000);
+    assert!(!memory.is_read_only());
+
+    let mut mapping = memory.map_mut().unwrap();
+    assert_eq!(mapping.len(), 10000);
+    let mut contents = vec![1; 10000];
+    mapping.read_at(0, &mut contents).unwrap();
+    assert!(contents.iter().all(|&b| b == 0));
+    mapping.write_at(9999, &[42]).unwrap();
+    assert_eq!(mapping.write_at(9999, &[1, 2]), Err(StatusCode::BAD_VALUE));
+    assert_eq!(
+        mapping.read_at(usize::MAX, &mut [0]),
+        Err(StatusCode::BAD_VALUE)
+    );
+
+    let clone = memory.try_clone().unwrap();
+    drop(memory);
+    let mut byte = [0];
+    clone.map().unwrap().read_at(9999, &mut byte).unwrap();
+    assert_eq!(byte, [42]);
+
+    // Writable mappings prevent sealing, read-only ones don't
+    let read_only = clone.map().unwrap();
+    assert_eq!(clone.set_read_only(), Err(StatusCode::INVALID_OPERATION));
+    drop(mapping);
+    clone.set_read_only().unwrap();
+    assert!(clone.is_read_only());
+    assert_eq!(clone.map_mut().err(), Some(StatusCode::INVALID_OPERATION));
+    let contents = unsafe {
+        // Safety: The memory is read-only.
+        read_only.as_slice()
+    };
+    assert_eq!(contents[9999], 42);
+
+    assert_eq!(
+        SharedMemory::create("empty", 0).err(),
+        Some(StatusCode::BAD_VALUE)
+    );
+}
+
+#[test]
+fn test_allocator() {
+    let page_size = page_size();
+    let allocator =
+        SharedMemoryAllocator::new(SharedMemory::create("heap", 4 * page_size).unwrap());
+
+    let a = allocator.allocate(1).unwrap();
+    let b = allocator.allocate(page_size + 1).unwrap();
+    assert_eq!(a.offset(), 0);
+    assert_eq!(a.size(), 1);
+    assert_eq!(b.offset(), page_size);
+    assert_eq!(
+        allocator.allocate(2 * page_size).err(),
+        Some(StatusCode::NO_MEMORY)
+    );
+
+    b.map_mut().unwrap().write_at(page_size, &[7]).unwrap();
+    let mut byte = [0];
+    allocator
+        .memory()
+        .map()
+        .unwrap()
+        .read_at(2 * page_size, &mut byte)
+        .unwrap();
+    assert_eq!(byte, [7]);
+
+    // Freed regions are merged with their neighbours
+    drop(a);
+    drop(b);
+    let c = allocator.allocate(4 * page_size).unwrap();
+    assert_eq!(c.offset(), 0);
+}
+
+#[test]
+fn test_parcel() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let mut service = Binder::new(()).as_binder();
+    let mut parcel = Parcel::new_for_test(&mut service).unwrap();
+    let start = parcel.get_data_position();
+
+    let allocator =
+        SharedMemoryAllocator::new(SharedMemory::create("heap", 3 * page_size()).unwrap());
+    let _first = allocator.allocate(100).unwrap();
+    let region = allocator.allocate(100).unwrap();
+    region.map_mut().unwrap().write_at(0, &[3; 100]).unwrap();
+
+    parcel.write(&region).unwrap();
+    parcel.write(allocator.memory()).unwrap();
+    parcel.write(&Option::<SharedMemory>::None).unwrap();
+
+    unsafe {
+        parcel.set_data_position(start).unwrap();
+    }
+
+    let received: SharedMemoryRegion = parcel.read().unwrap();
+    assert_eq!(received.offset(), page_size());
+    assert_eq!(received.size(), 100);
+    let mut contents = [0; 100];
+    received.map().unwrap().read_at(0, &mut contents).unwrap();
+    assert_eq!(contents, [3; 100]);
+    let memory: SharedMemory = parcel.read().unwrap();
+    assert_eq!(memory.size(), 3 * page_size());
+    assert!(parcel.read::<Option<SharedMemory>>().unwrap().is_none());
+}
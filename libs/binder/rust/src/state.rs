@@ -14,16 +14,96 @@
  * limitations under the License.
  */
 
+use crate::error::{status_from_io_error, Result, StatusCode};
 use crate::sys;
 
 use libc::{pid_t, uid_t};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// Maximum number of threads in the thread pool if it is not set, as in
+/// libbinder.
+const DEFAULT_MAX_THREADS: u32 = 15;
+
+type ThreadHook = Box<dyn Fn() + Send + Sync>;
+
+/// Hooks run by the threads joining the thread pool.
+#[derive(Default)]
+struct ThreadHooks {
+    on_start: Option<ThreadHook>,
+    on_stop: Option<ThreadHook>,
+}
+
+/// Thread pool started by a [`ProcessStateBuilder`].
+struct Pool {
+    hooks: Arc<ThreadHooks>,
+    thread_name_prefix: String,
+    /// Number of threads started so far
+    spawned: u32,
+}
+
+impl Pool {
+    /// Start threads until `count` threads have been started in total.
+    fn spawn_up_to(&mut self, count: u32) -> Result<()> {
+        let pid = std::process::id();
+        while self.spawned < count {
+            thread::Builder::new()
+                .name(format!("{}:{}_{:X}", self.thread_name_prefix, pid, self.spawned))
+                .spawn(ProcessState::join_thread_pool)
+                .map_err(status_from_io_error)?;
+            self.spawned += 1;
+        }
+        Ok(())
+    }
+}
+
+static MAX_THREADS: AtomicU32 = AtomicU32::new(DEFAULT_MAX_THREADS);
+
+/// Number of threads in the thread pool which joined it through this crate.
+static POOL_THREADS: AtomicU32 = AtomicU32::new(0);
+
+/// Set once libbinder may have started threads which are not counted in
+/// `POOL_THREADS`.
+static LIBBINDER_THREADS: AtomicBool = AtomicBool::new(false);
+
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+fn lock_pool() -> MutexGuard<'static, Option<Pool>> {
+    // A panic while holding the lock can't leave the pool inconsistent.
+    POOL.lock().unwrap_or_else(|e| e.into_inner())
+}
 
 /// Static utility functions to manage Binder process state.
 pub struct ProcessState;
 
 impl ProcessState {
+    /// Create a builder to configure and start the Binder IPC thread pool.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use binder::ProcessState;
+    /// ProcessState::builder()
+    ///     .max_threads(4)
+    ///     .thread_name_prefix("foo")
+    ///     .on_thread_start(|| eprintln!("binder thread started"))
+    ///     .on_thread_stop(|| eprintln!("binder thread stopped"))
+    ///     .start()?;
+    /// ProcessState::join_thread_pool();
+    /// # Ok::<(), binder::StatusCode>(())
+    /// ```
+    pub fn builder() -> ProcessStateBuilder {
+        ProcessStateBuilder {
+            max_threads: DEFAULT_MAX_THREADS,
+            thread_name_prefix: "binder".to_owned(),
+            hooks: Default::default(),
+        }
+    }
+
     /// Start the Binder IPC thread pool
     pub fn start_thread_pool() {
+        LIBBINDER_THREADS.store(true, Ordering::SeqCst);
         unsafe {
             // Safety: Safe FFI
             sys::ABinderProcess_startThreadPool();
@@ -35,19 +115,146 @@ impl ProcessState {
     /// By default, after startThreadPool is called, this is 15. If it is called
     /// additional times, it will only prevent the kernel from starting new
     /// threads and will not delete already existing threads.
+    ///
+    /// If the thread pool was started by a [`ProcessStateBuilder`], libbinder
+    /// is still kept from starting threads, and the builder's threads are
+    /// started instead until there are `num_threads` of them.
     pub fn set_thread_pool_max_thread_count(num_threads: u32) {
+        let mut pool = lock_pool();
+        MAX_THREADS.store(num_threads, Ordering::SeqCst);
+        if let Some(pool) = pool.as_mut() {
+            if let Err(e) = pool.spawn_up_to(num_threads) {
+                log::error!("Failed to start binder thread: {:?}", e);
+            }
+            return;
+        }
         unsafe {
             // Safety: Safe FFI
             sys::ABinderProcess_setThreadPoolMaxThreadCount(num_threads);
         }
     }
 
-    /// Block on the Binder IPC thread pool
+    /// The maximum number of threads in the thread pool, as set by
+    /// [`set_thread_pool_max_thread_count`](Self::set_thread_pool_max_thread_count)
+    /// or [`ProcessStateBuilder::max_threads`].
+    pub fn thread_pool_max_thread_count() -> u32 {
+        MAX_THREADS.load(Ordering::SeqCst)
+    }
+
+    /// The number of threads currently in the thread pool, or `None` if it is
+    /// not known.
+    ///
+    /// libbinder doesn't report the threads it starts, so the count is only
+    /// known if [`start_thread_pool`](Self::start_thread_pool) was never
+    /// called. The thread pool then consists of the threads started by
+    /// [`ProcessStateBuilder::start`] and the threads blocked in
+    /// [`join_thread_pool`](Self::join_thread_pool).
+    pub fn thread_pool_thread_count() -> Option<u32> {
+        if LIBBINDER_THREADS.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(POOL_THREADS.load(Ordering::SeqCst))
+    }
+
+    /// Block on the Binder IPC thread pool. This never returns.
+    ///
+    /// If the thread pool was started with [`ProcessState::builder`], the
+    /// thread hooks are run on this thread as well.
     pub fn join_thread_pool() {
+        let hooks = lock_pool().as_ref().map(|pool| pool.hooks.clone());
+        POOL_THREADS.fetch_add(1, Ordering::SeqCst);
+        if let Some(on_start) = hooks.as_ref().and_then(|h| h.on_start.as_ref()) {
+            on_start();
+        }
         unsafe {
             // Safety: Safe FFI
             sys::ABinderProcess_joinThreadPool();
         }
+        if let Some(on_stop) = hooks.as_ref().and_then(|h| h.on_stop.as_ref()) {
+            on_stop();
+        }
+        POOL_THREADS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Builder for the Binder IPC thread pool, created by
+/// [`ProcessState::builder`].
+///
+/// Unlike [`ProcessState::start_thread_pool`], which lets libbinder start
+/// threads when the kernel asks for them, all the threads of the pool are
+/// started up front by [`start`](Self::start), so that they are named and run
+/// the hooks of the builder.
+pub struct ProcessStateBuilder {
+    max_threads: u32,
+    thread_name_prefix: String,
+    hooks: ThreadHooks,
+}
+
+impl ProcessStateBuilder {
+    /// Set the number of threads started in the thread pool, not counting
+    /// threads calling [`ProcessState::join_thread_pool`]. Defaults to 15.
+    pub fn max_threads(mut self, max_threads: u32) -> Self {
+        self.max_threads = max_threads;
+        self
+    }
+
+    /// Set the prefix of the names of the threads in the thread pool, which
+    /// are named `<prefix>:<pid>_<n>` like libbinder threads. Defaults to
+    /// `binder`.
+    ///
+    /// Names longer than 15 bytes are truncated by the kernel.
+    pub fn thread_name_prefix(mut self, prefix: &str) -> Self {
+        self.thread_name_prefix = prefix.to_owned();
+        self
+    }
+
+    /// Set a hook run by each thread joining the thread pool before it
+    /// handles any transaction, for instance to set its scheduling policy.
+    pub fn on_thread_start<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.hooks.on_start = Some(Box::new(hook));
+        self
+    }
+
+    /// Set a hook run by each thread leaving the thread pool, after it has
+    /// handled its last transaction. Threads only leave the pool when
+    /// libbinder stops them, for instance when the binder driver is closed.
+    pub fn on_thread_stop<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.hooks.on_stop = Some(Box::new(hook));
+        self
+    }
+
+    /// Start the thread pool.
+    ///
+    /// Returns `INVALID_OPERATION` if the thread pool was already started by
+    /// a builder.
+    pub fn start(self) -> Result<()> {
+        let mut pool = lock_pool();
+        if pool.is_some() {
+            return Err(StatusCode::INVALID_OPERATION);
+        }
+        // Keep libbinder from starting threads which would not run the hooks.
+        let success = unsafe {
+            // Safety: Safe FFI
+            sys::ABinderProcess_setThreadPoolMaxThreadCount(0)
+        };
+        if !success {
+            return Err(StatusCode::INVALID_OPERATION);
+        }
+        MAX_THREADS.store(self.max_threads, Ordering::SeqCst);
+        // The threads wait for the lock to be released before running the
+        // hooks.
+        pool.insert(Pool {
+            hooks: Arc::new(self.hooks),
+            thread_name_prefix: self.thread_name_prefix,
+            spawned: 0,
+        })
+        .spawn_up_to(self.max_threads)
     }
 }
 
@@ -131,3 +338,59 @@ impl ThreadState {
         })
     }
 }
+
+#[test]
+fn test_builder() {
+    use std::process::Command;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    // The thread pool can't be stopped and keeps libbinder from starting
+    // threads, so run this test alone in a child process.
+    const CHILD: &str = "BINDER_RS_TEST_BUILDER_CHILD";
+    if std::env::var_os(CHILD).is_none() {
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "state::test_builder", "--test-threads=1"])
+            .env(CHILD, "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+
+    let (started, names) = channel();
+    let started = Mutex::new(started);
+    assert_eq!(ProcessState::thread_pool_thread_count(), Some(0));
+    ProcessState::builder()
+        .max_threads(2)
+        .thread_name_prefix("test")
+        .on_thread_start(move || {
+            let name = thread::current().name().map(str::to_owned);
+            let _ = started.lock().unwrap().send(name);
+        })
+        .start()
+        .expect("Could not start thread pool");
+
+    let prefix = format!("test:{}_", std::process::id());
+    for _ in 0..2 {
+        let name = names.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        assert!(name.starts_with(&prefix[..prefix.len().min(15)]));
+    }
+    assert_eq!(ProcessState::thread_pool_max_thread_count(), 2);
+    assert_eq!(ProcessState::thread_pool_thread_count(), Some(2));
+
+    // Raising the maximum starts more threads of the builder
+    ProcessState::set_thread_pool_max_thread_count(3);
+    let name = names.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+    assert!(name.starts_with(&prefix[..prefix.len().min(15)]));
+    assert_eq!(ProcessState::thread_pool_max_thread_count(), 3);
+    assert_eq!(ProcessState::thread_pool_thread_count(), Some(3));
+    ProcessState::set_thread_pool_max_thread_count(1);
+    assert!(names.recv_timeout(Duration::from_millis(100)).is_err());
+    assert_eq!(ProcessState::thread_pool_thread_count(), Some(3));
+
+    assert_eq!(
+        ProcessState::builder().start().err(),
+        Some(StatusCode::INVALID_OPERATION)
+    );
+}
@@ -0,0 +1,471 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! In-process test doubles for binder clients and services.
+//!
+//! [`FakeServiceManager`] replaces the default service manager for the current
+//! process while it is installed. [`add_service`](crate::add_service),
+//! [`get_service`](crate::get_service) and
+//! [`get_interface`](crate::get_interface) then resolve to local objects
+//! registered with the fake, so clients and services can be unit tested on a
+//! host without a binder driver or `servicemanager`.
+//!
+//! This module is only built with the `testing` feature, and for the unit
+//! tests of this crate, so that production code doesn't pay for its hooks.
+//! Tests get it by linking `libbinder_rs_testing` instead of `libbinder_rs`.
+//!
+//! Interfaces retrieved from the fake are always handed out as proxies, even
+//! though the service is local. Every call is therefore parceled and goes
+//! through [`IBinder::transact`](crate::IBinder::transact), where the fake can
+//! inject failures and simulate the death of the service.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use binder::{Binder, Interface, StatusCode};
+//! use binder::testing::FakeServiceManager;
+//!
+//! let sm = FakeServiceManager::install();
+//! binder::add_service("example", Binder::new(()).as_binder())?;
+//! assert!(binder::get_service("example").is_some());
+//!
+//! // Make every transaction to the service time out.
+//! sm.set_transaction_error("example", Some(StatusCode::TIMED_OUT))?;
+//!
+//! // Kill the service, firing any linked death recipients.
+//! sm.kill_service("example")?;
+//! # Ok::<(), StatusCode>(())
+//! ```
+
+use crate::binder::AsNative;
+use crate::error::{Result, StatusCode};
+use crate::proxy::{DeathRecipient, SpIBinder};
+use crate::sys;
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+/// Set while a [`FakeServiceManager`] is installed, so that the hooks on the
+/// transaction path are a single atomic load when no fake is in use.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Serializes users of the fake, which is a process-wide resource. Unit tests
+/// run in parallel threads of the same process.
+static INSTALL_LOCK: Mutex<()> = Mutex::new(());
+
+/// State of the currently installed fake, if any.
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+/// The death recipient whose callback is running, and the thread running it,
+/// so that a `DeathRecipient` cannot be destroyed by another thread while its
+/// callback is running. No lock is held while the callback runs, so it may
+/// drop death recipients itself.
+static DELIVERING: Mutex<Option<(ThreadId, usize)>> = Mutex::new(None);
+
+/// Signalled when a death callback returns.
+static DELIVERED: Condvar = Condvar::new();
+
+/// A death recipient linked to a binder hosted by the fake.
+struct LinkedRecipient {
+    cookie: usize,
+    binder_died: unsafe extern "C" fn(*mut c_void),
+}
+
+#[derive(Default)]
+struct Registry {
+    services: BTreeMap<String, SpIBinder>,
+    /// Errors injected into transactions, keyed by `AIBinder` address.
+    errors: HashMap<usize, StatusCode>,
+    /// Binders killed through the fake. We keep a strong reference so that
+    /// the address cannot be reused by a new binder object.
+    dead: HashMap<usize, SpIBinder>,
+    recipients: HashMap<usize, Vec<LinkedRecipient>>,
+}
+
+impl Registry {
+    fn find(&self, name: &str) -> Result<usize> {
+        self.services
+            .get(name)
+            .map(|binder| binder.as_native() as usize)
+            .ok_or(StatusCode::NAME_NOT_FOUND)
+    }
+
+    fn is_known(&self, binder: usize) -> bool {
+        self.dead.contains_key(&binder)
+            || self
+                .services
+                .values()
+                .any(|service| service.as_native() as usize == binder)
+    }
+}
+
+fn lock<T>(mutex: &'static Mutex<T>) -> MutexGuard<'static, T> {
+    // A panicking test must not take every later test down with it.
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run `f` on the registry of the installed fake, if there is one.
+fn with_registry<R>(f: impl FnOnce(&mut Registry) -> R) -> Option<R> {
+    if !INSTALLED.load(Ordering::Acquire) {
+        return None;
+    }
+    lock(&REGISTRY).as_mut().map(f)
+}
+
+/// A fake service manager that hosts services in the current process.
+///
+/// The fake is installed process-wide for the lifetime of this object.
+/// Installing a second fake blocks until the first one is dropped, so tests
+/// that use it are serialized.
+pub struct FakeServiceManager {
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl FakeServiceManager {
+    /// Install a new, empty fake service manager for this process.
+    pub fn install() -> FakeServiceManager {
+        let guard = lock(&INSTALL_LOCK);
+        *lock(&REGISTRY) = Some(Registry::default());
+        INSTALLED.store(true, Ordering::Release);
+        FakeServiceManager { _guard: guard }
+    }
+
+    /// Register a service with the fake.
+    ///
+    /// This is equivalent to calling [`add_service`](crate::add_service)
+    /// while the fake is installed.
+    pub fn add_service(&self, identifier: &str, binder: SpIBinder) {
+        with_registry(|registry| registry.services.insert(identifier.to_owned(), binder));
+    }
+
+    /// Return the names of all services currently registered with the fake.
+    pub fn list_services(&self) -> Vec<String> {
+        list_services()
+    }
+
+    /// Make all transactions to the named service fail with `error`, for
+    /// instance `StatusCode::DEAD_OBJECT` or `StatusCode::TIMED_OUT`.
+    ///
+    /// Passing `None` clears a previously injected error.
+    pub fn set_transaction_error(&self, identifier: &str, error: Option<StatusCode>) -> Result<()> {
+        with_registry(|registry| {
+            let binder = registry.find(identifier)?;
+            match error {
+                Some(error) => registry.errors.insert(binder, error),
+                None => registry.errors.remove(&binder),
+            };
+            Ok(())
+        })
+        .unwrap_or(Err(StatusCode::NO_INIT))
+    }
+
+    /// Simulate the death of the named service.
+    ///
+    /// The service is unregistered, all later transactions to it fail with
+    /// `StatusCode::DEAD_OBJECT`, and every [`DeathRecipient`] linked to it is
+    /// called on the current thread before this method returns.
+    pub fn kill_service(&self, identifier: &str) -> Result<()> {
+        let binder = with_registry(|registry| {
+            let binder = registry.find(identifier)?;
+            let service = registry.services.remove(identifier).unwrap();
+            registry.errors.remove(&binder);
+            registry.dead.insert(binder, service);
+            Ok(binder)
+        })
+        .unwrap_or(Err(StatusCode::NO_INIT))?;
+
+        // Recipients are taken from the registry one at a time, so that those
+        // dropped by an earlier callback are not called.
+        loop {
+            let mut delivering = lock(&DELIVERING);
+            let recipient = with_registry(|registry| {
+                let linked = registry.recipients.get_mut(&binder)?;
+                if linked.is_empty() {
+                    None
+                } else {
+                    Some(linked.remove(0))
+                }
+            })
+            .flatten();
+            let recipient = match recipient {
+                Some(recipient) => recipient,
+                None => break,
+            };
+            *delivering = Some((thread::current().id(), recipient.cookie));
+            drop(delivering);
+
+            unsafe {
+                // Safety: The cookie and callback were taken from a live
+                // `DeathRecipient` in `link_to_death`. A `DeathRecipient`
+                // removes itself from the registry on drop, and waits for us
+                // to clear `DELIVERING` if it is dropped by another thread, so
+                // the cookie is still valid here.
+                (recipient.binder_died)(recipient.cookie as *mut c_void);
+            }
+
+            *lock(&DELIVERING) = None;
+            DELIVERED.notify_all();
+        }
+        with_registry(|registry| registry.recipients.remove(&binder));
+        Ok(())
+    }
+}
+
+impl Drop for FakeServiceManager {
+    fn drop(&mut self) {
+        INSTALLED.store(false, Ordering::Release);
+        // Drop the registered services outside of the lock, since destroying
+        // a service runs arbitrary user code.
+        let registry = lock(&REGISTRY).take();
+        drop(registry);
+    }
+}
+
+/// Returns true if `binder` is hosted by the installed fake service manager.
+///
+/// Used by [`declare_binder_interface!`] to hand out proxies for fake
+/// services, so that calls to them go through `transact`.
+#[doc(hidden)]
+pub fn is_intercepted(binder: &SpIBinder) -> bool {
+    let binder = binder.as_native() as usize;
+    with_registry(|registry| registry.is_known(binder)).unwrap_or(false)
+}
+
+pub(crate) fn is_installed() -> bool {
+    INSTALLED.load(Ordering::Acquire)
+}
+
+pub(crate) fn add_service(identifier: &str, binder: SpIBinder) -> Result<()> {
+    with_registry(|registry| registry.services.insert(identifier.to_owned(), binder))
+        .ok_or(StatusCode::NO_INIT)
+        .map(|_| ())
+}
+
+pub(crate) fn get_service(identifier: &str) -> Option<SpIBinder> {
+    with_registry(|registry| registry.services.get(identifier).cloned()).flatten()
+}
+
+pub(crate) fn list_services() -> Vec<String> {
+    with_registry(|registry| registry.services.keys().cloned().collect()).unwrap_or_default()
+}
+
+/// Check whether a transaction to `binder` should fail.
+pub(crate) fn check_transaction(binder: *const sys::AIBinder) -> Result<()> {
+    let binder = binder as usize;
+    with_registry(|registry| {
+        if registry.dead.contains_key(&binder) {
+            Err(StatusCode::DEAD_OBJECT)
+        } else {
+            registry.errors.get(&binder).map_or(Ok(()), |e| Err(*e))
+        }
+    })
+    .unwrap_or(Ok(()))
+}
+
+pub(crate) fn is_dead(binder: *const sys::AIBinder) -> bool {
+    let binder = binder as usize;
+    with_registry(|registry| registry.dead.contains_key(&binder)).unwrap_or(false)
+}
+
+/// Link a death recipient to a binder hosted by the fake.
+///
+/// Returns `None` if the binder is not hosted by the fake, in which case the
+/// real binder library should handle the request.
+pub(crate) fn link_to_death(
+    binder: *const sys::AIBinder,
+    recipient: &DeathRecipient,
+) -> Option<Result<()>> {
+    let binder = binder as usize;
+    with_registry(|registry| {
+        if !registry.is_known(binder) {
+            return None;
+        }
+        if registry.dead.contains_key(&binder) {
+            return Some(Err(StatusCode::DEAD_OBJECT));
+        }
+        registry
+            .recipients
+            .entry(binder)
+            .or_default()
+            .push(LinkedRecipient {
+                cookie: recipient.get_cookie() as usize,
+                binder_died: recipient.get_callback(),
+            });
+        Some(Ok(()))
+    })
+    .flatten()
+}
+
+/// Unlink a death recipient from a binder hosted by the fake.
+///
+/// Returns `None` if the binder is not hosted by the fake.
+pub(crate) fn unlink_to_death(
+    binder: *const sys::AIBinder,
+    recipient: &DeathRecipient,
+) -> Option<Result<()>> {
+    let binder = binder as usize;
+    let cookie = recipient.get_cookie() as usize;
+    with_registry(|registry| {
+        if !registry.is_known(binder) {
+            return None;
+        }
+        let linked = registry.recipients.entry(binder).or_default();
+        let len = linked.len();
+        linked.retain(|r| r.cookie != cookie);
+        if linked.len() == len {
+            Some(Err(StatusCode::NAME_NOT_FOUND))
+        } else {
+            Some(Ok(()))
+        }
+    })
+    .flatten()
+}
+
+/// Forget a death recipient that is being destroyed.
+pub(crate) fn forget_recipient(recipient: &DeathRecipient) {
+    if !is_installed() {
+        return;
+    }
+    let cookie = recipient.get_cookie() as usize;
+    let mut delivering = lock(&DELIVERING);
+    // A callback may drop other recipients, but another thread must wait for
+    // the callback of this recipient to return.
+    while matches!(*delivering, Some((thread, running))
+        if running == cookie && thread != thread::current().id())
+    {
+        delivering = DELIVERED
+            .wait(delivering)
+            .unwrap_or_else(|e| e.into_inner());
+    }
+    with_registry(|registry| {
+        for linked in registry.recipients.values_mut() {
+            linked.retain(|r| r.cookie != cookie);
+        }
+    });
+}
+
+#[test]
+fn test_fake_service_registry() {
+    use crate::binder::Interface;
+    use crate::native::Binder;
+
+    let sm = FakeServiceManager::install();
+    let service = Binder::new(()).as_binder();
+
+    crate::add_service("fake_registry_test", service.clone()).unwrap();
+    assert_eq!(crate::get_service("fake_registry_test"), Some(service.clone()));
+    assert_eq!(crate::get_service("fake_registry_missing"), None);
+    assert_eq!(crate::check_service("fake_registry_test"), Some(service));
+    assert_eq!(crate::check_service("fake_registry_missing"), None);
+    assert_eq!(sm.list_services(), ["fake_registry_test"]);
+    assert_eq!(crate::list_services(), Ok(vec!["fake_registry_test".to_owned()]));
+}
+
+#[test]
+fn test_fake_transaction_errors() {
+    use crate::binder::{IBinder, Interface};
+    use crate::native::Binder;
+
+    let sm = FakeServiceManager::install();
+    let service = Binder::new(()).as_binder();
+    sm.add_service("fake_errors_test", service.clone());
+
+    assert!(service
+        .transact(SpIBinder::FIRST_CALL_TRANSACTION, 0, |_| Ok(()))
+        .is_ok());
+
+    sm.set_transaction_error("fake_errors_test", Some(StatusCode::TIMED_OUT))
+        .unwrap();
+    assert_eq!(
+        service
+            .transact(SpIBinder::FIRST_CALL_TRANSACTION, 0, |_| Ok(()))
+            .err(),
+        Some(StatusCode::TIMED_OUT)
+    );
+
+    sm.set_transaction_error("fake_errors_test", None).unwrap();
+    assert!(service
+        .transact(SpIBinder::FIRST_CALL_TRANSACTION, 0, |_| Ok(()))
+        .is_ok());
+
+    assert_eq!(
+        sm.set_transaction_error("fake_errors_missing", Some(StatusCode::DEAD_OBJECT)),
+        Err(StatusCode::NAME_NOT_FOUND)
+    );
+}
+
+#[test]
+fn test_fake_death_notifications() {
+    use crate::binder::{IBinder, Interface};
+    use crate::native::Binder;
+    use std::sync::Arc;
+
+    let sm = FakeServiceManager::install();
+    let mut service = Binder::new(()).as_binder();
+    sm.add_service("fake_death_test", service.clone());
+
+    let died = Arc::new(AtomicBool::new(false));
+    let mut recipient = {
+        let died = died.clone();
+        DeathRecipient::new(move || died.store(true, Ordering::Relaxed))
+    };
+    service.link_to_death(&mut recipient).unwrap();
+
+    sm.kill_service("fake_death_test").unwrap();
+
+    assert!(died.load(Ordering::Relaxed));
+    assert!(!service.is_binder_alive());
+    assert_eq!(service.ping_binder(), Err(StatusCode::DEAD_OBJECT));
+    assert_eq!(crate::get_service("fake_death_test"), None);
+    assert_eq!(
+        service.link_to_death(&mut recipient),
+        Err(StatusCode::DEAD_OBJECT)
+    );
+}
+
+#[test]
+fn test_fake_death_callback_drops_recipient() {
+    use crate::binder::{IBinder, Interface};
+    use crate::native::Binder;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    thread_local! {
+        static OTHER: RefCell<Option<DeathRecipient>> = const { RefCell::new(None) };
+    }
+
+    let sm = FakeServiceManager::install();
+    let mut service = Binder::new(()).as_binder();
+    sm.add_service("fake_death_drop_test", service.clone());
+
+    let other_died = Arc::new(AtomicBool::new(false));
+    let mut other = {
+        let other_died = other_died.clone();
+        DeathRecipient::new(move || other_died.store(true, Ordering::Relaxed))
+    };
+    let mut recipient = DeathRecipient::new(|| OTHER.with(|other| drop(other.take())));
+    service.link_to_death(&mut recipient).unwrap();
+    service.link_to_death(&mut other).unwrap();
+    OTHER.with(|slot| *slot.borrow_mut() = Some(other));
+
+    sm.kill_service("fake_death_drop_test").unwrap();
+
+    assert!(OTHER.with(|other| other.borrow().is_none()));
+    assert!(!other_died.load(Ordering::Relaxed));
+}
@@ -0,0 +1,86 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Fuzzer for the `Deserialize` implementations in the binder crate.
+//!
+//! The first byte of the input selects the type to read, and the rest is the
+//! parcel data.
+
+#![no_main]
+
+use binder::parcel::{Deserialize, ParcelFileDescriptor};
+use binder::{fuzz, SpIBinder, Status};
+use libfuzzer_sys::fuzz_target;
+
+/// Read one value of type `D` and then keep reading values of the same type
+/// until the data runs out, so that the fuzzer also exercises reads that
+/// start in the middle of malformed data.
+fn read_all<D: Deserialize>(data: &[u8]) {
+    if let Ok(parcel) = fuzz::parcel_from_bytes(data) {
+        while parcel.read::<D>().is_ok() {}
+    }
+}
+
+macro_rules! readers {
+    ($($ty:ty),* $(,)?) => {
+        &[$(read_all::<$ty>),*]
+    };
+}
+
+const READERS: &[fn(&[u8])] = readers![
+    bool,
+    u8,
+    i8,
+    u16,
+    i16,
+    u32,
+    i32,
+    u64,
+    i64,
+    f32,
+    f64,
+    String,
+    Option<String>,
+    Vec<bool>,
+    Vec<u8>,
+    Vec<i8>,
+    Vec<u16>,
+    Vec<i16>,
+    Vec<u32>,
+    Vec<i32>,
+    Vec<u64>,
+    Vec<i64>,
+    Vec<f32>,
+    Vec<f64>,
+    Vec<String>,
+    Vec<Option<String>>,
+    Option<Vec<u8>>,
+    Option<Vec<i32>>,
+    Option<Vec<Option<String>>>,
+    Status,
+    ParcelFileDescriptor,
+    Option<ParcelFileDescriptor>,
+    Vec<Option<ParcelFileDescriptor>>,
+    SpIBinder,
+    Option<SpIBinder>,
+    Vec<Option<SpIBinder>>,
+];
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((selector, data)) = data.split_first() {
+        READERS[*selector as usize % READERS.len()](data);
+    }
+});
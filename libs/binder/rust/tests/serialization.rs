@@ -72,9 +72,65 @@ impl ReadParcelTest for BpReadParcelTest {}
 
 impl ReadParcelTest for () {}
 
-#[allow(clippy::float_cmp)]
 fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
                parcel: &Parcel, reply: &mut Parcel) -> Result<()> {
+    match code {
+        bindings::Transaction_TEST_FILE_DESCRIPTOR => {
+            let file1 = parcel.read::<ParcelFileDescriptor>()?;
+            let file2 = parcel.read::<ParcelFileDescriptor>()?;
+            let files = parcel.read::<Vec<Option<ParcelFileDescriptor>>>()?;
+
+            reply.write(&file1)?;
+            reply.write(&file2)?;
+            reply.write(&files)?;
+        }
+        bindings::Transaction_TEST_IBINDER => {
+            assert!(parcel.read::<Option<SpIBinder>>()?.is_some());
+            assert!(parcel.read::<Option<SpIBinder>>()?.is_none());
+            let ibinders = parcel.read::<Option<Vec<Option<SpIBinder>>>>()?.unwrap();
+            assert_eq!(ibinders.len(), 2);
+            assert!(ibinders[0].is_some());
+            assert!(ibinders[1].is_none());
+            assert!(parcel.read::<Option<Vec<Option<SpIBinder>>>>()?.is_none());
+
+            let service = unsafe {
+                SERVICE
+                    .as_ref()
+                    .expect("Global binder service not initialized")
+                    .clone()
+            };
+            reply.write(&service)?;
+            reply.write(&(None as Option<&SpIBinder>))?;
+            reply.write(&[Some(&service), None][..])?;
+            reply.write(&(None as Option<Vec<Option<&SpIBinder>>>))?;
+        }
+        bindings::Transaction_TEST_FAIL => {
+            return Err(StatusCode::FAILED_TRANSACTION)
+        }
+        _ => {
+            // The in-memory parcel backend must read the data written by
+            // libbinder and write exactly the same reply bytes as
+            // libbinder_ndk.
+            let request = Parcel::from_bytes(parcel_data(parcel, parcel.get_data_position())?);
+            read_request(code, &request)?;
+            assert_eq!(request.read::<i32>(), Err(StatusCode::NOT_ENOUGH_DATA));
+
+            read_request(code, parcel)?;
+            write_reply(code, reply)?;
+
+            let mut memory_reply = Parcel::new_in_memory();
+            write_reply(code, &mut memory_reply)?;
+            assert_eq!(memory_reply.as_bytes().unwrap(), &parcel_data(reply, 0)?[..]);
+        }
+    }
+
+    assert_eq!(parcel.read::<i32>(), Err(StatusCode::NOT_ENOUGH_DATA));
+    Ok(())
+}
+
+/// Read and check the request data of a plain data transaction.
+#[allow(clippy::float_cmp)]
+fn read_request(code: TransactionCode, parcel: &Parcel) -> Result<()> {
     match code {
         bindings::Transaction_TEST_BOOL => {
             assert_eq!(parcel.read::<bool>()?, true);
@@ -83,11 +139,6 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
                 bindings::TESTDATA_BOOL
             });
             assert_eq!(parcel.read::<Option<Vec<bool>>>()?, None);
-
-            reply.write(&true)?;
-            reply.write(&false)?;
-            reply.write(&unsafe { bindings::TESTDATA_BOOL }[..])?;
-            reply.write(&(None as Option<Vec<bool>>))?;
         }
         bindings::Transaction_TEST_BYTE => {
             assert_eq!(parcel.read::<i8>()?, 0);
@@ -96,13 +147,6 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
             assert_eq!(parcel.read::<Vec<i8>>()?, unsafe { bindings::TESTDATA_I8 });
             assert_eq!(parcel.read::<Vec<u8>>()?, unsafe { bindings::TESTDATA_U8 });
             assert_eq!(parcel.read::<Option<Vec<i8>>>()?, None);
-
-            reply.write(&0i8)?;
-            reply.write(&1i8)?;
-            reply.write(&i8::max_value())?;
-            reply.write(&unsafe { bindings::TESTDATA_I8 }[..])?;
-            reply.write(&unsafe { bindings::TESTDATA_U8 }[..])?;
-            reply.write(&(None as Option<Vec<i8>>))?;
         }
         bindings::Transaction_TEST_U16 => {
             assert_eq!(parcel.read::<u16>()?, 0);
@@ -112,12 +156,6 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
                 bindings::TESTDATA_CHARS
             });
             assert_eq!(parcel.read::<Option<Vec<u16>>>()?, None);
-
-            reply.write(&0u16)?;
-            reply.write(&1u16)?;
-            reply.write(&u16::max_value())?;
-            reply.write(&unsafe { bindings::TESTDATA_CHARS }[..])?;
-            reply.write(&(None as Option<Vec<u16>>))?;
         }
         bindings::Transaction_TEST_I32 => {
             assert_eq!(parcel.read::<i32>()?, 0);
@@ -127,12 +165,6 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
                 bindings::TESTDATA_I32
             });
             assert_eq!(parcel.read::<Option<Vec<i32>>>()?, None);
-
-            reply.write(&0i32)?;
-            reply.write(&1i32)?;
-            reply.write(&i32::max_value())?;
-            reply.write(&unsafe { bindings::TESTDATA_I32 }[..])?;
-            reply.write(&(None as Option<Vec<i32>>))?;
         }
         bindings::Transaction_TEST_I64 => {
             assert_eq!(parcel.read::<i64>()?, 0);
@@ -142,12 +174,6 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
                 bindings::TESTDATA_I64
             });
             assert_eq!(parcel.read::<Option<Vec<i64>>>()?, None);
-
-            reply.write(&0i64)?;
-            reply.write(&1i64)?;
-            reply.write(&i64::max_value())?;
-            reply.write(&unsafe { bindings::TESTDATA_I64 }[..])?;
-            reply.write(&(None as Option<Vec<i64>>))?;
         }
         bindings::Transaction_TEST_U64 => {
             assert_eq!(parcel.read::<u64>()?, 0);
@@ -157,12 +183,6 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
                 bindings::TESTDATA_U64
             });
             assert_eq!(parcel.read::<Option<Vec<u64>>>()?, None);
-
-            reply.write(&0u64)?;
-            reply.write(&1u64)?;
-            reply.write(&u64::max_value())?;
-            reply.write(&unsafe { bindings::TESTDATA_U64 }[..])?;
-            reply.write(&(None as Option<Vec<u64>>))?;
         }
         bindings::Transaction_TEST_F32 => {
             assert_eq!(parcel.read::<f32>()?, 0f32);
@@ -170,10 +190,6 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
             assert!(floats[0].is_nan());
             assert_eq!(floats[1..], unsafe { bindings::TESTDATA_FLOAT }[1..]);
             assert_eq!(parcel.read::<Option<Vec<f32>>>()?, None);
-
-            reply.write(&0f32)?;
-            reply.write(&unsafe { bindings::TESTDATA_FLOAT }[..])?;
-            reply.write(&(None as Option<Vec<f32>>))?;
         }
         bindings::Transaction_TEST_F64 => {
             assert_eq!(parcel.read::<f64>()?, 0f64);
@@ -181,10 +197,6 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
             assert!(doubles[0].is_nan());
             assert_eq!(doubles[1..], unsafe { bindings::TESTDATA_DOUBLE }[1..]);
             assert_eq!(parcel.read::<Option<Vec<f64>>>()?, None);
-
-            reply.write(&0f64)?;
-            reply.write(&unsafe { bindings::TESTDATA_DOUBLE }[..])?;
-            reply.write(&(None as Option<Vec<f64>>))?;
         }
         bindings::Transaction_TEST_STRING => {
             let s: Option<String> = parcel.read()?;
@@ -206,7 +218,84 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
             }
             let s: Option<Vec<Option<String>>> = parcel.read()?;
             assert_eq!(s, None);
+        }
+        bindings::Transaction_TEST_STATUS => {
+            let status: Status = parcel.read()?;
+            assert!(status.is_ok());
+            let status: Status = parcel.read()?;
+            assert_eq!(status.exception_code(), ExceptionCode::NULL_POINTER);
+            assert_eq!(
+                status.get_description(),
+                "Status(-4, EX_NULL_POINTER): 'a status message'"
+            );
+            let status: Status = parcel.read()?;
+            assert_eq!(status.service_specific_error(), 42);
+            assert_eq!(
+                status.get_description(),
+                "Status(-8, EX_SERVICE_SPECIFIC): '42: a service-specific error'"
+            );
+        }
+        _ => return Err(StatusCode::UNKNOWN_TRANSACTION),
+    }
+    Ok(())
+}
 
+/// Write the reply data of a plain data transaction.
+fn write_reply(code: TransactionCode, reply: &mut Parcel) -> Result<()> {
+    match code {
+        bindings::Transaction_TEST_BOOL => {
+            reply.write(&true)?;
+            reply.write(&false)?;
+            reply.write(&unsafe { bindings::TESTDATA_BOOL }[..])?;
+            reply.write(&(None as Option<Vec<bool>>))?;
+        }
+        bindings::Transaction_TEST_BYTE => {
+            reply.write(&0i8)?;
+            reply.write(&1i8)?;
+            reply.write(&i8::max_value())?;
+            reply.write(&unsafe { bindings::TESTDATA_I8 }[..])?;
+            reply.write(&unsafe { bindings::TESTDATA_U8 }[..])?;
+            reply.write(&(None as Option<Vec<i8>>))?;
+        }
+        bindings::Transaction_TEST_U16 => {
+            reply.write(&0u16)?;
+            reply.write(&1u16)?;
+            reply.write(&u16::max_value())?;
+            reply.write(&unsafe { bindings::TESTDATA_CHARS }[..])?;
+            reply.write(&(None as Option<Vec<u16>>))?;
+        }
+        bindings::Transaction_TEST_I32 => {
+            reply.write(&0i32)?;
+            reply.write(&1i32)?;
+            reply.write(&i32::max_value())?;
+            reply.write(&unsafe { bindings::TESTDATA_I32 }[..])?;
+            reply.write(&(None as Option<Vec<i32>>))?;
+        }
+        bindings::Transaction_TEST_I64 => {
+            reply.write(&0i64)?;
+            reply.write(&1i64)?;
+            reply.write(&i64::max_value())?;
+            reply.write(&unsafe { bindings::TESTDATA_I64 }[..])?;
+            reply.write(&(None as Option<Vec<i64>>))?;
+        }
+        bindings::Transaction_TEST_U64 => {
+            reply.write(&0u64)?;
+            reply.write(&1u64)?;
+            reply.write(&u64::max_value())?;
+            reply.write(&unsafe { bindings::TESTDATA_U64 }[..])?;
+            reply.write(&(None as Option<Vec<u64>>))?;
+        }
+        bindings::Transaction_TEST_F32 => {
+            reply.write(&0f32)?;
+            reply.write(&unsafe { bindings::TESTDATA_FLOAT }[..])?;
+            reply.write(&(None as Option<Vec<f32>>))?;
+        }
+        bindings::Transaction_TEST_F64 => {
+            reply.write(&0f64)?;
+            reply.write(&unsafe { bindings::TESTDATA_DOUBLE }[..])?;
+            reply.write(&(None as Option<Vec<f64>>))?;
+        }
+        bindings::Transaction_TEST_STRING => {
             let strings: Vec<Option<String>> = unsafe {
                 bindings::TESTDATA_STRS
                     .iter()
@@ -226,51 +315,7 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
             reply.write(&strings)?;
             reply.write(&(None as Option<Vec<String>>))?;
         }
-        bindings::Transaction_TEST_FILE_DESCRIPTOR => {
-            let file1 = parcel.read::<ParcelFileDescriptor>()?;
-            let file2 = parcel.read::<ParcelFileDescriptor>()?;
-            let files = parcel.read::<Vec<Option<ParcelFileDescriptor>>>()?;
-
-            reply.write(&file1)?;
-            reply.write(&file2)?;
-            reply.write(&files)?;
-        }
-        bindings::Transaction_TEST_IBINDER => {
-            assert!(parcel.read::<Option<SpIBinder>>()?.is_some());
-            assert!(parcel.read::<Option<SpIBinder>>()?.is_none());
-            let ibinders = parcel.read::<Option<Vec<Option<SpIBinder>>>>()?.unwrap();
-            assert_eq!(ibinders.len(), 2);
-            assert!(ibinders[0].is_some());
-            assert!(ibinders[1].is_none());
-            assert!(parcel.read::<Option<Vec<Option<SpIBinder>>>>()?.is_none());
-
-            let service = unsafe {
-                SERVICE
-                    .as_ref()
-                    .expect("Global binder service not initialized")
-                    .clone()
-            };
-            reply.write(&service)?;
-            reply.write(&(None as Option<&SpIBinder>))?;
-            reply.write(&[Some(&service), None][..])?;
-            reply.write(&(None as Option<Vec<Option<&SpIBinder>>>))?;
-        }
         bindings::Transaction_TEST_STATUS => {
-            let status: Status = parcel.read()?;
-            assert!(status.is_ok());
-            let status: Status = parcel.read()?;
-            assert_eq!(status.exception_code(), ExceptionCode::NULL_POINTER);
-            assert_eq!(
-                status.get_description(),
-                "Status(-4, EX_NULL_POINTER): 'a status message'"
-            );
-            let status: Status = parcel.read()?;
-            assert_eq!(status.service_specific_error(), 42);
-            assert_eq!(
-                status.get_description(),
-                "Status(-8, EX_SERVICE_SPECIFIC): '42: a service-specific error'"
-            );
-
             reply.write(&Status::ok())?;
             reply.write(&Status::new_exception(
                 ExceptionCode::NULL_POINTER,
@@ -281,12 +326,24 @@ fn on_transact(_service: &dyn ReadParcelTest, code: TransactionCode,
                 Some(&CString::new("a service-specific error").unwrap()),
             ))?;
         }
-        bindings::Transaction_TEST_FAIL => {
-            return Err(StatusCode::FAILED_TRANSACTION)
-        }
         _ => return Err(StatusCode::UNKNOWN_TRANSACTION),
     }
-
-    assert_eq!(parcel.read::<i32>(), Err(StatusCode::NOT_ENOUGH_DATA));
     Ok(())
 }
+
+/// Returns the data of `parcel` from `start` to the end, leaving the data
+/// position unchanged.
+fn parcel_data(parcel: &Parcel, start: i32) -> Result<Vec<u8>> {
+    let position = parcel.get_data_position();
+    let mut data = vec![];
+    unsafe {
+        parcel.set_data_position(start)?;
+    }
+    while parcel.get_data_position() < parcel.get_data_size() {
+        data.extend_from_slice(&parcel.read::<u32>()?.to_ne_bytes());
+    }
+    unsafe {
+        parcel.set_data_position(position)?;
+    }
+    Ok(data)
+}